@@ -33,3 +33,6 @@ cat server.crt ca.crt > server.chain
 openssl asn1parse -in ca.crt -out ca.der
 openssl rsa -in server.key -out server.rsa
 rm *.csr ca.crt ca.key server.crt server.key
+
+# Only used to check that ECDSA keys are refused where RSA ones are required
+openssl genpkey -algorithm EC -pkeyopt ec_paramgen_curve:P-256 -outform DER -out ecdsa.der
@@ -0,0 +1,22 @@
+// Writes seed inputs for each target to corpus/<target>/; run from the fuzz/ directory
+extern crate quinn_fuzz;
+
+use std::fs;
+use std::io;
+use std::path::Path;
+
+fn write(target: &str, seeds: Vec<Vec<u8>>) -> io::Result<()> {
+    let dir = Path::new("corpus").join(target);
+    fs::create_dir_all(&dir)?;
+    for (i, seed) in seeds.iter().enumerate() {
+        fs::write(dir.join(format!("seed-{}", i)), seed)?;
+    }
+    Ok(())
+}
+
+fn main() -> io::Result<()> {
+    write("frame", quinn_fuzz::frames())?;
+    write("header", quinn_fuzz::headers())?;
+    write("transport_parameters", quinn_fuzz::transport_parameters())?;
+    Ok(())
+}
@@ -0,0 +1,18 @@
+#![no_main]
+extern crate bytes;
+#[macro_use]
+extern crate libfuzzer_sys;
+extern crate quinn;
+
+use bytes::Bytes;
+use quinn::fuzzing::{decode_all, Codec};
+
+fuzz_target!(|data: &[u8]| {
+    // Whatever decodes has to encode again
+    if let Ok(frames) = decode_all(&Bytes::from(data)) {
+        let mut buf = Vec::new();
+        for frame in frames {
+            frame.encode(&mut buf);
+        }
+    }
+});
@@ -0,0 +1,33 @@
+#![no_main]
+#[macro_use]
+extern crate libfuzzer_sys;
+extern crate quinn;
+
+use quinn::fuzzing::Packet;
+
+// The first byte picks the local connection ID length, which comes from configuration
+const CID_LENGTHS: [usize; 16] = [0, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15, 16, 17, 18];
+
+fuzz_target!(|data: &[u8]| {
+    if data.is_empty() {
+        return;
+    }
+    let cid_len = CID_LENGTHS[data[0] as usize % CID_LENGTHS.len()];
+    let mut buf = data[1..].to_vec();
+    // Walk through coalesced packets the way the endpoint does
+    while !buf.is_empty() {
+        let rest = {
+            let partial = match Packet::start_decode(&mut buf, cid_len) {
+                Ok(partial) => partial,
+                Err(_) => return,
+            };
+            let _ = partial.dst_cid();
+            match partial.split_coalesced() {
+                Ok((_, rest)) => rest.len(),
+                Err(_) => return,
+            }
+        };
+        let start = buf.len() - rest;
+        buf = buf[start..].to_vec();
+    }
+});
@@ -0,0 +1,16 @@
+#![no_main]
+#[macro_use]
+extern crate libfuzzer_sys;
+extern crate quinn;
+
+use quinn::fuzzing::{ClientTransportParameters, Codec, ServerTransportParameters};
+use std::io::Cursor;
+
+fuzz_target!(|data: &[u8]| {
+    if let Ok(params) = ClientTransportParameters::decode(&mut Cursor::new(data)) {
+        params.encode(&mut Vec::new());
+    }
+    if let Ok(params) = ServerTransportParameters::decode(&mut Cursor::new(data)) {
+        params.encode(&mut Vec::new());
+    }
+});
@@ -0,0 +1,238 @@
+use std::collections::BTreeMap;
+use std::time::{Duration, Instant};
+
+use frame::{Ack, AckFrame, EcnCounts};
+use parameters::DEFAULT_ACK_DELAY_EXPONENT;
+use socket::EcnCodepoint;
+
+pub struct AckTracker {
+    ranges: BTreeMap<u64, u64>,
+    largest_time: Option<Instant>,
+    unacked: usize,
+    threshold: usize,
+    immediate: bool,
+    deadline: Option<Instant>,
+    max_delay: Duration,
+    delay_exponent: u8,
+    ecn: Option<EcnCounts>,
+}
+
+impl AckTracker {
+    pub fn new(max_delay: Duration, threshold: usize) -> Self {
+        Self {
+            ranges: BTreeMap::new(),
+            largest_time: None,
+            unacked: 0,
+            threshold,
+            immediate: false,
+            deadline: None,
+            max_delay,
+            delay_exponent: DEFAULT_ACK_DELAY_EXPONENT,
+            ecn: None,
+        }
+    }
+
+    pub fn on_receive(&mut self, number: u64, ack_eliciting: bool, now: Instant) {
+        let largest = self.largest();
+        let in_order = largest.map_or(true, |largest| number == largest + 1);
+        self.insert(number);
+        if largest.map_or(true, |largest| number > largest) {
+            self.largest_time = Some(now);
+        }
+
+        if !ack_eliciting {
+            return;
+        }
+        self.unacked += 1;
+        // Reordering or loss is worth telling the peer about right away
+        if !in_order || self.unacked >= self.threshold {
+            self.immediate = true;
+        } else if self.deadline.is_none() {
+            self.deadline = Some(now + self.max_delay);
+        }
+    }
+
+    pub fn on_ecn(&mut self, ecn: EcnCodepoint) {
+        let counts = self.ecn.get_or_insert_with(EcnCounts::default);
+        match ecn {
+            EcnCodepoint::Ect0 => counts.ect0 += 1,
+            EcnCodepoint::Ect1 => counts.ect1 += 1,
+            EcnCodepoint::Ce => {
+                counts.ce += 1;
+                // Congestion feedback is only useful if it arrives quickly
+                self.immediate = true;
+            }
+        }
+    }
+
+    // The delay goes out in units of 2^exponent microseconds, as advertised to the peer
+    pub fn set_delay_exponent(&mut self, exponent: u8) {
+        self.delay_exponent = exponent;
+    }
+
+    pub fn pending(&self) -> bool {
+        self.unacked > 0
+    }
+
+    pub fn should_send(&self, now: Instant) -> bool {
+        self.immediate || self.deadline.map_or(false, |deadline| deadline <= now)
+    }
+
+    pub fn deadline(&self) -> Option<Instant> {
+        self.deadline
+    }
+
+    pub fn frame(&self, now: Instant) -> Option<AckFrame> {
+        let mut ranges = self.ranges.iter().rev();
+        let (&start, &largest) = ranges.next()?;
+        let mut blocks = vec![Ack::Ack(largest - start)];
+        let mut smallest = start;
+        for (&start, &end) in ranges {
+            blocks.push(Ack::Gap(smallest - end - 2));
+            blocks.push(Ack::Ack(end - start));
+            smallest = start;
+        }
+
+        let delay = self.largest_time
+            .map_or(Duration::from_secs(0), |time| now.duration_since(time));
+        let micros = delay.as_secs() * 1_000_000 + u64::from(delay.subsec_micros());
+        Some(AckFrame {
+            largest,
+            ack_delay: micros >> self.delay_exponent,
+            blocks,
+            ecn: self.ecn,
+        })
+    }
+
+    pub fn on_ack_sent(&mut self) {
+        self.unacked = 0;
+        self.immediate = false;
+        self.deadline = None;
+    }
+
+    fn largest(&self) -> Option<u64> {
+        self.ranges.values().next_back().cloned()
+    }
+
+    fn insert(&mut self, number: u64) {
+        let (mut start, mut end) = (number, number);
+        if let Some((&lower, &upper)) = self.ranges.range(..=number).next_back() {
+            if upper >= number {
+                return;
+            }
+            if upper + 1 == number {
+                start = lower;
+            }
+        }
+        let next = self.ranges.get(&(number + 1)).cloned();
+        if let Some(upper) = next {
+            self.ranges.remove(&(number + 1));
+            end = upper;
+        }
+        self.ranges.insert(start, end);
+
+        // Old ranges are the least useful to the peer, so those go first
+        if self.ranges.len() > MAX_ACK_RANGES {
+            let lowest = *self.ranges.keys().next().unwrap();
+            self.ranges.remove(&lowest);
+        }
+    }
+}
+
+pub const DEFAULT_ACK_THRESHOLD: usize = 2;
+const MAX_ACK_RANGES: usize = 32;
+
+#[cfg(test)]
+mod tests {
+    use super::{AckTracker, DEFAULT_ACK_THRESHOLD};
+    use frame::{Ack, EcnCounts};
+    use socket::EcnCodepoint;
+    use std::time::{Duration, Instant};
+
+    #[test]
+    fn test_ack_blocks() {
+        let now = Instant::now();
+        let mut acks = AckTracker::new(Duration::from_millis(25), DEFAULT_ACK_THRESHOLD);
+        for number in &[10, 9, 8, 5, 4, 12] {
+            acks.on_receive(*number, false, now);
+        }
+        let frame = acks.frame(now).unwrap();
+        assert_eq!(frame.largest, 12);
+        assert_eq!(
+            frame.blocks,
+            vec![Ack::Ack(0), Ack::Gap(0), Ack::Ack(2), Ack::Gap(1), Ack::Ack(1)]
+        );
+        assert_eq!(frame.ranges(), vec![(12, 12), (8, 10), (4, 5)]);
+
+        acks.on_receive(11, false, now);
+        assert_eq!(acks.frame(now).unwrap().ranges(), vec![(8, 12), (4, 5)]);
+    }
+
+    #[test]
+    fn test_delayed_ack() {
+        let now = Instant::now();
+        let mut acks = AckTracker::new(Duration::from_millis(25), DEFAULT_ACK_THRESHOLD);
+        acks.on_receive(0, false, now);
+        assert!(!acks.pending());
+
+        acks.on_receive(1, true, now);
+        assert!(acks.pending());
+        assert!(!acks.should_send(now));
+        assert_eq!(acks.deadline(), Some(now + Duration::from_millis(25)));
+        assert!(acks.should_send(now + Duration::from_millis(25)));
+
+        acks.on_receive(2, true, now);
+        assert!(acks.should_send(now));
+        acks.on_ack_sent();
+        assert!(!acks.pending());
+
+        acks.on_receive(5, true, now);
+        assert!(acks.should_send(now));
+    }
+
+    #[test]
+    fn test_ack_frequency() {
+        let now = Instant::now();
+        let mut acks = AckTracker::new(Duration::from_millis(25), 4);
+        for number in 0..3 {
+            acks.on_receive(number, true, now);
+        }
+        assert!(!acks.should_send(now));
+        // Packets that only acknowledge never count towards the threshold
+        acks.on_receive(3, false, now);
+        assert!(!acks.should_send(now));
+        acks.on_receive(4, true, now);
+        assert!(acks.should_send(now));
+    }
+
+    #[test]
+    fn test_ack_delay_exponent() {
+        let now = Instant::now();
+        let mut acks = AckTracker::new(Duration::from_millis(25), DEFAULT_ACK_THRESHOLD);
+        acks.on_receive(0, true, now);
+        let later = now + Duration::from_millis(8);
+        assert_eq!(acks.frame(later).unwrap().ack_delay, 1000);
+        acks.set_delay_exponent(0);
+        assert_eq!(acks.frame(later).unwrap().ack_delay, 8000);
+    }
+
+    #[test]
+    fn test_ecn_counts() {
+        let now = Instant::now();
+        let mut acks = AckTracker::new(Duration::from_millis(25), DEFAULT_ACK_THRESHOLD);
+        acks.on_receive(0, true, now);
+        assert_eq!(acks.frame(now).unwrap().ecn, None);
+
+        acks.on_ecn(EcnCodepoint::Ect0);
+        acks.on_ecn(EcnCodepoint::Ect0);
+        assert!(!acks.should_send(now));
+        acks.on_ecn(EcnCodepoint::Ce);
+        assert!(acks.should_send(now));
+        let counts = EcnCounts {
+            ect0: 2,
+            ect1: 0,
+            ce: 1,
+        };
+        assert_eq!(acks.frame(now).unwrap().ecn, Some(counts));
+    }
+}
@@ -0,0 +1,212 @@
+use bytes::Bytes;
+
+use std::cmp;
+use std::collections::BTreeMap;
+
+use super::{QuicError, QuicResult, TransportError};
+
+pub struct Assembler {
+    offset: u64,
+    chunks: BTreeMap<u64, Bytes>,
+    // Ranges past the offset that were already handed out by unordered reads
+    delivered: BTreeMap<u64, u64>,
+    buffered: usize,
+    limit: usize,
+}
+
+impl Assembler {
+    pub fn new(limit: usize) -> Self {
+        Self {
+            offset: 0,
+            chunks: BTreeMap::new(),
+            delivered: BTreeMap::new(),
+            buffered: 0,
+            limit,
+        }
+    }
+
+    pub fn offset(&self) -> u64 {
+        self.offset
+    }
+
+    pub fn buffered(&self) -> usize {
+        self.buffered
+    }
+
+    pub fn set_limit(&mut self, limit: usize) {
+        self.limit = limit;
+    }
+
+    pub fn insert(&mut self, offset: u64, data: &Bytes) -> QuicResult<()> {
+        let end = offset + data.len() as u64;
+        let mut cursor = cmp::max(offset, self.offset);
+        if cursor >= end {
+            return Ok(());
+        }
+
+        // Only keep the parts we don't have yet; data already buffered or delivered wins
+        let mut pieces = Vec::new();
+        for (start, stop) in self.occupied(cursor, end) {
+            if start > cursor {
+                pieces.push((cursor, start));
+            }
+            cursor = cmp::max(cursor, stop);
+        }
+        if cursor < end {
+            pieces.push((cursor, end));
+        }
+
+        let new = pieces
+            .iter()
+            .map(|&(start, end)| (end - start) as usize)
+            .sum::<usize>();
+        if self.buffered + new > self.limit {
+            return Err(QuicError::Transport(
+                TransportError::FlowControlError,
+                format!("stream receive buffer limit {} exceeded", self.limit),
+            ));
+        }
+
+        for (start, stop) in pieces {
+            let from = (start - offset) as usize;
+            let to = (stop - offset) as usize;
+            self.chunks.insert(start, data.slice(from, to));
+        }
+        self.buffered += new;
+        Ok(())
+    }
+
+    pub fn read(&mut self) -> Option<Bytes> {
+        let data = self.chunks.remove(&self.offset)?;
+        self.buffered -= data.len();
+        let end = self.offset + data.len() as u64;
+        self.advance(end);
+        Some(data)
+    }
+
+    // The lowest buffered chunk and where it belongs, whether or not anything before it is missing
+    pub fn read_unordered(&mut self) -> Option<(u64, Bytes)> {
+        let start = *self.chunks.keys().next()?;
+        let data = self.chunks.remove(&start).unwrap();
+        self.buffered -= data.len();
+        let end = start + data.len() as u64;
+        if start == self.offset {
+            self.advance(end);
+        } else {
+            self.delivered.insert(start, end);
+        }
+        Some((start, data))
+    }
+
+    pub fn clear(&mut self) {
+        self.chunks.clear();
+        self.delivered.clear();
+        self.buffered = 0;
+    }
+
+    // Ranges are split at each other's boundaries on insert, so anything delivered earlier
+    // that has become contiguous starts exactly where the last one ended
+    fn advance(&mut self, mut end: u64) {
+        while let Some(next) = self.delivered.remove(&end) {
+            end = next;
+        }
+        self.offset = end;
+    }
+
+    // Buffered and delivered ranges overlapping [start, end), in order
+    fn occupied(&self, start: u64, end: u64) -> Vec<(u64, u64)> {
+        let chunks = self.chunks.range(..start).next_back().into_iter();
+        let chunks = chunks.chain(self.chunks.range(start..end));
+        let chunks = chunks.map(|(&from, chunk)| (from, from + chunk.len() as u64));
+        let delivered = self.delivered.range(..start).next_back().into_iter();
+        let delivered = delivered.chain(self.delivered.range(start..end));
+        let delivered = delivered.map(|(&from, &to)| (from, to));
+        let mut ranges = chunks
+            .chain(delivered)
+            .filter(|&(_, to)| to > start)
+            .collect::<Vec<_>>();
+        ranges.sort();
+        ranges
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Assembler;
+    use bytes::Bytes;
+
+    fn insert(buf: &mut Assembler, offset: u64, data: &'static [u8]) -> bool {
+        buf.insert(offset, &Bytes::from_static(data)).is_ok()
+    }
+
+    #[test]
+    fn test_out_of_order() {
+        let mut buf = Assembler::new(1024);
+        assert!(insert(&mut buf, 4, b"efgh"));
+        assert_eq!(buf.read(), None);
+        assert!(insert(&mut buf, 0, b"abcd"));
+        assert_eq!(buf.read(), Some(Bytes::from_static(b"abcd")));
+        assert_eq!(buf.read(), Some(Bytes::from_static(b"efgh")));
+        assert_eq!(buf.read(), None);
+        assert_eq!(buf.offset(), 8);
+        assert_eq!(buf.buffered(), 0);
+    }
+
+    #[test]
+    fn test_overlapping() {
+        let mut buf = Assembler::new(1024);
+        assert!(insert(&mut buf, 2, b"cd"));
+        assert!(insert(&mut buf, 6, b"gh"));
+        assert!(insert(&mut buf, 0, b"abcdefghij"));
+        assert_eq!(buf.buffered(), 10);
+
+        let mut out = Vec::new();
+        while let Some(data) = buf.read() {
+            out.extend_from_slice(&data);
+        }
+        assert_eq!(&out[..], b"abcdefghij");
+
+        assert!(insert(&mut buf, 4, b"efghijkl"));
+        assert_eq!(buf.read(), Some(Bytes::from_static(b"kl")));
+    }
+
+    #[test]
+    fn test_contained() {
+        let mut buf = Assembler::new(1024);
+        assert!(insert(&mut buf, 2, b"cdefgh"));
+        assert!(insert(&mut buf, 4, b"ef"));
+        assert_eq!(buf.buffered(), 6);
+        assert!(insert(&mut buf, 0, b"ab"));
+        assert_eq!(buf.read(), Some(Bytes::from_static(b"ab")));
+        assert_eq!(buf.read(), Some(Bytes::from_static(b"cdefgh")));
+        assert_eq!(buf.read(), None);
+    }
+
+    #[test]
+    fn test_unordered() {
+        let mut buf = Assembler::new(1024);
+        assert!(insert(&mut buf, 4, b"efgh"));
+        assert!(insert(&mut buf, 10, b"kl"));
+        assert_eq!(buf.read_unordered(), Some((4, Bytes::from_static(b"efgh"))));
+        assert_eq!(buf.offset(), 0);
+
+        // Retransmissions of data already handed out aren't delivered again
+        assert!(insert(&mut buf, 0, b"abcdefghij"));
+        assert_eq!(buf.buffered(), 8);
+        assert_eq!(buf.read_unordered(), Some((0, Bytes::from_static(b"abcd"))));
+        assert_eq!(buf.offset(), 8);
+        assert_eq!(buf.read(), Some(Bytes::from_static(b"ij")));
+        assert_eq!(buf.read(), Some(Bytes::from_static(b"kl")));
+        assert_eq!(buf.offset(), 12);
+        assert_eq!(buf.buffered(), 0);
+    }
+
+    #[test]
+    fn test_limit() {
+        let mut buf = Assembler::new(8);
+        assert!(insert(&mut buf, 4, b"efgh"));
+        assert!(!insert(&mut buf, 8, b"ijklm"));
+        assert!(insert(&mut buf, 0, b"abcdefgh"));
+        assert_eq!(buf.buffered(), 8);
+    }
+}
@@ -0,0 +1,228 @@
+// Header protection needs the raw block ciphers behind the negotiated AEAD, which ring doesn't
+// expose, so the two that QUIC's cipher suites use are implemented here
+
+pub struct Aes {
+    round_keys: Vec<[u8; 16]>,
+    sbox: [u8; 256],
+}
+
+impl Aes {
+    // Takes a 128 or 256 bit key
+    pub fn new(key: &[u8]) -> Option<Self> {
+        if key.len() != 16 && key.len() != 32 {
+            return None;
+        }
+        let sbox = sbox();
+        let nk = key.len() / 4;
+        let rounds = nk + 6;
+        let mut words = vec![[0u8; 4]; 4 * (rounds + 1)];
+        for (i, word) in key.chunks(4).enumerate() {
+            words[i].copy_from_slice(word);
+        }
+        let mut rcon = 1u8;
+        for i in nk..words.len() {
+            let mut temp = words[i - 1];
+            if i % nk == 0 {
+                temp = [
+                    sbox[temp[1] as usize] ^ rcon,
+                    sbox[temp[2] as usize],
+                    sbox[temp[3] as usize],
+                    sbox[temp[0] as usize],
+                ];
+                rcon = xtime(rcon);
+            } else if nk > 6 && i % nk == 4 {
+                for byte in temp.iter_mut() {
+                    *byte = sbox[*byte as usize];
+                }
+            }
+            for j in 0..4 {
+                words[i][j] = words[i - nk][j] ^ temp[j];
+            }
+        }
+
+        let round_keys = words
+            .chunks(4)
+            .map(|chunk| {
+                let mut round_key = [0u8; 16];
+                for (j, word) in chunk.iter().enumerate() {
+                    round_key[4 * j..4 * j + 4].copy_from_slice(word);
+                }
+                round_key
+            })
+            .collect();
+        Some(Aes { round_keys, sbox })
+    }
+
+    pub fn encrypt_block(&self, block: &mut [u8; 16]) {
+        let rounds = self.round_keys.len() - 1;
+        add_round_key(block, &self.round_keys[0]);
+        for round_key in &self.round_keys[1..rounds] {
+            self.sub_bytes(block);
+            shift_rows(block);
+            mix_columns(block);
+            add_round_key(block, round_key);
+        }
+        self.sub_bytes(block);
+        shift_rows(block);
+        add_round_key(block, &self.round_keys[rounds]);
+    }
+
+    fn sub_bytes(&self, block: &mut [u8; 16]) {
+        for byte in block.iter_mut() {
+            *byte = self.sbox[*byte as usize];
+        }
+    }
+}
+
+// Multiplicative inverse in GF(2^8) followed by the affine transform, walking the field with
+// the generator 3
+fn sbox() -> [u8; 256] {
+    let mut sbox = [0u8; 256];
+    let (mut p, mut q) = (1u8, 1u8);
+    loop {
+        p ^= xtime(p);
+        q ^= q << 1;
+        q ^= q << 2;
+        q ^= q << 4;
+        if q & 0x80 != 0 {
+            q ^= 0x09;
+        }
+        let affine = q ^ q.rotate_left(1) ^ q.rotate_left(2) ^ q.rotate_left(3) ^ q.rotate_left(4);
+        sbox[p as usize] = affine ^ 0x63;
+        if p == 1 {
+            break;
+        }
+    }
+    sbox[0] = 0x63;
+    sbox
+}
+
+fn xtime(x: u8) -> u8 {
+    (x << 1) ^ if x & 0x80 != 0 { 0x1b } else { 0 }
+}
+
+fn add_round_key(block: &mut [u8; 16], round_key: &[u8; 16]) {
+    for (byte, key) in block.iter_mut().zip(round_key.iter()) {
+        *byte ^= key;
+    }
+}
+
+// The state is stored column by column, so row r of column c is at 4 * c + r
+fn shift_rows(block: &mut [u8; 16]) {
+    let old = *block;
+    for c in 0..4 {
+        for r in 1..4 {
+            block[4 * c + r] = old[4 * ((c + r) % 4) + r];
+        }
+    }
+}
+
+fn mix_columns(block: &mut [u8; 16]) {
+    for column in block.chunks_mut(4) {
+        let a = [column[0], column[1], column[2], column[3]];
+        let all = a[0] ^ a[1] ^ a[2] ^ a[3];
+        for i in 0..4 {
+            column[i] = a[i] ^ all ^ xtime(a[i] ^ a[(i + 1) % 4]);
+        }
+    }
+}
+
+// One 64 byte block of ChaCha20 keystream, as defined in RFC 8439
+pub fn chacha20_block(key: &[u8; 32], counter: u32, nonce: &[u8; 12]) -> [u8; 64] {
+    let mut state = [0u32; 16];
+    state[..4].copy_from_slice(&[0x6170_7865, 0x3320_646e, 0x7962_2d32, 0x6b20_6574]);
+    for (i, word) in key.chunks(4).enumerate() {
+        state[4 + i] = read_u32_le(word);
+    }
+    state[12] = counter;
+    for (i, word) in nonce.chunks(4).enumerate() {
+        state[13 + i] = read_u32_le(word);
+    }
+
+    let mut working = state;
+    for _ in 0..10 {
+        quarter_round(&mut working, 0, 4, 8, 12);
+        quarter_round(&mut working, 1, 5, 9, 13);
+        quarter_round(&mut working, 2, 6, 10, 14);
+        quarter_round(&mut working, 3, 7, 11, 15);
+        quarter_round(&mut working, 0, 5, 10, 15);
+        quarter_round(&mut working, 1, 6, 11, 12);
+        quarter_round(&mut working, 2, 7, 8, 13);
+        quarter_round(&mut working, 3, 4, 9, 14);
+    }
+
+    let mut out = [0u8; 64];
+    for (i, chunk) in out.chunks_mut(4).enumerate() {
+        let word = working[i].wrapping_add(state[i]);
+        for (j, byte) in chunk.iter_mut().enumerate() {
+            *byte = (word >> (8 * j)) as u8;
+        }
+    }
+    out
+}
+
+fn quarter_round(state: &mut [u32; 16], a: usize, b: usize, c: usize, d: usize) {
+    state[a] = state[a].wrapping_add(state[b]);
+    state[d] = (state[d] ^ state[a]).rotate_left(16);
+    state[c] = state[c].wrapping_add(state[d]);
+    state[b] = (state[b] ^ state[c]).rotate_left(12);
+    state[a] = state[a].wrapping_add(state[b]);
+    state[d] = (state[d] ^ state[a]).rotate_left(8);
+    state[c] = state[c].wrapping_add(state[d]);
+    state[b] = (state[b] ^ state[c]).rotate_left(7);
+}
+
+fn read_u32_le(bytes: &[u8]) -> u32 {
+    bytes
+        .iter()
+        .rev()
+        .fold(0, |word, &byte| (word << 8) | u32::from(byte))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{chacha20_block, Aes};
+
+    fn counting(len: usize) -> Vec<u8> {
+        (0..len as u8).collect()
+    }
+
+    // FIPS 197, appendix C
+    #[test]
+    fn test_aes_block() {
+        let plaintext = b"\x00\x11\x22\x33\x44\x55\x66\x77\x88\x99\xaa\xbb\xcc\xdd\xee\xff";
+
+        let mut block = *plaintext;
+        Aes::new(&counting(16)).unwrap().encrypt_block(&mut block);
+        assert_eq!(
+            &block,
+            b"\x69\xc4\xe0\xd8\x6a\x7b\x04\x30\xd8\xcd\xb7\x80\x70\xb4\xc5\x5a"
+        );
+
+        let mut block = *plaintext;
+        Aes::new(&counting(32)).unwrap().encrypt_block(&mut block);
+        assert_eq!(
+            &block,
+            b"\x8e\xa2\xb7\xca\x51\x67\x45\xbf\xea\xfc\x49\x90\x4b\x49\x60\x89"
+        );
+
+        assert!(Aes::new(&counting(24)).is_none());
+    }
+
+    // RFC 8439, section 2.3.2
+    #[test]
+    fn test_chacha20_block() {
+        let mut key = [0u8; 32];
+        key.copy_from_slice(&counting(32));
+        let nonce = b"\x00\x00\x00\x09\x00\x00\x00\x4a\x00\x00\x00\x00";
+        let block = chacha20_block(&key, 1, nonce);
+        assert_eq!(
+            &block[..16],
+            b"\x10\xf1\xe7\xe4\xd1\x3b\x59\x15\x50\x0f\xdd\x1f\xa3\x20\x71\xc4"
+        );
+        assert_eq!(
+            &block[48..],
+            b"\xb5\x12\x9c\xd1\xde\x16\x4e\xb9\xcb\xd0\x83\xe8\xa2\x50\x3c\x4e"
+        );
+    }
+}
@@ -2,6 +2,7 @@ use futures::{task, Async, Future, Poll};
 
 use super::{QuicError, QuicResult};
 use conn_state::ConnectionState;
+use endpoint::EndpointConfig;
 use parameters::ClientTransportParameters;
 use streams::Streams;
 use tls;
@@ -18,13 +19,26 @@ pub struct Client {
 
 impl Client {
     pub fn connect(server: &str, port: u16) -> QuicResult<ConnectFuture> {
+        Self::connect_with_token(server, port, Vec::new())
+    }
+
+    pub fn connect_with_token(
+        server: &str,
+        port: u16,
+        token: Vec<u8>,
+    ) -> QuicResult<ConnectFuture> {
         let tls = tls::client_session(None, server, &ClientTransportParameters::default())?;
-        let conn_state = ConnectionState::new(tls, None);
+        let mut conn_state = ConnectionState::new(tls, None, &EndpointConfig::default());
+        conn_state.set_token(token);
         let addr = (server, port).to_socket_addrs()?.next().ok_or_else(|| {
             QuicError::General(format!("no address found for '{}:{}'", server, port))
         })?;
         ConnectFuture::new(conn_state, addr)
     }
+
+    pub fn new_token(&self) -> Option<Vec<u8>> {
+        self.conn_state.new_token().map(|token| token.to_vec())
+    }
 }
 
 impl Future for Client {
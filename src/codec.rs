@@ -1,6 +1,75 @@
 use bytes::{Buf, BufMut};
 
-pub struct VarLen(pub u64);
+use super::{QuicError, QuicResult};
+
+// Only values up to MAX have an encoding, so they can't be set directly
+pub struct VarLen(u64);
+
+impl VarLen {
+    pub const MAX: u64 = 4_611_686_018_427_387_903;
+
+    pub fn new(value: u64) -> QuicResult<Self> {
+        if value > VarLen::MAX {
+            return Err(QuicError::InvalidEncoding(format!(
+                "too large for variable-length encoding: {}",
+                value
+            )));
+        }
+        Ok(VarLen(value))
+    }
+
+    // For values the protocol already keeps in range, such as decoded fields and buffer lengths
+    pub(crate) fn bounded(value: u64) -> Self {
+        match VarLen::new(value) {
+            Ok(var) => var,
+            Err(e) => panic!("{}", e),
+        }
+    }
+
+    pub fn value(&self) -> u64 {
+        self.0
+    }
+
+    // Always takes up `len` bytes, so a length field can be reserved before its value is known
+    pub fn encode_with_len<T: BufMut>(&self, buf: &mut T, len: usize) -> QuicResult<()> {
+        let max = match len {
+            1 => 63,
+            2 => 16_383,
+            4 => 1_073_741_823,
+            8 => VarLen::MAX,
+            _ => {
+                return Err(QuicError::InvalidEncoding(format!(
+                    "invalid variable-length size {}",
+                    len
+                )))
+            }
+        };
+        if self.0 > max {
+            return Err(QuicError::InvalidEncoding(format!(
+                "{} does not fit in {} bytes",
+                self.0, len
+            )));
+        }
+        self.put(buf, len);
+        Ok(())
+    }
+
+    fn put<T: BufMut>(&self, buf: &mut T, len: usize) {
+        match len {
+            1 => buf.put_u8(self.0 as u8),
+            2 => buf.put_u16_be(self.0 as u16 | 16384),
+            4 => buf.put_u32_be(self.0 as u32 | 2_147_483_648),
+            8 => buf.put_u64_be(self.0 | 13_835_058_055_282_163_712),
+            _ => panic!("impossible variable-length encoding"),
+        }
+    }
+}
+
+impl From<u32> for VarLen {
+    fn from(value: u32) -> Self {
+        VarLen(u64::from(value))
+    }
+}
 
 impl BufLen for VarLen {
     fn buf_len(&self) -> usize {
@@ -8,41 +77,36 @@ impl BufLen for VarLen {
             v if v <= 63 => 1,
             v if v <= 16_383 => 2,
             v if v <= 1_073_741_823 => 4,
-            v if v <= 4_611_686_018_427_387_903 => 8,
-            v => panic!("too large for variable-length encoding: {}", v),
+            _ => 8,
         }
     }
 }
 
 impl Codec for VarLen {
     fn encode<T: BufMut>(&self, buf: &mut T) {
-        match self.buf_len() {
-            1 => buf.put_u8(self.0 as u8),
-            2 => buf.put_u16_be(self.0 as u16 | 16384),
-            4 => buf.put_u32_be(self.0 as u32 | 2_147_483_648),
-            8 => buf.put_u64_be(self.0 | 13_835_058_055_282_163_712),
-            _ => panic!("impossible variable-length encoding"),
-        }
+        self.put(buf, self.buf_len())
     }
 
-    fn decode<T: Buf>(buf: &mut T) -> Self {
-        let first = buf.get_u8();
+    fn decode<T: Buf>(buf: &mut T) -> QuicResult<Self> {
+        let first = buf.try_get_u8()?;
         let be_val = first & 0x3f;
         let val = match first >> 6 {
             0 => u64::from(be_val),
-            1 => u64::from(be_val) << 8 | u64::from(buf.get_u8()),
+            1 => u64::from(be_val) << 8 | u64::from(buf.try_get_u8()?),
             2 => {
+                buf.check_remaining(3)?;
                 u64::from(be_val) << 24 | u64::from(buf.get_u8()) << 16
                     | u64::from(buf.get_u16_be())
             }
             3 => {
+                buf.check_remaining(7)?;
                 u64::from(be_val) << 56 | u64::from(buf.get_u8()) << 48
                     | u64::from(buf.get_u16_be()) << 32
                     | u64::from(buf.get_u32_be())
             }
             v => panic!("impossible variable length encoding: {}", v),
         };
-        VarLen(val)
+        Ok(VarLen(val))
     }
 }
 
@@ -71,15 +135,86 @@ where
     }
 }
 
-pub trait Codec {
+pub trait Codec: Sized {
     fn encode<T: BufMut>(&self, buf: &mut T);
-    fn decode<T: Buf>(buf: &mut T) -> Self;
+    fn decode<T: Buf>(buf: &mut T) -> QuicResult<Self>;
+
+    // Leaves the buffer untouched rather than panicking if the encoding doesn't fit
+    fn try_encode<T: BufMut>(&self, buf: &mut T) -> QuicResult<usize>
+    where
+        Self: BufLen,
+    {
+        let (len, remaining) = (self.buf_len(), buf.remaining_mut());
+        if len > remaining {
+            return Err(QuicError::InsufficientSpace(len, remaining));
+        }
+        self.encode(buf);
+        let written = remaining - buf.remaining_mut();
+        debug_assert_eq!(written, len);
+        Ok(written)
+    }
 }
 
+pub trait BufExt: Buf {
+    fn check_remaining(&self, len: usize) -> QuicResult<()> {
+        if self.remaining() < len {
+            Err(QuicError::UnexpectedEnd)
+        } else {
+            Ok(())
+        }
+    }
+
+    fn try_get_u8(&mut self) -> QuicResult<u8> {
+        self.check_remaining(1)?;
+        Ok(self.get_u8())
+    }
+
+    fn try_get_u16_be(&mut self) -> QuicResult<u16> {
+        self.check_remaining(2)?;
+        Ok(self.get_u16_be())
+    }
+
+    fn try_get_u32_be(&mut self) -> QuicResult<u32> {
+        self.check_remaining(4)?;
+        Ok(self.get_u32_be())
+    }
+
+    fn try_get_u64_be(&mut self) -> QuicResult<u64> {
+        self.check_remaining(8)?;
+        Ok(self.get_u64_be())
+    }
+
+    fn try_copy_to_slice(&mut self, dst: &mut [u8]) -> QuicResult<()> {
+        self.check_remaining(dst.len())?;
+        self.copy_to_slice(dst);
+        Ok(())
+    }
+
+    fn try_advance(&mut self, len: usize) -> QuicResult<()> {
+        self.check_remaining(len)?;
+        self.advance(len);
+        Ok(())
+    }
+}
+
+impl<T: Buf> BufExt for T {}
+
 #[cfg(test)]
 mod tests {
-    use super::{Codec, VarLen};
+    use super::{BufLen, Codec, QuicError, VarLen};
+    use proptest::prelude::*;
     use std::io::Cursor;
+
+    proptest! {
+        #[test]
+        fn test_var_len_codec(value in 0..1u64 << 62) {
+            let mut buf = Vec::new();
+            VarLen(value).encode(&mut buf);
+            prop_assert_eq!(VarLen(value).buf_len(), buf.len());
+            prop_assert_eq!(VarLen::decode(&mut Cursor::new(&buf)).unwrap().0, value);
+        }
+    }
+
     #[test]
     fn test_var_len_encoding_8() {
         let num = 151_288_809_941_952_652;
@@ -90,7 +225,7 @@ mod tests {
         assert_eq!(bytes[..], *buf);
 
         let mut read = Cursor::new(bytes);
-        assert_eq!(VarLen::decode(&mut read).0, num);
+        assert_eq!(VarLen::decode(&mut read).unwrap().0, num);
     }
     #[test]
     fn test_var_len_encoding_4() {
@@ -102,7 +237,7 @@ mod tests {
         assert_eq!(bytes[..], *buf);
 
         let mut read = Cursor::new(bytes);
-        assert_eq!(VarLen::decode(&mut read).0, num);
+        assert_eq!(VarLen::decode(&mut read).unwrap().0, num);
     }
     #[test]
     fn test_var_len_encoding_2() {
@@ -114,7 +249,7 @@ mod tests {
         assert_eq!(bytes[..], *buf);
 
         let mut read = Cursor::new(bytes);
-        assert_eq!(VarLen::decode(&mut read).0, num);
+        assert_eq!(VarLen::decode(&mut read).unwrap().0, num);
     }
     #[test]
     fn test_var_len_encoding_1_short() {
@@ -126,6 +261,61 @@ mod tests {
         assert_eq!(bytes[..], *buf);
 
         let mut read = Cursor::new(bytes);
-        assert_eq!(VarLen::decode(&mut read).0, num);
+        assert_eq!(VarLen::decode(&mut read).unwrap().0, num);
+    }
+
+    #[test]
+    fn test_var_len_truncated() {
+        for bytes in &[&b"\x9d\x7f\x3e"[..], b"\x7b", b"\xc2\x19\x7c\x5e\xff\x14\xe8", b""] {
+            match VarLen::decode(&mut Cursor::new(bytes)) {
+                Err(QuicError::UnexpectedEnd) => {}
+                v => panic!("unexpected result {:?}", v.map(|v| v.0)),
+            }
+        }
+    }
+
+    #[test]
+    fn test_try_encode() {
+        let mut bytes = [0; 6];
+        {
+            let mut write = Cursor::new(&mut bytes[..3]);
+            match VarLen(494_878_333).try_encode(&mut write) {
+                Err(QuicError::InsufficientSpace(4, 3)) => {}
+                v => panic!("unexpected result {:?}", v),
+            }
+            assert_eq!(write.position(), 0);
+            assert_eq!(VarLen(15_293).try_encode(&mut write).unwrap(), 2);
+            assert_eq!(VarLen(37).try_encode(&mut write).unwrap(), 1);
+            assert!(VarLen(37).try_encode(&mut write).is_err());
+        }
+        assert_eq!(bytes[..3], b"\x7b\xbd\x25"[..]);
+    }
+
+    #[test]
+    fn test_var_len_max() {
+        assert_eq!(VarLen::new(VarLen::MAX).unwrap().buf_len(), 8);
+        assert_eq!(VarLen::from(u32::max_value()).value(), u64::from(u32::max_value()));
+        match VarLen::new(VarLen::MAX + 1) {
+            Err(QuicError::InvalidEncoding(_)) => {}
+            v => panic!("unexpected result {:?}", v.map(|v| v.0)),
+        }
+    }
+
+    #[test]
+    fn test_var_len_encode_with_len() {
+        let mut buf = Vec::new();
+        VarLen(37).encode_with_len(&mut buf, 2).unwrap();
+        assert_eq!(buf, b"\x40\x25");
+        assert_eq!(VarLen::decode(&mut Cursor::new(&buf)).unwrap().0, 37);
+
+        buf.clear();
+        VarLen(37).encode_with_len(&mut buf, 8).unwrap();
+        assert_eq!(buf.len(), 8);
+        assert_eq!(VarLen::decode(&mut Cursor::new(&buf)).unwrap().0, 37);
+
+        buf.clear();
+        assert!(VarLen(16_384).encode_with_len(&mut buf, 2).is_err());
+        assert!(VarLen(37).encode_with_len(&mut buf, 3).is_err());
+        assert!(buf.is_empty());
     }
 }
@@ -0,0 +1,331 @@
+use std::cmp;
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+
+use super::{nanos, CongestionController, INITIAL_WINDOW, MAX_DATAGRAM_SIZE, NANOS_PER_SEC};
+
+pub struct Bbr {
+    mode: Mode,
+    window: usize,
+    in_flight: usize,
+    btl_bw: u64,
+    bw_samples: VecDeque<(u64, u64)>,
+    min_rtt: Option<Duration>,
+    min_rtt_stamp: Option<Instant>,
+    probe_rtt_done: Option<Instant>,
+    delivered: u64,
+    delivered_time: Option<Instant>,
+    sent: VecDeque<SendState>,
+    round: u64,
+    next_round_delivered: u64,
+    full_bw: u64,
+    full_bw_rounds: u32,
+    filled_pipe: bool,
+    cycle_index: usize,
+    cycle_stamp: Option<Instant>,
+}
+
+impl Bbr {
+    pub fn new() -> Self {
+        Self {
+            mode: Mode::Startup,
+            window: INITIAL_WINDOW,
+            in_flight: 0,
+            btl_bw: 0,
+            bw_samples: VecDeque::new(),
+            min_rtt: None,
+            min_rtt_stamp: None,
+            probe_rtt_done: None,
+            delivered: 0,
+            delivered_time: None,
+            sent: VecDeque::new(),
+            round: 0,
+            next_round_delivered: 0,
+            full_bw: 0,
+            full_bw_rounds: 0,
+            filled_pipe: false,
+            cycle_index: 0,
+            cycle_stamp: None,
+        }
+    }
+
+    fn bdp(&self) -> usize {
+        let rtt = match self.min_rtt {
+            Some(rtt) => rtt,
+            None => return INITIAL_WINDOW,
+        };
+        (self.btl_bw * nanos(rtt) / NANOS_PER_SEC) as usize
+    }
+
+    fn pacing_gain(&self) -> f64 {
+        match self.mode {
+            Mode::Startup => HIGH_GAIN,
+            Mode::Drain => 1.0 / HIGH_GAIN,
+            Mode::ProbeBw => PACING_GAIN_CYCLE[self.cycle_index],
+            Mode::ProbeRtt => 1.0,
+        }
+    }
+
+    fn update_min_rtt(&mut self, now: Instant, sample: Duration) {
+        let expired = self.min_rtt_stamp
+            .map_or(false, |stamp| now > stamp + Duration::from_secs(MIN_RTT_WINDOW));
+        if expired || self.min_rtt.map_or(true, |min| sample <= min) {
+            self.min_rtt = Some(sample);
+            self.min_rtt_stamp = Some(now);
+        }
+
+        if expired && self.mode != Mode::ProbeRtt {
+            self.mode = Mode::ProbeRtt;
+            self.probe_rtt_done = None;
+        }
+    }
+
+    fn update_bandwidth(&mut self, sample: u64) {
+        self.bw_samples.push_back((self.round, sample));
+        while let Some(&(round, _)) = self.bw_samples.front() {
+            if round + BW_WINDOW_ROUNDS >= self.round {
+                break;
+            }
+            self.bw_samples.pop_front();
+        }
+        self.btl_bw = self.bw_samples.iter().map(|&(_, bw)| bw).max().unwrap_or(0);
+    }
+
+    fn check_full_pipe(&mut self) {
+        if self.btl_bw >= self.full_bw + self.full_bw / 4 {
+            self.full_bw = self.btl_bw;
+            self.full_bw_rounds = 0;
+            return;
+        }
+        self.full_bw_rounds += 1;
+        if self.full_bw_rounds >= FULL_BW_ROUNDS {
+            self.filled_pipe = true;
+        }
+    }
+
+    fn update_mode(&mut self, now: Instant) {
+        match self.mode {
+            Mode::Startup => if self.filled_pipe {
+                self.mode = Mode::Drain;
+            },
+            Mode::Drain => if self.in_flight <= self.bdp() {
+                self.enter_probe_bw(now);
+            },
+            Mode::ProbeBw => {
+                let min_rtt = self.min_rtt.unwrap_or_else(|| Duration::from_millis(0));
+                if self.cycle_stamp.map_or(true, |stamp| now > stamp + min_rtt) {
+                    self.cycle_index = (self.cycle_index + 1) % PACING_GAIN_CYCLE.len();
+                    self.cycle_stamp = Some(now);
+                }
+            }
+            Mode::ProbeRtt => match self.probe_rtt_done {
+                None => if self.in_flight <= MIN_WINDOW {
+                    self.probe_rtt_done = Some(now + Duration::from_millis(PROBE_RTT_DURATION));
+                },
+                Some(done) => if now >= done {
+                    self.min_rtt_stamp = Some(now);
+                    if self.filled_pipe {
+                        self.enter_probe_bw(now);
+                    } else {
+                        self.mode = Mode::Startup;
+                    }
+                },
+            },
+        }
+    }
+
+    fn enter_probe_bw(&mut self, now: Instant) {
+        self.mode = Mode::ProbeBw;
+        self.cycle_index = 0;
+        self.cycle_stamp = Some(now);
+    }
+
+    fn update_window(&mut self, acked: usize) {
+        if self.mode == Mode::ProbeRtt {
+            self.window = MIN_WINDOW;
+            return;
+        }
+        let gain = match self.mode {
+            Mode::Startup => HIGH_GAIN,
+            // Without pacing, the window is what drains the queue built up in startup
+            Mode::Drain => 1.0,
+            _ => CWND_GAIN,
+        };
+        let target = (self.bdp() as f64 * gain) as usize;
+        self.window = if self.filled_pipe {
+            cmp::min(self.window + acked, target)
+        } else if self.window < target || self.delivered < INITIAL_WINDOW as u64 {
+            self.window + acked
+        } else {
+            self.window
+        };
+        self.window = cmp::max(self.window, MIN_WINDOW);
+    }
+}
+
+impl CongestionController for Bbr {
+    fn on_packet_sent(&mut self, now: Instant, bytes: usize) {
+        let delivered_time = *self.delivered_time.get_or_insert(now);
+        self.in_flight += bytes;
+        self.sent.push_back(SendState {
+            time: now,
+            delivered: self.delivered,
+            delivered_time,
+        });
+    }
+
+    fn on_ack(&mut self, now: Instant, sent: Instant, bytes: usize, _: Duration) {
+        self.in_flight = self.in_flight.saturating_sub(bytes);
+        self.delivered += bytes as u64;
+        self.delivered_time = Some(now);
+
+        let mut state = None;
+        while self.sent.front().map_or(false, |state| state.time <= sent) {
+            state = self.sent.pop_front();
+        }
+
+        if let Some(state) = state {
+            if state.delivered >= self.next_round_delivered {
+                self.next_round_delivered = self.delivered;
+                self.round += 1;
+                if !self.filled_pipe {
+                    self.check_full_pipe();
+                }
+            }
+
+            let interval = nanos(now - state.delivered_time);
+            if interval > 0 {
+                let rate = (self.delivered - state.delivered) * NANOS_PER_SEC / interval;
+                self.update_bandwidth(rate);
+            }
+            self.update_min_rtt(now, now - sent);
+        }
+
+        self.update_mode(now);
+        self.update_window(bytes);
+    }
+
+    fn on_loss(&mut self, _: Instant, _: Instant, bytes: usize) {
+        // BBR models the path rather than reacting to individual losses
+        self.in_flight = self.in_flight.saturating_sub(bytes);
+    }
+
+    fn window(&self) -> usize {
+        self.window
+    }
+
+    fn pacing_rate(&self) -> Option<u64> {
+        if self.btl_bw == 0 {
+            return None;
+        }
+        Some((self.btl_bw as f64 * self.pacing_gain()) as u64)
+    }
+}
+
+struct SendState {
+    time: Instant,
+    delivered: u64,
+    delivered_time: Instant,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Mode {
+    Startup,
+    Drain,
+    ProbeBw,
+    ProbeRtt,
+}
+
+const HIGH_GAIN: f64 = 2.885;
+const CWND_GAIN: f64 = 2.0;
+const PACING_GAIN_CYCLE: [f64; 8] = [1.25, 0.75, 1.0, 1.0, 1.0, 1.0, 1.0, 1.0];
+const MIN_WINDOW: usize = 4 * MAX_DATAGRAM_SIZE;
+const BW_WINDOW_ROUNDS: u64 = 10;
+const FULL_BW_ROUNDS: u32 = 3;
+const MIN_RTT_WINDOW: u64 = 10;
+const PROBE_RTT_DURATION: u64 = 200;
+
+#[cfg(test)]
+mod tests {
+    use super::{Bbr, Mode};
+    use congestion::{CongestionController, MAX_DATAGRAM_SIZE};
+    use std::cmp;
+    use std::collections::VecDeque;
+    use std::time::{Duration, Instant};
+
+    // A path with a 100ms RTT and a bottleneck delivering one packet per
+    // millisecond (1.2 MB/s, so the BDP is about 120 kB)
+    struct Path {
+        now: Instant,
+        bottleneck_free: Instant,
+        in_flight: VecDeque<(Instant, Instant)>,
+        bytes: usize,
+    }
+
+    impl Path {
+        fn new(start: Instant) -> Self {
+            Self {
+                now: start,
+                bottleneck_free: start,
+                in_flight: VecDeque::new(),
+                bytes: 0,
+            }
+        }
+
+        fn run(&mut self, cc: &mut Bbr, duration: Duration) {
+            let rtt = Duration::from_millis(100);
+            let end = self.now + duration;
+            while self.now < end {
+                let mut offset = 0;
+                while self.bytes + MAX_DATAGRAM_SIZE <= cc.window() {
+                    let sent = self.now + Duration::new(0, offset);
+                    offset += 1;
+                    let departure =
+                        cmp::max(sent, self.bottleneck_free) + Duration::from_millis(1);
+                    self.bottleneck_free = departure;
+                    self.in_flight.push_back((sent, departure + rtt));
+                    self.bytes += MAX_DATAGRAM_SIZE;
+                    cc.on_packet_sent(sent, MAX_DATAGRAM_SIZE);
+                }
+
+                let (sent, ack) = self.in_flight.pop_front().unwrap();
+                self.bytes -= MAX_DATAGRAM_SIZE;
+                self.now = ack;
+                cc.on_ack(ack, sent, MAX_DATAGRAM_SIZE, rtt);
+            }
+        }
+    }
+
+    #[test]
+    fn test_startup_finds_bandwidth() {
+        let mut cc = Bbr::new();
+        assert_eq!(cc.pacing_rate(), None);
+
+        let mut path = Path::new(Instant::now());
+        path.run(&mut cc, Duration::from_secs(5));
+        assert!(cc.filled_pipe);
+        assert_eq!(cc.mode, Mode::ProbeBw);
+        assert!(cc.btl_bw > 1_100_000 && cc.btl_bw < 1_300_000, "{}", cc.btl_bw);
+        assert!(cc.window() > 200_000 && cc.window() < 300_000, "{}", cc.window());
+        assert!(cc.pacing_rate().is_some());
+    }
+
+    #[test]
+    fn test_probe_rtt() {
+        let start = Instant::now();
+        let mut cc = Bbr::new();
+        let mut path = Path::new(start);
+        path.run(&mut cc, Duration::from_secs(11));
+
+        // Pretend the minimum RTT was measured long ago on a faster path
+        cc.min_rtt = Some(Duration::from_millis(10));
+        cc.min_rtt_stamp = Some(start);
+        path.run(&mut cc, Duration::from_millis(50));
+        assert_eq!(cc.mode, Mode::ProbeRtt);
+        assert_eq!(cc.window(), 4 * MAX_DATAGRAM_SIZE);
+
+        path.run(&mut cc, Duration::from_secs(1));
+        assert_eq!(cc.mode, Mode::ProbeBw);
+        assert_eq!(cc.min_rtt, Some(Duration::from_millis(101)));
+    }
+}
@@ -0,0 +1,208 @@
+use std::cmp;
+use std::time::{Duration, Instant};
+
+use super::{CongestionController, INITIAL_WINDOW, MAX_DATAGRAM_SIZE, MINIMUM_WINDOW};
+
+pub struct Cubic {
+    window: usize,
+    ssthresh: usize,
+    recovery_start: Option<Instant>,
+    epoch_start: Option<Instant>,
+    // The following are measured in segments, as in RFC 8312
+    w_max: f64,
+    k: f64,
+    w_est: f64,
+}
+
+impl Cubic {
+    pub fn new() -> Self {
+        Self {
+            window: INITIAL_WINDOW,
+            ssthresh: usize::max_value(),
+            recovery_start: None,
+            epoch_start: None,
+            w_max: 0.0,
+            k: 0.0,
+            w_est: 0.0,
+        }
+    }
+
+    fn in_recovery(&self, sent: Instant) -> bool {
+        self.recovery_start.map_or(false, |start| sent <= start)
+    }
+
+    fn segments(&self) -> f64 {
+        self.window as f64 / MAX_DATAGRAM_SIZE as f64
+    }
+
+    fn start_epoch(&mut self, now: Instant) -> Instant {
+        let cwnd = self.segments();
+        if self.w_max < cwnd {
+            self.w_max = cwnd;
+            self.k = 0.0;
+        } else {
+            self.k = ((self.w_max - cwnd) / C).cbrt();
+        }
+        self.w_est = cwnd;
+        self.epoch_start = Some(now);
+        now
+    }
+}
+
+impl CongestionController for Cubic {
+    fn on_packet_sent(&mut self, _: Instant, _: usize) {}
+
+    fn on_ack(&mut self, now: Instant, sent: Instant, bytes: usize, rtt: Duration) {
+        if self.in_recovery(sent) {
+            return;
+        }
+        if self.window < self.ssthresh {
+            self.window += bytes;
+            return;
+        }
+
+        let epoch_start = match self.epoch_start {
+            Some(start) => start,
+            None => self.start_epoch(now),
+        };
+        let cwnd = self.segments();
+        let acked = bytes as f64 / MAX_DATAGRAM_SIZE as f64;
+        let t = seconds(now - epoch_start) + seconds(rtt);
+        let target = C * (t - self.k).powi(3) + self.w_max;
+        self.w_est += 3.0 * (1.0 - BETA) / (1.0 + BETA) * acked / cwnd;
+
+        let next = if target < self.w_est {
+            // TCP-friendly region: grow at least as fast as Reno would
+            self.w_est
+        } else if target > cwnd {
+            cwnd + (target - cwnd) / cwnd * acked
+        } else {
+            cwnd
+        };
+        if next > cwnd {
+            self.window = (next * MAX_DATAGRAM_SIZE as f64) as usize;
+        }
+    }
+
+    fn on_loss(&mut self, now: Instant, sent: Instant, _: usize) {
+        if self.in_recovery(sent) {
+            return;
+        }
+        self.recovery_start = Some(now);
+        self.epoch_start = None;
+
+        let cwnd = self.segments();
+        // Fast convergence: release bandwidth for new flows when the window keeps shrinking
+        self.w_max = if cwnd < self.w_max {
+            cwnd * (1.0 + BETA) / 2.0
+        } else {
+            cwnd
+        };
+        self.window = cmp::max(
+            (self.window as f64 * BETA) as usize,
+            MINIMUM_WINDOW,
+        );
+        self.ssthresh = self.window;
+    }
+
+    fn window(&self) -> usize {
+        self.window
+    }
+}
+
+fn seconds(duration: Duration) -> f64 {
+    duration.as_secs() as f64 + f64::from(duration.subsec_nanos()) / 1e9
+}
+
+const C: f64 = 0.4;
+const BETA: f64 = 0.7;
+
+#[cfg(test)]
+mod tests {
+    use super::Cubic;
+    use congestion::{CongestionController, INITIAL_WINDOW, MAX_DATAGRAM_SIZE};
+    use std::time::{Duration, Instant};
+
+    fn segments(cc: &Cubic) -> f64 {
+        cc.window() as f64 / MAX_DATAGRAM_SIZE as f64
+    }
+
+    // Acknowledging a whole window at once takes Cubic straight to its target, so after each
+    // round trip the window should sit where the RFC 8312 curves put it one RTT ahead
+    fn ack_rounds(cc: &mut Cubic, start: Instant, rtt: Duration, rounds: u32) -> Vec<f64> {
+        (1..rounds + 1)
+            .map(|round| {
+                let now = start + rtt * round;
+                let window = cc.window();
+                cc.on_ack(now, now, window, rtt);
+                segments(cc)
+            })
+            .collect()
+    }
+
+    fn assert_follows(trace: &[f64], reference: &Fn(f64) -> f64) {
+        for (round, actual) in trace.iter().enumerate() {
+            let expected = reference((round + 1) as f64);
+            // The window is kept in whole bytes
+            assert!(
+                (actual - expected).abs() < 1e-3,
+                "window {} after round {} deviates from reference {}",
+                actual,
+                round + 1,
+                expected
+            );
+        }
+    }
+
+    #[test]
+    fn test_cubic_region() {
+        let start = Instant::now();
+        let rtt = Duration::from_millis(100);
+        let mut cc = Cubic::new();
+        for _ in 0..90 {
+            cc.on_ack(start, start, MAX_DATAGRAM_SIZE, rtt);
+        }
+        assert_eq!(segments(&cc), 100.0);
+
+        cc.on_loss(start + rtt / 2, start, MAX_DATAGRAM_SIZE);
+        assert_eq!(segments(&cc), 70.0);
+
+        // W_cubic(t) = C * (t - K)^3 + W_max, with K = cbrt(W_max * (1 - beta) / C); the
+        // window is concave up to W_max = 100 at K, about 4.2s in, and convex after it
+        let trace = ack_rounds(&mut cc, start, rtt, 80);
+        let k = (100.0 * 0.3 / 0.4f64).cbrt();
+        assert_follows(&trace, &|round| 0.4 * (round * 0.1 - k).powi(3) + 100.0);
+        assert!(trace[41] < 100.0 && trace[42] > 100.0);
+    }
+
+    #[test]
+    fn test_tcp_friendly_region() {
+        let start = Instant::now();
+        let rtt = Duration::from_millis(10);
+        let mut cc = Cubic::new();
+        cc.on_loss(start, start, MAX_DATAGRAM_SIZE);
+        assert_eq!(segments(&cc), 7.0);
+
+        // With a small W_max and short round trips, Reno would outgrow the cubic curve, so the
+        // window follows W_est(t) = W_max * beta + 3 * (1 - beta) / (1 + beta) * t / RTT instead
+        let trace = ack_rounds(&mut cc, start, rtt, 100);
+        assert_follows(&trace, &|round| 10.0 * 0.7 + 3.0 * 0.3 / 1.7 * round);
+        // A second in, the cubic curve hasn't even climbed back to W_max
+        let k = (10.0 * 0.3 / 0.4f64).cbrt();
+        let cubic = 0.4 * (1.0 - k).powi(3) + 10.0;
+        assert!(cubic < 10.0 && trace[99] > 50.0);
+    }
+
+    #[test]
+    fn test_fast_convergence() {
+        let start = Instant::now();
+        let mut cc = Cubic::new();
+        cc.on_loss(start, start, MAX_DATAGRAM_SIZE);
+        assert_eq!(cc.w_max, 10.0);
+
+        let later = start + Duration::from_millis(100);
+        cc.on_loss(later, later, MAX_DATAGRAM_SIZE);
+        assert!((cc.w_max - 7.0 * 1.7 / 2.0).abs() < 1e-9);
+        assert_eq!(cc.window(), (INITIAL_WINDOW * 7 / 10) * 7 / 10);
+    }
+}
@@ -0,0 +1,55 @@
+use std::time::{Duration, Instant};
+
+mod bbr;
+mod cubic;
+mod new_reno;
+mod pacing;
+
+pub use self::bbr::Bbr;
+pub use self::cubic::Cubic;
+pub use self::new_reno::NewReno;
+pub use self::pacing::Pacer;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Algorithm {
+    NewReno,
+    Cubic,
+    Bbr,
+}
+
+impl Algorithm {
+    pub(crate) fn build(self) -> Box<CongestionController> {
+        match self {
+            Algorithm::NewReno => Box::new(NewReno::new()),
+            Algorithm::Cubic => Box::new(Cubic::new()),
+            Algorithm::Bbr => Box::new(Bbr::new()),
+        }
+    }
+}
+
+impl Default for Algorithm {
+    fn default() -> Self {
+        Algorithm::NewReno
+    }
+}
+
+pub trait CongestionController {
+    fn on_packet_sent(&mut self, now: Instant, bytes: usize);
+    fn on_ack(&mut self, now: Instant, sent: Instant, bytes: usize, rtt: Duration);
+    fn on_loss(&mut self, now: Instant, sent: Instant, bytes: usize);
+    fn window(&self) -> usize;
+
+    fn pacing_rate(&self) -> Option<u64> {
+        None
+    }
+}
+
+pub const MAX_DATAGRAM_SIZE: usize = 1200;
+pub const INITIAL_WINDOW: usize = 10 * MAX_DATAGRAM_SIZE;
+pub const MINIMUM_WINDOW: usize = 2 * MAX_DATAGRAM_SIZE;
+pub const DEFAULT_PACING_BURST: usize = 10;
+pub const NANOS_PER_SEC: u64 = 1_000_000_000;
+
+pub fn nanos(duration: Duration) -> u64 {
+    duration.as_secs() * NANOS_PER_SEC + u64::from(duration.subsec_nanos())
+}
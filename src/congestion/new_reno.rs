@@ -0,0 +1,82 @@
+use std::cmp;
+use std::time::{Duration, Instant};
+
+use super::{CongestionController, INITIAL_WINDOW, MAX_DATAGRAM_SIZE, MINIMUM_WINDOW};
+
+pub struct NewReno {
+    window: usize,
+    ssthresh: usize,
+    recovery_start: Option<Instant>,
+}
+
+impl NewReno {
+    pub fn new() -> Self {
+        Self {
+            window: INITIAL_WINDOW,
+            ssthresh: usize::max_value(),
+            recovery_start: None,
+        }
+    }
+
+    fn in_recovery(&self, sent: Instant) -> bool {
+        self.recovery_start.map_or(false, |start| sent <= start)
+    }
+}
+
+impl CongestionController for NewReno {
+    fn on_packet_sent(&mut self, _: Instant, _: usize) {}
+
+    fn on_ack(&mut self, _: Instant, sent: Instant, bytes: usize, _: Duration) {
+        if self.in_recovery(sent) {
+            return;
+        }
+        if self.window < self.ssthresh {
+            self.window += bytes;
+        } else {
+            self.window += MAX_DATAGRAM_SIZE * bytes / self.window;
+        }
+    }
+
+    fn on_loss(&mut self, now: Instant, sent: Instant, _: usize) {
+        // Only reduce the window once per round trip
+        if self.in_recovery(sent) {
+            return;
+        }
+        self.recovery_start = Some(now);
+        self.window = cmp::max(self.window / 2, MINIMUM_WINDOW);
+        self.ssthresh = self.window;
+    }
+
+    fn window(&self) -> usize {
+        self.window
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::NewReno;
+    use congestion::{CongestionController, INITIAL_WINDOW, MAX_DATAGRAM_SIZE};
+    use std::time::{Duration, Instant};
+
+    #[test]
+    fn test_slow_start_and_recovery() {
+        let start = Instant::now();
+        let rtt = Duration::from_millis(100);
+        let mut cc = NewReno::new();
+        cc.on_ack(start, start, MAX_DATAGRAM_SIZE, rtt);
+        assert_eq!(cc.window(), INITIAL_WINDOW + MAX_DATAGRAM_SIZE);
+
+        let loss = start + Duration::from_millis(10);
+        cc.on_loss(loss, start, MAX_DATAGRAM_SIZE);
+        let reduced = (INITIAL_WINDOW + MAX_DATAGRAM_SIZE) / 2;
+        assert_eq!(cc.window(), reduced);
+
+        // Losses and acks from before the reduction don't change the window again
+        cc.on_loss(loss, start, MAX_DATAGRAM_SIZE);
+        cc.on_ack(loss, start, MAX_DATAGRAM_SIZE, rtt);
+        assert_eq!(cc.window(), reduced);
+
+        cc.on_ack(loss, loss + Duration::from_millis(1), MAX_DATAGRAM_SIZE, rtt);
+        assert_eq!(cc.window(), reduced + MAX_DATAGRAM_SIZE * MAX_DATAGRAM_SIZE / reduced);
+    }
+}
@@ -0,0 +1,107 @@
+use std::cmp;
+use std::time::{Duration, Instant};
+
+use super::{nanos, MAX_DATAGRAM_SIZE, NANOS_PER_SEC};
+
+pub struct Pacer {
+    capacity: u64,
+    tokens: u64,
+    // Credit short of a whole byte, in bytes times nanoseconds per second, so frequent polls
+    // don't round it away
+    fraction: u64,
+    last: Option<Instant>,
+}
+
+impl Pacer {
+    pub fn new(burst: usize) -> Self {
+        let capacity = (cmp::max(burst, 1) * MAX_DATAGRAM_SIZE) as u64;
+        Self {
+            capacity,
+            tokens: capacity,
+            fraction: 0,
+            last: None,
+        }
+    }
+
+    pub fn delay(&mut self, now: Instant, rate: u64, bytes: usize) -> Option<Instant> {
+        if let Some(last) = self.last {
+            if now > last {
+                let credit = rate
+                    .saturating_mul(nanos(now - last))
+                    .saturating_add(self.fraction);
+                self.tokens = self.tokens.saturating_add(credit / NANOS_PER_SEC);
+                self.fraction = credit % NANOS_PER_SEC;
+                if self.tokens >= self.capacity {
+                    self.tokens = self.capacity;
+                    self.fraction = 0;
+                }
+            }
+        }
+        self.last = Some(now);
+
+        let bytes = bytes as u64;
+        if self.tokens >= bytes || rate == 0 {
+            return None;
+        }
+        let missing = (bytes - self.tokens) * NANOS_PER_SEC - self.fraction;
+        let wait = (missing + rate - 1) / rate;
+        Some(now + Duration::new(wait / NANOS_PER_SEC, (wait % NANOS_PER_SEC) as u32))
+    }
+
+    pub fn on_sent(&mut self, bytes: usize) {
+        self.tokens = self.tokens.saturating_sub(bytes as u64);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Pacer;
+    use congestion::MAX_DATAGRAM_SIZE;
+    use std::time::{Duration, Instant};
+
+    #[test]
+    fn test_burst_then_spread() {
+        let start = Instant::now();
+        let rate = 100 * MAX_DATAGRAM_SIZE as u64;
+        let mut pacer = Pacer::new(2);
+
+        for _ in 0..2 {
+            assert_eq!(pacer.delay(start, rate, MAX_DATAGRAM_SIZE), None);
+            pacer.on_sent(MAX_DATAGRAM_SIZE);
+        }
+        assert_eq!(
+            pacer.delay(start, rate, MAX_DATAGRAM_SIZE),
+            Some(start + Duration::from_millis(10))
+        );
+
+        let later = start + Duration::from_millis(10);
+        assert_eq!(pacer.delay(later, rate, MAX_DATAGRAM_SIZE), None);
+        pacer.on_sent(MAX_DATAGRAM_SIZE);
+
+        // Idle time doesn't accumulate more credit than the burst size
+        let idle = later + Duration::from_secs(1);
+        for _ in 0..2 {
+            assert_eq!(pacer.delay(idle, rate, MAX_DATAGRAM_SIZE), None);
+            pacer.on_sent(MAX_DATAGRAM_SIZE);
+        }
+        assert!(pacer.delay(idle, rate, MAX_DATAGRAM_SIZE).is_some());
+    }
+
+    #[test]
+    fn test_frequent_polls_keep_credit() {
+        let start = Instant::now();
+        // A byte per microsecond, polled every tenth of one
+        let rate = 1_000_000;
+        let mut pacer = Pacer::new(1);
+        assert_eq!(pacer.delay(start, rate, MAX_DATAGRAM_SIZE), None);
+        pacer.on_sent(MAX_DATAGRAM_SIZE);
+
+        let ready = start + Duration::new(0, MAX_DATAGRAM_SIZE as u32 * 1000);
+        let mut now = start;
+        while now < ready {
+            assert_eq!(pacer.delay(now, rate, MAX_DATAGRAM_SIZE), Some(ready));
+            now += Duration::new(0, 100);
+        }
+        assert_eq!(pacer.delay(now, rate, MAX_DATAGRAM_SIZE), None);
+    }
+}
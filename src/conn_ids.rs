@@ -0,0 +1,302 @@
+use rand::{thread_rng, Rng};
+
+use std::collections::VecDeque;
+use std::mem;
+use std::sync::Arc;
+
+use super::{QuicError, QuicResult, TransportError};
+use frame::{NewConnectionIdFrame, RetireConnectionIdFrame};
+use types::ConnectionId;
+
+// Makes the CIDs an endpoint hands out, so a load balancer in front of it can find the server
+// a connection belongs to from the CID alone. Every CID has to be cid_len() bytes long, since
+// that's how short headers are parsed.
+pub trait ConnectionIdGenerator: Send + Sync {
+    fn generate(&self) -> ConnectionId;
+    fn cid_len(&self) -> u8;
+}
+
+pub struct RandomConnectionIdGenerator {
+    len: u8,
+}
+
+impl RandomConnectionIdGenerator {
+    pub fn new(len: u8) -> Self {
+        assert!(len == 0 || (len >= 4 && len <= 18), "invalid connection ID length {}", len);
+        Self { len }
+    }
+}
+
+impl ConnectionIdGenerator for RandomConnectionIdGenerator {
+    fn generate(&self) -> ConnectionId {
+        ConnectionId::random(&mut thread_rng(), self.len)
+    }
+
+    fn cid_len(&self) -> u8 {
+        self.len
+    }
+}
+
+// Plaintext routing in the style of QUIC-LB: the first byte carries the config rotation bits
+// and the length of what follows, then comes the server ID, then random bytes
+pub struct ServerIdGenerator {
+    rotation: u8,
+    server_id: Vec<u8>,
+    len: u8,
+}
+
+impl ServerIdGenerator {
+    pub fn new(rotation: u8, server_id: &[u8], len: u8) -> Self {
+        assert!(rotation < 3, "config rotation {} is reserved", rotation);
+        assert!(len >= 4 && len <= 18, "invalid connection ID length {}", len);
+        assert!(
+            !server_id.is_empty() && server_id.len() < len as usize,
+            "server ID doesn't fit in a {}-byte connection ID",
+            len
+        );
+        Self {
+            rotation,
+            server_id: server_id.to_vec(),
+            len,
+        }
+    }
+
+    // What a load balancer sharing this config would route the CID by
+    pub fn server_id<'a>(&self, cid: &'a ConnectionId) -> Option<&'a [u8]> {
+        if cid.len != self.len || cid[0] >> 6 != self.rotation {
+            return None;
+        }
+        Some(&cid[1..1 + self.server_id.len()])
+    }
+}
+
+impl ConnectionIdGenerator for ServerIdGenerator {
+    fn generate(&self) -> ConnectionId {
+        let mut bytes = [0; 18];
+        let bytes = &mut bytes[..self.len as usize];
+        thread_rng().fill_bytes(bytes);
+        bytes[0] = self.rotation << 6 | (self.len - 1);
+        bytes[1..1 + self.server_id.len()].copy_from_slice(&self.server_id);
+        ConnectionId::new(bytes)
+    }
+
+    fn cid_len(&self) -> u8 {
+        self.len
+    }
+}
+
+pub struct ConnectionIdManager {
+    generator: Arc<ConnectionIdGenerator>,
+    next_sequence: u64,
+    issued: Vec<IssuedId>,
+    unrouted: Vec<ConnectionId>,
+    retired: Vec<ConnectionId>,
+    initial_retired: bool,
+    remote: VecDeque<IssuedId>,
+    remote_sequence: u64,
+}
+
+impl ConnectionIdManager {
+    pub fn new(generator: Arc<ConnectionIdGenerator>) -> Self {
+        Self {
+            generator,
+            next_sequence: 1,
+            issued: Vec::new(),
+            unrouted: Vec::new(),
+            retired: Vec::new(),
+            initial_retired: false,
+            remote: VecDeque::new(),
+            remote_sequence: 0,
+        }
+    }
+
+    pub fn issue(&mut self) -> NewConnectionIdFrame {
+        let mut rng = thread_rng();
+        let mut reset_token = [0; 16];
+        rng.fill_bytes(&mut reset_token);
+        let id = IssuedId {
+            sequence: self.next_sequence,
+            cid: self.generator.generate(),
+            reset_token,
+        };
+        self.next_sequence += 1;
+        self.issued.push(id);
+        self.unrouted.push(id.cid);
+
+        NewConnectionIdFrame {
+            sequence: id.sequence,
+            id: id.cid,
+            reset_token: id.reset_token,
+        }
+    }
+
+    pub fn generate(&self) -> ConnectionId {
+        self.generator.generate()
+    }
+
+    pub fn issued(&self) -> impl Iterator<Item = &ConnectionId> {
+        self.issued.iter().map(|id| &id.cid)
+    }
+
+    // Issued since the last call, so the endpoint can start routing them
+    pub fn take_unrouted(&mut self) -> Vec<ConnectionId> {
+        mem::replace(&mut self.unrouted, Vec::new())
+    }
+
+    // The handshake CID has sequence number 0, but isn't tracked with the issued ones
+    pub fn retire(&mut self, sequence: u64, initial: ConnectionId) -> QuicResult<bool> {
+        if sequence >= self.next_sequence {
+            return Err(QuicError::Transport(
+                TransportError::ProtocolViolation,
+                format!("retired connection ID {} was never issued", sequence),
+            ));
+        }
+        let cid = if sequence == 0 {
+            if self.initial_retired {
+                return Ok(false);
+            }
+            self.initial_retired = true;
+            initial
+        } else {
+            match self.issued.iter().position(|id| id.sequence == sequence) {
+                Some(pos) => self.issued.remove(pos).cid,
+                None => return Ok(false),
+            }
+        };
+        self.retired.push(cid);
+        Ok(true)
+    }
+
+    pub fn take_retired(&mut self) -> Vec<ConnectionId> {
+        mem::replace(&mut self.retired, Vec::new())
+    }
+
+    pub fn received(&mut self, frame: &NewConnectionIdFrame) {
+        if frame.sequence <= self.remote_sequence
+            || self.remote.iter().any(|id| id.sequence == frame.sequence)
+        {
+            return;
+        }
+
+        let id = IssuedId {
+            sequence: frame.sequence,
+            cid: frame.id,
+            reset_token: frame.reset_token,
+        };
+        let pos = self.remote
+            .iter()
+            .position(|other| other.sequence > id.sequence)
+            .unwrap_or_else(|| self.remote.len());
+        self.remote.insert(pos, id);
+    }
+
+    pub fn available(&self) -> usize {
+        self.remote.len()
+    }
+
+    // The peer is told to stop expecting the CID we switch away from
+    pub fn rotate(&mut self) -> Option<(ConnectionId, RetireConnectionIdFrame)> {
+        let retired = RetireConnectionIdFrame(self.remote_sequence);
+        self.remote.pop_front().map(|id| {
+            self.remote_sequence = id.sequence;
+            (id.cid, retired)
+        })
+    }
+}
+
+#[derive(Clone, Copy)]
+struct IssuedId {
+    sequence: u64,
+    cid: ConnectionId,
+    reset_token: [u8; 16],
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{ConnectionIdGenerator, ConnectionIdManager, RandomConnectionIdGenerator,
+                ServerIdGenerator};
+    use frame::RetireConnectionIdFrame;
+    use types::ConnectionId;
+    use QuicError;
+
+    use std::sync::Arc;
+
+    fn manager() -> ConnectionIdManager {
+        ConnectionIdManager::new(Arc::new(RandomConnectionIdGenerator::new(8)))
+    }
+
+    #[test]
+    fn test_server_id_generator() {
+        let generator = ServerIdGenerator::new(1, &[0xab, 0xcd], 8);
+        let first = generator.generate();
+        let second = generator.generate();
+        assert_ne!(first, second);
+        assert_eq!(first.len, 8);
+        assert_eq!(first[0], 0x47);
+        assert_eq!(generator.server_id(&first), Some(&[0xab, 0xcd][..]));
+        assert_eq!(generator.server_id(&second), Some(&[0xab, 0xcd][..]));
+
+        let other = ServerIdGenerator::new(2, &[0xab, 0xcd], 8);
+        assert_eq!(other.server_id(&first), None);
+        assert_eq!(generator.server_id(&ConnectionId::new(&[0x47; 12])), None);
+    }
+
+    #[test]
+    fn test_issue_with_generator() {
+        let generator = Arc::new(ServerIdGenerator::new(0, &[7; 3], 10));
+        let mut cids = ConnectionIdManager::new(generator.clone());
+        let frame = cids.issue();
+        assert_eq!(generator.server_id(&frame.id), Some(&[7; 3][..]));
+        assert_eq!(generator.server_id(&cids.generate()), Some(&[7; 3][..]));
+    }
+
+    #[test]
+    fn test_rotate_in_sequence_order() {
+        let mut remote = manager();
+        let first = remote.issue();
+        let second = remote.issue();
+
+        let mut local = manager();
+        local.received(&second);
+        local.received(&first);
+        local.received(&first);
+        assert_eq!(local.available(), 2);
+
+        assert_eq!(local.rotate(), Some((first.id, RetireConnectionIdFrame(0))));
+        local.received(&first);
+        assert_eq!(local.rotate(), Some((second.id, RetireConnectionIdFrame(1))));
+        assert_eq!(local.rotate(), None);
+    }
+
+    #[test]
+    fn test_take_unrouted() {
+        let mut cids = manager();
+        let first = cids.issue();
+        let second = cids.issue();
+        assert_eq!(cids.take_unrouted(), vec![first.id, second.id]);
+        assert!(cids.take_unrouted().is_empty());
+        let third = cids.issue();
+        assert_eq!(cids.take_unrouted(), vec![third.id]);
+        assert_eq!(cids.issued().count(), 3);
+    }
+
+    #[test]
+    fn test_retire() {
+        let initial = ConnectionId::new(&[0; 8]);
+        let mut cids = manager();
+        let first = cids.issue();
+        cids.take_unrouted();
+
+        assert_eq!(cids.retire(first.sequence, initial).unwrap(), true);
+        assert_eq!(cids.retire(first.sequence, initial).unwrap(), false);
+        assert_eq!(cids.retire(0, initial).unwrap(), true);
+        assert_eq!(cids.retire(0, initial).unwrap(), false);
+        assert_eq!(cids.take_retired(), vec![first.id, initial]);
+        assert_eq!(cids.issued().count(), 0);
+
+        match cids.retire(2, initial) {
+            Err(QuicError::Transport(..)) => {}
+            res => panic!("unexpected result: {:?}", res),
+        }
+    }
+}
@@ -1,64 +1,140 @@
+use futures::task;
 use rand::{thread_rng, Rng};
 
-use std::collections::VecDeque;
+use std::cmp;
+use std::collections::{HashMap, VecDeque};
 use std::io::Cursor;
 use std::mem;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 
-use super::{QuicError, QuicResult, QUIC_VERSION};
+use super::{ConnectError, QuicError, QuicResult, TransportError, QUIC_VERSION};
+use acks::AckTracker;
+use assembler::Assembler;
+use clock::Clock;
 use codec::{BufLen, Codec};
-use crypto::{PacketKey, Secret};
-use frame::{Ack, AckFrame, CloseFrame, Frame, PaddingFrame, PathFrame, StreamFrame};
-use packet::{Header, LongType, Packet, PartialDecode, ShortType};
+use congestion::{Algorithm, Pacer};
+use conn_ids::ConnectionIdManager;
+use crypto::{EncryptionLevel, KeyChain, Secret, HEADER_SAMPLE_LEN};
+use datagrams::Datagrams;
+use endpoint::{AdmissionFilter, EndpointConfig};
+use events::{Event, Events};
+use frame::{CloseFrame, CryptoFrame, DatagramFrame, Frame, MaxDataFrame, MaxStreamIdFrame,
+            NewTokenFrame, PathFrame, RetireConnectionIdFrame, StreamIdBlockedFrame};
+use mtu::MtuDiscovery;
+use packet::{self, Header, LongType, Packet, PartialDecode, ShortType};
+use packetizer::{pad_to, Packetizer};
 use parameters::{ClientTransportParameters, ServerTransportParameters, TransportParameters};
+use pn::PacketNumberSpace;
+use pool::BufferPool;
+use qlog::Qlog;
+use recovery::{Recovery, SentPacket};
+use socket::EcnCodepoint;
 use streams::{Dir, Streams};
 use tls;
-use types::{ConnectionId, Side, GENERATED_CID_LENGTH};
+use types::{ConnectionId, Side, StreamId};
 
 pub struct ConnectionState<T> {
     side: Side,
     state: State,
     local: PeerData,
     remote: PeerData,
-    src_pn: u32,
-    secret: Secret,
-    prev_secret: Option<Secret>,
+    space: PacketNumberSpace,
+    recovery: Recovery,
+    acks: AckTracker,
+    handshake_acks: AckTracker,
+    pacer: Pacer,
+    keys: KeyChain,
+    crypto: [CryptoStream; 4],
     pub streams: Streams,
+    pub datagrams: Datagrams,
     queue: VecDeque<Vec<u8>>,
+    coalesce: bool,
+    // Frames for 1-RTT packets, and those that may also go out in 0-RTT ones
+    control: VecDeque<Frame>,
+    early_control: VecDeque<Frame>,
+    cids: ConnectionIdManager,
+    spin: Option<bool>,
+    token: Vec<u8>,
+    new_token: Option<Vec<u8>>,
+    initial_hello: Vec<u8>,
+    retried: bool,
+    address_validated: bool,
+    bytes_received: u64,
+    bytes_sent: u64,
+    early_data: Arc<Mutex<EarlyData>>,
+    close_reason: Arc<Mutex<Option<CloseReason>>>,
+    protocol: Arc<Mutex<Option<String>>>,
+    stats: Arc<Mutex<ConnectionStats>>,
+    events: Events,
+    handshake: Arc<Mutex<HandshakeStatus>>,
+    accept_early_data: bool,
+    resend_early_data: bool,
+    resumption: Option<(String, ParamsCache)>,
+    last_activity: Instant,
+    last_sent: Instant,
+    keep_alive: Option<Duration>,
+    started: Instant,
+    handshake_timeout: Duration,
+    close_packet: Option<Vec<u8>>,
+    close_deadline: Option<Instant>,
+    received_while_closing: u64,
+    path_challenge: Option<[u8; 8]>,
+    path_deadline: Option<Instant>,
+    migration_allowed: bool,
+    congestion: Algorithm,
+    mtu: MtuDiscovery,
+    qlog: Option<Qlog>,
+    clock: Arc<Clock>,
     tls: T,
+    pool: BufferPool,
+    admission: Option<Admission>,
 }
 
 impl<T> ConnectionState<T>
 where
     T: tls::Session + tls::QuicSide,
 {
-    pub fn new(tls: T, secret: Option<Secret>) -> Self {
+    pub fn new(tls: T, secret: Option<Secret>, config: &EndpointConfig) -> Self {
         let mut rng = thread_rng();
         let dst_cid = rng.gen();
         let side = tls.side();
 
         let secret = if side == Side::Client {
             debug_assert!(secret.is_none());
-            Secret::Handshake(dst_cid)
+            Secret::Initial(dst_cid)
         } else if let Some(secret) = secret {
             secret
         } else {
             panic!("need secret for client conn_state");
         };
 
-        let local = PeerData::new(rng.gen());
-        let (num_recv_bidi, num_recv_uni) = (
-            u64::from(local.params.max_streams_bidi),
-            u64::from(local.params.max_stream_id_uni),
-        );
-        let (max_recv_bidi, max_recv_uni) = if side == Side::Client {
-            (1 + 4 * num_recv_bidi, 3 + 4 * num_recv_uni)
-        } else {
-            (4 * num_recv_bidi, 1 + 4 * num_recv_uni)
-        };
+        let cids = ConnectionIdManager::new(config.cid_generator());
+        let mut local = PeerData::new(cids.generate());
+        local.params = config.transport_parameters();
+
+        let mtu = MtuDiscovery::new(local.params.max_packet_size);
+        let max_ack_delay = Duration::from_millis(u64::from(local.params.max_ack_delay));
+        let ack_threshold = config.transport_config().ack_threshold();
+        let mut acks = AckTracker::new(max_ack_delay, ack_threshold);
+        acks.set_delay_exponent(local.params.ack_delay_exponent);
+        let mut handshake_acks = AckTracker::new(max_ack_delay, ack_threshold);
+        handshake_acks.set_delay_exponent(local.params.ack_delay_exponent);
+        let clock = config.clock_source();
+        let qlog = config
+            .qlog_writer(side, &local.cid)
+            .map(|out| Qlog::new(out, side, &local.cid));
 
-        let mut streams = Streams::new(side);
-        streams.update_max_id(max_recv_bidi);
-        streams.update_max_id(max_recv_uni);
+        let mut streams = Streams::with_config(side, config.transport_config());
+        streams.set_receive_buffer(config.receive_buffer_size());
+        streams.set_connection_buffer(config.connection_buffer_size());
+        streams.set_send_buffer(config.send_buffer_size());
+        streams.set_write_coalescing(config.write_coalescing());
+        streams.set_reset_code(config.reset_error_code());
+        streams.set_clock(clock.clone());
+        let events = Events::new();
+        streams.set_events(events.clone());
 
         ConnectionState {
             tls,
@@ -66,35 +142,516 @@ where
             state: State::Start,
             remote: PeerData::new(dst_cid),
             local,
-            src_pn: rng.gen(),
-            secret,
-            prev_secret: None,
+            space: PacketNumberSpace::new(rng.gen_range(0, MAX_INITIAL_PN)),
+            recovery: Recovery::new(config.congestion_algorithm().build()),
+            acks,
+            handshake_acks,
+            pacer: Pacer::new(config.pacing_burst_size()),
+            keys: KeyChain::new(side, &secret, config.crypto()),
+            crypto: [
+                CryptoStream::new(),
+                CryptoStream::new(),
+                CryptoStream::new(),
+                CryptoStream::new(),
+            ],
             streams,
+            datagrams: Datagrams::new(),
             queue: VecDeque::new(),
+            coalesce: false,
+            control: VecDeque::new(),
+            early_control: VecDeque::new(),
+            cids,
+            spin: if config.spin_bit_enabled() {
+                Some(false)
+            } else {
+                None
+            },
+            token: Vec::new(),
+            new_token: None,
+            initial_hello: Vec::new(),
+            retried: false,
+            // Clients picked the server's address themselves
+            address_validated: side == Side::Client,
+            bytes_received: 0,
+            bytes_sent: 0,
+            early_data: Arc::new(Mutex::new(EarlyData::Unavailable)),
+            close_reason: Arc::new(Mutex::new(None)),
+            protocol: Arc::new(Mutex::new(None)),
+            stats: Arc::new(Mutex::new(ConnectionStats::default())),
+            events,
+            handshake: Arc::new(Mutex::new(HandshakeStatus::default())),
+            accept_early_data: config.early_data_enabled(),
+            resend_early_data: config.early_data_resent(),
+            resumption: None,
+            last_activity: clock.now(),
+            last_sent: clock.now(),
+            keep_alive: config.transport_config().keep_alive(),
+            started: clock.now(),
+            handshake_timeout: config.transport_config().handshake_timeout(),
+            close_packet: None,
+            close_deadline: None,
+            received_while_closing: 0,
+            path_challenge: None,
+            path_deadline: None,
+            migration_allowed: false,
+            congestion: config.congestion_algorithm(),
+            mtu,
+            qlog,
+            clock,
+            pool: BufferPool::default(),
+            admission: None,
         }
     }
 
+    pub fn side(&self) -> Side {
+        self.side
+    }
+
+    pub fn now(&self) -> Instant {
+        self.clock.now()
+    }
+
     pub fn is_handshaking(&self) -> bool {
         match self.state {
-            State::Connected => false,
-            _ => true,
+            State::Start | State::InitialSent | State::Handshaking => true,
+            _ => false,
+        }
+    }
+
+    pub fn is_closed(&self) -> bool {
+        match self.state {
+            State::Closing | State::Draining => true,
+            _ => false,
+        }
+    }
+
+    pub fn close_deadline(&self) -> Option<Instant> {
+        if self.is_closed() {
+            self.close_deadline
+        } else {
+            None
+        }
+    }
+
+    pub fn start_path_validation(&mut self, now: Instant) {
+        let token = thread_rng().gen();
+        self.path_challenge = Some(token);
+        self.path_deadline = Some(now + self.recovery.pto() * 3);
+        self.mtu.reset(now + self.recovery.pto());
+        self.control.push_back(Frame::PathChallenge(PathFrame(token)));
+    }
+
+    pub fn is_validating_path(&self) -> bool {
+        self.path_challenge.is_some()
+    }
+
+    pub fn path_validation_deadline(&self) -> Option<Instant> {
+        self.path_deadline
+    }
+
+    pub fn mtu_probe_deadline(&self) -> Option<Instant> {
+        match self.state {
+            State::Connected => self.mtu.deadline(),
+            _ => None,
+        }
+    }
+
+    pub fn abandon_path_validation(&mut self) {
+        self.path_challenge = None;
+        self.path_deadline = None;
+    }
+
+    // Only the newest non-probing packet says where the peer is now; anything else could be
+    // reordered from the old path or just a probe
+    pub fn is_migration_allowed(&self) -> bool {
+        self.migration_allowed && !self.is_handshaking()
+    }
+
+    // A NAT rebinding usually only changes the port, and the path behind it stays the same.
+    // Until it is validated, the new address only gets a few times what arrived from it,
+    // starting with the datagram of `len` bytes that moved the peer there
+    pub fn on_peer_migrated(&mut self, now: Instant, same_host: bool, len: usize) {
+        if !same_host {
+            self.recovery.new_path(self.congestion.build());
+        }
+        self.address_validated = false;
+        self.bytes_received = len as u64;
+        self.bytes_sent = 0;
+        self.start_path_validation(now);
+    }
+
+    // Migration only happens after the handshake, so the path we fall back to was validated
+    pub fn on_path_failed(&mut self) {
+        self.abandon_path_validation();
+        self.recovery.restore_path();
+        self.address_validated = true;
+    }
+
+    // Initial and Handshake packets are acknowledged on their own, in packets of the same kind
+    fn acks_for(&mut self, level: EncryptionLevel) -> &mut AckTracker {
+        match level {
+            EncryptionLevel::Initial | EncryptionLevel::Handshake => &mut self.handshake_acks,
+            EncryptionLevel::ZeroRtt | EncryptionLevel::OneRtt => &mut self.acks,
+        }
+    }
+
+    pub fn ack_deadline(&self) -> Option<Instant> {
+        let handshake = if self.keys.has(EncryptionLevel::Handshake) {
+            self.handshake_acks.deadline()
+        } else {
+            None
+        };
+        let application = match self.state {
+            State::Connected => self.acks.deadline(),
+            _ => None,
+        };
+        match (handshake, application) {
+            (Some(handshake), Some(application)) => Some(handshake.min(application)),
+            (handshake, application) => handshake.or(application),
+        }
+    }
+
+    pub fn early_data(&self) -> Arc<Mutex<EarlyData>> {
+        self.early_data.clone()
+    }
+
+    pub fn stats(&self) -> Arc<Mutex<ConnectionStats>> {
+        self.stats.clone()
+    }
+
+    fn set_early_data(&self, status: EarlyData) {
+        *self.early_data.lock().unwrap() = status;
+    }
+
+    pub fn close_reason(&self) -> Arc<Mutex<Option<CloseReason>>> {
+        self.close_reason.clone()
+    }
+
+    fn set_close_reason(&self, reason: CloseReason) {
+        {
+            let mut current = self.close_reason.lock().unwrap();
+            if current.is_some() {
+                return;
+            }
+            self.events.push(Event::ConnectionLost {
+                error: reason.to_error(),
+            });
+            *current = Some(reason);
+        }
+        // Anyone still waiting for the handshake has to learn that it won't complete
+        self.handshake.lock().unwrap().wake();
+    }
+
+    pub fn handshake(&self) -> Arc<Mutex<HandshakeStatus>> {
+        self.handshake.clone()
+    }
+
+    pub fn events(&self) -> Events {
+        self.events.clone()
+    }
+
+    pub fn protocol(&self) -> Arc<Mutex<Option<String>>> {
+        self.protocol.clone()
+    }
+
+    fn check_protocol(&self) -> QuicResult<()> {
+        match self.tls.get_alpn_protocol() {
+            Some(protocol) => {
+                *self.protocol.lock().unwrap() = Some(protocol.into());
+                Ok(())
+            }
+            None => Err(QuicError::Transport(
+                TransportError::Crypto(tls::NO_APPLICATION_PROTOCOL),
+                "no application protocol in common with peer".into(),
+            )),
+        }
+    }
+
+    pub fn export_keying_material(
+        &self,
+        label: &[u8],
+        context: Option<&[u8]>,
+        len: usize,
+    ) -> QuicResult<Vec<u8>> {
+        if self.is_handshaking() {
+            return Err(QuicError::General(
+                "keying material is only available after the handshake".into(),
+            ));
         }
+        let mut output = vec![0; len];
+        self.tls.export_keying_material(&mut output, label, context)?;
+        Ok(output)
+    }
+
+    pub fn can_send_early(&self) -> bool {
+        self.side == Side::Client && self.is_handshaking()
+            && self.keys.has(EncryptionLevel::ZeroRtt)
     }
 
     pub fn queued(&mut self) -> QuicResult<Option<&Vec<u8>>> {
-        let mut frames = vec![];
-        while let Some(frame) = self.streams.queued() {
-            frames.push(frame);
+        self.flush_frames()?;
+        match self.queue.front() {
+            Some(packet) if self.is_amplification_limited(packet.len()) => Ok(None),
+            front => Ok(front),
+        }
+    }
+
+    // Until the peer's address is validated, we may only send a few times what we received
+    fn is_amplification_limited(&self, len: usize) -> bool {
+        !self.address_validated
+            && self.bytes_sent + len as u64 > AMPLIFICATION_FACTOR * self.bytes_received
+    }
+
+    fn flush_frames(&mut self) -> QuicResult<()> {
+        if self.is_closed() {
+            return Ok(());
+        }
+        let now = self.clock.now();
+        // Handshake packets that nothing was sent in reply to are still acknowledged in time
+        if self.keys.has(EncryptionLevel::Handshake) && self.handshake_acks.should_send(now) {
+            if let Some(ack) = self.handshake_acks.frame(now) {
+                self.build_packet(Some(LongType::Handshake), vec![Frame::Ack(ack)])?;
+                self.handshake_acks.on_ack_sent();
+            }
+        }
+
+        let ptype = if !self.is_handshaking() {
+            // Whatever was waiting for a 0-RTT packet goes out in a 1-RTT one instead
+            while let Some(frame) = self.early_control.pop_back() {
+                self.control.push_front(frame);
+            }
+            None
+        } else if self.can_send_early() {
+            Some(LongType::Protected)
+        } else {
+            return Ok(());
+        };
+
+        // Outstanding acknowledgements ride along with whatever else we send
+        let mut ack = match ptype {
+            None if self.acks.pending() => self.acks.frame(now),
+            _ => None,
+        };
+        let reserved = ack.as_ref().map_or(0, |ack| ack.buf_len());
+        let max_payload = self.max_payload(ptype)? - reserved;
+        while let Some(frame) = self.datagrams.queued() {
+            if frame.buf_len() + 1 > max_payload {
+                debug!("dropping datagram of {} bytes that does not fit", frame.0.len());
+                continue;
+            }
+            match ptype {
+                None => self.control.push_back(Frame::Datagram(frame)),
+                Some(_) => self.early_control.push_back(Frame::Datagram(frame)),
+            }
+        }
+        let mut packetizer = Packetizer::new(max_payload, self.recovery.send_budget());
+        loop {
+            let mut payload = {
+                let control = match ptype {
+                    None => &mut self.control,
+                    Some(_) => &mut self.early_control,
+                };
+                match packetizer.next_packet(control, &mut self.streams) {
+                    Some(payload) => payload,
+                    None => break,
+                }
+            };
+            debug_assert!(ptype.is_none() || payload.iter().all(Frame::is_0rtt_allowed));
+            if let Some(ack) = ack.take() {
+                payload.insert(0, Frame::Ack(ack));
+                self.acks.on_ack_sent();
+            }
+            self.build_packet(ptype, payload)?;
+        }
+        if let Some(ack) = ack {
+            if self.acks.should_send(now) {
+                self.build_packet(None, vec![Frame::Ack(ack)])?;
+                self.acks.on_ack_sent();
+            }
         }
 
-        if !frames.is_empty() {
-            self.build_packet(None, frames)?
+        if ptype.is_none() && self.recovery.can_send() {
+            if let Some(size) = self.mtu.next_probe(now) {
+                self.send_mtu_probe(size)?;
+            }
         }
-        Ok(self.queue.front())
+        Ok(())
+    }
+
+    fn max_payload(&self, ptype: Option<LongType>) -> QuicResult<usize> {
+        let (header_len, level) = match ptype {
+            Some(_) => (
+                14 + self.remote.cid.len as usize + self.local.cid.len as usize,
+                EncryptionLevel::ZeroRtt,
+            ),
+            None => (5 + self.remote.cid.len as usize, EncryptionLevel::OneRtt),
+        };
+        Ok(self.mtu.current() - header_len - self.keys.tag_len(level)?)
+    }
+
+    fn send_mtu_probe(&mut self, size: u16) -> QuicResult<()> {
+        let number = self.space.peek_next();
+        let header_len = 1 + self.remote.cid.len as usize + self.space.encoded_len(number);
+        let tag_len = self.keys.tag_len(EncryptionLevel::OneRtt)?;
+        let mut payload = vec![Frame::Ping];
+        pad_to(&mut payload, size as usize - header_len - tag_len);
+        self.build_packet(None, payload)?;
+        self.recovery.on_mtu_probe_sent(number);
+        self.mtu.on_probe_sent(number, size);
+        Ok(())
+    }
+
+    pub(crate) fn ecn_codepoint(&self) -> Option<EcnCodepoint> {
+        self.recovery.ecn_codepoint()
     }
 
     pub fn pop_queue(&mut self) {
-        self.queue.pop_front();
+        if let Some(packet) = self.queue.pop_front() {
+            self.bytes_sent += packet.len() as u64;
+            self.pacer.on_sent(packet.len());
+            self.pool.put(packet);
+        }
+    }
+
+    pub(crate) fn buffer_pool(&self) -> &BufferPool {
+        &self.pool
+    }
+
+    pub(crate) fn set_buffer_pool(&mut self, pool: BufferPool) {
+        self.pool = pool;
+    }
+
+    pub fn pacing_delay(&mut self, now: Instant) -> Option<Instant> {
+        let len = self.queue.front()?.len();
+        let rate = self.recovery.pacing_rate();
+        self.pacer.delay(now, rate, len)
+    }
+
+    pub fn idle_timeout(&self) -> Option<Duration> {
+        let (local, remote) = (self.local.params.idle_timeout, self.remote.params.idle_timeout);
+        let timeout = match (local, remote) {
+            (0, 0) => return None,
+            (0, timeout) | (timeout, 0) => timeout,
+            (local, remote) => cmp::min(local, remote),
+        };
+        Some(Duration::from_secs(u64::from(timeout)))
+    }
+
+    pub fn idle_deadline(&self) -> Option<Instant> {
+        if self.is_closed() {
+            return None;
+        }
+        self.idle_timeout()
+            .map(|timeout| self.last_activity + timeout)
+    }
+
+    pub fn on_idle_timeout(&mut self, now: Instant) -> QuicResult<bool> {
+        match self.idle_deadline() {
+            Some(deadline) if deadline <= now => {
+                let reason = "idle timeout";
+                self.set_close_reason(CloseReason::Local(TransportError::NoError, reason.into()));
+                self.close(TransportError::NoError, reason)?;
+                Ok(true)
+            }
+            _ => Ok(false),
+        }
+    }
+
+    pub fn handshake_deadline(&self) -> Option<Instant> {
+        if !self.is_handshaking() {
+            return None;
+        }
+        Some(self.started + self.handshake_timeout)
+    }
+
+    pub fn on_handshake_timeout(&mut self, now: Instant) -> QuicResult<bool> {
+        match self.handshake_deadline() {
+            Some(deadline) if deadline <= now => {
+                self.abort_handshake(ConnectError::TimedOut)?;
+                Ok(true)
+            }
+            _ => Ok(false),
+        }
+    }
+
+    pub(crate) fn abort_handshake(&mut self, error: ConnectError) -> QuicResult<()> {
+        debug!("aborting handshake: {}", error);
+        let reason = error.to_string();
+        self.set_close_reason(CloseReason::Handshake(error));
+        self.close(TransportError::NoError, &reason)
+    }
+
+    pub fn keep_alive_deadline(&self) -> Option<Instant> {
+        if self.state != State::Connected {
+            return None;
+        }
+        self.keep_alive
+            .map(|interval| self.last_sent + interval)
+    }
+
+    pub fn send_keep_alive(&mut self, now: Instant) {
+        self.control.push_back(Frame::Ping);
+        self.last_sent = now;
+    }
+
+    pub fn close(&mut self, error: TransportError, reason: &str) -> QuicResult<()> {
+        self.close_with(Frame::ConnectionClose(CloseFrame {
+            code: error.code(),
+            reason: reason.into(),
+        }))
+    }
+
+    pub fn close_application(&mut self, code: u16, reason: &str) -> QuicResult<()> {
+        self.close_with(Frame::ApplicationClose(CloseFrame {
+            code,
+            reason: reason.into(),
+        }))
+    }
+
+    fn close_with(&mut self, frame: Frame) -> QuicResult<()> {
+        if self.is_closed() {
+            return Ok(());
+        }
+        let ptype = match self.state {
+            // Refused before the handshake started, so only Initial keys exist
+            State::Start => Some(LongType::Initial),
+            _ if self.is_handshaking() => Some(LongType::Handshake),
+            _ => {
+                // Get pending stream data out ahead of the close
+                self.queued()?;
+                None
+            }
+        };
+        self.control.clear();
+        self.early_control.clear();
+        // Keep the close packet in a datagram of its own so it can be resent as is
+        self.coalesce = false;
+        self.build_packet(ptype, vec![frame])?;
+        self.coalesce = false;
+        self.close_packet = self.queue.back().cloned();
+        self.enter_closed(State::Closing);
+        Ok(())
+    }
+
+    fn enter_closed(&mut self, state: State) {
+        self.state = state;
+        self.close_deadline = Some(self.clock.now() + self.recovery.pto() * 3);
+    }
+
+    fn on_packet_while_closing(&mut self) {
+        // Only answer with the close packet on exponentially spaced arrivals,
+        // so a peer that keeps sending can't make us flood it
+        self.received_while_closing += 1;
+        if !self.received_while_closing.is_power_of_two() {
+            return;
+        }
+        if let Some(ref packet) = self.close_packet {
+            self.queue.push_back(packet.clone());
+        }
+    }
+
+    pub fn local_cid(&self) -> ConnectionId {
+        self.local.cid
     }
 
     pub fn pick_unused_cid<F>(&mut self, is_used: F) -> ConnectionId
@@ -102,60 +659,114 @@ where
         F: Fn(ConnectionId) -> bool,
     {
         while is_used(self.local.cid) {
-            self.local.cid = thread_rng().gen();
+            self.local.cid = self.cids.generate();
         }
         self.local.cid
     }
 
-    fn encode_key(&self, h: &Header) -> PacketKey {
-        if let Some(LongType::Handshake) = h.ptype() {
-            if let Some(ref secret @ Secret::Handshake(_)) = self.prev_secret {
-                return secret.build_key(self.side);
+    pub fn set_token(&mut self, token: Vec<u8>) {
+        self.token = token;
+    }
+
+    pub fn new_token(&self) -> Option<&[u8]> {
+        self.new_token.as_ref().map(|token| &token[..])
+    }
+
+    pub(crate) fn send_new_token(&mut self, token: Vec<u8>) {
+        self.control.push_back(Frame::NewToken(NewTokenFrame(token)));
+    }
+
+    pub(crate) fn refuse(&mut self) {
+        self.admission = Some(Admission::Refuse);
+    }
+
+    pub(crate) fn check_admission(&mut self, addr: SocketAddr, filter: AdmissionFilter) {
+        self.admission = Some(Admission::Check(addr, filter));
+    }
+
+    // Decided on the client's first handshake data, before TLS does any work for it
+    fn admit(&mut self, hello: &[u8]) -> bool {
+        match self.admission.take() {
+            None => true,
+            Some(Admission::Refuse) => false,
+            Some(Admission::Check(addr, filter)) => {
+                let server_name = tls::client_hello_sni(hello);
+                filter(&addr, server_name.as_ref().map(|name| &name[..]))
             }
         }
-        self.secret.build_key(self.side)
     }
 
-    pub(crate) fn decode_key(&self, h: &Header) -> PacketKey {
-        if let Some(LongType::Handshake) = h.ptype() {
-            if let Some(ref secret @ Secret::Handshake(_)) = self.prev_secret {
-                return secret.build_key(self.side.other());
-            }
+    pub(crate) fn set_address_validated(&mut self) {
+        self.address_validated = true;
+    }
+
+    pub fn initiate_key_update(&mut self) -> QuicResult<()> {
+        if self.is_handshaking() {
+            return Err(QuicError::General(
+                "cannot update keys during the handshake".into(),
+            ));
         }
-        self.secret.build_key(self.side.other())
+        self.keys.update()
+    }
+
+    pub(crate) fn take_unrouted_cids(&mut self) -> Vec<ConnectionId> {
+        self.cids.take_unrouted()
+    }
+
+    pub(crate) fn take_retired_cids(&mut self) -> Vec<ConnectionId> {
+        self.cids.take_retired()
+    }
+
+    pub fn rotate_remote_cid(&mut self) -> Option<ConnectionId> {
+        let (cid, retired) = self.cids.rotate()?;
+        self.remote.cid = cid;
+        self.control.push_back(Frame::RetireConnectionId(retired));
+        Some(cid)
     }
 
     pub(crate) fn set_secret(&mut self, secret: Secret) {
-        let old = mem::replace(&mut self.secret, secret);
-        self.prev_secret = Some(old);
+        self.keys.install(EncryptionLevel::OneRtt, &secret);
     }
 
     fn build_packet(&mut self, ptype: Option<LongType>, mut payload: Vec<Frame>) -> QuicResult<()> {
-        let number = self.src_pn;
-        self.src_pn += 1;
+        let number = self.space.take_next();
+        let level = match ptype {
+            Some(LongType::Handshake) => EncryptionLevel::Handshake,
+            Some(LongType::Protected) => EncryptionLevel::ZeroRtt,
+            Some(_) => EncryptionLevel::Initial,
+            None => EncryptionLevel::OneRtt,
+        };
 
-        let mut payload_len = (payload.buf_len() + self.secret.tag_len()) as u64;
-        if ptype == Some(LongType::Initial) && payload_len < 1200 {
-            payload.push(Frame::Padding(PaddingFrame((1200 - payload_len) as usize)));
-            payload_len = 1200;
+        let tag_len = self.keys.tag_len(level)?;
+        if ptype == Some(LongType::Initial) && self.side == Side::Client {
+            pad_to(&mut payload, 1200 - tag_len);
         } else if ptype == None {
             debug_assert_eq!(self.state, State::Connected);
+            // Header protection samples the ciphertext 4 bytes past the packet number
+            let min_len = 4 + HEADER_SAMPLE_LEN - self.space.encoded_len(number);
+            pad_to(&mut payload, min_len.saturating_sub(tag_len));
         }
+        let payload_len = (payload.buf_len() + tag_len) as u64;
 
         let (dst_cid, src_cid) = (self.remote.cid, self.local.cid);
-        debug_assert_eq!(src_cid.len, GENERATED_CID_LENGTH);
         let header = match ptype {
             Some(ltype) => Header::Long {
                 ptype: ltype,
                 version: QUIC_VERSION,
                 dst_cid,
                 src_cid,
+                token: if ltype == LongType::Initial {
+                    self.token.clone()
+                } else {
+                    Vec::new()
+                },
                 len: payload_len,
                 number,
             },
             None => Header::Short {
-                key_phase: false,
-                ptype: ShortType::Four,
+                key_phase: self.keys.key_phase(),
+                spin: self.spin.unwrap_or_else(|| thread_rng().gen()),
+                ptype: ShortType::from_len(self.space.encoded_len(number)),
                 dst_cid,
                 number,
             },
@@ -164,164 +775,704 @@ where
         self.queue_packet(Packet { header, payload })
     }
 
-    #[cfg_attr(feature = "cargo-clippy", allow(needless_pass_by_value))]
     pub fn queue_packet(&mut self, packet: Packet) -> QuicResult<()> {
-        let key = self.encode_key(&packet.header);
-        let len = packet.buf_len() + key.algorithm().tag_len();
-        let mut buf = vec![0u8; len];
-        packet.encode(&key, &mut buf)?;
-        self.queue.push_back(buf);
+        let level = EncryptionLevel::of(&packet.header);
+        let len = packet.buf_len() + self.keys.tag_len(level)?;
+        let mut buf = self.pool.get(len);
+        packet.encode(&self.keys, &mut buf)?;
+
+        // Packets with a length field can share a datagram with the packets after them
+        let max_len = self.mtu.current();
+        let coalesce = self.coalesce
+            && self.queue
+                .back()
+                .map_or(false, |datagram| datagram.len() + len <= max_len);
+        if coalesce {
+            self.queue.back_mut().unwrap().extend_from_slice(&buf);
+            self.pool.put(buf);
+        } else {
+            self.queue.push_back(buf);
+        }
+        self.coalesce = match packet.header {
+            Header::Long { .. } => true,
+            _ => false,
+        };
+
+        let now = self.clock.now();
+        self.last_sent = now;
+        let Packet { header, payload } = packet;
+        if let Some(ref mut qlog) = self.qlog {
+            qlog.packet_sent(&header, len, &payload);
+        }
+        let number = header.number();
+        let sent = SentPacket::new(number, header.ptype(), now, len, payload);
+        self.recovery.on_packet_sent(number, sent);
+        self.on_metrics_updated();
         Ok(())
     }
 
-    pub(crate) fn handle(&mut self, buf: &mut [u8]) -> QuicResult<()> {
-        self.handle_partial(Packet::start_decode(buf))
+    pub fn loss_detection_timer(&self) -> Option<Instant> {
+        if self.is_closed() {
+            return None;
+        }
+        self.recovery
+            .timeout(self.side == Side::Client && self.is_handshaking())
     }
 
-    pub(crate) fn handle_partial(&mut self, partial: PartialDecode) -> QuicResult<()> {
-        let key = self.decode_key(&partial.header);
-        self.handle_packet(partial.finish(&key)?)
+    pub fn on_loss_timeout(&mut self, now: Instant) -> QuicResult<()> {
+        if self.is_closed() {
+            return Ok(());
+        }
+        let (probes, next) = (self.recovery.pto_count(), self.space.peek_next());
+        let lost = self.recovery.on_timeout(now);
+        self.on_metrics_updated();
+        self.retransmit(lost)?;
+        // A probe has to elicit an acknowledgement, even when there was nothing left to resend
+        if self.recovery.pto_count() > probes && self.space.peek_next() == next {
+            self.send_probe()?;
+        }
+        Ok(())
     }
 
-    #[cfg_attr(feature = "cargo-clippy", allow(needless_pass_by_value))]
-    fn handle_packet(&mut self, p: Packet) -> QuicResult<()> {
-        let dst_cid = match p.header {
-            Header::Long {
-                dst_cid, src_cid, ..
-            } => match self.state {
-                State::Start | State::InitialSent => {
-                    self.remote.cid = src_cid;
-                    dst_cid
-                }
-                _ => dst_cid,
-            },
-            Header::Short { dst_cid, .. } => if let State::Connected = self.state {
-                dst_cid
-            } else {
-                return Err(QuicError::General(format!(
-                    "short header received in {:?} state",
-                    self.state
-                )));
-            },
+    fn send_probe(&mut self) -> QuicResult<()> {
+        let ptype = if !self.is_handshaking() {
+            None
+        } else if self.keys.has(EncryptionLevel::Handshake) {
+            Some(LongType::Handshake)
+        } else {
+            Some(LongType::Initial)
         };
+        self.build_packet(ptype, vec![Frame::Ping])
+    }
 
-        if self.state != State::Start && dst_cid != self.local.cid {
-            return Err(QuicError::General(format!(
-                "invalid destination CID {:?} received (expected {:?})",
-                dst_cid, self.local.cid
-            )));
+    fn on_metrics_updated(&mut self) {
+        *self.stats.lock().unwrap() = ConnectionStats {
+            rtt: self.recovery.rtt(),
+            congestion_window: self.recovery.window(),
+            bytes_in_flight: self.recovery.bytes_in_flight(),
+            pto_count: self.recovery.pto_count(),
+            peer_streams_blocked: self.streams.peer_blocked(),
+        };
+        self.streams.set_rtt(self.recovery.rtt());
+        if let Some(ref mut qlog) = self.qlog {
+            qlog.metrics_updated(&self.recovery);
         }
+    }
 
-        let mut payload = vec![
-            Frame::Ack(AckFrame {
-                largest: p.number(),
-                ack_delay: 0,
-                blocks: vec![Ack::Ack(0)],
-            }),
-        ];
-
-        let mut received_tls = false;
-        let mut wrote_handshake = false;
-        for frame in &p.payload {
-            match frame {
-                Frame::Stream(f) if f.id == 0 => {
-                    received_tls = true;
-                    if let Some(frame) = self.handle_tls(Some(f))? {
-                        payload.push(Frame::Stream(frame));
-                        wrote_handshake = true;
-                    }
-                }
-                Frame::PathChallenge(PathFrame(token)) => {
-                    payload.push(Frame::PathResponse(PathFrame(*token)));
-                }
-                Frame::ApplicationClose(CloseFrame { code, reason }) => {
-                    return Err(QuicError::ApplicationClose(*code, reason.clone()));
+    fn on_packets_acked(&mut self, acked: Vec<SentPacket>) {
+        let now = self.clock.now();
+        for packet in acked {
+            self.mtu.on_acked(packet.number, now);
+            for frame in packet.frames {
+                if let Frame::Stream(f) = frame {
+                    self.streams.on_stream_acked(&f);
                 }
-                Frame::ConnectionClose(CloseFrame { code, reason }) => {
-                    return Err(QuicError::ConnectionClose(*code, reason.clone()));
-                }
-                Frame::Ack(_)
-                | Frame::Padding(_)
-                | Frame::PathResponse(_)
-                | Frame::Stream(_)
-                | Frame::Ping
-                | Frame::StreamIdBlocked(_) => {}
             }
         }
+    }
 
-        match self.state {
-            State::Start | State::InitialSent => {
-                self.state = State::Handshaking;
+    fn retransmit(&mut self, lost: Vec<SentPacket>) -> QuicResult<()> {
+        let now = self.clock.now();
+        for packet in lost {
+            if let Some(ref mut qlog) = self.qlog {
+                qlog.packet_lost(packet.ptype, packet.number);
             }
-            State::Handshaking if !received_tls => {
-                if let Some(frame) = self.handle_tls(None)? {
-                    payload.push(Frame::Stream(frame));
-                    wrote_handshake = true;
+            self.mtu.on_lost(packet.number, now);
+            let mut frames = Vec::new();
+            for frame in packet.frames {
+                match frame {
+                    // A response to the lost challenge could still arrive, but only one
+                    // answering the new token validates the path
+                    Frame::PathChallenge(_) if self.path_challenge.is_some() => {
+                        let token = thread_rng().gen();
+                        self.path_challenge = Some(token);
+                        frames.push(Frame::PathChallenge(PathFrame(token)));
+                    }
+                    frame => frames.extend(self.streams.regenerate(frame)),
                 }
             }
-            _ => {}
-        }
-
-        if self.state == State::Connected && !wrote_handshake {
-            self.build_packet(None, payload)
-        } else {
-            self.build_packet(Some(LongType::Handshake), payload)
+            if frames.is_empty() {
+                continue;
+            }
+            match packet.ptype {
+                // Early data that is still outstanding goes out in 1-RTT packets
+                Some(LongType::Protected) if !self.is_handshaking() => self.control.extend(frames),
+                Some(LongType::Protected) => self.early_control.extend(frames),
+                Some(ptype) => self.build_packet(Some(ptype), frames)?,
+                None => self.control.extend(frames),
+            }
         }
+        Ok(())
     }
 
-    fn handle_tls(&mut self, frame: Option<&StreamFrame>) -> QuicResult<Option<StreamFrame>> {
-        let (handshake, new_secret) =
-            tls::process_handshake_messages(&mut self.tls, frame.map(|f| f.data.as_ref()))?;
+    pub(crate) fn handle(&mut self, buf: &mut [u8]) -> QuicResult<()> {
+        self.handle_ecn(buf, None)
+    }
 
-        if let Some(secret) = new_secret {
-            self.set_secret(secret);
-            self.state = State::Connected;
+    pub(crate) fn handle_ecn(
+        &mut self,
+        buf: &mut [u8],
+        ecn: Option<EcnCodepoint>,
+    ) -> QuicResult<()> {
+        match self.handle_datagram(buf, ecn) {
+            Err(QuicError::Transport(error, reason)) => {
+                debug!("closing connection after protocol error {}: {}", error, reason);
+                self.set_close_reason(CloseReason::Local(error, reason.clone()));
+                self.close(error, &reason)
+            }
+            // Tell the peer why TLS gave up rather than going away silently
+            Err(QuicError::Tls(e)) => {
+                let (error, reason) = (TransportError::Crypto(tls::alert_for(&e)), e.to_string());
+                debug!("closing connection after TLS error: {}", reason);
+                self.set_close_reason(CloseReason::Local(error, reason.clone()));
+                self.close(error, &reason)
+            }
+            result => result,
+        }
+    }
 
-            let params = match self.tls.get_quic_transport_parameters() {
+    fn handle_datagram(&mut self, buf: &mut [u8], ecn: Option<EcnCodepoint>) -> QuicResult<()> {
+        self.bytes_received += buf.len() as u64;
+        self.migration_allowed = false;
+        let mut buf = buf;
+        while !buf.is_empty() {
+            let datagram = mem::replace(&mut buf, &mut []);
+            if self.side == Side::Client && self.state == State::InitialSent {
+                if let Some((dst_cid, src_cid, versions)) = packet::version_negotiation(datagram) {
+                    // A real one echoes the CIDs from our Initial, which an off-path attacker
+                    // never saw
+                    if dst_cid == self.local.cid && src_cid == self.remote.cid {
+                        self.on_version_negotiation(versions);
+                    } else {
+                        debug!("ignoring version negotiation for other connection IDs");
+                    }
+                    return Ok(());
+                }
+            }
+            let cid_len = self.local.cid.len as usize;
+            let (partial, rest) = match Packet::start_decode(datagram, cid_len)
+                .and_then(PartialDecode::split_coalesced)
+            {
+                Ok(split) => split,
+                Err(e) => {
+                    debug!("dropping malformed packet: {:?}", e);
+                    return Ok(());
+                }
+            };
+            self.handle_partial(partial, ecn)?;
+            buf = rest;
+        }
+        Ok(())
+    }
+
+    fn on_version_negotiation(&mut self, versions: Vec<u32>) {
+        // Servers only send these for versions they don't support, so this one is forged
+        if versions.contains(&QUIC_VERSION) {
+            debug!("ignoring version negotiation that lists our own version");
+            return;
+        }
+        debug!("server only supports versions {:?}", versions);
+        self.set_close_reason(CloseReason::Handshake(ConnectError::VersionMismatch(versions)));
+        // Without a version in common, there's no way to tell the server we're leaving
+        self.enter_closed(State::Draining);
+    }
+
+    pub(crate) fn handle_partial(
+        &mut self,
+        mut partial: PartialDecode,
+        ecn: Option<EcnCodepoint>,
+    ) -> QuicResult<()> {
+        match self.state {
+            State::Closing => {
+                self.on_packet_while_closing();
+                return Ok(());
+            }
+            State::Draining => return Ok(()),
+            _ => {}
+        }
+
+        if let Header::Retry { .. } = partial.header {
+            return self.handle_retry(&partial.header);
+        }
+
+        if EncryptionLevel::of(&partial.header) == EncryptionLevel::ZeroRtt
+            && !self.keys.has(EncryptionLevel::ZeroRtt)
+        {
+            debug!("dropping 0-RTT packet without early data keys");
+            if self.side == Side::Server {
+                self.set_early_data(EarlyData::Rejected);
+            }
+            return Ok(());
+        }
+
+        if let Err(e) = partial.remove_protection(&self.keys) {
+            debug!("dropping packet with invalid header protection: {:?}", e);
+            return Ok(());
+        }
+        let number = self.space
+            .expand(partial.header.number(), partial.header.pn_len());
+        partial.header.set_number(number);
+
+        let size = partial.size();
+        let packet = match partial.finish(&mut self.keys) {
+            Ok(packet) => packet,
+            // The payload authenticated, so the peer really did send these frames
+            Err(e @ QuicError::UnexpectedEnd)
+            | Err(e @ QuicError::InvalidEncoding(_))
+            | Err(e @ QuicError::UnknownFrameType(_)) => {
+                return Err(QuicError::Transport(
+                    TransportError::FrameEncodingError,
+                    e.to_string(),
+                ));
+            }
+            Err(e) => {
+                debug!("dropping packet that failed to decrypt: {:?}", e);
+                return Ok(());
+            }
+        };
+        if self.space.is_duplicate(number) {
+            debug!("dropping duplicate packet {}", number);
+            return Ok(());
+        }
+        if let Header::Short { spin, .. } = packet.header {
+            self.migration_allowed = self.space
+                .largest_received()
+                .map_or(true, |largest| number > largest)
+                && !packet.payload.iter().all(Frame::is_probing);
+            self.update_spin(spin, number);
+        }
+        self.space.on_receive(number);
+        // Each coalesced packet counts, since they all carried the datagram's mark
+        if let Some(ecn) = ecn {
+            self.acks_for(EncryptionLevel::of(&packet.header)).on_ecn(ecn);
+        }
+        self.last_activity = self.clock.now();
+        if let Some(ref mut qlog) = self.qlog {
+            qlog.packet_received(&packet.header, size, &packet.payload);
+        }
+        self.handle_packet(packet)
+    }
+
+    // The server can't read the 0-RTT packets, so waiting for them to be declared lost would
+    // only hold their data up
+    fn on_early_data_rejected(&mut self) -> QuicResult<()> {
+        let rejected = self.recovery.discard(LongType::Protected);
+        let mut ids = rejected
+            .iter()
+            .flat_map(|packet| packet.frames.iter())
+            .filter_map(|frame| match *frame {
+                Frame::Stream(ref f) => Some(f.id),
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+        ids.sort();
+        ids.dedup();
+        debug!("server rejected 0-RTT data for streams {:?}", ids);
+        if !self.resend_early_data {
+            // Stream frames aren't regenerated for failed streams, but everything else still is
+            self.streams.early_data_rejected(&ids);
+        }
+        self.events.push(Event::EarlyDataRejected(ids));
+        self.retransmit(rejected)
+    }
+
+    // The server echoes the spin bit and the client inverts it, so it flips once per round trip
+    fn update_spin(&mut self, received: bool, number: u64) {
+        if self.space.largest_received().map_or(false, |largest| number <= largest) {
+            return;
+        }
+        if let Some(ref mut spin) = self.spin {
+            *spin = match self.side {
+                Side::Client => !received,
+                Side::Server => received,
+            };
+        }
+    }
+
+    fn handle_retry(&mut self, header: &Header) -> QuicResult<()> {
+        let (dst_cid, src_cid, orig_dst_cid, token) = match *header {
+            Header::Retry {
+                dst_cid,
+                src_cid,
+                orig_dst_cid,
+                ref token,
+                ..
+            } => (dst_cid, src_cid, orig_dst_cid, token.clone()),
+            _ => unreachable!(),
+        };
+
+        if self.side != Side::Client || self.state != State::InitialSent {
+            debug!("dropping unexpected Retry packet in {:?} state", self.state);
+            return Ok(());
+        }
+        if self.retried {
+            // Asked to retry the retried Initial, which can only go on forever
+            if orig_dst_cid == self.remote.cid && dst_cid == self.local.cid {
+                return self.abort_handshake(ConnectError::RetryLoop);
+            }
+            debug!("dropping Retry after having retried");
+            return Ok(());
+        }
+        if orig_dst_cid != self.remote.cid {
+            debug!(
+                "dropping Retry for {:?} (expected {:?})",
+                orig_dst_cid, self.remote.cid
+            );
+            return Ok(());
+        }
+        // Anything else would either not reach us or leave the Initial keys unchanged
+        if dst_cid != self.local.cid || src_cid == orig_dst_cid || token.is_empty() {
+            debug!("dropping invalid Retry from {:?}", src_cid);
+            return Ok(());
+        }
+
+        // Initial keys are bound to the destination CID, so they have to be derived again
+        self.retried = true;
+        self.remote.cid = src_cid;
+        self.token = token;
+        self.keys
+            .install(EncryptionLevel::Initial, &Secret::Initial(src_cid));
+
+        let hello = self.initial_hello.clone();
+        self.build_packet(
+            Some(LongType::Initial),
+            vec![
+                Frame::Crypto(CryptoFrame {
+                    offset: 0,
+                    data: hello.into(),
+                }),
+            ],
+        )
+    }
+
+    #[cfg_attr(feature = "cargo-clippy", allow(needless_pass_by_value))]
+    fn handle_packet(&mut self, p: Packet) -> QuicResult<()> {
+        let dst_cid = match p.header {
+            Header::Long {
+                dst_cid, src_cid, ..
+            } => match self.state {
+                State::Start | State::InitialSent => {
+                    self.remote.cid = src_cid;
+                    dst_cid
+                }
+                _ => dst_cid,
+            },
+            Header::Retry { .. } => unreachable!(),
+            Header::Short { dst_cid, .. } => if let State::Connected = self.state {
+                dst_cid
+            } else {
+                return Err(QuicError::General(format!(
+                    "short header received in {:?} state",
+                    self.state
+                )));
+            },
+        };
+
+        let known_cid = dst_cid == self.local.cid || self.cids.issued().any(|cid| *cid == dst_cid);
+        if self.state != State::Start && !known_cid {
+            return Err(QuicError::General(format!(
+                "invalid destination CID {:?} received (expected {:?})",
+                dst_cid, self.local.cid
+            )));
+        }
+
+        if self.side == Side::Server && p.header.ptype() == Some(LongType::Handshake) {
+            self.address_validated = true;
+        }
+
+        if p.header.ptype() == Some(LongType::Protected) && self.side == Side::Client {
+            return Err(QuicError::Transport(
+                TransportError::ProtocolViolation,
+                "0-RTT packet received by client".into(),
+            ));
+        }
+
+        let level = EncryptionLevel::of(&p.header);
+        let handshake_level = level == EncryptionLevel::Initial
+            || level == EncryptionLevel::Handshake;
+        if let Some(frame) = p.payload.iter().find(|f| !f.is_allowed_at(level)) {
+            return Err(QuicError::Transport(
+                TransportError::ProtocolViolation,
+                format!("frame not allowed in {:?} packet: {:?}", level, frame),
+            ));
+        }
+
+        let now = self.clock.now();
+        let ack_eliciting = p.payload.iter().any(Frame::is_ack_eliciting);
+        self.acks_for(level)
+            .on_receive(p.number(), ack_eliciting, now);
+        // Handshake packets are never acknowledged late
+        let ack_now = ack_eliciting && handshake_level;
+
+        let mut payload = Vec::new();
+
+        let mut received_tls = false;
+        for frame in &p.payload {
+            match frame {
+                Frame::Crypto(f) => {
+                    let data = self.crypto[level.index()].received(f)?;
+                    if !data.is_empty() && !self.admit(&data) {
+                        // SERVER_BUSY, which later drafts call CONNECTION_REFUSED
+                        return self.close(TransportError::ServerBusy, "connection refused");
+                    }
+                    // Retransmitted handshake data has already been passed to TLS
+                    if !data.is_empty() {
+                        received_tls = true;
+                        if let Some(frame) = self.handle_tls(Some(&data))? {
+                            payload.push(Frame::Crypto(frame));
+                        }
+                    }
+                }
+                Frame::Stream(f) => {
+                    self.streams.received_frame(f)?;
+                }
+                Frame::PathChallenge(PathFrame(token)) => {
+                    payload.push(Frame::PathResponse(PathFrame(*token)));
+                }
+                Frame::Ack(f) => {
+                    if handshake_level && self.recovery.acks_application_data(f) {
+                        return Err(QuicError::Transport(
+                            TransportError::ProtocolViolation,
+                            format!("ACK of application data in {:?} packet", level),
+                        ));
+                    }
+                    self.space.on_ack(f.largest);
+                    let (acked, lost) = self.recovery.on_ack_received(f, self.clock.now());
+                    self.on_metrics_updated();
+                    self.on_packets_acked(acked);
+                    self.retransmit(lost)?;
+                }
+                Frame::MaxData(MaxDataFrame(max)) => {
+                    self.streams.update_max_data(*max);
+                }
+                Frame::MaxStreamData(f) => {
+                    self.streams.update_max_stream_data(f.id, f.max)?;
+                }
+                Frame::MaxStreamId(MaxStreamIdFrame(id)) => {
+                    self.streams.update_max_id(*id);
+                }
+                Frame::StreamIdBlocked(StreamIdBlockedFrame(id)) => {
+                    self.streams.stream_id_blocked_received(*id)?;
+                }
+                Frame::NewConnectionId(f) => {
+                    self.cids.received(f);
+                }
+                Frame::RetireConnectionId(RetireConnectionIdFrame(sequence)) => {
+                    // Keep the peer supplied with spare IDs as it retires them
+                    if self.cids.retire(*sequence, self.local.cid)? && self.local.cid.len > 0 {
+                        let frame = self.cids.issue();
+                        self.control.push_back(Frame::NewConnectionId(frame));
+                    }
+                }
+                Frame::NewToken(NewTokenFrame(token)) => {
+                    if self.side == Side::Server {
+                        return Err(QuicError::Transport(
+                            TransportError::ProtocolViolation,
+                            "NEW_TOKEN received by server".into(),
+                        ));
+                    }
+                    self.new_token = Some(token.clone());
+                }
+                Frame::Datagram(f) => {
+                    let max = usize::from(self.local.params.max_datagram_frame_size);
+                    if f.buf_len() + 1 > max {
+                        return Err(QuicError::Transport(
+                            TransportError::ProtocolViolation,
+                            "DATAGRAM frame exceeds max_datagram_frame_size".into(),
+                        ));
+                    }
+                    self.datagrams.received(DatagramFrame(f.0.clone()));
+                    self.events.push(Event::DatagramReceived);
+                }
+                Frame::RstStream(f) => {
+                    self.streams.reset(f.id, f.error_code, f.final_offset)?;
+                }
+                Frame::StopSending(f) => {
+                    self.streams.stop_sending_received(f.id, f.error_code)?;
+                }
+                Frame::ApplicationClose(CloseFrame { code, reason }) => {
+                    debug!("application closed by peer ({}): {}", code, reason);
+                    self.set_close_reason(CloseReason::Application(*code, reason.clone()));
+                    self.enter_closed(State::Draining);
+                    return Ok(());
+                }
+                Frame::ConnectionClose(CloseFrame { code, reason }) => {
+                    let error = TransportError::from(*code);
+                    debug!("connection closed by peer ({}): {}", error, reason);
+                    self.set_close_reason(CloseReason::Remote(error, reason.clone()));
+                    self.enter_closed(State::Draining);
+                    return Ok(());
+                }
+                Frame::PathResponse(PathFrame(token)) => {
+                    if self.path_challenge == Some(*token) {
+                        self.address_validated = true;
+                        self.recovery.path_validated();
+                        self.abandon_path_validation();
+                    }
+                }
+                Frame::Blocked(_)
+                | Frame::Padding(_)
+                | Frame::Ping
+                | Frame::StreamBlocked(_) => {}
+            }
+        }
+
+        match self.state {
+            State::Start | State::InitialSent => {
+                self.state = State::Handshaking;
+            }
+            State::Handshaking if !received_tls => {
+                if let Some(frame) = self.handle_tls(None)? {
+                    payload.push(Frame::Crypto(frame));
+                }
+            }
+            _ => {}
+        }
+
+        // Replies go out at the level their frames belong to
+        let (mut handshake, mut application): (Vec<_>, Vec<_>) = payload
+            .into_iter()
+            .partition(|frame| frame.is_allowed_at(EncryptionLevel::Handshake));
+        if !handshake.is_empty() || ack_now || self.handshake_acks.should_send(now) {
+            if let Some(ack) = self.handshake_acks.frame(now) {
+                handshake.insert(0, Frame::Ack(ack));
+                self.handshake_acks.on_ack_sent();
+            }
+            if !handshake.is_empty() {
+                self.build_packet(Some(LongType::Handshake), handshake)?;
+            }
+        }
+
+        // Only handshake packets can be answered before 1-RTT keys are ready
+        if self.state != State::Connected || application.is_empty() && !self.acks.should_send(now) {
+            return Ok(());
+        }
+        if let Some(ack) = self.acks.frame(now) {
+            application.insert(0, Frame::Ack(ack));
+            self.acks.on_ack_sent();
+        }
+        self.build_packet(None, application)
+    }
+
+    fn apply_remote_params(&mut self) {
+        let max_send_bidi = StreamId::new(
+            self.side,
+            Dir::Bidi,
+            u64::from(self.remote.params.max_streams_bidi),
+        );
+        let max_send_uni = StreamId::new(
+            self.side,
+            Dir::Uni,
+            u64::from(self.remote.params.max_stream_id_uni),
+        );
+        self.streams.update_max_id(max_send_bidi);
+        self.streams.update_max_id(max_send_uni);
+        let window_bidi = u64::from(self.remote.params.max_streams_bidi) + 1;
+        self.streams.set_send_window(Dir::Bidi, window_bidi);
+        let window_uni = u64::from(self.remote.params.max_stream_id_uni) + 1;
+        self.streams.set_send_window(Dir::Uni, window_uni);
+        self.streams.set_send_limits(
+            u64::from(self.remote.params.max_data),
+            u64::from(self.remote.params.max_stream_data),
+        );
+        self.mtu.set_peer_max(self.remote.params.max_packet_size);
+        self.datagrams.set_max_size(self.remote.params.max_datagram_frame_size);
+        self.recovery.set_ack_delay(
+            self.remote.params.ack_delay_exponent,
+            Duration::from_millis(u64::from(self.remote.params.max_ack_delay)),
+        );
+    }
+
+    fn handle_tls(&mut self, data: Option<&[u8]>) -> QuicResult<Option<CryptoFrame>> {
+        let (handshake, new_secret) = tls::process_handshake_messages(&mut self.tls, data)?;
+
+        // The server has picked a protocol by the time it answers the ClientHello
+        if self.side == Side::Server && self.state == State::Start && !handshake.is_empty() {
+            self.check_protocol()?;
+        }
+
+        if self.side == Side::Server && self.state == State::Start && self.accept_early_data {
+            if let Some(secret) = tls::early_secret(&self.tls) {
+                self.keys.install(EncryptionLevel::ZeroRtt, &secret);
+                self.set_early_data(EarlyData::Pending);
+            }
+        }
+
+        if let Some(secret) = new_secret {
+            if self.side == Side::Client {
+                self.check_protocol()?;
+            }
+            self.set_secret(secret);
+            self.state = State::Connected;
+            self.mtu.reset(self.clock.now() + self.recovery.pto());
+
+            let params = match self.tls.get_quic_transport_parameters() {
                 None => {
-                    return Err(QuicError::General(
+                    return Err(QuicError::Transport(
+                        TransportError::TransportParameterError,
                         "no transport parameters received".into(),
                     ));
                 }
                 Some(bytes) => {
                     let mut read = Cursor::new(bytes);
                     if self.side == Side::Client {
-                        ServerTransportParameters::decode(&mut read).parameters
+                        ServerTransportParameters::decode(&mut read)?.parameters
                     } else {
-                        ClientTransportParameters::decode(&mut read).parameters
+                        ClientTransportParameters::decode(&mut read)?.parameters
                     }
                 }
             };
 
             mem::replace(&mut self.remote.params, params);
+            self.apply_remote_params();
+            if let Some((ref name, ref cache)) = self.resumption {
+                let mut cache = cache.lock().unwrap();
+                cache.insert(name.clone(), self.remote.params.clone());
+            }
 
-            let (num_send_bidi, num_send_uni) = (
-                u64::from(self.remote.params.max_streams_bidi),
-                u64::from(self.remote.params.max_stream_id_uni),
-            );
-            let (max_send_bidi, max_send_uni) = if self.side == Side::Server {
-                (1 + 4 * num_send_bidi, 3 + 4 * num_send_uni)
-            } else {
-                (4 * num_send_bidi, 1 + 4 * num_send_uni)
+            if *self.early_data.lock().unwrap() == EarlyData::Pending {
+                let accepted = match self.side {
+                    Side::Client => self.tls.early_data_accepted(),
+                    Side::Server => true,
+                };
+                if self.side == Side::Client {
+                    self.keys.discard(EncryptionLevel::ZeroRtt);
+                }
+                self.set_early_data(if accepted {
+                    EarlyData::Accepted
+                } else {
+                    EarlyData::Rejected
+                });
+                if self.side == Side::Client && !accepted {
+                    self.on_early_data_rejected()?;
+                }
+            }
+
+            // Peers can't switch between CIDs we don't have
+            if self.local.cid.len > 0 {
+                for _ in 0..ISSUED_CIDS {
+                    let frame = self.cids.issue();
+                    self.control.push_back(Frame::NewConnectionId(frame));
+                }
+            }
+            let data = HandshakeData {
+                version: QUIC_VERSION,
+                protocol: self.protocol.lock().unwrap().clone(),
+                server_name: match self.resumption {
+                    Some((ref name, _)) => Some(name.clone()),
+                    None => self.tls.server_name(),
+                },
+                peer_params: self.remote.params.clone(),
+                early_data_accepted: *self.early_data.lock().unwrap() == EarlyData::Accepted,
             };
-            self.streams.update_max_id(max_send_bidi);
-            self.streams.update_max_id(max_send_uni);
+            self.handshake.lock().unwrap().complete(data);
+            self.events.push(Event::HandshakeComplete);
         }
 
-        let mut stream = self.streams
-            .received(0)
-            .ok_or_else(|| QuicError::General("no incoming packets allowed on stream 0".into()))?;
-        let offset = stream.get_offset();
-        stream.set_offset(offset + handshake.len() as u64);
-
+        // Everything after the client's first flight goes out in Handshake packets
         if !handshake.is_empty() {
-            Ok(Some(StreamFrame {
-                id: 0,
-                fin: false,
-                offset,
-                len: Some(handshake.len() as u64),
-                data: handshake,
-            }))
+            let stream = &mut self.crypto[EncryptionLevel::Handshake.index()];
+            Ok(Some(stream.send(handshake)))
         } else {
             Ok(None)
         }
@@ -329,30 +1480,40 @@ where
 }
 
 impl ConnectionState<tls::ClientSession> {
+    pub(crate) fn set_resumption(&mut self, server_name: String, cache: ParamsCache) {
+        self.resumption = Some((server_name, cache));
+    }
+
+    fn start_early_data(&mut self) {
+        let params = match self.resumption {
+            Some((ref name, ref cache)) => match cache.lock().unwrap().get(name) {
+                Some(params) => params.clone(),
+                None => return,
+            },
+            None => return,
+        };
+        if let Some(secret) = tls::early_secret(&self.tls) {
+            self.keys.install(EncryptionLevel::ZeroRtt, &secret);
+            // Until the handshake completes, we can only go by the limits the
+            // server announced on the previous connection
+            self.remote.params = params;
+            self.apply_remote_params();
+            self.set_early_data(EarlyData::Pending);
+        }
+    }
+
     pub(crate) fn initial(&mut self) -> QuicResult<()> {
         let (handshake, new_secret) = tls::process_handshake_messages(&mut self.tls, None)?;
         if let Some(secret) = new_secret {
             self.set_secret(secret);
         }
 
-        let mut stream = self.streams.init_send(Dir::Bidi).ok_or_else(|| {
-            QuicError::General("no bidirectional stream available for initial packet".into())
-        })?;
-        stream.set_offset(handshake.len() as u64);
-
+        self.initial_hello = handshake.clone();
         self.state = State::InitialSent;
-        self.build_packet(
-            Some(LongType::Initial),
-            vec![
-                Frame::Stream(StreamFrame {
-                    id: 0,
-                    fin: false,
-                    offset: 0,
-                    len: Some(handshake.len() as u64),
-                    data: handshake,
-                }),
-            ],
-        )
+        let frame = self.crypto[EncryptionLevel::Initial.index()].send(handshake);
+        self.build_packet(Some(LongType::Initial), vec![Frame::Crypto(frame)])?;
+        self.start_early_data();
+        Ok(())
     }
 }
 
@@ -370,19 +1531,184 @@ impl PeerData {
     }
 }
 
+// Handshake data exchanged at one encryption level, with offsets independent of the others
+struct CryptoStream {
+    sent: u64,
+    recv: Assembler,
+}
+
+impl CryptoStream {
+    fn new() -> Self {
+        Self {
+            sent: 0,
+            recv: Assembler::new(MAX_CRYPTO_BUFFER),
+        }
+    }
+
+    fn send(&mut self, data: Vec<u8>) -> CryptoFrame {
+        let offset = self.sent;
+        self.sent += data.len() as u64;
+        CryptoFrame {
+            offset,
+            data: data.into(),
+        }
+    }
+
+    // Returns whatever is now contiguous with the data already handed to TLS
+    fn received(&mut self, frame: &CryptoFrame) -> QuicResult<Vec<u8>> {
+        self.recv.insert(frame.offset, &frame.data).map_err(|_| {
+            QuicError::Transport(
+                TransportError::ProtocolViolation,
+                "too much out-of-order handshake data".into(),
+            )
+        })?;
+        let mut data = Vec::new();
+        while let Some(chunk) = self.recv.read() {
+            data.extend_from_slice(&chunk);
+        }
+        Ok(data)
+    }
+}
+
+pub type ParamsCache = Arc<Mutex<HashMap<String, TransportParameters>>>;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct ConnectionStats {
+    pub rtt: Duration,
+    pub congestion_window: usize,
+    pub bytes_in_flight: usize,
+    pub pto_count: u32,
+    pub peer_streams_blocked: u64,
+}
+
+// What was agreed on during the handshake
+#[derive(Clone, Debug, PartialEq)]
+pub struct HandshakeData {
+    pub version: u32,
+    pub protocol: Option<String>,
+    pub server_name: Option<String>,
+    pub peer_params: TransportParameters,
+    pub early_data_accepted: bool,
+}
+
+#[derive(Default)]
+pub struct HandshakeStatus {
+    data: Option<HandshakeData>,
+    waiting: Vec<task::Task>,
+}
+
+impl HandshakeStatus {
+    pub fn data(&self) -> Option<HandshakeData> {
+        self.data.clone()
+    }
+
+    pub fn wait(&mut self) {
+        if !self.waiting.iter().any(|task| task.will_notify_current()) {
+            self.waiting.push(task::current());
+        }
+    }
+
+    fn complete(&mut self, data: HandshakeData) {
+        self.data = Some(data);
+        self.wake();
+    }
+
+    fn wake(&mut self) {
+        for task in self.waiting.drain(..) {
+            task.notify();
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum EarlyData {
+    Unavailable,
+    Pending,
+    Accepted,
+    Rejected,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum CloseReason {
+    Local(TransportError, String),
+    Remote(TransportError, String),
+    Application(u16, String),
+    Handshake(ConnectError),
+}
+
+impl CloseReason {
+    pub fn to_error(&self) -> QuicError {
+        match *self {
+            CloseReason::Handshake(ref error) => QuicError::Connect(error.clone()),
+            CloseReason::Local(error, ref reason) => QuicError::Transport(error, reason.clone()),
+            CloseReason::Remote(error, ref reason) => {
+                QuicError::ConnectionClose(error, reason.clone())
+            }
+            CloseReason::Application(code, ref reason) => {
+                QuicError::ApplicationClose(code, reason.clone())
+            }
+        }
+    }
+
+    pub fn to_connect_error(&self) -> ConnectError {
+        match *self {
+            CloseReason::Handshake(ref error) => error.clone(),
+            CloseReason::Local(TransportError::Crypto(alert), ref reason)
+            | CloseReason::Remote(TransportError::Crypto(alert), ref reason) => {
+                ConnectError::TlsAlert(alert, reason.clone())
+            }
+            ref reason => ConnectError::Closed(reason.to_error().to_string()),
+        }
+    }
+}
+
+const ISSUED_CIDS: usize = 2;
+
+const MAX_CRYPTO_BUFFER: usize = 64 * 1024;
+
+const AMPLIFICATION_FACTOR: u64 = 3;
+
+// The first packet number is random but leaves room below 2^32 so long headers, which always
+// carry four bytes, don't wrap during the handshake
+const MAX_INITIAL_PN: u64 = (1 << 32) - 1024;
+
+enum Admission {
+    Refuse,
+    Check(SocketAddr, AdmissionFilter),
+}
+
 #[derive(Debug, PartialEq)]
 enum State {
     Start,
     InitialSent,
     Handshaking,
     Connected,
+    Closing,
+    Draining,
 }
 
 #[cfg(test)]
 pub mod tests {
     use super::{ClientTransportParameters, ConnectionId, ServerTransportParameters};
-    use super::{tls, ConnectionState, Packet, Secret};
+    use super::{tls, CloseReason, ConnectionState, EarlyData, EncryptionLevel, EndpointConfig,
+                Frame, Header, LongType, MaxDataFrame, Packet, ParamsCache, Secret};
+    use acks::AckTracker;
+    use bytes::Bytes;
+    use clock::MockClock;
+    use codec::Codec;
+    use conn_ids::ServerIdGenerator;
+    use crypto::{AeadAlgorithm, HashAlgorithm};
+    use events::Event;
+    use frame::{PathFrame, StreamFrame};
+    use recovery::SentPacket;
+    use streams::{Dir, StreamRef};
+    use futures::{Async, Stream};
+    use std::time::{Duration, Instant};
     use std::sync::Arc;
+    use types::{StreamId, GENERATED_CID_LENGTH};
+    use {ConnectError, QuicError, TransportError, QUIC_VERSION};
+
+    const CID_LEN: usize = GENERATED_CID_LENGTH as usize;
 
     #[test]
     fn test_encoded_handshake() {
@@ -391,7 +1717,7 @@ pub mod tests {
         let mut cp = c.queued().unwrap().unwrap().clone();
         c.pop_queue();
 
-        let mut s = server_conn_state(Packet::start_decode(&mut cp).dst_cid());
+        let mut s = server_conn_state(Packet::start_decode(&mut cp, CID_LEN).unwrap().dst_cid());
         s.handle(&mut cp).unwrap();
         let mut sp = s.queued().unwrap().unwrap().clone();
         s.pop_queue();
@@ -406,7 +1732,7 @@ pub mod tests {
             sp = s.queued().unwrap().unwrap().clone();
             s.pop_queue();
 
-            let header = Packet::start_decode(&mut sp).header;
+            let header = Packet::start_decode(&mut sp, CID_LEN).unwrap().header;
             if header.ptype().is_none() {
                 break;
             }
@@ -425,7 +1751,8 @@ pub mod tests {
         let mut initial = c.queued().unwrap().unwrap().clone();
         c.pop_queue();
 
-        let mut s = server_conn_state(Packet::start_decode(&mut initial).dst_cid());
+        let hs_cid = Packet::start_decode(&mut initial, CID_LEN).unwrap().dst_cid();
+        let mut s = server_conn_state(hs_cid);
         s.handle(&mut initial).unwrap();
         let mut server_hello = s.queued().unwrap().unwrap().clone();
 
@@ -433,17 +1760,1024 @@ pub mod tests {
         assert!(c.queued().unwrap().is_some());
     }
 
-    pub fn server_conn_state(hs_cid: ConnectionId) -> ConnectionState<tls::ServerSession> {
-        ConnectionState::new(
-            tls::server_session(
-                &Arc::new(tls::tests::server_config()),
+    #[test]
+    fn test_duplicate_handshake_data() {
+        let mut c = client_conn_state();
+        c.initial().unwrap();
+        let initial = c.queued().unwrap().unwrap().clone();
+        c.pop_queue();
+
+        let hs_cid = Packet::start_decode(&mut initial.clone(), CID_LEN).unwrap().dst_cid();
+        let mut s = server_conn_state(hs_cid);
+        s.handle(&mut initial.clone()).unwrap();
+        // A retransmitted ClientHello must not reach TLS a second time
+        s.handle(&mut initial.clone()).unwrap();
+        assert!(!s.is_closed());
+
+        while deliver(&mut s, &mut c) | deliver(&mut c, &mut s) {}
+        assert!(!c.is_handshaking() && !s.is_handshaking());
+    }
+
+    #[test]
+    fn test_retry() {
+        let mut c = client_conn_state();
+        c.initial().unwrap();
+        let mut initial = c.queued().unwrap().unwrap().clone();
+        c.pop_queue();
+
+        let orig_dst_cid = Packet::start_decode(&mut initial, CID_LEN).unwrap().dst_cid();
+        let local_cid = c.local_cid();
+        let retry = move |orig_dst_cid, src_cid| {
+            let mut buf = Vec::new();
+            Header::Retry {
+                version: QUIC_VERSION,
+                dst_cid: local_cid,
+                src_cid,
+                orig_dst_cid,
+                token: vec![1, 2, 3],
+            }.encode(&mut buf);
+            buf
+        };
+
+        let new_cid = ConnectionId::new(&[9; 8]);
+        c.handle(&mut retry(new_cid, new_cid)).unwrap();
+        assert!(c.queued().unwrap().is_none());
+        c.handle(&mut retry(orig_dst_cid, orig_dst_cid)).unwrap();
+        assert!(c.queued().unwrap().is_none());
+
+        c.handle(&mut retry(orig_dst_cid, new_cid)).unwrap();
+        let mut initial = c.queued().unwrap().unwrap().clone();
+        c.pop_queue();
+        match Packet::start_decode(&mut initial, CID_LEN).unwrap().header {
+            Header::Long {
+                dst_cid, ref token, ..
+            } => {
+                assert_eq!(dst_cid, new_cid);
+                assert_eq!(token, &[1, 2, 3]);
+            }
+            ref header => panic!("unexpected header {:?}", header),
+        }
+
+        // Only a server deriving its keys from the new CID can read the Initial now
+        let mut other = server_conn_state(orig_dst_cid);
+        other.handle(&mut initial.clone()).unwrap();
+        assert!(other.queued().unwrap().is_none());
+
+        let mut s = server_conn_state(new_cid);
+        s.handle(&mut initial).unwrap();
+        while deliver(&mut s, &mut c) | deliver(&mut c, &mut s) {}
+        assert!(!c.is_handshaking() && !s.is_handshaking());
+    }
+
+    #[test]
+    fn test_retry_loop() {
+        let mut c = client_conn_state();
+        c.initial().unwrap();
+        let mut initial = c.queued().unwrap().unwrap().clone();
+        c.pop_queue();
+
+        let orig_dst_cid = Packet::start_decode(&mut initial, CID_LEN).unwrap().dst_cid();
+        let local_cid = c.local_cid();
+        let retry = move |orig_dst_cid, src_cid| {
+            let mut buf = Vec::new();
+            Header::Retry {
+                version: QUIC_VERSION,
+                dst_cid: local_cid,
+                src_cid,
+                orig_dst_cid,
+                token: vec![1, 2, 3],
+            }.encode(&mut buf);
+            buf
+        };
+
+        let (first, second) = (ConnectionId::new(&[9; 8]), ConnectionId::new(&[10; 8]));
+        c.handle(&mut retry(orig_dst_cid, first)).unwrap();
+        c.pop_queue();
+        // A stale copy of the first Retry is harmless
+        c.handle(&mut retry(orig_dst_cid, first)).unwrap();
+        assert!(!c.is_closed());
+
+        c.handle(&mut retry(first, second)).unwrap();
+        assert!(c.is_closed());
+        assert_eq!(
+            *c.close_reason().lock().unwrap(),
+            Some(CloseReason::Handshake(ConnectError::RetryLoop))
+        );
+    }
+
+    #[test]
+    fn test_version_negotiation() {
+        let mut c = client_conn_state();
+        c.initial().unwrap();
+        c.pop_queue();
+
+        let (local_cid, remote_cid) = (c.local_cid(), c.remote.cid);
+        let version_negotiation = |dst_cid: ConnectionId, versions: &[u32]| {
+            let mut buf = vec![0x80, 0, 0, 0, 0, (dst_cid.cil() << 4) | remote_cid.cil()];
+            buf.extend_from_slice(&dst_cid);
+            buf.extend_from_slice(&remote_cid);
+            for version in versions {
+                buf.extend_from_slice(&[
+                    (version >> 24) as u8,
+                    (version >> 16) as u8,
+                    (version >> 8) as u8,
+                    *version as u8,
+                ]);
+            }
+            buf
+        };
+        let mut forged = version_negotiation(local_cid, &[0x0a0a_0a0a, QUIC_VERSION]);
+        let mut spoofed = version_negotiation(ConnectionId::new(&[9; 8]), &[0x0a0a_0a0a, 1]);
+        let mut unsupported = version_negotiation(local_cid, &[0x0a0a_0a0a, 1]);
+
+        c.handle(&mut forged).unwrap();
+        assert!(!c.is_closed());
+        // Only someone who saw our Initial knows which CIDs to put in
+        c.handle(&mut spoofed).unwrap();
+        assert!(!c.is_closed());
+        c.handle(&mut unsupported).unwrap();
+        assert!(c.is_closed());
+        assert!(c.queued().unwrap().is_none());
+        assert_eq!(
+            c.close_reason().lock().unwrap().as_ref().unwrap().to_connect_error(),
+            ConnectError::VersionMismatch(vec![0x0a0a_0a0a, 1])
+        );
+    }
+
+    #[test]
+    fn test_zero_length_cid() {
+        let config = EndpointConfig::default().connection_id_length(0);
+        let (mut c, mut s) = connected_with(&config);
+        assert_eq!(c.local_cid().len, 0);
+        assert_eq!(c.cids.issued().count(), 0);
+
+        c.close_application(0, "done").unwrap();
+        assert!(deliver(&mut c, &mut s));
+        assert!(s.is_closed());
+    }
+
+    #[test]
+    fn test_cid_generator() {
+        let generator = Arc::new(ServerIdGenerator::new(0, &[1, 2], CID_LEN as u8));
+        let config = EndpointConfig::default().connection_id_generator(generator.clone());
+        let (c, s) = connected_with(&config);
+        for cid in &[c.local_cid(), s.local_cid()] {
+            assert_eq!(generator.server_id(cid), Some(&[1, 2][..]));
+        }
+        assert!(s.cids.issued().count() > 0);
+        for cid in s.cids.issued() {
+            assert_eq!(generator.server_id(cid), Some(&[1, 2][..]));
+        }
+    }
+
+    #[test]
+    fn test_amplification_limit() {
+        let mut c = client_conn_state();
+        c.initial().unwrap();
+        let mut initial = c.queued().unwrap().unwrap().clone();
+        c.pop_queue();
+
+        let hs_cid = Packet::start_decode(&mut initial, CID_LEN).unwrap().dst_cid();
+        let mut s = server_conn_state(hs_cid);
+        s.handle(&mut initial).unwrap();
+        let mut server_hello = s.queued().unwrap().unwrap().clone();
+        s.pop_queue();
+
+        s.build_packet(Some(LongType::Handshake), vec![Frame::Ping])
+            .unwrap();
+        s.bytes_sent = 3 * s.bytes_received;
+        assert!(s.queued().unwrap().is_none());
+
+        c.handle(&mut server_hello).unwrap();
+        let mut finished = c.queued().unwrap().unwrap().clone();
+        s.handle(&mut finished).unwrap();
+        assert!(s.address_validated);
+        assert!(s.queued().unwrap().is_some());
+    }
+
+    #[test]
+    fn test_0rtt_without_keys_rejected() {
+        let mut c = client_conn_state();
+        c.initial().unwrap();
+        let mut initial = c.queued().unwrap().unwrap().clone();
+        c.pop_queue();
+
+        let early = Secret::For1Rtt(
+            AeadAlgorithm::Aes128Gcm,
+            HashAlgorithm::Sha256,
+            vec![1; 16],
+            vec![1; 16],
+        );
+        c.keys.install(EncryptionLevel::ZeroRtt, &early);
+        c.build_packet(Some(LongType::Protected), vec![Frame::Ping])
+            .unwrap();
+        let mut protected = c.queued().unwrap().unwrap().clone();
+
+        let hs_cid = Packet::start_decode(&mut initial, CID_LEN).unwrap().dst_cid();
+        let mut s = server_conn_state(hs_cid);
+        s.handle(&mut initial).unwrap();
+        s.handle(&mut protected).unwrap();
+        assert_eq!(*s.early_data().lock().unwrap(), EarlyData::Rejected);
+    }
+
+    #[test]
+    fn test_rejected_0rtt_resent() {
+        let config = EndpointConfig::default();
+        let (_, mut s, _) = rejected_early_data(client_conn_state_with(&config), &config);
+        let mut recv = s.streams.received(StreamId(0)).unwrap();
+        assert_eq!(recv.read().unwrap(), Some(Bytes::from(&b"early"[..])));
+    }
+
+    #[test]
+    fn test_rejected_0rtt_notify() {
+        let config = EndpointConfig::default().resend_rejected_early_data(false);
+        let c = client_conn_state_with(&config);
+        let mut events = c.events().listen().unwrap();
+        let (_, mut s, mut stream) = rejected_early_data(c, &config);
+        loop {
+            match events.poll().unwrap() {
+                Async::Ready(Some(Event::EarlyDataRejected(ids))) => {
+                    assert_eq!(ids, vec![StreamId(0)]);
+                    break;
+                }
+                Async::Ready(Some(_)) => {}
+                event => panic!("unexpected event {:?}", event),
+            }
+        }
+        match stream.write(b"more") {
+            Err(QuicError::StreamReset(..)) => {}
+            res => panic!("unexpected result {:?}", res),
+        }
+        let mut recv = s.streams.received(StreamId(0)).unwrap();
+        assert!(recv.read().is_err());
+    }
+
+    #[test]
+    fn test_accepted_0rtt() {
+        let config = EndpointConfig::default().accept_early_data(true);
+        let client_config = tls::tests::client_config();
+        let server_config = Arc::new(tls::tests::server_config());
+        let cache = ParamsCache::default();
+
+        // The first connection leaves a session ticket and the server's parameters behind
+        let (mut c, mut s) = resumable(&client_config, &server_config, &cache, &config);
+        assert_eq!(*c.early_data().lock().unwrap(), EarlyData::Unavailable);
+        while deliver(&mut s, &mut c) | deliver(&mut c, &mut s) {}
+        assert!(!c.is_handshaking() && !s.is_handshaking());
+
+        let (mut c, mut s) = resumable(&client_config, &server_config, &cache, &config);
+        assert_eq!(*c.early_data().lock().unwrap(), EarlyData::Pending);
+        let mut stream = c.streams.init_send(Dir::Bidi).unwrap();
+        assert_eq!(stream.write(b"early").unwrap(), 5);
+        deliver(&mut c, &mut s);
+        assert_eq!(*s.early_data().lock().unwrap(), EarlyData::Pending);
+
+        while deliver(&mut s, &mut c) | deliver(&mut c, &mut s) {}
+        assert!(!c.is_handshaking() && !s.is_handshaking());
+        assert_eq!(*c.early_data().lock().unwrap(), EarlyData::Accepted);
+        assert_eq!(*s.early_data().lock().unwrap(), EarlyData::Accepted);
+        let mut recv = s.streams.received(StreamId(0)).unwrap();
+        assert_eq!(recv.read().unwrap(), Some(Bytes::from(&b"early"[..])));
+    }
+
+    // Sessions built from shared configs and parameter cache, so that later connections resume
+    // earlier ones. The server has handled the client's Initial.
+    fn resumable(
+        client_config: &tls::ClientConfig,
+        server_config: &Arc<tls::ServerConfig>,
+        cache: &ParamsCache,
+        config: &EndpointConfig,
+    ) -> (
+        ConnectionState<tls::ClientSession>,
+        ConnectionState<tls::ServerSession>,
+    ) {
+        let tls = tls::client_session(
+            Some(client_config.clone()),
+            "Localhost",
+            &ClientTransportParameters::default(),
+        ).unwrap();
+        let mut c = ConnectionState::new(tls, None, config);
+        c.set_resumption("Localhost".into(), cache.clone());
+        c.initial().unwrap();
+        let mut initial = c.queued().unwrap().unwrap().clone();
+        c.pop_queue();
+
+        let hs_cid = Packet::start_decode(&mut initial, CID_LEN).unwrap().dst_cid();
+        let tls = tls::server_session(server_config, &ServerTransportParameters::default());
+        let mut s = ConnectionState::new(tls, Some(Secret::Initial(hs_cid)), config);
+        s.handle(&mut initial).unwrap();
+        (c, s)
+    }
+
+    // The client writes to a stream in 0-RTT packets that the server has no keys for
+    fn rejected_early_data(
+        mut c: ConnectionState<tls::ClientSession>,
+        config: &EndpointConfig,
+    ) -> (
+        ConnectionState<tls::ClientSession>,
+        ConnectionState<tls::ServerSession>,
+        StreamRef,
+    ) {
+        c.initial().unwrap();
+        let mut initial = c.queued().unwrap().unwrap().clone();
+        c.pop_queue();
+
+        let early = Secret::For1Rtt(
+            AeadAlgorithm::Aes128Gcm,
+            HashAlgorithm::Sha256,
+            vec![1; 16],
+            vec![1; 16],
+        );
+        c.keys.install(EncryptionLevel::ZeroRtt, &early);
+        c.set_early_data(EarlyData::Pending);
+        c.streams.update_max_id(StreamId(0));
+        c.streams.set_send_limits(1 << 20, 1 << 20);
+        let mut stream = c.streams.init_send(Dir::Bidi).unwrap();
+        assert_eq!(stream.write(b"early").unwrap(), 5);
+        let mut protected = c.queued().unwrap().unwrap().clone();
+        c.pop_queue();
+
+        let hs_cid = Packet::start_decode(&mut initial, CID_LEN).unwrap().dst_cid();
+        let mut s = server_conn_state_with(hs_cid, config);
+        s.handle(&mut initial).unwrap();
+        s.handle(&mut protected).unwrap();
+        while deliver(&mut s, &mut c) | deliver(&mut c, &mut s) {}
+        assert!(!c.is_handshaking() && !s.is_handshaking());
+        assert_eq!(*c.early_data().lock().unwrap(), EarlyData::Rejected);
+        (c, s, stream)
+    }
+
+    #[test]
+    fn test_idle_timeout_negotiation() {
+        let mut c = client_conn_state();
+        assert_eq!(c.idle_timeout(), Some(Duration::from_secs(300)));
+        c.remote.params.idle_timeout = 10;
+        assert_eq!(c.idle_timeout(), Some(Duration::from_secs(10)));
+        c.local.params.idle_timeout = 0;
+        assert_eq!(c.idle_timeout(), Some(Duration::from_secs(10)));
+        c.remote.params.idle_timeout = 0;
+        assert_eq!(c.idle_timeout(), None);
+        assert_eq!(c.keep_alive_deadline(), None);
+    }
+
+    #[test]
+    fn test_closing_resends_close() {
+        let mut c = client_conn_state();
+        c.initial().unwrap();
+        let mut initial = c.queued().unwrap().unwrap().clone();
+        c.pop_queue();
+
+        let hs_cid = Packet::start_decode(&mut initial, CID_LEN).unwrap().dst_cid();
+        let mut s = server_conn_state(hs_cid);
+        s.handle(&mut initial).unwrap();
+        let server_hello = s.queued().unwrap().unwrap().clone();
+
+        c.close(TransportError::NoError, "bye").unwrap();
+        assert!(c.is_closed());
+        assert!(c.close_deadline().is_some());
+        let close = c.queued().unwrap().unwrap().clone();
+        c.pop_queue();
+
+        let mut resent = 0;
+        for _ in 0..5 {
+            c.handle(&mut server_hello.clone()).unwrap();
+            while let Some(packet) = c.queued().unwrap().cloned() {
+                assert_eq!(packet, close);
+                c.pop_queue();
+                resent += 1;
+            }
+        }
+        assert_eq!(resent, 3);
+    }
+
+    #[test]
+    fn test_application_close_flushes_data() {
+        let (mut c, mut s) = connected();
+
+        let mut stream = c.streams.init_send(Dir::Bidi).unwrap();
+        assert_eq!(stream.write(b"hi").unwrap(), 2);
+        c.close_application(7, "done").unwrap();
+
+        let mut sent = Vec::new();
+        while let Some(packet) = c.queued().unwrap().cloned() {
+            c.pop_queue();
+            sent.push(packet);
+        }
+        assert_eq!(sent.len(), 2);
+        for mut packet in sent {
+            s.handle(&mut packet).unwrap();
+        }
+        assert!(s.is_closed());
+        let mut received = s.streams.received(stream.id()).unwrap();
+        assert_eq!(received.read().unwrap(), Some(Bytes::from_static(b"hi")));
+    }
+
+    #[test]
+    fn test_packets_fill_mtu() {
+        let (mut c, mut s) = connected();
+        let mut stream = c.streams.init_send(Dir::Bidi).unwrap();
+        let written = stream.write(&[7; 5000]).unwrap();
+        c.control.push_back(Frame::MaxData(MaxDataFrame(1 << 40)));
+
+        let mtu = c.mtu.current();
+        let mut sizes = Vec::new();
+        while let Some(mut packet) = c.queued().unwrap().cloned() {
+            c.pop_queue();
+            sizes.push(packet.len());
+            s.handle(&mut packet).unwrap();
+        }
+        assert!(sizes.len() > 1);
+        assert!(sizes.iter().all(|&size| size <= mtu));
+        // Room is left for a four byte packet number, so a shorter one is all that's unused
+        assert!(sizes[0] >= mtu - 3);
+
+        let mut received = s.streams.received(stream.id()).unwrap();
+        let mut len = 0;
+        while let Some(data) = received.read().unwrap() {
+            len += data.len();
+        }
+        assert_eq!(len, written);
+    }
+
+    #[test]
+    fn test_coalesced_packets() {
+        let (mut c, mut s) = connected();
+
+        c.build_packet(Some(LongType::Handshake), vec![Frame::Ping])
+            .unwrap();
+        c.build_packet(None, vec![Frame::Ping]).unwrap();
+        c.build_packet(None, vec![Frame::Ping]).unwrap();
+        assert_eq!(c.queue.len(), 2);
+
+        let mut datagram = c.queued().unwrap().unwrap().clone();
+        s.handle(&mut datagram).unwrap();
+        assert_eq!(s.space.largest_received(), Some(c.space.peek_next() - 2));
+    }
+
+    #[test]
+    fn test_malformed_packets_dropped() {
+        let (mut c, mut s) = connected();
+
+        c.build_packet(None, vec![Frame::Ping]).unwrap();
+        let mut packet = c.queued().unwrap().unwrap().clone();
+        let last = packet.len() - 1;
+        packet[last] ^= 1;
+        s.handle(&mut packet).unwrap();
+
+        s.handle(&mut [0xff; 3]).unwrap();
+        s.handle(&mut []).unwrap();
+        assert!(!s.is_closed());
+    }
+
+    #[test]
+    fn test_events() {
+        let (mut c, mut s) = connected();
+        let mut events = s.events().listen().unwrap();
+        let mut next = move || match events.poll().unwrap() {
+            Async::Ready(Some(event)) => event,
+            event => panic!("unexpected {:?}", event),
+        };
+        match next() {
+            Event::HandshakeComplete => {}
+            event => panic!("unexpected {:?}", event),
+        }
+
+        let mut stream = c.streams.init_send(Dir::Uni).unwrap();
+        stream.write(b"hi").unwrap();
+        deliver(&mut c, &mut s);
+        match (next(), next()) {
+            (Event::StreamOpened(opened), Event::StreamReadable(readable)) => {
+                assert_eq!(opened, stream.id());
+                assert_eq!(readable, stream.id());
+            }
+            events => panic!("unexpected {:?}", events),
+        }
+
+        c.close(TransportError::NoError, "done").unwrap();
+        deliver(&mut c, &mut s);
+        match next() {
+            Event::ConnectionLost { .. } => {}
+            event => panic!("unexpected {:?}", event),
+        }
+    }
+
+    #[test]
+    fn test_replayed_packets_dropped() {
+        let (mut c, mut s) = connected();
+
+        c.build_packet(None, vec![Frame::Ping]).unwrap();
+        let packet = c.queued().unwrap().unwrap().clone();
+        s.handle(&mut packet.clone()).unwrap();
+        assert!(s.acks.pending());
+        s.acks.on_ack_sent();
+
+        // A replay must not be acknowledged again
+        s.handle(&mut packet.clone()).unwrap();
+        assert!(!s.acks.pending());
+        assert!(!s.is_closed());
+    }
+
+    #[test]
+    fn test_protocol_violation_closes() {
+        let (mut c, mut s) = connected();
+
+        let frame = StreamFrame {
+            id: StreamId(4000),
+            fin: false,
+            offset: 0,
+            len: Some(1),
+            data: Bytes::from_static(&[0]),
+        };
+        c.build_packet(None, vec![Frame::Stream(frame)]).unwrap();
+        let mut packet = c.queued().unwrap().unwrap().clone();
+        c.pop_queue();
+        s.handle(&mut packet).unwrap();
+        assert!(s.is_closed());
+        match *s.close_reason().lock().unwrap() {
+            Some(CloseReason::Local(TransportError::StreamIdError, _)) => {}
+            ref reason => panic!("unexpected close reason {:?}", reason),
+        }
+
+        assert!(deliver(&mut s, &mut c));
+        assert!(c.is_closed());
+        match *c.close_reason().lock().unwrap() {
+            Some(CloseReason::Remote(TransportError::StreamIdError, _)) => {}
+            ref reason => panic!("unexpected close reason {:?}", reason),
+        }
+    }
+
+    #[test]
+    fn test_frames_checked_against_level() {
+        let (mut c, mut s) = connected();
+        let frame = StreamFrame {
+            id: StreamId(0),
+            fin: false,
+            offset: 0,
+            len: Some(1),
+            data: Bytes::from_static(&[0]),
+        };
+        c.build_packet(Some(LongType::Handshake), vec![Frame::Stream(frame)])
+            .unwrap();
+        assert!(deliver(&mut c, &mut s));
+        match *s.close_reason().lock().unwrap() {
+            Some(CloseReason::Local(TransportError::ProtocolViolation, _)) => {}
+            ref reason => panic!("unexpected close reason {:?}", reason),
+        }
+
+        // Handshake packets can't acknowledge 1-RTT packets
+        let (mut c, mut s) = connected();
+        s.build_packet(None, vec![Frame::Ping]).unwrap();
+        assert!(deliver(&mut s, &mut c));
+        let ack = c.acks.frame(Instant::now()).unwrap();
+        c.build_packet(Some(LongType::Handshake), vec![Frame::Ack(ack)])
+            .unwrap();
+        assert!(deliver(&mut c, &mut s));
+        match *s.close_reason().lock().unwrap() {
+            Some(CloseReason::Local(TransportError::ProtocolViolation, _)) => {}
+            ref reason => panic!("unexpected close reason {:?}", reason),
+        }
+    }
+
+    #[test]
+    fn test_handshake_ack_deadline() {
+        let clock = MockClock::new();
+        let config = EndpointConfig::default().clock(Arc::new(clock.clone()));
+        let (mut c, _) = connected_with(&config);
+        let delay = Duration::from_millis(25);
+        c.acks = AckTracker::new(delay, 10);
+        c.handshake_acks = AckTracker::new(delay, 10);
+        c.handshake_acks.on_receive(0, true, c.now());
+        let deadline = c.ack_deadline().unwrap();
+        assert_eq!(deadline, c.now() + delay);
+
+        clock.advance_to(deadline);
+        assert!(c.queued().unwrap().is_some());
+        assert_eq!(c.ack_deadline(), None);
+    }
+
+    #[test]
+    fn test_datagrams() {
+        let (mut c, mut s) = connected();
+
+        s.local.params.max_datagram_frame_size = 100;
+        c.datagrams.set_max_size(100);
+        c.datagrams.send(Bytes::from_static(b"unreliable")).unwrap();
+        assert!(deliver(&mut c, &mut s));
+        assert_eq!(
+            s.datagrams.incoming().wait().next().unwrap().unwrap(),
+            Bytes::from_static(b"unreliable")
+        );
+
+        s.local.params.max_datagram_frame_size = 0;
+        c.datagrams.send(Bytes::from_static(b"unwanted")).unwrap();
+        assert!(deliver(&mut c, &mut s));
+        assert!(s.is_closed());
+        match *s.close_reason().lock().unwrap() {
+            Some(CloseReason::Local(TransportError::ProtocolViolation, _)) => {}
+            ref reason => panic!("unexpected close reason {:?}", reason),
+        }
+    }
+
+    #[test]
+    fn test_idle_timeout_with_clock() {
+        let clock = MockClock::new();
+        let config = EndpointConfig::default()
+            .idle_timeout(Duration::from_secs(10))
+            .clock(Arc::new(clock.clone()));
+        let (mut c, _) = connected_with(&config);
+
+        let deadline = c.idle_deadline().unwrap();
+        clock.advance(Duration::from_secs(9));
+        assert!(!c.on_idle_timeout(c.now()).unwrap());
+        assert!(!c.is_closed());
+
+        clock.advance_to(deadline);
+        assert!(c.on_idle_timeout(c.now()).unwrap());
+        assert!(c.is_closed());
+        match *c.close_reason().lock().unwrap() {
+            Some(CloseReason::Local(TransportError::NoError, _)) => {}
+            ref reason => panic!("unexpected close reason {:?}", reason),
+        }
+    }
+
+    #[test]
+    fn test_handshake_timeout_with_clock() {
+        let clock = MockClock::new();
+        let config = EndpointConfig::default()
+            .handshake_timeout(Duration::from_secs(10))
+            .clock(Arc::new(clock.clone()));
+        let mut c = client_conn_state_with(&config);
+        c.initial().unwrap();
+        c.pop_queue();
+
+        let deadline = c.handshake_deadline().unwrap();
+        clock.advance(Duration::from_secs(9));
+        assert!(!c.on_handshake_timeout(c.now()).unwrap());
+        assert!(!c.is_closed());
+
+        clock.advance_to(deadline);
+        assert!(c.on_handshake_timeout(c.now()).unwrap());
+        assert!(c.is_closed());
+        assert_eq!(c.handshake_deadline(), None);
+        assert_eq!(
+            *c.close_reason().lock().unwrap(),
+            Some(CloseReason::Handshake(ConnectError::TimedOut))
+        );
+
+        // Connections that made it through never time out this way
+        let (c, _) = connected_with(&config);
+        assert_eq!(c.handshake_deadline(), None);
+    }
+
+    #[test]
+    fn test_pto_with_clock() {
+        let clock = MockClock::new();
+        let config = EndpointConfig::default().clock(Arc::new(clock.clone()));
+        let (mut c, _) = connected_with(&config);
+
+        // The packet never arrives, so only the probe timeout can recover it
+        let start = c.now();
+        c.build_packet(None, vec![Frame::MaxData(MaxDataFrame(1))]).unwrap();
+        c.pop_queue();
+        let deadline = c.loss_detection_timer().unwrap();
+        assert!(deadline > start);
+
+        clock.advance_to(deadline);
+        c.on_loss_timeout(c.now()).unwrap();
+        assert!(c.queued().unwrap().is_some());
+        c.pop_queue();
+
+        let backoff = c.loss_detection_timer().unwrap();
+        assert!(backoff - c.now() > deadline - start);
+    }
+
+    #[test]
+    fn test_handshake_probes_with_clock() {
+        let clock = MockClock::new();
+        let config = EndpointConfig::default().clock(Arc::new(clock.clone()));
+        let mut c = client_conn_state_with(&config);
+        c.initial().unwrap();
+        c.pop_queue();
+
+        // Nothing from the server gets through, but the client must not give up probing
+        for count in 1..4 {
+            let deadline = c.loss_detection_timer().unwrap();
+            clock.advance_to(deadline);
+            c.on_loss_timeout(c.now()).unwrap();
+            assert!(c.queued().unwrap().is_some());
+            while c.queued().unwrap().is_some() {
+                c.pop_queue();
+            }
+            assert_eq!(c.stats().lock().unwrap().pto_count, count);
+        }
+    }
+
+    #[test]
+    fn test_handshake_data() {
+        let (c, s) = connected();
+        let client = c.handshake().lock().unwrap().data().unwrap();
+        let server = s.handshake().lock().unwrap().data().unwrap();
+        assert_eq!(client.version, QUIC_VERSION);
+        assert_eq!(client.protocol, Some("hq-11".into()));
+        assert_eq!(client.protocol, server.protocol);
+        let server_name = server.server_name.map(|name| name.to_lowercase());
+        assert_eq!(server_name, Some("localhost".into()));
+        assert_eq!(client.peer_params, s.local.params);
+        assert_eq!(server.peer_params, c.local.params);
+        assert!(!client.early_data_accepted);
+    }
+
+    #[test]
+    fn test_alpn() {
+        let (c, s) = connected();
+        assert_eq!(*c.protocol().lock().unwrap(), Some("hq-11".into()));
+        assert_eq!(*s.protocol().lock().unwrap(), Some("hq-11".into()));
+
+        let mut config = tls::tests::client_config();
+        config.alpn_protocols = vec!["h3".into()];
+        let params = ClientTransportParameters::default();
+        let mut c = ConnectionState::new(
+            tls::client_session(Some(config), "Localhost", &params).unwrap(),
+            None,
+            &EndpointConfig::default(),
+        );
+        c.initial().unwrap();
+        let mut initial = c.queued().unwrap().unwrap().clone();
+        c.pop_queue();
+
+        let hs_cid = Packet::start_decode(&mut initial, CID_LEN).unwrap().dst_cid();
+        let mut s = server_conn_state(hs_cid);
+        s.handle(&mut initial).unwrap();
+        assert!(s.is_closed());
+        assert_eq!(*s.protocol().lock().unwrap(), None);
+        match *s.close_reason().lock().unwrap() {
+            Some(CloseReason::Local(TransportError::Crypto(tls::NO_APPLICATION_PROTOCOL), _)) => {}
+            ref reason => panic!("unexpected close reason {:?}", reason),
+        }
+    }
+
+    #[test]
+    fn test_export_keying_material() {
+        let c = client_conn_state();
+        assert!(c.export_keying_material(b"label", None, 32).is_err());
+
+        let (c, s) = connected();
+        let client = c.export_keying_material(b"label", Some(b"context"), 32).unwrap();
+        let server = s.export_keying_material(b"label", Some(b"context"), 32).unwrap();
+        assert_eq!(client.len(), 32);
+        assert_eq!(client, server);
+        let other = c.export_keying_material(b"other", Some(b"context"), 32).unwrap();
+        assert_ne!(client, other);
+    }
+
+    #[test]
+    fn test_sni_certificate() {
+        let (certs, key) = tls::tests::server_certs();
+        let config = Arc::new(
+            tls::ServerConfigBuilder::new()
+                .sni_certificate("example.com", certs, key)
+                .unwrap()
+                .build(),
+        );
+        assert!(sni_handshake(&config, "example.com"));
+        assert!(!sni_handshake(&config, "localhost"));
+    }
+
+    fn sni_handshake(config: &Arc<tls::ServerConfig>, hostname: &str) -> bool {
+        let params = ClientTransportParameters::default();
+        let mut c = ConnectionState::new(
+            tls::client_session(Some(tls::tests::client_config()), hostname, &params).unwrap(),
+            None,
+            &EndpointConfig::default(),
+        );
+        c.initial().unwrap();
+        let mut initial = c.queued().unwrap().unwrap().clone();
+        c.pop_queue();
+
+        let hs_cid = Packet::start_decode(&mut initial, CID_LEN).unwrap().dst_cid();
+        let mut s = ConnectionState::new(
+            tls::server_session(config, &ServerTransportParameters::default()),
+            Some(Secret::Initial(hs_cid)),
+            &EndpointConfig::default(),
+        );
+        if s.handle(&mut initial).is_err() {
+            return false;
+        }
+        while deliver(&mut s, &mut c) | deliver(&mut c, &mut s) {}
+        !c.is_handshaking() && !s.is_handshaking()
+    }
+
+    #[test]
+    fn test_path_validation() {
+        let (mut c, mut s) = connected();
+
+        s.start_path_validation(Instant::now());
+        assert!(s.is_validating_path());
+        assert!(s.path_validation_deadline().is_some());
+        assert!(deliver(&mut s, &mut c));
+        assert!(deliver(&mut c, &mut s));
+        assert!(!s.is_validating_path());
+        assert_eq!(s.path_validation_deadline(), None);
+    }
+
+    #[test]
+    fn test_migration_follows_newest_packet() {
+        let (mut c, mut s) = connected();
+        c.build_packet(None, vec![Frame::Ping]).unwrap();
+        let mut older = c.queued().unwrap().unwrap().clone();
+        c.pop_queue();
+        c.build_packet(None, vec![Frame::Ping]).unwrap();
+        let mut newer = c.queued().unwrap().unwrap().clone();
+        c.pop_queue();
+
+        s.handle(&mut newer).unwrap();
+        assert!(s.is_migration_allowed());
+        s.handle(&mut older).unwrap();
+        assert!(!s.is_migration_allowed());
+
+        c.build_packet(None, vec![Frame::PathChallenge(PathFrame([1; 8]))])
+            .unwrap();
+        let mut probe = c.queued().unwrap().unwrap().clone();
+        s.handle(&mut probe).unwrap();
+        assert!(!s.is_migration_allowed());
+    }
+
+    #[test]
+    fn test_lost_path_challenge_renewed() {
+        let (mut c, _) = connected();
+        c.start_path_validation(c.now());
+        let first = c.path_challenge.unwrap();
+        let lost = SentPacket::new(
+            c.space.peek_next(),
+            None,
+            c.now(),
+            100,
+            vec![Frame::PathChallenge(PathFrame(first))],
+        );
+        c.retransmit(vec![lost]).unwrap();
+        let renewed = c.path_challenge.unwrap();
+        assert_ne!(renewed, first);
+        assert_eq!(
+            c.control.back(),
+            Some(&Frame::PathChallenge(PathFrame(renewed)))
+        );
+    }
+
+    #[test]
+    fn test_lost_mtu_probe() {
+        let (mut c, mut s) = connected();
+        c.send_mtu_probe(1400).unwrap();
+        c.pop_queue();
+        assert_eq!(c.mtu_probe_deadline(), None);
+
+        // Later packets being acknowledged declares the probe lost
+        for _ in 0..3 {
+            c.build_packet(None, vec![Frame::MaxData(MaxDataFrame(1))])
+                .unwrap();
+        }
+        assert!(deliver(&mut c, &mut s));
+        assert!(deliver(&mut s, &mut c));
+        assert!(c.mtu_probe_deadline().is_some());
+
+        // None of the probe's PING and padding is sent again
+        while let Some(packet) = c.queued().unwrap().cloned() {
+            c.pop_queue();
+            assert!(packet.len() < 1400);
+        }
+    }
+
+    #[test]
+    fn test_failed_migration_restores_path() {
+        let (_, mut s) = connected();
+        let rtt = s.recovery.rtt();
+        s.on_peer_migrated(Instant::now(), true, 100);
+        assert!(s.is_validating_path());
+        assert_eq!(s.recovery.rtt(), rtt);
+        s.on_path_failed();
+
+        s.on_peer_migrated(Instant::now(), false, 100);
+        assert_ne!(s.recovery.rtt(), rtt);
+        s.on_path_failed();
+        assert!(!s.is_validating_path());
+        assert_eq!(s.recovery.rtt(), rtt);
+    }
+
+    #[test]
+    fn test_migration_amplification_limit() {
+        let (mut c, mut s) = connected();
+        s.on_peer_migrated(Instant::now(), true, 1200);
+        let mut challenge = s.queued().unwrap().unwrap().clone();
+        s.pop_queue();
+
+        s.build_packet(None, vec![Frame::Ping]).unwrap();
+        s.bytes_sent = 3 * s.bytes_received;
+        assert!(s.queued().unwrap().is_none());
+
+        c.handle(&mut challenge).unwrap();
+        assert!(deliver(&mut c, &mut s));
+        assert!(s.address_validated);
+        assert!(s.queued().unwrap().is_some());
+    }
+
+    #[test]
+    fn test_spin_bit() {
+        let (mut c, mut s) = connected();
+        let spin = c.spin.unwrap();
+        c.build_packet(None, vec![Frame::Ping]).unwrap();
+        assert!(deliver(&mut c, &mut s));
+        assert_eq!(s.spin, Some(spin));
+        s.build_packet(None, vec![Frame::Ping]).unwrap();
+        assert!(deliver(&mut s, &mut c));
+        assert_eq!(c.spin, Some(!spin));
+
+        let (mut c, mut s) = connected_with(&EndpointConfig::default().spin_bit(false));
+        c.build_packet(None, vec![Frame::Ping]).unwrap();
+        assert!(deliver(&mut c, &mut s));
+        assert_eq!((c.spin, s.spin), (None, None));
+    }
+
+    #[test]
+    fn test_retire_connection_id() {
+        let (mut c, mut s) = connected();
+        let initial = s.local_cid();
+        assert_eq!(s.take_unrouted_cids().len(), 2);
+        assert_eq!(c.cids.available(), 2);
+
+        let cid = c.rotate_remote_cid().unwrap();
+        assert!(deliver(&mut c, &mut s));
+        assert_eq!(s.take_retired_cids(), vec![initial]);
+        assert_eq!(s.take_unrouted_cids().len(), 1);
+        assert!(s.cids.issued().any(|issued| *issued == cid));
+
+        // The replacement gives the client a spare ID again
+        assert!(deliver(&mut s, &mut c));
+        assert_eq!(c.cids.available(), 2);
+        assert!(!s.is_closed());
+    }
+
+    fn connected() -> (
+        ConnectionState<tls::ClientSession>,
+        ConnectionState<tls::ServerSession>,
+    ) {
+        connected_with(&EndpointConfig::default())
+    }
+
+    fn connected_with(
+        config: &EndpointConfig,
+    ) -> (
+        ConnectionState<tls::ClientSession>,
+        ConnectionState<tls::ServerSession>,
+    ) {
+        let mut c = client_conn_state_with(config);
+        c.initial().unwrap();
+        let mut initial = c.queued().unwrap().unwrap().clone();
+        c.pop_queue();
+
+        let hs_cid = Packet::start_decode(&mut initial, CID_LEN).unwrap().dst_cid();
+        let mut s = server_conn_state_with(hs_cid, config);
+        s.handle(&mut initial).unwrap();
+        while deliver(&mut s, &mut c) | deliver(&mut c, &mut s) {}
+        assert!(!c.is_handshaking() && !s.is_handshaking());
+        (c, s)
+    }
+
+    fn deliver<A, B>(from: &mut ConnectionState<A>, to: &mut ConnectionState<B>) -> bool
+    where
+        A: tls::Session + tls::QuicSide,
+        B: tls::Session + tls::QuicSide,
+    {
+        let mut delivered = false;
+        while let Some(mut packet) = from.queued().unwrap().cloned() {
+            from.pop_queue();
+            to.handle(&mut packet).unwrap();
+            delivered = true;
+        }
+        delivered
+    }
+
+    pub fn server_conn_state(hs_cid: ConnectionId) -> ConnectionState<tls::ServerSession> {
+        server_conn_state_with(hs_cid, &EndpointConfig::default())
+    }
+
+    fn server_conn_state_with(
+        hs_cid: ConnectionId,
+        config: &EndpointConfig,
+    ) -> ConnectionState<tls::ServerSession> {
+        ConnectionState::new(
+            tls::server_session(
+                &Arc::new(tls::tests::server_config()),
                 &ServerTransportParameters::default(),
             ),
-            Some(Secret::Handshake(hs_cid)),
+            Some(Secret::Initial(hs_cid)),
+            config,
         )
     }
 
     pub fn client_conn_state() -> ConnectionState<tls::ClientSession> {
+        client_conn_state_with(&EndpointConfig::default())
+    }
+
+    fn client_conn_state_with(config: &EndpointConfig) -> ConnectionState<tls::ClientSession> {
         ConnectionState::new(
             tls::client_session(
                 Some(tls::tests::client_config()),
@@ -451,6 +2785,7 @@ pub mod tests {
                 &ClientTransportParameters::default(),
             ).unwrap(),
             None,
+            config,
         )
     }
 }
@@ -0,0 +1,648 @@
+use bytes::Bytes;
+use futures::sync::mpsc::{self, Receiver, Sender, UnboundedReceiver, UnboundedSender};
+use futures::sync::oneshot;
+use futures::{task, Async, AsyncSink, Future, Poll, Sink, Stream};
+
+use conn_state::{CloseReason, ConnectionState, ConnectionStats, EarlyData, HandshakeData,
+                 HandshakeStatus};
+use datagrams::{Datagrams, RecvDatagrams};
+use endpoint::{BacklogSlot, Route, Usage};
+use events::{ConnectionEvents, Events};
+use pool::BufferPool;
+use socket::{self, EcnCodepoint, Socket};
+use streams::{AcceptUni, IncomingStreams, OpenBi, OpenBiBatch, OpenOptions, OpenUni, StreamLimits,
+              Streams};
+use super::{ConnectError, QuicError, QuicResult};
+use timers::{TimerHandle, Timeout, Timers};
+use tls;
+use token::TokenStore;
+use types::{ConnectionId, Side};
+
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use tokio::net::UdpSocket;
+use tokio::timer::Delay;
+
+#[derive(Clone)]
+pub struct Connection {
+    remote: Arc<Mutex<SocketAddr>>,
+    streams: Streams,
+    datagrams: Datagrams,
+    commands: UnboundedSender<Command>,
+    early_data: Arc<Mutex<EarlyData>>,
+    close_reason: Arc<Mutex<Option<CloseReason>>>,
+    protocol: Arc<Mutex<Option<String>>>,
+    stats: Arc<Mutex<ConnectionStats>>,
+    events: Events,
+    handshake: Arc<Mutex<HandshakeStatus>>,
+}
+
+impl Connection {
+    // Follows the peer when it migrates, e.g. after a NAT rebinding
+    pub fn remote_address(&self) -> SocketAddr {
+        *self.remote.lock().unwrap()
+    }
+
+    pub fn streams(&self) -> Streams {
+        self.streams.clone()
+    }
+
+    pub fn incoming_streams(&self) -> IncomingStreams {
+        self.streams.incoming()
+    }
+
+    // Bounds how long a request on this connection may take, on the endpoint's timers
+    pub fn timeout<F>(&self, future: F, after: Duration) -> Timeout<F> {
+        self.streams.timeout(future, after)
+    }
+
+    pub fn open_uni(&self) -> OpenUni {
+        self.open_uni_with(OpenOptions::default())
+    }
+
+    pub fn open_uni_with(&self, options: OpenOptions) -> OpenUni {
+        OpenUni::new(&self.streams, options)
+    }
+
+    pub fn open_bi(&self) -> OpenBi {
+        self.open_bi_with(OpenOptions::default())
+    }
+
+    pub fn open_bi_with(&self, options: OpenOptions) -> OpenBi {
+        OpenBi::new(&self.streams, options)
+    }
+
+    // Opens consecutive streams at once, waiting until the peer allows all of them
+    pub fn open_bi_batch(&self, count: usize) -> OpenBiBatch {
+        OpenBiBatch::new(&self.streams, count)
+    }
+
+    pub fn accept_uni(&self) -> AcceptUni {
+        self.streams.accept_uni()
+    }
+
+    pub fn stream_limits(&self) -> StreamLimits {
+        self.streams.limits()
+    }
+
+    pub fn send_datagram(&self, data: Bytes) -> QuicResult<()> {
+        self.datagrams.send(data)
+    }
+
+    pub fn recv_datagrams(&self) -> RecvDatagrams {
+        self.datagrams.incoming()
+    }
+
+    pub fn early_data(&self) -> EarlyData {
+        *self.early_data.lock().unwrap()
+    }
+
+    pub fn protocol(&self) -> Option<String> {
+        self.protocol.lock().unwrap().clone()
+    }
+
+    pub fn stats(&self) -> ConnectionStats {
+        *self.stats.lock().unwrap()
+    }
+
+    pub fn events(&self) -> QuicResult<ConnectionEvents> {
+        self.events.listen()
+    }
+
+    // Connections can be used for early data before this resolves
+    pub fn handshake(&self) -> HandshakeFuture {
+        HandshakeFuture {
+            status: self.handshake.clone(),
+            close_reason: self.close_reason.clone(),
+        }
+    }
+
+    pub fn close_reason(&self) -> Option<QuicError> {
+        self.close_reason
+            .lock()
+            .unwrap()
+            .as_ref()
+            .map(CloseReason::to_error)
+    }
+
+    pub fn initiate_key_update(&self) -> QuicResult<()> {
+        self.command(Command::UpdateKeys)
+    }
+
+    pub fn rotate_connection_id(&self) -> QuicResult<()> {
+        self.command(Command::RotateConnectionId)
+    }
+
+    pub fn migrate(&self, socket: UdpSocket) -> QuicResult<()> {
+        self.command(Command::Migrate(socket))
+    }
+
+    pub fn export_keying_material(
+        &self,
+        label: &[u8],
+        context: Option<&[u8]>,
+        len: usize,
+    ) -> KeyingMaterial {
+        let (done_tx, done_rx) = oneshot::channel();
+        let context = context.map(|context| context.to_vec());
+        let _ = self.command(Command::ExportKeyingMaterial(label.to_vec(), context, len, done_tx));
+        KeyingMaterial { done: done_rx }
+    }
+
+    pub fn close(&self, code: u16, reason: &str) -> CloseFuture {
+        let (done_tx, done_rx) = oneshot::channel();
+        // If the driver is already gone, the sender is dropped and we resolve right away
+        let _ = self.command(Command::Close(code, reason.into(), done_tx));
+        CloseFuture { done: done_rx }
+    }
+
+    fn command(&self, command: Command) -> QuicResult<()> {
+        self.commands
+            .unbounded_send(command)
+            .map_err(|_| QuicError::General("connection has been closed".into()))
+    }
+}
+
+#[must_use = "futures do nothing unless polled"]
+pub struct CloseFuture {
+    done: oneshot::Receiver<()>,
+}
+
+impl Future for CloseFuture {
+    type Item = ();
+    type Error = QuicError;
+
+    fn poll(&mut self) -> Poll<(), QuicError> {
+        match self.done.poll() {
+            Ok(Async::NotReady) => Ok(Async::NotReady),
+            Ok(Async::Ready(())) | Err(oneshot::Canceled) => Ok(Async::Ready(())),
+        }
+    }
+}
+
+#[must_use = "futures do nothing unless polled"]
+pub struct HandshakeFuture {
+    status: Arc<Mutex<HandshakeStatus>>,
+    close_reason: Arc<Mutex<Option<CloseReason>>>,
+}
+
+impl Future for HandshakeFuture {
+    type Item = HandshakeData;
+    type Error = QuicError;
+
+    fn poll(&mut self) -> Poll<HandshakeData, QuicError> {
+        let mut status = self.status.lock().unwrap();
+        if let Some(data) = status.data() {
+            return Ok(Async::Ready(data));
+        }
+        if let Some(ref reason) = *self.close_reason.lock().unwrap() {
+            return Err(QuicError::Connect(reason.to_connect_error()));
+        }
+        status.wait();
+        Ok(Async::NotReady)
+    }
+}
+
+#[must_use = "futures do nothing unless polled"]
+pub struct KeyingMaterial {
+    done: oneshot::Receiver<QuicResult<Vec<u8>>>,
+}
+
+impl Future for KeyingMaterial {
+    type Item = Vec<u8>;
+    type Error = QuicError;
+
+    fn poll(&mut self) -> Poll<Vec<u8>, QuicError> {
+        match self.done.poll() {
+            Ok(Async::NotReady) => Ok(Async::NotReady),
+            Ok(Async::Ready(result)) => result.map(Async::Ready),
+            Err(oneshot::Canceled) => Err(QuicError::General("connection has been closed".into())),
+        }
+    }
+}
+
+pub(crate) enum Command {
+    UpdateKeys,
+    Close(u16, String, oneshot::Sender<()>),
+    ExportKeyingMaterial(
+        Vec<u8>,
+        Option<Vec<u8>>,
+        usize,
+        oneshot::Sender<QuicResult<Vec<u8>>>,
+    ),
+    Migrate(UdpSocket),
+    RotateConnectionId,
+    // Given up on by whoever started it, e.g. after losing a race against another attempt
+    Abandon,
+}
+
+pub(crate) struct ConnectionDriver<T> {
+    addr: SocketAddr,
+    state: ConnectionState<T>,
+    tokens: Option<Arc<TokenStore>>,
+    backlog: Option<BacklogSlot>,
+    usage: Option<Usage>,
+    send: Sender<(SocketAddr, Option<EcnCodepoint>, Vec<u8>)>,
+    recv: Receiver<(SocketAddr, Option<EcnCodepoint>, Vec<u8>)>,
+    routes: UnboundedSender<Route>,
+    routed: Vec<ConnectionId>,
+    pool: BufferPool,
+    socket: Option<(UdpSocket, Vec<u8>)>,
+    prev_addr: Option<SocketAddr>,
+    remote: Arc<Mutex<SocketAddr>>,
+    established: Option<UnboundedSender<Connection>>,
+    commands: (UnboundedSender<Command>, UnboundedReceiver<Command>),
+    timer: TimerHandle,
+    // Pacing works at a finer grain than the endpoint's timer wheel
+    pace_timer: Option<Delay>,
+    close_waiters: Vec<oneshot::Sender<()>>,
+}
+
+impl<T> ConnectionDriver<T>
+where
+    T: tls::Session + tls::QuicSide,
+{
+    pub(crate) fn new(
+        addr: SocketAddr,
+        mut state: ConnectionState<T>,
+        send: Sender<(SocketAddr, Option<EcnCodepoint>, Vec<u8>)>,
+        recv: Receiver<(SocketAddr, Option<EcnCodepoint>, Vec<u8>)>,
+        routes: UnboundedSender<Route>,
+        established: UnboundedSender<Connection>,
+        timers: &Timers,
+        initial_cid: Option<ConnectionId>,
+    ) -> Self {
+        state.streams.set_timers(timers.clone());
+        // Servers are also reached through the CID the client picked for its Initial
+        let mut routed = vec![state.local_cid()];
+        routed.extend(initial_cid);
+        let pool = state.buffer_pool().clone();
+        Self {
+            addr,
+            state,
+            tokens: None,
+            backlog: None,
+            usage: None,
+            send,
+            recv,
+            routes,
+            routed,
+            pool,
+            socket: None,
+            prev_addr: None,
+            remote: Arc::new(Mutex::new(addr)),
+            established: Some(established),
+            commands: mpsc::unbounded(),
+            timer: timers.handle(),
+            pace_timer: None,
+            close_waiters: Vec::new(),
+        }
+    }
+
+    pub(crate) fn commands(&self) -> UnboundedSender<Command> {
+        self.commands.0.clone()
+    }
+
+    pub(crate) fn issue_token(&mut self, tokens: Arc<TokenStore>) {
+        self.tokens = Some(tokens);
+    }
+
+    pub(crate) fn hold_backlog_slot(&mut self, slot: BacklogSlot) {
+        self.backlog = Some(slot);
+    }
+
+    pub(crate) fn track_usage(&mut self, usage: Usage) {
+        self.usage = Some(usage);
+    }
+
+    fn poll_pacer(&mut self) -> bool {
+        let now = self.state.now();
+        let deadline = match self.state.pacing_delay(now) {
+            Some(deadline) => deadline,
+            None => {
+                self.pace_timer = None;
+                return true;
+            }
+        };
+
+        let mut timer = Delay::new(deadline);
+        match timer.poll() {
+            Ok(Async::Ready(())) => true,
+            Ok(Async::NotReady) => {
+                self.pace_timer = Some(timer);
+                false
+            }
+            Err(e) => {
+                error!("pacing timer failed: {:?}", e);
+                true
+            }
+        }
+    }
+
+    // The endpoint only needs to know about whichever of our timers goes off first
+    fn next_deadline(&self) -> Option<Instant> {
+        [
+            self.state.loss_detection_timer(),
+            self.state.idle_deadline(),
+            self.state.handshake_deadline(),
+            self.state.keep_alive_deadline(),
+            self.state.close_deadline(),
+            self.state.path_validation_deadline(),
+            self.state.mtu_probe_deadline(),
+            self.state.ack_deadline(),
+        ].iter()
+            .filter_map(|&deadline| deadline)
+            .min()
+    }
+
+    fn poll_incoming(&mut self) -> Option<(SocketAddr, Option<EcnCodepoint>, Vec<u8>)> {
+        if let Some((ref mut socket, ref mut buf)) = self.socket {
+            match Socket::poll_recv_ecn(socket, buf) {
+                Ok(Async::Ready((len, addr, ecn))) => {
+                    return Some((addr, ecn, self.pool.copy_of(&buf[..len])))
+                }
+                Ok(Async::NotReady) => {}
+                Err(e) => error!("error receiving on migrated socket: {:?}", e),
+            }
+        }
+        match self.recv.poll() {
+            Ok(Async::Ready(Some(msg))) => Some(msg),
+            Ok(Async::Ready(None)) | Ok(Async::NotReady) => None,
+            Err(e) => {
+                error!("error from endpoint: {:?}", e);
+                None
+            }
+        }
+    }
+
+    fn on_packet_from(&mut self, addr: SocketAddr, len: usize) {
+        if addr == self.addr || Some(addr) == self.prev_addr || !self.state.is_migration_allowed() {
+            return;
+        }
+        // The peer has moved; start using the new address, but fall back to the
+        // old one if the new path can't be validated
+        debug!("peer {:?} migrated to {:?}", self.addr, addr);
+        if self.prev_addr.is_none() {
+            self.prev_addr = Some(self.addr);
+        }
+        let same_host = addr.ip() == self.addr.ip();
+        self.set_addr(addr);
+        let now = self.state.now();
+        self.state.on_peer_migrated(now, same_host, len);
+    }
+
+    fn set_addr(&mut self, addr: SocketAddr) {
+        self.addr = addr;
+        *self.remote.lock().unwrap() = addr;
+    }
+
+    fn update_routes(&mut self) {
+        // New IDs are routed before retired ones are evicted, so there is always one to look up
+        if let Some(&existing) = self.routed.first() {
+            for cid in self.state.take_unrouted_cids() {
+                if self.routes.unbounded_send(Route::Issued(cid, existing)).is_ok() {
+                    self.routed.push(cid);
+                }
+            }
+        }
+        for cid in self.state.take_retired_cids() {
+            self.routed.retain(|routed| *routed != cid);
+            if cid.len > 0 {
+                let _ = self.routes.unbounded_send(Route::Retired(cid));
+            }
+        }
+    }
+
+    fn rotate_cid(&mut self) -> QuicResult<()> {
+        match self.state.rotate_remote_cid() {
+            Some(cid) => {
+                debug!("switched to connection ID {:?} for {:?}", cid, self.addr);
+                Ok(())
+            }
+            None => Err(QuicError::General("no spare connection IDs from peer".into())),
+        }
+    }
+
+    fn migrate(&mut self, socket: UdpSocket) -> QuicResult<()> {
+        if self.state.side() != Side::Client {
+            return Err(QuicError::General("only clients can migrate".into()));
+        }
+        if let Err(e) = socket::enable_ecn(&socket) {
+            debug!("not reading ECN marks on migrated socket: {:?}", e);
+        }
+        self.socket = Some((socket, vec![0u8; 65536]));
+        let now = self.state.now();
+        self.state.start_path_validation(now);
+        self.state.send_keep_alive(now);
+        Ok(())
+    }
+
+    fn transmit(&mut self, msg: Vec<u8>) -> bool {
+        let ecn = self.state.ecn_codepoint();
+        if let Some((ref mut socket, _)) = self.socket {
+            return match Socket::poll_send_ecn(socket, &msg, &self.addr, ecn) {
+                Ok(Async::Ready(_)) => true,
+                Ok(Async::NotReady) => false,
+                Err(e) => {
+                    error!("error sending on migrated socket: {:?}", e);
+                    true
+                }
+            };
+        }
+        match self.send.start_send((self.addr, ecn, msg)) {
+            Ok(AsyncSink::Ready) => true,
+            Ok(AsyncSink::NotReady(msg)) => {
+                error!("start send not ready: {:?}", msg);
+                false
+            }
+            Err(e) => {
+                error!("error sending: {:?}", e);
+                false
+            }
+        }
+    }
+}
+
+fn expired(deadline: Option<Instant>, now: Instant) -> bool {
+    deadline.map_or(false, |deadline| deadline <= now)
+}
+
+impl<T> Future for ConnectionDriver<T>
+where
+    T: tls::Session + tls::QuicSide,
+{
+    type Item = ();
+    type Error = ();
+    fn poll(&mut self) -> Poll<(), ()> {
+        self.state.streams.set_task(task::current());
+        self.state.datagrams.set_task(task::current());
+        loop {
+            let mut received = false;
+            if let Some((addr, ecn, mut msg)) = self.poll_incoming() {
+                let len = msg.len();
+                let result = self.state.handle_ecn(&mut msg, ecn);
+                self.pool.put(msg);
+                if let Err(e) = result {
+                    error!("error handling packet from {:?}: {:?}", addr, e);
+                    return Ok(Async::Ready(()));
+                }
+                self.on_packet_from(addr, len);
+                received = true;
+            }
+
+            while let Ok(Async::Ready(Some(command))) = self.commands.1.poll() {
+                let result = match command {
+                    Command::UpdateKeys => self.state.initiate_key_update(),
+                    Command::Close(code, reason, done) => {
+                        self.close_waiters.push(done);
+                        self.state.close_application(code, &reason)
+                    }
+                    Command::ExportKeyingMaterial(label, context, len, done) => {
+                        let context = context.as_ref().map(|context| &context[..]);
+                        let _ = done.send(self.state.export_keying_material(&label, context, len));
+                        Ok(())
+                    }
+                    Command::Migrate(socket) => self.migrate(socket),
+                    Command::RotateConnectionId => self.rotate_cid(),
+                    Command::Abandon if self.state.is_handshaking() => self.state
+                        .abort_handshake(ConnectError::Closed("connection abandoned".into())),
+                    Command::Abandon => self.state.close_application(0, "connection abandoned"),
+                };
+                if let Err(e) = result {
+                    error!("error handling command for {:?}: {:?}", self.addr, e);
+                }
+            }
+
+            self.update_routes();
+
+            let now = self.state.now();
+            if expired(self.state.loss_detection_timer(), now) {
+                if let Err(e) = self.state.on_loss_timeout(now) {
+                    error!("error handling loss timeout for {:?}: {:?}", self.addr, e);
+                    return Ok(Async::Ready(()));
+                }
+            }
+
+            if expired(self.state.idle_deadline(), now) {
+                match self.state.on_idle_timeout(now) {
+                    Ok(true) => debug!("connection to {:?} timed out", self.addr),
+                    Ok(false) => {}
+                    Err(e) => {
+                        error!("error closing idle connection to {:?}: {:?}", self.addr, e);
+                        return Ok(Async::Ready(()));
+                    }
+                }
+            }
+
+            if expired(self.state.handshake_deadline(), now) {
+                match self.state.on_handshake_timeout(now) {
+                    Ok(true) => debug!("handshake with {:?} timed out", self.addr),
+                    Ok(false) => {}
+                    Err(e) => {
+                        error!("error aborting handshake with {:?}: {:?}", self.addr, e);
+                        return Ok(Async::Ready(()));
+                    }
+                }
+            }
+
+            if expired(self.state.close_deadline(), now) {
+                for done in self.close_waiters.drain(..) {
+                    let _ = done.send(());
+                }
+                return Ok(Async::Ready(()));
+            }
+
+            if expired(self.state.path_validation_deadline(), now) {
+                self.state.on_path_failed();
+                if let Some(addr) = self.prev_addr.take() {
+                    debug!("path to {:?} failed validation, reverting to {:?}", self.addr, addr);
+                    self.set_addr(addr);
+                }
+            } else if !self.state.is_validating_path() {
+                self.prev_addr = None;
+            }
+
+            // Delayed ACKs and the next MTU probe go out with the queued packets below
+            if expired(self.state.keep_alive_deadline(), now) {
+                self.state.send_keep_alive(now);
+            }
+
+            if !self.state.is_handshaking() {
+                if let Some(tokens) = self.tokens.take() {
+                    let token = tokens.mint(&self.addr);
+                    self.state.send_new_token(token);
+                }
+            }
+            // With 0-RTT keys, the application can start using the connection right away
+            let usable = !self.state.is_handshaking() || self.state.can_send_early();
+            if usable && !self.state.is_closed() {
+                if let Some(established) = self.established.take() {
+                    let conn = Connection {
+                        remote: self.remote.clone(),
+                        streams: self.state.streams.clone(),
+                        datagrams: self.state.datagrams.clone(),
+                        commands: self.commands.0.clone(),
+                        early_data: self.state.early_data(),
+                        close_reason: self.state.close_reason(),
+                        protocol: self.state.protocol(),
+                        stats: self.state.stats(),
+                        events: self.state.events(),
+                        handshake: self.state.handshake(),
+                    };
+                    if established.unbounded_send(conn).is_err() {
+                        debug!("nobody waiting for connection to {:?}", self.addr);
+                    } else if let Some(slot) = self.backlog.take() {
+                        slot.hand_over();
+                    }
+                }
+            }
+
+            let mut sent = false;
+            let pool = &self.pool;
+            let msg = match self.state.queued() {
+                Ok(msg) => msg.map(|msg| pool.copy_of(msg)),
+                Err(e) => {
+                    error!("error from connection state: {:?}", e);
+                    None
+                }
+            };
+            if let Some(msg) = msg {
+                if self.poll_pacer() {
+                    sent = self.transmit(msg);
+                }
+            }
+            if sent {
+                self.state.pop_queue();
+            }
+
+            match self.send.poll_complete() {
+                Ok(Async::Ready(())) => {}
+                Ok(Async::NotReady) => {}
+                Err(e) => error!("error from flushing sender: {:?}", e),
+            }
+
+            if !(received || sent) {
+                break;
+            }
+        }
+        if let Some(ref mut usage) = self.usage {
+            if !self.state.is_handshaking() {
+                usage.handshake_done();
+            }
+            usage.set_buffered(self.state.streams.buffered());
+        }
+        self.timer.set(self.next_deadline());
+        Ok(Async::NotReady)
+    }
+}
+
+impl<T> Drop for ConnectionDriver<T> {
+    fn drop(&mut self) {
+        // Zero-length CIDs are routed by address, which the endpoint cleans up lazily
+        for cid in self.routed.drain(..).filter(|cid| cid.len > 0) {
+            let _ = self.routes.unbounded_send(Route::Retired(cid));
+        }
+    }
+}
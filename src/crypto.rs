@@ -2,52 +2,473 @@ use bytes::{Buf, BufMut};
 
 use std::fmt;
 use std::io::Cursor;
+use std::ptr;
+use std::sync::Arc;
 
-use ring::{digest, hkdf, aead::{self, OpeningKey, SealingKey}};
-
-pub use ring::aead::AES_128_GCM;
-pub use ring::digest::SHA256;
-pub use ring::hmac::SigningKey;
+use ring::{digest, hkdf, hmac, aead::{self, OpeningKey, SealingKey}};
 
 use super::{QuicError, QuicResult};
+use ciphers::{self, Aes};
+use packet::{Header, LongType};
 use types::{ConnectionId, Side};
 
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum EncryptionLevel {
+    Initial,
+    Handshake,
+    ZeroRtt,
+    OneRtt,
+}
+
+impl EncryptionLevel {
+    pub fn of(header: &Header) -> Self {
+        match header.ptype() {
+            Some(LongType::Initial) | Some(LongType::Retry) => EncryptionLevel::Initial,
+            Some(LongType::Handshake) => EncryptionLevel::Handshake,
+            Some(LongType::Protected) => EncryptionLevel::ZeroRtt,
+            None => EncryptionLevel::OneRtt,
+        }
+    }
+
+    pub fn index(self) -> usize {
+        match self {
+            EncryptionLevel::Initial => 0,
+            EncryptionLevel::Handshake => 1,
+            EncryptionLevel::ZeroRtt => 2,
+            EncryptionLevel::OneRtt => 3,
+        }
+    }
+}
+
+// The AEADs a TLS 1.3 cipher suite can name; providers map these to their own library's types
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum AeadAlgorithm {
+    Aes128Gcm,
+    Aes256Gcm,
+    ChaCha20Poly1305,
+}
+
+impl AeadAlgorithm {
+    pub fn key_len(self) -> usize {
+        match self {
+            AeadAlgorithm::Aes128Gcm => 16,
+            AeadAlgorithm::Aes256Gcm | AeadAlgorithm::ChaCha20Poly1305 => 32,
+        }
+    }
+
+    pub fn nonce_len(self) -> usize {
+        12
+    }
+
+    pub fn tag_len(self) -> usize {
+        16
+    }
+
+    // rustls describes the negotiated suite with ring's algorithm statics
+    pub(crate) fn from_ring(alg: &'static aead::Algorithm) -> Option<Self> {
+        if ptr::eq(alg, &aead::AES_128_GCM) {
+            Some(AeadAlgorithm::Aes128Gcm)
+        } else if ptr::eq(alg, &aead::AES_256_GCM) {
+            Some(AeadAlgorithm::Aes256Gcm)
+        } else if ptr::eq(alg, &aead::CHACHA20_POLY1305) {
+            Some(AeadAlgorithm::ChaCha20Poly1305)
+        } else {
+            None
+        }
+    }
+
+    fn ring(self) -> &'static aead::Algorithm {
+        match self {
+            AeadAlgorithm::Aes128Gcm => &aead::AES_128_GCM,
+            AeadAlgorithm::Aes256Gcm => &aead::AES_256_GCM,
+            AeadAlgorithm::ChaCha20Poly1305 => &aead::CHACHA20_POLY1305,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum HashAlgorithm {
+    Sha256,
+    Sha384,
+}
+
+impl HashAlgorithm {
+    pub fn output_len(self) -> usize {
+        match self {
+            HashAlgorithm::Sha256 => 32,
+            HashAlgorithm::Sha384 => 48,
+        }
+    }
+
+    pub(crate) fn from_ring(alg: &'static digest::Algorithm) -> Option<Self> {
+        if ptr::eq(alg, &digest::SHA256) {
+            Some(HashAlgorithm::Sha256)
+        } else if ptr::eq(alg, &digest::SHA384) {
+            Some(HashAlgorithm::Sha384)
+        } else {
+            None
+        }
+    }
+
+    fn ring(self) -> &'static digest::Algorithm {
+        match self {
+            HashAlgorithm::Sha256 => &digest::SHA256,
+            HashAlgorithm::Sha384 => &digest::SHA384,
+        }
+    }
+}
+
+// Everything packet protection needs from a crypto library. Keys are built once for each key
+// phase and then used for every packet in it; the key material passed in is always as long as
+// the algorithm's key_len().
+pub trait CryptoProvider: Send + Sync {
+    fn aead_key(&self, alg: AeadAlgorithm, key: &[u8]) -> Box<AeadKey>;
+
+    fn header_key(&self, alg: AeadAlgorithm, key: &[u8]) -> Box<HeaderKey>;
+
+    fn hkdf_extract(&self, hash: HashAlgorithm, salt: &[u8], ikm: &[u8]) -> Vec<u8>;
+
+    fn hkdf_expand(&self, hash: HashAlgorithm, prk: &[u8], info: &[u8], out: &mut [u8]);
+}
+
+pub trait AeadKey: Send + Sync {
+    fn seal(
+        &self,
+        nonce: &[u8],
+        ad: &[u8],
+        in_out: &mut [u8],
+        out_suffix_capacity: usize,
+    ) -> QuicResult<usize>;
+
+    fn open<'a>(&self, nonce: &[u8], ad: &[u8], in_out: &'a mut [u8]) -> QuicResult<&'a mut [u8]>;
+}
+
+// Computes the mask for the first byte and packet number from a sample of the ciphertext
+pub trait HeaderKey: Send + Sync {
+    fn mask(&self, sample: &[u8]) -> QuicResult<[u8; HEADER_MASK_LEN]>;
+}
+
+pub struct RingProvider;
+
+impl CryptoProvider for RingProvider {
+    fn aead_key(&self, alg: AeadAlgorithm, key: &[u8]) -> Box<AeadKey> {
+        // Only a key of the wrong length is refused, and the length comes from the algorithm
+        Box::new(RingAeadKey {
+            sealing: SealingKey::new(alg.ring(), key).expect("key length matches algorithm"),
+            opening: OpeningKey::new(alg.ring(), key).expect("key length matches algorithm"),
+        })
+    }
+
+    fn header_key(&self, alg: AeadAlgorithm, key: &[u8]) -> Box<HeaderKey> {
+        match alg {
+            AeadAlgorithm::ChaCha20Poly1305 => {
+                let mut hp = [0u8; 32];
+                hp.copy_from_slice(key);
+                Box::new(ChaChaHeaderKey(hp))
+            }
+            AeadAlgorithm::Aes128Gcm | AeadAlgorithm::Aes256Gcm => Box::new(AesHeaderKey(
+                Aes::new(key).expect("key length matches algorithm"),
+            )),
+        }
+    }
+
+    fn hkdf_extract(&self, hash: HashAlgorithm, salt: &[u8], ikm: &[u8]) -> Vec<u8> {
+        // ring keeps the PRK opaque, so compute HMAC(salt, IKM) directly
+        let salt = hmac::SigningKey::new(hash.ring(), salt);
+        hmac::sign(&salt, ikm).as_ref().to_vec()
+    }
+
+    fn hkdf_expand(&self, hash: HashAlgorithm, prk: &[u8], info: &[u8], out: &mut [u8]) {
+        hkdf::expand(&hmac::SigningKey::new(hash.ring(), prk), info, out);
+    }
+}
+
+struct RingAeadKey {
+    sealing: SealingKey,
+    opening: OpeningKey,
+}
+
+impl AeadKey for RingAeadKey {
+    fn seal(
+        &self,
+        nonce: &[u8],
+        ad: &[u8],
+        in_out: &mut [u8],
+        out_suffix_capacity: usize,
+    ) -> QuicResult<usize> {
+        aead::seal_in_place(&self.sealing, nonce, ad, in_out, out_suffix_capacity)
+            .map_err(|_| QuicError::EncryptError)
+    }
+
+    fn open<'a>(&self, nonce: &[u8], ad: &[u8], in_out: &'a mut [u8]) -> QuicResult<&'a mut [u8]> {
+        aead::open_in_place(&self.opening, nonce, ad, 0, in_out)
+            .map_err(|_| QuicError::DecryptError)
+    }
+}
+
+// ring doesn't expose the block ciphers behind its AEADs, so header protection uses our own
+struct AesHeaderKey(Aes);
+
+impl HeaderKey for AesHeaderKey {
+    fn mask(&self, sample: &[u8]) -> QuicResult<[u8; HEADER_MASK_LEN]> {
+        if sample.len() < HEADER_SAMPLE_LEN {
+            return Err(QuicError::UnexpectedEnd);
+        }
+        let mut block = [0u8; HEADER_SAMPLE_LEN];
+        block.copy_from_slice(&sample[..HEADER_SAMPLE_LEN]);
+        self.0.encrypt_block(&mut block);
+        let mut mask = [0; HEADER_MASK_LEN];
+        mask.copy_from_slice(&block[..HEADER_MASK_LEN]);
+        Ok(mask)
+    }
+}
+
+struct ChaChaHeaderKey([u8; 32]);
+
+impl HeaderKey for ChaChaHeaderKey {
+    fn mask(&self, sample: &[u8]) -> QuicResult<[u8; HEADER_MASK_LEN]> {
+        if sample.len() < HEADER_SAMPLE_LEN {
+            return Err(QuicError::UnexpectedEnd);
+        }
+        // The sample supplies the block counter, then the nonce
+        let counter = Cursor::new(&sample[..4]).get_u32_le();
+        let mut nonce = [0u8; 12];
+        nonce.copy_from_slice(&sample[4..HEADER_SAMPLE_LEN]);
+        let block = ciphers::chacha20_block(&self.0, counter, &nonce);
+        let mut mask = [0; HEADER_MASK_LEN];
+        mask.copy_from_slice(&block[..HEADER_MASK_LEN]);
+        Ok(mask)
+    }
+}
+
+pub struct KeyChain {
+    side: Side,
+    provider: Arc<CryptoProvider>,
+    levels: [Option<LevelKeys>; 4],
+    secret: Option<Secret>,
+    key_phase: bool,
+    phase_start: Option<u64>,
+    prev: Option<LevelKeys>,
+}
+
+impl KeyChain {
+    pub fn new(side: Side, initial: &Secret, provider: Arc<CryptoProvider>) -> Self {
+        let mut keys = KeyChain {
+            side,
+            provider,
+            levels: [None, None, None, None],
+            secret: None,
+            key_phase: false,
+            phase_start: None,
+            prev: None,
+        };
+        keys.install(EncryptionLevel::Initial, initial);
+        keys
+    }
+
+    pub fn install(&mut self, level: EncryptionLevel, secret: &Secret) {
+        self.levels[level.index()] = Some(LevelKeys {
+            local: secret.build_key(self.side, &*self.provider),
+            remote: secret.build_key(self.side.other(), &*self.provider),
+        });
+        if level == EncryptionLevel::OneRtt {
+            self.secret = Some(secret.clone());
+            self.key_phase = false;
+            self.phase_start = Some(0);
+            self.prev = None;
+        }
+    }
+
+    pub fn discard(&mut self, level: EncryptionLevel) {
+        self.levels[level.index()] = None;
+    }
+
+    pub fn key_phase(&self) -> bool {
+        self.key_phase
+    }
+
+    pub fn update(&mut self) -> QuicResult<()> {
+        if self.phase_start.is_none() {
+            return Err(QuicError::General("key update already in progress".into()));
+        }
+        let secret = self.next_secret()?;
+        let remote = secret.build_key(self.side.other(), &*self.provider);
+        self.commit_update(secret, remote, None);
+        Ok(())
+    }
+
+    fn next_secret(&self) -> QuicResult<Secret> {
+        match self.secret {
+            Some(ref secret) => secret.update(&*self.provider),
+            None => Err(QuicError::General("no 1-RTT keys to update".into())),
+        }
+    }
+
+    fn commit_update(&mut self, secret: Secret, remote: PacketKey, phase_start: Option<u64>) {
+        let mut keys = LevelKeys {
+            local: secret.build_key(self.side, &*self.provider),
+            remote,
+        };
+        let prev = self.levels[EncryptionLevel::OneRtt.index()].take();
+        if let Some(ref prev) = prev {
+            // Header protection keys are not affected by key updates
+            keys.local.hp = prev.local.hp.clone();
+            keys.remote.hp = prev.remote.hp.clone();
+        }
+        self.levels[EncryptionLevel::OneRtt.index()] = Some(keys);
+        self.prev = prev;
+        self.secret = Some(secret);
+        self.key_phase = !self.key_phase;
+        self.phase_start = phase_start;
+    }
+
+    pub fn has(&self, level: EncryptionLevel) -> bool {
+        self.levels[level.index()].is_some()
+    }
+
+    fn keys(&self, level: EncryptionLevel) -> QuicResult<&LevelKeys> {
+        if let Some(ref keys) = self.levels[level.index()] {
+            return Ok(keys);
+        }
+        // The TLS stack doesn't hand out handshake traffic secrets yet, so
+        // Handshake packets are protected with the Initial keys until it does
+        match (level, &self.levels[EncryptionLevel::Initial.index()]) {
+            (EncryptionLevel::Handshake, &Some(ref keys)) => Ok(keys),
+            _ => Err(QuicError::General(format!("no keys for {:?}", level))),
+        }
+    }
+
+    pub fn local_header_mask(
+        &self,
+        level: EncryptionLevel,
+        sample: &[u8],
+    ) -> QuicResult<[u8; HEADER_MASK_LEN]> {
+        self.keys(level)?.local.header_mask(sample)
+    }
+
+    pub fn remote_header_mask(
+        &self,
+        level: EncryptionLevel,
+        sample: &[u8],
+    ) -> QuicResult<[u8; HEADER_MASK_LEN]> {
+        self.keys(level)?.remote.header_mask(sample)
+    }
+
+    pub fn tag_len(&self, level: EncryptionLevel) -> QuicResult<usize> {
+        Ok(self.keys(level)?.local.algorithm().tag_len())
+    }
+
+    pub fn seal(
+        &self,
+        level: EncryptionLevel,
+        number: u64,
+        ad: &[u8],
+        in_out: &mut [u8],
+        out_suffix_capacity: usize,
+    ) -> QuicResult<usize> {
+        self.keys(level)?
+            .local
+            .encrypt(number, ad, in_out, out_suffix_capacity)
+    }
+
+    pub fn open<'a>(
+        &self,
+        level: EncryptionLevel,
+        number: u64,
+        ad: &[u8],
+        input: &'a mut [u8],
+    ) -> QuicResult<&'a mut [u8]> {
+        self.keys(level)?.remote.decrypt(number, ad, input)
+    }
+
+    pub fn open_1rtt<'a>(
+        &mut self,
+        key_phase: bool,
+        number: u64,
+        ad: &[u8],
+        input: &'a mut [u8],
+    ) -> QuicResult<&'a mut [u8]> {
+        if key_phase == self.key_phase {
+            let out = self.keys(EncryptionLevel::OneRtt)?
+                .remote
+                .decrypt(number, ad, input)?;
+            if self.phase_start.map_or(true, |start| number < start) {
+                self.phase_start = Some(number);
+            }
+            return Ok(out);
+        }
+
+        let reordered = self.phase_start.map_or(true, |start| number < start);
+        if let (true, Some(prev)) = (reordered, self.prev.as_ref()) {
+            return prev.remote.decrypt(number, ad, input);
+        }
+
+        // The peer has initiated a key update; the key that opened the packet is kept for the
+        // rest of the new phase
+        let secret = self.next_secret()?;
+        let remote = secret.build_key(self.side.other(), &*self.provider);
+        let out = remote.decrypt(number, ad, input)?;
+        self.commit_update(secret, remote, Some(number));
+        Ok(out)
+    }
+}
+
+struct LevelKeys {
+    local: PacketKey,
+    remote: PacketKey,
+}
+
+#[derive(Clone)]
 pub enum Secret {
-    Handshake(ConnectionId),
-    For1Rtt(
-        &'static aead::Algorithm,
-        &'static digest::Algorithm,
-        Vec<u8>,
-        Vec<u8>,
-    ),
+    Initial(ConnectionId),
+    For1Rtt(AeadAlgorithm, HashAlgorithm, Vec<u8>, Vec<u8>),
 }
 
 impl Secret {
     pub fn tag_len(&self) -> usize {
         match self {
-            Secret::Handshake(_) => AES_128_GCM.tag_len(),
+            Secret::Initial(_) => AeadAlgorithm::Aes128Gcm.tag_len(),
             Secret::For1Rtt(aead_alg, _, _, _) => aead_alg.tag_len(),
         }
     }
 
-    pub fn build_key(&self, side: Side) -> PacketKey {
+    pub fn update(&self, provider: &CryptoProvider) -> QuicResult<Secret> {
+        match self {
+            Secret::Initial(_) => Err(QuicError::General(
+                "initial secrets cannot be updated".into(),
+            )),
+            Secret::For1Rtt(aead_alg, hash_alg, ref client_secret, ref server_secret) => {
+                Ok(Secret::For1Rtt(
+                    *aead_alg,
+                    *hash_alg,
+                    updated_secret(provider, *hash_alg, client_secret),
+                    updated_secret(provider, *hash_alg, server_secret),
+                ))
+            }
+        }
+    }
+
+    pub fn build_key(&self, side: Side, provider: &CryptoProvider) -> PacketKey {
         match self {
-            Secret::Handshake(cid) => {
+            Secret::Initial(cid) => {
+                // draft-11 still uses the handshake labels for Initial packets
                 let label = if side == Side::Client {
                     b"client hs"
                 } else {
                     b"server hs"
                 };
                 PacketKey::new(
-                    &AES_128_GCM,
-                    &SHA256,
-                    &expanded_handshake_secret(*cid, label),
+                    provider,
+                    AeadAlgorithm::Aes128Gcm,
+                    HashAlgorithm::Sha256,
+                    &expanded_initial_secret(provider, *cid, label),
                 )
             }
             Secret::For1Rtt(aead_alg, hash_alg, ref client_secret, ref server_secret) => {
                 PacketKey::new(
-                    aead_alg,
-                    hash_alg,
+                    provider,
+                    *aead_alg,
+                    *hash_alg,
                     match side {
                         Side::Client => client_secret,
                         Side::Server => server_secret,
@@ -61,114 +482,276 @@ impl Secret {
 impl fmt::Debug for Secret {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match self {
-            Secret::Handshake(cid) => write!(f, "Handshake({:?})", cid),
+            Secret::Initial(cid) => write!(f, "Initial({:?})", cid),
             Secret::For1Rtt(_, _, _, _) => write!(f, "For1Rtt(<secret>)"),
         }
     }
 }
 
 pub struct PacketKey {
-    alg: &'static aead::Algorithm,
-    data: Vec<u8>,
-    split: usize,
+    alg: AeadAlgorithm,
+    key: Box<AeadKey>,
+    iv: Vec<u8>,
+    hp: Arc<HeaderKey>,
 }
 
 impl PacketKey {
     pub fn new(
-        aead_alg: &'static aead::Algorithm,
-        hash_alg: &'static digest::Algorithm,
+        provider: &CryptoProvider,
+        aead_alg: AeadAlgorithm,
+        hash_alg: HashAlgorithm,
         secret: &[u8],
     ) -> Self {
-        let mut res = Self {
+        let (key, iv, hp) = expand_keys(provider, aead_alg, hash_alg, secret);
+        Self {
             alg: aead_alg,
-            data: vec![0; aead_alg.key_len() + aead_alg.nonce_len()],
-            split: aead_alg.key_len(),
-        };
-        let secret_key = SigningKey::new(hash_alg, secret);
-        qhkdf_expand(&secret_key, b"key", &mut res.data[..res.split]);
-        qhkdf_expand(&secret_key, b"iv", &mut res.data[res.split..]);
-        res
+            key: provider.aead_key(aead_alg, &key),
+            iv,
+            hp: Arc::from(provider.header_key(aead_alg, &hp)),
+        }
+    }
+
+    pub fn header_mask(&self, sample: &[u8]) -> QuicResult<[u8; HEADER_MASK_LEN]> {
+        self.hp.mask(sample)
     }
 
-    pub fn algorithm(&self) -> &aead::Algorithm {
+    pub fn algorithm(&self) -> AeadAlgorithm {
         self.alg
     }
 
-    pub fn write_nonce(&self, number: u32, out: &mut [u8]) {
+    pub fn write_nonce(&self, number: u64, out: &mut [u8]) {
         debug_assert_eq!(out.len(), self.alg.nonce_len());
         let out = {
             let mut write = Cursor::new(out);
             write.put_u32_be(0);
-            write.put_u64_be(u64::from(number));
+            write.put_u64_be(number);
             debug_assert_eq!(write.remaining(), 0);
             write.into_inner()
         };
-        let iv = &self.data[self.split..];
-        for i in 0..self.alg.nonce_len() {
-            out[i] ^= iv[i];
+        for (byte, iv) in out.iter_mut().zip(&self.iv) {
+            *byte ^= iv;
         }
     }
 
     pub fn encrypt(
         &self,
-        number: u32,
+        number: u64,
         ad: &[u8],
         in_out: &mut [u8],
         out_suffix_capacity: usize,
     ) -> QuicResult<usize> {
-        let key = SealingKey::new(self.alg, &self.data[..self.split])
-            .map_err(|_| QuicError::EncryptError)?;
-        let mut nonce_buf = [0u8; aead::MAX_TAG_LEN];
+        let mut nonce_buf = [0u8; NONCE_LEN];
         let nonce = &mut nonce_buf[..self.alg.nonce_len()];
         self.write_nonce(number, nonce);
-        aead::seal_in_place(&key, &*nonce, ad, in_out, out_suffix_capacity)
-            .map_err(|_| QuicError::EncryptError)
+        self.key.seal(&*nonce, ad, in_out, out_suffix_capacity)
     }
 
     pub fn decrypt<'a>(
         &self,
-        number: u32,
+        number: u64,
         ad: &[u8],
         input: &'a mut [u8],
     ) -> QuicResult<&'a mut [u8]> {
-        let key = OpeningKey::new(self.alg, &self.data[..self.split])
-            .map_err(|_| QuicError::DecryptError)?;
-        let mut nonce_buf = [0u8; aead::MAX_TAG_LEN];
+        let mut nonce_buf = [0u8; NONCE_LEN];
         let nonce = &mut nonce_buf[..self.alg.nonce_len()];
         self.write_nonce(number, nonce);
-        aead::open_in_place(&key, &*nonce, ad, 0, input).map_err(|_| QuicError::DecryptError)
+        self.key.open(&*nonce, ad, input)
     }
 }
 
-pub fn expanded_handshake_secret(conn_id: ConnectionId, label: &[u8]) -> Vec<u8> {
-    let prk = handshake_secret(conn_id);
-    let mut out = vec![0u8; SHA256.output_len];
-    qhkdf_expand(&prk, label, &mut out);
+// The AEAD key, IV and header protection key for one direction of a traffic secret
+fn expand_keys(
+    provider: &CryptoProvider,
+    aead_alg: AeadAlgorithm,
+    hash_alg: HashAlgorithm,
+    secret: &[u8],
+) -> (Vec<u8>, Vec<u8>, Vec<u8>) {
+    let mut key = vec![0; aead_alg.key_len()];
+    let mut iv = vec![0; aead_alg.nonce_len()];
+    let mut hp = vec![0; aead_alg.key_len()];
+    qhkdf_expand(provider, hash_alg, secret, b"key", &mut key);
+    qhkdf_expand(provider, hash_alg, secret, b"iv", &mut iv);
+    qhkdf_expand(provider, hash_alg, secret, b"hp", &mut hp);
+    (key, iv, hp)
+}
+
+// Both sides derive these from the destination CID of the client's first Initial packet
+// (or the one a Retry told it to use), so no handshake is needed to agree on them
+pub fn expanded_initial_secret(
+    provider: &CryptoProvider,
+    conn_id: ConnectionId,
+    label: &[u8],
+) -> Vec<u8> {
+    let prk = provider.hkdf_extract(HashAlgorithm::Sha256, INITIAL_SALT, &conn_id);
+    let mut out = vec![0u8; HashAlgorithm::Sha256.output_len()];
+    qhkdf_expand(provider, HashAlgorithm::Sha256, &prk, label, &mut out);
+    out
+}
+
+fn updated_secret(provider: &CryptoProvider, hash_alg: HashAlgorithm, secret: &[u8]) -> Vec<u8> {
+    let mut out = vec![0u8; hash_alg.output_len()];
+    qhkdf_expand(provider, hash_alg, secret, b"traffic upd", &mut out);
     out
 }
 
-pub fn qhkdf_expand(key: &SigningKey, label: &[u8], out: &mut [u8]) {
+pub fn qhkdf_expand(
+    provider: &CryptoProvider,
+    hash_alg: HashAlgorithm,
+    prk: &[u8],
+    label: &[u8],
+    out: &mut [u8],
+) {
     let mut info = Vec::with_capacity(2 + 1 + 5 + out.len());
     info.put_u16_be(out.len() as u16);
     info.put_u8(5 + (label.len() as u8));
     info.extend_from_slice(b"QUIC ");
     info.extend_from_slice(&label);
-    hkdf::expand(key, &info, out);
+    provider.hkdf_expand(hash_alg, prk, &info, out);
 }
 
-fn handshake_secret(conn_id: ConnectionId) -> SigningKey {
-    let key = SigningKey::new(&SHA256, HANDSHAKE_SALT);
-    let mut buf = Vec::with_capacity(8);
-    buf.put_slice(&conn_id);
-    hkdf::extract(&key, &buf)
-}
+pub const HEADER_SAMPLE_LEN: usize = 16;
+pub const HEADER_MASK_LEN: usize = 5;
+const NONCE_LEN: usize = 12;
 
-const HANDSHAKE_SALT: &[u8; 20] =
+// The salt defined for QUIC_VERSION (draft-11, 0xff00000b)
+const INITIAL_SALT: &[u8; 20] =
     b"\x9c\x10\x8f\x98\x52\x0a\x5c\x5c\x32\x96\x8e\x95\x0e\x8a\x2c\x5f\xe0\x6d\x6c\x38";
 
 #[cfg(test)]
 mod tests {
-    use types::ConnectionId;
+    use super::{AeadAlgorithm, AeadKey, CryptoProvider, EncryptionLevel, HashAlgorithm, HeaderKey,
+                KeyChain, RingProvider, Secret, HEADER_MASK_LEN};
+    use types::{ConnectionId, Side};
+    use QuicResult;
+
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    fn ring() -> Arc<CryptoProvider> {
+        Arc::new(RingProvider)
+    }
+
+    #[test]
+    fn test_key_chain_seal_open() {
+        let cid = ConnectionId {
+            len: 8,
+            bytes: [1, 2, 3, 4, 5, 6, 7, 8, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0],
+        };
+        let client = KeyChain::new(Side::Client, &Secret::Initial(cid), ring());
+        let server = KeyChain::new(Side::Server, &Secret::Initial(cid), ring());
+
+        let tag_len = client.tag_len(EncryptionLevel::Handshake).unwrap();
+        let mut buf = b"hello world".to_vec();
+        buf.extend_from_slice(&vec![0; tag_len]);
+        let len = client
+            .seal(EncryptionLevel::Handshake, 42, b"header", &mut buf, tag_len)
+            .unwrap();
+        assert_eq!(len, buf.len());
+
+        assert!(server.open(EncryptionLevel::OneRtt, 42, b"header", &mut buf.clone()).is_err());
+        assert!(server.open(EncryptionLevel::Initial, 43, b"header", &mut buf.clone()).is_err());
+        let opened = server
+            .open(EncryptionLevel::Initial, 42, b"header", &mut buf)
+            .unwrap();
+        assert_eq!(opened, b"hello world");
+    }
+
+    #[test]
+    fn test_key_update() {
+        let cid = ConnectionId {
+            len: 8,
+            bytes: [1, 2, 3, 4, 5, 6, 7, 8, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0],
+        };
+        let secret = Secret::For1Rtt(
+            AeadAlgorithm::Aes128Gcm,
+            HashAlgorithm::Sha256,
+            vec![1; 32],
+            vec![2; 32],
+        );
+        let mut client = KeyChain::new(Side::Client, &Secret::Initial(cid), ring());
+        let mut server = KeyChain::new(Side::Server, &Secret::Initial(cid), ring());
+        client.install(EncryptionLevel::OneRtt, &secret);
+        server.install(EncryptionLevel::OneRtt, &secret);
+
+        let seal = |keys: &KeyChain, number: u64| {
+            let mut buf = vec![number as u8; 20];
+            keys.seal(EncryptionLevel::OneRtt, number, b"", &mut buf, 16)
+                .unwrap();
+            buf
+        };
+
+        let old = seal(&client, 1);
+        client.update().unwrap();
+        assert!(client.key_phase());
+        assert!(client.update().is_err());
+        let new = seal(&client, 2);
+
+        assert_eq!(server.open_1rtt(true, 2, b"", &mut new.clone()).unwrap(), &[2; 4]);
+        assert!(server.key_phase());
+        assert_eq!(server.open_1rtt(false, 1, b"", &mut old.clone()).unwrap(), &[1; 4]);
+
+        let reply = seal(&server, 3);
+        assert_eq!(client.open_1rtt(true, 3, b"", &mut reply.clone()).unwrap(), &[3; 4]);
+        client.update().unwrap();
+        assert!(!client.key_phase());
+    }
+
+    #[test]
+    fn test_header_mask() {
+        let cid = ConnectionId {
+            len: 8,
+            bytes: [1, 2, 3, 4, 5, 6, 7, 8, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0],
+        };
+        let client = KeyChain::new(Side::Client, &Secret::Initial(cid), ring());
+        let server = KeyChain::new(Side::Server, &Secret::Initial(cid), ring());
+
+        let sample = [7u8; 16];
+        let mask = client
+            .local_header_mask(EncryptionLevel::Initial, &sample)
+            .unwrap();
+        assert_eq!(
+            server
+                .remote_header_mask(EncryptionLevel::Initial, &sample)
+                .unwrap(),
+            mask
+        );
+        assert_ne!(
+            client
+                .remote_header_mask(EncryptionLevel::Initial, &sample)
+                .unwrap(),
+            mask
+        );
+        assert!(
+            client
+                .local_header_mask(EncryptionLevel::Initial, &sample[..15])
+                .is_err()
+        );
+    }
+
+    // Sample and mask values from draft-ietf-quic-tls, appendix A
+    #[test]
+    fn test_header_mask_vectors() {
+        let key = b"\x9f\x50\x44\x9e\x04\xa0\xe8\x10\x28\x3a\x1e\x99\x33\xad\xed\xd2";
+        let sample = b"\xd1\xb1\xc9\x8d\xd7\x68\x9f\xb8\xec\x11\xd2\x42\xb1\x23\xdc\x9b";
+        assert_eq!(
+            RingProvider
+                .header_key(AeadAlgorithm::Aes128Gcm, key)
+                .mask(sample)
+                .unwrap(),
+            [0x43, 0x7b, 0x9a, 0xec, 0x36]
+        );
+
+        let key = b"\x25\xa2\x82\xb9\xe8\x2f\x06\xf2\x1f\x48\x89\x17\xa4\xfc\x8f\x1b\
+                    \x73\x57\x36\x85\x60\x85\x97\xd0\xef\xcb\x07\x6b\x0a\xb7\xa7\xa4";
+        let sample = b"\x5e\x5c\xd5\x5c\x41\xf6\x90\x80\x57\x5d\x79\x99\xc2\x5a\x5b\xfb";
+        assert_eq!(
+            RingProvider
+                .header_key(AeadAlgorithm::ChaCha20Poly1305, key)
+                .mask(sample)
+                .unwrap(),
+            [0xae, 0xfe, 0xfe, 0x7d, 0x03]
+        );
+    }
 
     #[test]
     fn test_handshake_client() {
@@ -178,22 +761,149 @@ mod tests {
                 0x83, 0x94, 0xc8, 0xf0, 0x3e, 0x51, 0x57, 0x08, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0
             ],
         };
-        let client_handshake_secret = super::expanded_handshake_secret(hs_cid, b"client hs");
+        let client_initial_secret =
+            super::expanded_initial_secret(&RingProvider, hs_cid, b"client hs");
         let expected = b"\x83\x55\xf2\x1a\x3d\x8f\x83\xec\xb3\xd0\xf9\x71\x08\xd3\xf9\x5e\
                          \x0f\x65\xb4\xd8\xae\x88\xa0\x61\x1e\xe4\x9d\xb0\xb5\x23\x59\x1d";
-        assert_eq!(&client_handshake_secret, expected);
-        let input = super::PacketKey::new(
-            &super::AES_128_GCM,
-            &super::SHA256,
-            &client_handshake_secret,
+        assert_eq!(&client_initial_secret, expected);
+        let (key, iv, _) = super::expand_keys(
+            &RingProvider,
+            AeadAlgorithm::Aes128Gcm,
+            HashAlgorithm::Sha256,
+            &client_initial_secret,
         );
         assert_eq!(
-            &input.data[..input.split],
+            &key[..],
             b"\x3a\xd0\x54\x2c\x4a\x85\x84\x74\x00\x63\x04\x9e\x3b\x3c\xaa\xb2"
         );
+        assert_eq!(&iv[..], b"\xd1\xfd\x26\x05\x42\x75\x3a\xba\x38\x58\x9b\xad");
+    }
+
+    // Counts key setup separately from per-packet use, so tests can check keys aren't rebuilt
+    struct Counting {
+        inner: RingProvider,
+        keys: Arc<AtomicUsize>,
+        seals: Arc<AtomicUsize>,
+        opens: Arc<AtomicUsize>,
+        masks: Arc<AtomicUsize>,
+    }
+
+    impl CryptoProvider for Counting {
+        fn aead_key(&self, alg: AeadAlgorithm, key: &[u8]) -> Box<AeadKey> {
+            self.keys.fetch_add(1, Ordering::SeqCst);
+            Box::new(CountingKey {
+                inner: self.inner.aead_key(alg, key),
+                seals: self.seals.clone(),
+                opens: self.opens.clone(),
+            })
+        }
+
+        fn header_key(&self, alg: AeadAlgorithm, key: &[u8]) -> Box<HeaderKey> {
+            Box::new(CountingHeaderKey {
+                inner: self.inner.header_key(alg, key),
+                masks: self.masks.clone(),
+            })
+        }
+
+        fn hkdf_extract(&self, hash: HashAlgorithm, salt: &[u8], ikm: &[u8]) -> Vec<u8> {
+            self.inner.hkdf_extract(hash, salt, ikm)
+        }
+
+        fn hkdf_expand(&self, hash: HashAlgorithm, prk: &[u8], info: &[u8], out: &mut [u8]) {
+            self.inner.hkdf_expand(hash, prk, info, out)
+        }
+    }
+
+    struct CountingKey {
+        inner: Box<AeadKey>,
+        seals: Arc<AtomicUsize>,
+        opens: Arc<AtomicUsize>,
+    }
+
+    impl AeadKey for CountingKey {
+        fn seal(
+            &self,
+            nonce: &[u8],
+            ad: &[u8],
+            in_out: &mut [u8],
+            out_suffix_capacity: usize,
+        ) -> QuicResult<usize> {
+            self.seals.fetch_add(1, Ordering::SeqCst);
+            self.inner.seal(nonce, ad, in_out, out_suffix_capacity)
+        }
+
+        fn open<'a>(
+            &self,
+            nonce: &[u8],
+            ad: &[u8],
+            in_out: &'a mut [u8],
+        ) -> QuicResult<&'a mut [u8]> {
+            self.opens.fetch_add(1, Ordering::SeqCst);
+            self.inner.open(nonce, ad, in_out)
+        }
+    }
+
+    struct CountingHeaderKey {
+        inner: Box<HeaderKey>,
+        masks: Arc<AtomicUsize>,
+    }
+
+    impl HeaderKey for CountingHeaderKey {
+        fn mask(&self, sample: &[u8]) -> QuicResult<[u8; HEADER_MASK_LEN]> {
+            self.masks.fetch_add(1, Ordering::SeqCst);
+            self.inner.mask(sample)
+        }
+    }
+
+    #[test]
+    fn test_custom_provider() {
+        let cid = ConnectionId {
+            len: 8,
+            bytes: [1, 2, 3, 4, 5, 6, 7, 8, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0],
+        };
+        let counting = Counting {
+            inner: RingProvider,
+            keys: Arc::new(AtomicUsize::new(0)),
+            seals: Arc::new(AtomicUsize::new(0)),
+            opens: Arc::new(AtomicUsize::new(0)),
+            masks: Arc::new(AtomicUsize::new(0)),
+        };
+        let (keys, seals, opens, masks) = (
+            counting.keys.clone(),
+            counting.seals.clone(),
+            counting.opens.clone(),
+            counting.masks.clone(),
+        );
+        let client = KeyChain::new(Side::Client, &Secret::Initial(cid), Arc::new(counting));
+        let server = KeyChain::new(Side::Server, &Secret::Initial(cid), ring());
+
+        for number in 1..3 {
+            let mut buf = b"hello world".to_vec();
+            buf.extend_from_slice(&[0; 16]);
+            client
+                .seal(EncryptionLevel::Initial, number, b"", &mut buf, 16)
+                .unwrap();
+            assert_eq!(
+                server
+                    .open(EncryptionLevel::Initial, number, b"", &mut buf)
+                    .unwrap(),
+                b"hello world"
+            );
+        }
+        let mask = client
+            .local_header_mask(EncryptionLevel::Initial, &[3; 16])
+            .unwrap();
         assert_eq!(
-            &input.data[input.split..],
-            b"\xd1\xfd\x26\x05\x42\x75\x3a\xba\x38\x58\x9b\xad"
+            server
+                .remote_header_mask(EncryptionLevel::Initial, &[3; 16])
+                .unwrap(),
+            mask
         );
+
+        // One key for each direction, reused for every packet
+        assert_eq!(keys.load(Ordering::SeqCst), 2);
+        assert_eq!(seals.load(Ordering::SeqCst), 2);
+        assert_eq!(masks.load(Ordering::SeqCst), 1);
+        assert_eq!(opens.load(Ordering::SeqCst), 0);
     }
 }
@@ -0,0 +1,155 @@
+use bytes::Bytes;
+use futures::{task, Async, Poll, Stream};
+
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+
+use super::{QuicError, QuicResult};
+use codec::BufLen;
+use frame::DatagramFrame;
+
+#[derive(Clone)]
+pub struct Datagrams {
+    inner: Arc<Mutex<Inner>>,
+}
+
+impl Datagrams {
+    pub fn new() -> Self {
+        Self {
+            inner: Arc::new(Mutex::new(Inner {
+                task: None,
+                recv_task: None,
+                outgoing: VecDeque::new(),
+                incoming: VecDeque::new(),
+                max_size: 0,
+            })),
+        }
+    }
+
+    pub fn set_task(&mut self, task: task::Task) {
+        let mut me = self.inner.lock().unwrap();
+        me.task = Some(task);
+    }
+
+    pub fn set_max_size(&mut self, max: u16) {
+        let mut me = self.inner.lock().unwrap();
+        me.max_size = usize::from(max);
+    }
+
+    pub fn max_size(&self) -> usize {
+        let me = self.inner.lock().unwrap();
+        me.max_size
+    }
+
+    pub fn send(&self, data: Bytes) -> QuicResult<()> {
+        let mut me = self.inner.lock().unwrap();
+        let frame = DatagramFrame(data);
+        if frame.buf_len() + 1 > me.max_size {
+            return Err(QuicError::General(format!(
+                "datagram of {} bytes exceeds the peer's limit of {}",
+                frame.0.len(),
+                me.max_size
+            )));
+        }
+        // Unreliable data is better dropped than delivered late
+        if me.outgoing.len() >= MAX_QUEUED {
+            me.outgoing.pop_front();
+        }
+        me.outgoing.push_back(frame);
+        if let Some(ref mut task) = me.task {
+            task.notify();
+        }
+        Ok(())
+    }
+
+    pub fn queued(&mut self) -> Option<DatagramFrame> {
+        let mut me = self.inner.lock().unwrap();
+        me.outgoing.pop_front()
+    }
+
+    pub fn received(&mut self, frame: DatagramFrame) {
+        let mut me = self.inner.lock().unwrap();
+        if me.incoming.len() >= MAX_QUEUED {
+            me.incoming.pop_front();
+        }
+        me.incoming.push_back(frame.0);
+        if let Some(task) = me.recv_task.take() {
+            task.notify();
+        }
+    }
+
+    pub fn incoming(&self) -> RecvDatagrams {
+        RecvDatagrams {
+            inner: self.inner.clone(),
+        }
+    }
+}
+
+pub struct RecvDatagrams {
+    inner: Arc<Mutex<Inner>>,
+}
+
+impl Stream for RecvDatagrams {
+    type Item = Bytes;
+    type Error = QuicError;
+
+    fn poll(&mut self) -> Poll<Option<Bytes>, QuicError> {
+        let mut me = self.inner.lock().unwrap();
+        match me.incoming.pop_front() {
+            Some(data) => Ok(Async::Ready(Some(data))),
+            None => {
+                me.recv_task = Some(task::current());
+                Ok(Async::NotReady)
+            }
+        }
+    }
+}
+
+struct Inner {
+    task: Option<task::Task>,
+    recv_task: Option<task::Task>,
+    outgoing: VecDeque<DatagramFrame>,
+    incoming: VecDeque<Bytes>,
+    max_size: usize,
+}
+
+const MAX_QUEUED: usize = 64;
+
+#[cfg(test)]
+mod tests {
+    use super::Datagrams;
+    use bytes::Bytes;
+    use frame::DatagramFrame;
+    use futures::{future, Async, Future, Stream};
+
+    #[test]
+    fn test_send_limit() {
+        let mut datagrams = Datagrams::new();
+        assert!(datagrams.send(Bytes::from_static(b"hello")).is_err());
+
+        datagrams.set_max_size(8);
+        assert!(datagrams.send(Bytes::from_static(b"hello")).is_ok());
+        assert!(datagrams.send(Bytes::from_static(b"hello!!")).is_err());
+        assert_eq!(
+            datagrams.queued(),
+            Some(DatagramFrame(Bytes::from_static(b"hello")))
+        );
+        assert_eq!(datagrams.queued(), None);
+    }
+
+    #[test]
+    fn test_receive() {
+        let mut datagrams = Datagrams::new();
+        let mut incoming = datagrams.incoming();
+        future::lazy(move || {
+            assert!(incoming.poll().unwrap().is_not_ready());
+            datagrams.received(DatagramFrame(Bytes::from_static(b"a")));
+            assert_eq!(
+                incoming.poll().unwrap(),
+                Async::Ready(Some(Bytes::from_static(b"a")))
+            );
+            Ok::<_, ()>(())
+        }).wait()
+            .unwrap();
+    }
+}
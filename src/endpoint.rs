@@ -0,0 +1,1758 @@
+use futures::sync::mpsc::{self, Receiver, Sender, UnboundedReceiver, UnboundedSender};
+use futures::{future, Async, AsyncSink, Future, Poll, Sink, Stream};
+
+use super::{ConnectError, QuicError, QuicResult, QUIC_VERSION};
+use clock::{Clock, SystemClock};
+use codec::Codec;
+use congestion::{Algorithm, DEFAULT_PACING_BURST};
+use conn_ids::{ConnectionIdGenerator, RandomConnectionIdGenerator};
+use conn_state::{CloseReason, ConnectionState, ParamsCache};
+use connection::{Command, Connection, ConnectionDriver};
+use crypto::{CryptoProvider, RingProvider, Secret};
+use packet::{Header, LongType, Packet};
+use parameters::{ClientTransportParameters, ServerTransportParameters, TransportConfig,
+                 TransportParameters};
+use pool::BufferPool;
+use qlog::QlogFactory;
+use resolver::{Resolution, Resolver, SystemResolver};
+use socket::{self, EcnCodepoint, RecvMeta, Socket, Transmit};
+use streams::{DEFAULT_CONNECTION_BUFFER, DEFAULT_RECEIVE_BUFFER, DEFAULT_SEND_BUFFER};
+use timers::{TimerHandle, Timers};
+use tls;
+use token::{HmacTokenStore, TokenStore};
+use types::{ConnectionId, Side, GENERATED_CID_LENGTH};
+
+use std::cmp;
+use std::collections::{HashMap, VecDeque, hash_map::Entry};
+use std::io::Write;
+use std::mem;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use tokio::timer::Delay;
+use tokio::{self, net::UdpSocket};
+
+// Datagrams handed to the socket at once, so they can share a syscall
+const SEND_BATCH_SIZE: usize = 32;
+// Each receive buffer must fit a full coalesced GRO read
+const RECV_BATCH_SIZE: usize = 16;
+const RECV_BUFFER_SIZE: usize = 65536;
+// Connections that can be handshaking or waiting to be accepted before new ones are refused
+pub const DEFAULT_ACCEPT_BACKLOG: usize = 128;
+// How long dual-stack connects give IPv6 before trying IPv4 too, as recommended by RFC 8305
+const DEFAULT_ATTEMPT_DELAY: u64 = 250;
+
+// Decides, from the client's address and requested server name, whether to take a connection
+pub type AdmissionFilter = Arc<Fn(&SocketAddr, Option<&str>) -> bool + Send + Sync>;
+
+#[derive(Clone)]
+pub struct EndpointConfig {
+    congestion: Algorithm,
+    pacing_burst: usize,
+    receive_buffer: usize,
+    connection_buffer: usize,
+    send_buffer: usize,
+    coalesce: usize,
+    low_latency: bool,
+    reset_code: u16,
+    early_data: bool,
+    resend_early_data: bool,
+    transport: TransportConfig,
+    backlog: usize,
+    admission: Option<AdmissionFilter>,
+    retry_threshold: Option<usize>,
+    max_connections: Option<usize>,
+    max_handshakes: Option<usize>,
+    max_buffered: Option<usize>,
+    qlog: Option<QlogFactory>,
+    clock: Arc<Clock>,
+    crypto: Arc<CryptoProvider>,
+    resolver: Arc<Resolver>,
+    tokens: Option<Arc<TokenStore>>,
+    cid_generator: Arc<ConnectionIdGenerator>,
+    attempt_delay: Duration,
+    spin_bit: bool,
+}
+
+impl Default for EndpointConfig {
+    fn default() -> Self {
+        Self {
+            congestion: Algorithm::default(),
+            pacing_burst: DEFAULT_PACING_BURST,
+            receive_buffer: DEFAULT_RECEIVE_BUFFER,
+            connection_buffer: DEFAULT_CONNECTION_BUFFER,
+            send_buffer: DEFAULT_SEND_BUFFER,
+            coalesce: 0,
+            low_latency: false,
+            reset_code: 0,
+            early_data: false,
+            resend_early_data: true,
+            transport: TransportConfig::default(),
+            backlog: DEFAULT_ACCEPT_BACKLOG,
+            admission: None,
+            retry_threshold: None,
+            max_connections: None,
+            max_handshakes: None,
+            max_buffered: None,
+            qlog: None,
+            clock: Arc::new(SystemClock),
+            crypto: Arc::new(RingProvider),
+            resolver: Arc::new(SystemResolver),
+            tokens: None,
+            cid_generator: Arc::new(RandomConnectionIdGenerator::new(GENERATED_CID_LENGTH)),
+            attempt_delay: Duration::from_millis(DEFAULT_ATTEMPT_DELAY),
+            spin_bit: true,
+        }
+    }
+}
+
+impl EndpointConfig {
+    pub fn congestion(mut self, algorithm: Algorithm) -> Self {
+        self.congestion = algorithm;
+        self
+    }
+
+    pub fn pacing_burst(mut self, packets: usize) -> Self {
+        self.pacing_burst = packets;
+        self
+    }
+
+    pub fn stream_receive_buffer(mut self, bytes: usize) -> Self {
+        self.receive_buffer = bytes;
+        self
+    }
+
+    pub fn connection_receive_buffer(mut self, bytes: usize) -> Self {
+        self.connection_buffer = bytes;
+        self
+    }
+
+    pub fn stream_send_buffer(mut self, bytes: usize) -> Self {
+        self.send_buffer = bytes;
+        self
+    }
+
+    // Small writes wait while earlier stream data is unacknowledged, until this many bytes
+    // are queued or the stream is flushed
+    pub fn coalesce_writes(mut self, bytes: usize) -> Self {
+        self.coalesce = bytes;
+        self
+    }
+
+    // Every write goes out in frames of its own as soon as it's made
+    pub fn low_latency(mut self, enabled: bool) -> Self {
+        self.low_latency = enabled;
+        self
+    }
+
+    pub fn stream_reset_code(mut self, error_code: u16) -> Self {
+        self.reset_code = error_code;
+        self
+    }
+
+    pub fn accept_early_data(mut self, accept: bool) -> Self {
+        self.early_data = accept;
+        self
+    }
+
+    // Whether clients send 0-RTT stream data again as 1-RTT once the server turns it down;
+    // otherwise, the streams fail and EarlyDataRejected says which ones
+    pub fn resend_rejected_early_data(mut self, resend: bool) -> Self {
+        self.resend_early_data = resend;
+        self
+    }
+
+    pub fn accept_backlog(mut self, connections: usize) -> Self {
+        self.backlog = connections;
+        self
+    }
+
+    // Consulted for each new connection once its first flight arrives; connections it rejects
+    // are refused before the TLS handshake does any work
+    pub fn admission<F>(mut self, filter: F) -> Self
+    where
+        F: Fn(&SocketAddr, Option<&str>) -> bool + Send + Sync + 'static,
+    {
+        self.admission = Some(Arc::new(filter));
+        self
+    }
+
+    // Answers every Initial without a valid token with a Retry, so no crypto work is done for a
+    // client until it has shown it can receive at its address
+    pub fn stateless_retry(mut self, enabled: bool) -> Self {
+        self.retry_threshold = if enabled { Some(0) } else { None };
+        self
+    }
+
+    // Only falls back to Retry once this many connections are handshaking or waiting to be
+    // accepted, as when under attack
+    pub fn stateless_retry_threshold(mut self, connections: usize) -> Self {
+        self.retry_threshold = Some(connections);
+        self
+    }
+
+    // Counts client and server connections alike; new ones beyond it are refused
+    pub fn max_connections(mut self, connections: usize) -> Self {
+        self.max_connections = Some(connections);
+        self
+    }
+
+    // Once this many handshakes are in flight, clients have to go through a Retry first, and
+    // those that come back while the endpoint is still this busy are refused
+    pub fn max_handshakes(mut self, handshakes: usize) -> Self {
+        self.max_handshakes = Some(handshakes);
+        self
+    }
+
+    // Stream data buffered across all connections, beyond which new connections are refused
+    pub fn max_buffered_bytes(mut self, bytes: usize) -> Self {
+        self.max_buffered = Some(bytes);
+        self
+    }
+
+    pub fn transport(mut self, config: TransportConfig) -> Self {
+        self.transport = config;
+        self
+    }
+
+    pub fn idle_timeout(mut self, timeout: Duration) -> Self {
+        self.transport = self.transport.idle_timeout(timeout);
+        self
+    }
+
+    pub fn handshake_timeout(mut self, timeout: Duration) -> Self {
+        self.transport = self.transport.handshake_timeout(timeout);
+        self
+    }
+
+    pub fn keep_alive_interval(mut self, interval: Duration) -> Self {
+        self.transport = self.transport.keep_alive_interval(interval);
+        self
+    }
+
+    pub fn max_concurrent_bidi_streams(mut self, count: u16) -> Self {
+        self.transport = self.transport.max_concurrent_bidi_streams(count);
+        self
+    }
+
+    pub fn max_concurrent_uni_streams(mut self, count: u16) -> Self {
+        self.transport = self.transport.max_concurrent_uni_streams(count);
+        self
+    }
+
+    pub fn max_datagram_frame_size(mut self, bytes: u16) -> Self {
+        self.transport = self.transport.max_datagram_frame_size(bytes);
+        self
+    }
+
+    // Called for every new connection; returning a writer enables qlog tracing for it
+    pub fn qlog<F>(mut self, factory: F) -> Self
+    where
+        F: Fn(Side, &[u8]) -> Option<Box<Write + Send>> + Send + Sync + 'static,
+    {
+        self.qlog = Some(Arc::new(factory));
+        self
+    }
+
+    pub fn clock(mut self, clock: Arc<Clock>) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    pub fn crypto_provider(mut self, provider: Arc<CryptoProvider>) -> Self {
+        self.crypto = provider;
+        self
+    }
+
+    pub fn resolver(mut self, resolver: Arc<Resolver>) -> Self {
+        self.resolver = resolver;
+        self
+    }
+
+    // Without one, each server signs tokens with a random key of its own
+    pub fn token_store(mut self, store: Arc<TokenStore>) -> Self {
+        self.tokens = Some(store);
+        self
+    }
+
+    // Zero-length CIDs save header bytes, but connections on the endpoint can then only
+    // be told apart by the peer's address
+    pub fn connection_id_length(mut self, len: u8) -> Self {
+        self.cid_generator = Arc::new(RandomConnectionIdGenerator::new(len));
+        self
+    }
+
+    // Every CID the endpoint issues comes from the generator, including those for Retry
+    // packets and the ones handed out with NEW_CONNECTION_ID
+    pub fn connection_id_generator(mut self, generator: Arc<ConnectionIdGenerator>) -> Self {
+        self.cid_generator = generator;
+        self
+    }
+
+    // How long connect_dual waits on the IPv6 attempt before starting one over IPv4
+    pub fn connection_attempt_delay(mut self, delay: Duration) -> Self {
+        self.attempt_delay = delay;
+        self
+    }
+
+    // With the spin bit disabled, short headers carry a random value instead, so on-path
+    // observers can't tell which connections opted out
+    pub fn spin_bit(mut self, enabled: bool) -> Self {
+        self.spin_bit = enabled;
+        self
+    }
+
+    pub(crate) fn congestion_algorithm(&self) -> Algorithm {
+        self.congestion
+    }
+
+    pub(crate) fn pacing_burst_size(&self) -> usize {
+        self.pacing_burst
+    }
+
+    pub(crate) fn receive_buffer_size(&self) -> usize {
+        self.receive_buffer
+    }
+
+    pub(crate) fn connection_buffer_size(&self) -> usize {
+        self.connection_buffer
+    }
+
+    pub(crate) fn send_buffer_size(&self) -> usize {
+        self.send_buffer
+    }
+
+    pub(crate) fn write_coalescing(&self) -> Option<usize> {
+        if self.low_latency {
+            None
+        } else {
+            Some(self.coalesce)
+        }
+    }
+
+    pub(crate) fn reset_error_code(&self) -> u16 {
+        self.reset_code
+    }
+
+    pub(crate) fn early_data_enabled(&self) -> bool {
+        self.early_data
+    }
+
+    pub(crate) fn early_data_resent(&self) -> bool {
+        self.resend_early_data
+    }
+
+    pub(crate) fn accept_backlog_size(&self) -> usize {
+        self.backlog
+    }
+
+    pub(crate) fn retry_threshold(&self) -> Option<usize> {
+        self.retry_threshold
+    }
+
+    pub(crate) fn connection_limit(&self) -> Option<usize> {
+        self.max_connections
+    }
+
+    pub(crate) fn handshake_limit(&self) -> Option<usize> {
+        self.max_handshakes
+    }
+
+    pub(crate) fn buffer_limit(&self) -> Option<usize> {
+        self.max_buffered
+    }
+
+    pub(crate) fn admission_filter(&self) -> Option<AdmissionFilter> {
+        self.admission.clone()
+    }
+
+    pub(crate) fn transport_config(&self) -> &TransportConfig {
+        &self.transport
+    }
+
+    pub(crate) fn clock_source(&self) -> Arc<Clock> {
+        self.clock.clone()
+    }
+
+    pub(crate) fn cid_length(&self) -> u8 {
+        self.cid_generator.cid_len()
+    }
+
+    pub(crate) fn cid_generator(&self) -> Arc<ConnectionIdGenerator> {
+        self.cid_generator.clone()
+    }
+
+    pub(crate) fn attempt_delay(&self) -> Duration {
+        self.attempt_delay
+    }
+
+    pub(crate) fn spin_bit_enabled(&self) -> bool {
+        self.spin_bit
+    }
+
+    pub(crate) fn crypto(&self) -> Arc<CryptoProvider> {
+        self.crypto.clone()
+    }
+
+    pub(crate) fn host_resolver(&self) -> Arc<Resolver> {
+        self.resolver.clone()
+    }
+
+    pub(crate) fn token_store_override(&self) -> Option<Arc<TokenStore>> {
+        self.tokens.clone()
+    }
+
+    pub(crate) fn qlog_writer(&self, side: Side, cid: &ConnectionId) -> Option<Box<Write + Send>> {
+        self.qlog.as_ref().and_then(|factory| factory(side, &cid[..]))
+    }
+
+    pub(crate) fn transport_parameters(&self) -> TransportParameters {
+        self.transport.parameters()
+    }
+}
+
+// Keeps the driver's routing table in sync with the connection IDs each connection uses
+pub(crate) enum Route {
+    Add(ConnectionId, SocketAddr, Sender<(SocketAddr, Option<EcnCodepoint>, Vec<u8>)>),
+    Issued(ConnectionId, ConnectionId),
+    Retired(ConnectionId),
+}
+
+#[derive(Clone)]
+pub struct Endpoint {
+    send: Sender<(SocketAddr, Option<EcnCodepoint>, Vec<u8>)>,
+    routes: UnboundedSender<Route>,
+    client_config: Option<tls::ClientConfig>,
+    config: Arc<EndpointConfig>,
+    params_cache: ParamsCache,
+    pool: BufferPool,
+    timers: Timers,
+    resources: Arc<Resources>,
+    // Set for wildcard IPv6 sockets we bound ourselves, which also carry IPv4 traffic
+    dual_stack: bool,
+}
+
+impl Endpoint {
+    pub fn new(addr: &SocketAddr) -> QuicResult<(Endpoint, Driver)> {
+        Self::with_config(addr, EndpointConfig::default())
+    }
+
+    pub fn with_config(
+        addr: &SocketAddr,
+        config: EndpointConfig,
+    ) -> QuicResult<(Endpoint, Driver)> {
+        let (mut endpoint, driver) = Self::with_socket(bind(addr)?, config)?;
+        endpoint.dual_stack = is_dual_stack(addr);
+        Ok((endpoint, driver))
+    }
+
+    pub fn with_socket(
+        socket: Box<Socket>,
+        config: EndpointConfig,
+    ) -> QuicResult<(Endpoint, Driver)> {
+        Ok(Self::build(socket, config, None))
+    }
+
+    pub fn listen(
+        addr: &SocketAddr,
+        tls_config: tls::ServerConfig,
+    ) -> QuicResult<(Endpoint, Driver, Incoming)> {
+        Self::listen_with_config(addr, tls_config, EndpointConfig::default())
+    }
+
+    pub fn listen_with_config(
+        addr: &SocketAddr,
+        tls_config: tls::ServerConfig,
+        config: EndpointConfig,
+    ) -> QuicResult<(Endpoint, Driver, Incoming)> {
+        let (mut endpoint, driver, incoming) =
+            Self::listen_with_socket(bind(addr)?, tls_config, config)?;
+        endpoint.dual_stack = is_dual_stack(addr);
+        Ok((endpoint, driver, incoming))
+    }
+
+    pub fn listen_with_socket(
+        socket: Box<Socket>,
+        tls_config: tls::ServerConfig,
+        config: EndpointConfig,
+    ) -> QuicResult<(Endpoint, Driver, Incoming)> {
+        let (incoming_tx, incoming_rx) = mpsc::unbounded();
+        let backlog = Arc::new(AtomicUsize::new(0));
+        let server = ServerData {
+            tls_config: Arc::new(tls_config),
+            tokens: config
+                .token_store_override()
+                .unwrap_or_else(|| Arc::new(HmacTokenStore::random())),
+            incoming: incoming_tx,
+            backlog: backlog.clone(),
+        };
+        let (endpoint, driver) = Self::build(socket, config, Some(server));
+        let incoming = Incoming {
+            recv: incoming_rx,
+            backlog,
+        };
+        Ok((endpoint, driver, incoming))
+    }
+
+    fn build(
+        socket: Box<Socket>,
+        config: EndpointConfig,
+        server: Option<ServerData>,
+    ) -> (Endpoint, Driver) {
+        let config = Arc::new(config);
+        let (send_tx, send_rx) = mpsc::channel(5);
+        let (routes_tx, routes_rx) = mpsc::unbounded();
+        let pool = BufferPool::default();
+        let timers = Timers::new(config.clock_source().now());
+        let resources = Arc::new(Resources::default());
+        let endpoint = Endpoint {
+            send: send_tx.clone(),
+            routes: routes_tx.clone(),
+            // Built once so that TLS sessions are cached across connections
+            client_config: Some(tls::build_client_config(None)),
+            config: config.clone(),
+            params_cache: ParamsCache::default(),
+            pool: pool.clone(),
+            timers: timers.clone(),
+            resources: resources.clone(),
+            dual_stack: false,
+        };
+        let driver = Driver {
+            socket,
+            config,
+            server,
+            in_bufs: vec![vec![0u8; RECV_BUFFER_SIZE]; RECV_BATCH_SIZE],
+            in_meta: vec![
+                RecvMeta {
+                    source: SocketAddr::from(([0, 0, 0, 0], 0)),
+                    len: 0,
+                    stride: 0,
+                    ecn: None,
+                };
+                RECV_BATCH_SIZE
+            ],
+            connections: HashMap::new(),
+            addresses: HashMap::new(),
+            send_queue: (send_tx, send_rx),
+            routes: (routes_tx, routes_rx),
+            outgoing: Vec::with_capacity(SEND_BATCH_SIZE),
+            pool,
+            timers,
+            timer: None,
+            resources,
+        };
+        (endpoint, driver)
+    }
+
+    pub fn set_client_config(&mut self, config: tls::ClientConfig) {
+        self.client_config = Some(config);
+    }
+
+    pub fn connect(&self, addr: &SocketAddr, server_name: &str) -> QuicResult<ConnectingFuture> {
+        let transport = self.config.transport_config().clone();
+        self.connect_with(addr, server_name, transport)
+    }
+
+    // Like connect, but with transport settings for this connection only
+    pub fn connect_with(
+        &self,
+        addr: &SocketAddr,
+        server_name: &str,
+        transport: TransportConfig,
+    ) -> QuicResult<ConnectingFuture> {
+        if self.resources.connections_exceeded(&self.config) {
+            return Err(QuicError::General("endpoint connection limit reached".into()));
+        }
+        let addr = &self.socket_address(addr);
+        let config = (*self.config).clone().transport(transport);
+        let tls = tls::client_session(
+            self.client_config.clone(),
+            server_name,
+            &ClientTransportParameters {
+                parameters: config.transport_parameters(),
+                ..ClientTransportParameters::default()
+            },
+        )?;
+        let mut state = ConnectionState::new(tls, None, &config);
+        let close_reason = state.close_reason();
+        state.set_resumption(server_name.into(), self.params_cache.clone());
+        state.set_buffer_pool(self.pool.clone());
+        state.initial()?;
+
+        let (recv_tx, recv_rx) = mpsc::channel(5);
+        self.routes
+            .unbounded_send(Route::Add(state.local_cid(), *addr, recv_tx))
+            .map_err(|_| QuicError::General("endpoint driver has gone away".into()))?;
+
+        let (established_tx, established_rx) = mpsc::unbounded();
+        let mut conn = ConnectionDriver::new(
+            *addr,
+            state,
+            self.send.clone(),
+            recv_rx,
+            self.routes.clone(),
+            established_tx,
+            &self.timers,
+            None,
+        );
+        conn.track_usage(Usage::new(&self.resources));
+        let commands = conn.commands();
+        tokio::executor::current_thread::spawn(conn);
+        Ok(ConnectingFuture {
+            recv: established_rx,
+            close_reason,
+            commands,
+        })
+    }
+
+    // Looks the host up with the configured resolver and tries its addresses one after another
+    // until a handshake completes; the host name is what the server's certificate is checked
+    // against
+    pub fn connect_host(&self, server_name: &str, port: u16) -> HostConnectingFuture {
+        HostConnectingFuture {
+            endpoint: self.clone(),
+            server_name: server_name.into(),
+            resolving: Some(self.config.host_resolver().resolve(server_name, port)),
+            addrs: VecDeque::new(),
+            attempt: None,
+            error: None,
+        }
+    }
+
+    // Happy eyeballs: the host's first IPv6 address gets a head start, then its first IPv4 one
+    // joins the race, so clients with broken IPv6 routing only lose the attempt delay
+    pub fn connect_dual(&self, host: &str, port: u16) -> DualConnectingFuture {
+        self.racing(self.config.host_resolver().resolve(host, port), host)
+    }
+
+    // Like connect_dual, for addresses that are already known
+    pub fn connect_racing(&self, addrs: &[SocketAddr], server_name: &str) -> DualConnectingFuture {
+        self.racing(Box::new(future::ok(addrs.to_vec())), server_name)
+    }
+
+    fn racing(&self, resolving: Resolution, server_name: &str) -> DualConnectingFuture {
+        DualConnectingFuture {
+            endpoint: self.clone(),
+            server_name: server_name.into(),
+            resolving: Some(resolving),
+            attempts: Vec::new(),
+            fallback: None,
+            deadline: None,
+            timer: self.timers.handle(),
+            error: None,
+        }
+    }
+
+    // A dual-stack socket reaches IPv4 hosts through their v4-mapped IPv6 addresses, which is
+    // also where their replies will appear to come from
+    fn socket_address(&self, addr: &SocketAddr) -> SocketAddr {
+        match *addr {
+            SocketAddr::V4(v4) if self.dual_stack => {
+                SocketAddr::new(IpAddr::V6(v4.ip().to_ipv6_mapped()), v4.port())
+            }
+            addr => addr,
+        }
+    }
+}
+
+#[must_use = "futures do nothing unless polled"]
+pub struct ConnectingFuture {
+    recv: UnboundedReceiver<Connection>,
+    close_reason: Arc<Mutex<Option<CloseReason>>>,
+    commands: UnboundedSender<Command>,
+}
+
+impl ConnectingFuture {
+    // Gives up on the connection, closing it even if the handshake has already completed
+    pub fn abandon(self) {
+        let _ = self.commands.unbounded_send(Command::Abandon);
+    }
+}
+
+impl Future for ConnectingFuture {
+    type Item = Connection;
+    type Error = QuicError;
+    fn poll(&mut self) -> Poll<Connection, QuicError> {
+        match self.recv.poll() {
+            Ok(Async::Ready(Some(conn))) => Ok(Async::Ready(conn)),
+            Ok(Async::Ready(None)) | Err(()) => {
+                let error = match *self.close_reason.lock().unwrap() {
+                    Some(ref reason) => reason.to_connect_error(),
+                    None => ConnectError::Closed("connection driver has gone away".into()),
+                };
+                Err(QuicError::Connect(error))
+            }
+            Ok(Async::NotReady) => Ok(Async::NotReady),
+        }
+    }
+}
+
+// Tries the host's addresses one at a time; dropping it abandons the attempt in progress
+#[must_use = "futures do nothing unless polled"]
+pub struct HostConnectingFuture {
+    endpoint: Endpoint,
+    server_name: String,
+    resolving: Option<Resolution>,
+    addrs: VecDeque<SocketAddr>,
+    attempt: Option<ConnectingFuture>,
+    error: Option<QuicError>,
+}
+
+impl Future for HostConnectingFuture {
+    type Item = Connection;
+    type Error = QuicError;
+
+    fn poll(&mut self) -> Poll<Connection, QuicError> {
+        if let Some(mut resolving) = self.resolving.take() {
+            match resolving.poll()? {
+                Async::Ready(addrs) => self.addrs = addrs.into_iter().collect(),
+                Async::NotReady => {
+                    self.resolving = Some(resolving);
+                    return Ok(Async::NotReady);
+                }
+            }
+        }
+
+        loop {
+            if let Some(mut attempt) = self.attempt.take() {
+                match attempt.poll() {
+                    Ok(Async::Ready(conn)) => return Ok(Async::Ready(conn)),
+                    Ok(Async::NotReady) => {
+                        self.attempt = Some(attempt);
+                        return Ok(Async::NotReady);
+                    }
+                    Err(e) => {
+                        debug!("connecting to {} failed: {}", self.server_name, e);
+                        self.error = Some(e);
+                    }
+                }
+            }
+            let addr = match self.addrs.pop_front() {
+                Some(addr) => addr,
+                None => {
+                    let name = &self.server_name;
+                    return Err(self.error.take().unwrap_or_else(|| {
+                        QuicError::General(format!("no addresses for {}", name))
+                    }));
+                }
+            };
+            match self.endpoint.connect(&addr, &self.server_name) {
+                Ok(attempt) => self.attempt = Some(attempt),
+                Err(e) => self.error = Some(e),
+            }
+        }
+    }
+}
+
+impl Drop for HostConnectingFuture {
+    fn drop(&mut self) {
+        if let Some(attempt) = self.attempt.take() {
+            attempt.abandon();
+        }
+    }
+}
+
+// Resolves to whichever attempt completes its handshake first; the others are abandoned, as
+// are all of them if the future is dropped
+#[must_use = "futures do nothing unless polled"]
+pub struct DualConnectingFuture {
+    endpoint: Endpoint,
+    server_name: String,
+    resolving: Option<Resolution>,
+    attempts: Vec<ConnectingFuture>,
+    // Started once the deadline passes, or as soon as every earlier attempt has failed
+    fallback: Option<SocketAddr>,
+    deadline: Option<Instant>,
+    timer: TimerHandle,
+    error: Option<QuicError>,
+}
+
+impl DualConnectingFuture {
+    fn start(&mut self, addrs: &[SocketAddr]) -> QuicResult<()> {
+        let v6 = addrs.iter().find(|addr| addr.is_ipv6()).cloned();
+        let v4 = addrs.iter().find(|addr| addr.is_ipv4()).cloned();
+        let (first, fallback) = match (v6, v4) {
+            (Some(v6), v4) => (v6, v4),
+            (None, Some(v4)) => (v4, None),
+            (None, None) => {
+                return Err(QuicError::General(format!(
+                    "no addresses for {}",
+                    self.server_name
+                )));
+            }
+        };
+        let config = &self.endpoint.config;
+        self.deadline = Some(config.clock_source().now() + config.attempt_delay());
+        self.attempts
+            .push(self.endpoint.connect(&first, &self.server_name)?);
+        self.fallback = fallback;
+        Ok(())
+    }
+}
+
+impl Future for DualConnectingFuture {
+    type Item = Connection;
+    type Error = QuicError;
+
+    fn poll(&mut self) -> Poll<Connection, QuicError> {
+        if let Some(mut resolving) = self.resolving.take() {
+            match resolving.poll()? {
+                Async::Ready(addrs) => self.start(&addrs)?,
+                Async::NotReady => {
+                    self.resolving = Some(resolving);
+                    return Ok(Async::NotReady);
+                }
+            }
+        }
+
+        loop {
+            let mut i = 0;
+            while i < self.attempts.len() {
+                let result = self.attempts[i].poll();
+                match result {
+                    Ok(Async::Ready(conn)) => {
+                        self.attempts.remove(i);
+                        for attempt in self.attempts.drain(..) {
+                            attempt.abandon();
+                        }
+                        self.fallback = None;
+                        return Ok(Async::Ready(conn));
+                    }
+                    Ok(Async::NotReady) => i += 1,
+                    Err(e) => {
+                        self.attempts.remove(i);
+                        self.error = Some(e);
+                    }
+                }
+            }
+
+            if let (Some(addr), Some(deadline)) = (self.fallback, self.deadline) {
+                let now = self.endpoint.config.clock_source().now();
+                if self.attempts.is_empty() || now >= deadline {
+                    self.fallback = None;
+                    match self.endpoint.connect(&addr, &self.server_name) {
+                        Ok(attempt) => self.attempts.push(attempt),
+                        Err(e) => self.error = Some(e),
+                    }
+                    continue;
+                }
+                self.timer.set(Some(deadline));
+            }
+
+            if self.attempts.is_empty() {
+                return Err(self.error.take().unwrap_or_else(|| {
+                    QuicError::General("no connection attempts left".into())
+                }));
+            }
+            return Ok(Async::NotReady);
+        }
+    }
+}
+
+impl Drop for DualConnectingFuture {
+    fn drop(&mut self) {
+        for attempt in self.attempts.drain(..) {
+            attempt.abandon();
+        }
+    }
+}
+
+pub struct Incoming {
+    recv: UnboundedReceiver<Connection>,
+    backlog: Arc<AtomicUsize>,
+}
+
+impl Stream for Incoming {
+    type Item = Connection;
+    type Error = QuicError;
+    fn poll(&mut self) -> Poll<Option<Connection>, QuicError> {
+        let conn = try_ready!(
+            self.recv
+                .poll()
+                .map_err(|_| QuicError::General("incoming connection channel failed".into()))
+        );
+        if conn.is_some() {
+            self.backlog.fetch_sub(1, Ordering::SeqCst);
+        }
+        Ok(Async::Ready(conn))
+    }
+}
+
+struct ServerData {
+    tls_config: Arc<tls::ServerConfig>,
+    tokens: Arc<TokenStore>,
+    incoming: UnboundedSender<Connection>,
+    // Connections handshaking or waiting in Incoming
+    backlog: Arc<AtomicUsize>,
+}
+
+// Holds a server connection's place in the accept backlog until it's queued for Incoming
+pub(crate) struct BacklogSlot(Option<Arc<AtomicUsize>>);
+
+impl BacklogSlot {
+    // Incoming gives the place up once the application takes the connection
+    pub(crate) fn hand_over(mut self) {
+        self.0 = None;
+    }
+}
+
+impl Drop for BacklogSlot {
+    fn drop(&mut self) {
+        if let Some(ref backlog) = self.0 {
+            backlog.fetch_sub(1, Ordering::SeqCst);
+        }
+    }
+}
+
+// What the endpoint's connections hold between them, so that new ones can be turned away
+// before the process runs out of memory
+#[derive(Default)]
+pub(crate) struct Resources {
+    connections: AtomicUsize,
+    handshakes: AtomicUsize,
+    buffered: AtomicUsize,
+}
+
+impl Resources {
+    fn connections_exceeded(&self, config: &EndpointConfig) -> bool {
+        exceeded(&self.connections, config.connection_limit())
+    }
+
+    fn handshakes_exceeded(&self, config: &EndpointConfig) -> bool {
+        exceeded(&self.handshakes, config.handshake_limit())
+    }
+
+    // Which limit, if any, a new connection would go over
+    fn exhausted(&self, config: &EndpointConfig) -> Option<&'static str> {
+        if self.connections_exceeded(config) {
+            Some("connection limit")
+        } else if self.handshakes_exceeded(config) {
+            Some("handshake limit")
+        } else if exceeded(&self.buffered, config.buffer_limit()) {
+            Some("buffer limit")
+        } else {
+            None
+        }
+    }
+}
+
+fn exceeded(usage: &AtomicUsize, limit: Option<usize>) -> bool {
+    limit.map_or(false, |limit| usage.load(Ordering::SeqCst) >= limit)
+}
+
+// A connection's share of the endpoint's resources, given back when its driver goes away
+pub(crate) struct Usage {
+    resources: Arc<Resources>,
+    handshaking: bool,
+    buffered: usize,
+}
+
+impl Usage {
+    fn new(resources: &Arc<Resources>) -> Self {
+        resources.connections.fetch_add(1, Ordering::SeqCst);
+        resources.handshakes.fetch_add(1, Ordering::SeqCst);
+        Self {
+            resources: resources.clone(),
+            handshaking: true,
+            buffered: 0,
+        }
+    }
+
+    pub(crate) fn handshake_done(&mut self) {
+        if self.handshaking {
+            self.handshaking = false;
+            self.resources.handshakes.fetch_sub(1, Ordering::SeqCst);
+        }
+    }
+
+    pub(crate) fn set_buffered(&mut self, bytes: usize) {
+        if bytes > self.buffered {
+            self.resources
+                .buffered
+                .fetch_add(bytes - self.buffered, Ordering::SeqCst);
+        } else {
+            self.resources
+                .buffered
+                .fetch_sub(self.buffered - bytes, Ordering::SeqCst);
+        }
+        self.buffered = bytes;
+    }
+}
+
+impl Drop for Usage {
+    fn drop(&mut self) {
+        self.handshake_done();
+        self.set_buffered(0);
+        self.resources.connections.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+#[must_use = "futures do nothing unless polled"]
+pub struct Driver {
+    socket: Box<Socket>,
+    config: Arc<EndpointConfig>,
+    server: Option<ServerData>,
+    in_bufs: Vec<Vec<u8>>,
+    in_meta: Vec<RecvMeta>,
+    connections: HashMap<ConnectionId, Sender<(SocketAddr, Option<EcnCodepoint>, Vec<u8>)>>,
+    addresses: HashMap<SocketAddr, Sender<(SocketAddr, Option<EcnCodepoint>, Vec<u8>)>>,
+    send_queue: (
+        Sender<(SocketAddr, Option<EcnCodepoint>, Vec<u8>)>,
+        Receiver<(SocketAddr, Option<EcnCodepoint>, Vec<u8>)>,
+    ),
+    routes: (UnboundedSender<Route>, UnboundedReceiver<Route>),
+    outgoing: Vec<Transmit>,
+    pool: BufferPool,
+    timers: Timers,
+    timer: Option<Delay>,
+    resources: Arc<Resources>,
+}
+
+impl Driver {
+    fn handle_datagram(&mut self, addr: SocketAddr, ecn: Option<EcnCodepoint>, buf: &mut [u8]) {
+        let (dst_cid, header) = {
+            let cid_len = self.config.cid_length() as usize;
+            let partial = match Packet::start_decode(buf, cid_len) {
+                Ok(partial) => partial,
+                Err(e) => {
+                    debug!("dropping invalid packet from {:?}: {:?}", addr, e);
+                    return;
+                }
+            };
+            debug!("incoming packet: {:?} {:?}", addr, partial.header);
+            (partial.dst_cid(), partial.header)
+        };
+
+        let cid = if self.connections.contains_key(&dst_cid) {
+            dst_cid
+        } else if header.ptype() == Some(LongType::Initial) {
+            match self.accept(addr, &header) {
+                Some(cid) => cid,
+                None => return,
+            }
+        } else {
+            dst_cid
+        };
+
+        let msg = self.pool.copy_of(buf);
+        if cid.len == 0 {
+            match self.addresses.entry(addr) {
+                Entry::Occupied(mut inner) => {
+                    if let Err(e) = forward_packet(inner.get_mut(), (addr, ecn, msg)) {
+                        debug!("dropping connection with {:?}: {:?}", addr, e);
+                        inner.remove();
+                    }
+                }
+                Entry::Vacant(_) => debug!("no connection with {:?}", addr),
+            }
+        } else {
+            match self.connections.entry(cid) {
+                Entry::Occupied(mut inner) => {
+                    if let Err(e) = forward_packet(inner.get_mut(), (addr, ecn, msg)) {
+                        debug!("dropping connection {:?}: {:?}", cid, e);
+                        inner.remove();
+                    }
+                }
+                Entry::Vacant(_) => debug!("connection ID {:?} unknown", cid),
+            }
+        }
+    }
+
+    fn accept(&mut self, addr: SocketAddr, header: &Header) -> Option<ConnectionId> {
+        let server = match self.server {
+            Some(ref server) => server,
+            None => {
+                debug!("dropping Initial from {:?} on client endpoint", addr);
+                return None;
+            }
+        };
+
+        let (src_cid, validated) = match *header {
+            Header::Long {
+                src_cid, ref token, ..
+            } => (src_cid, !token.is_empty() && server.tokens.validate(token, &addr)),
+            _ => return None,
+        };
+        let cid_len = self.config.cid_length();
+        let pending = server.backlog.load(Ordering::SeqCst);
+        let retry = self.resources.handshakes_exceeded(&self.config)
+            || self.config
+                .retry_threshold()
+                .map_or(false, |threshold| pending >= threshold);
+        // Without CIDs of our own, the client's next Initial couldn't be told apart
+        if retry && !validated && cid_len > 0 {
+            let retry = Header::Retry {
+                version: QUIC_VERSION,
+                dst_cid: src_cid,
+                src_cid: self.config.cid_generator().generate(),
+                orig_dst_cid: header.dst_cid(),
+                token: server.tokens.mint(&addr),
+            };
+            let mut contents = self.pool.get(0);
+            retry.encode(&mut contents);
+            debug!("asking {:?} to retry with a token", addr);
+            self.outgoing.push(Transmit {
+                destination: addr,
+                ecn: None,
+                contents,
+            });
+            return None;
+        }
+
+        let mut state = ConnectionState::new(
+            tls::server_session(
+                &server.tls_config,
+                &ServerTransportParameters {
+                    parameters: self.config.transport_parameters(),
+                    ..ServerTransportParameters::default()
+                },
+            ),
+            Some(Secret::Initial(header.dst_cid())),
+            &self.config,
+        );
+        if validated {
+            state.set_address_validated();
+        }
+        state.set_buffer_pool(self.pool.clone());
+        // Refused connections only live long enough to tell the client
+        let refused = if pending >= self.config.accept_backlog_size() {
+            debug!("refusing connection from {:?}: accept backlog is full", addr);
+            true
+        } else if let Some(limit) = self.resources.exhausted(&self.config) {
+            debug!("refusing connection from {:?}: endpoint {} reached", addr, limit);
+            true
+        } else {
+            false
+        };
+        if refused {
+            state.refuse();
+        } else if let Some(filter) = self.config.admission_filter() {
+            state.check_admission(addr, filter);
+        }
+
+        let connections = &mut self.connections;
+        let cid = state.pick_unused_cid(|cid| connections.contains_key(&cid));
+        let (recv_tx, recv_rx) = mpsc::channel(5);
+        let mut conn = ConnectionDriver::new(
+            addr,
+            state,
+            self.send_queue.0.clone(),
+            recv_rx,
+            self.routes.0.clone(),
+            server.incoming.clone(),
+            &self.timers,
+            Some(header.dst_cid()),
+        );
+        if !refused {
+            server.backlog.fetch_add(1, Ordering::SeqCst);
+            conn.hold_backlog_slot(BacklogSlot(Some(server.backlog.clone())));
+            conn.track_usage(Usage::new(&self.resources));
+            conn.issue_token(server.tokens.clone());
+        }
+        tokio::executor::current_thread::spawn(conn);
+        // Retransmitted Initials still carry the client's chosen destination CID
+        connections.insert(header.dst_cid(), recv_tx.clone());
+        if cid.len == 0 {
+            self.addresses.insert(addr, recv_tx);
+        } else {
+            connections.insert(cid, recv_tx);
+        }
+        Some(header.dst_cid())
+    }
+
+    // Keeps one timer armed for the earliest deadline of all the endpoint's connections
+    fn poll_timers(&mut self) -> bool {
+        let deadline = match self.timers.poll(self.config.clock_source().now()) {
+            Some(deadline) => deadline,
+            None => {
+                self.timer = None;
+                return false;
+            }
+        };
+        let timer = self.timer.get_or_insert_with(|| Delay::new(deadline));
+        if timer.deadline() != deadline {
+            timer.reset(deadline);
+        }
+        match timer.poll() {
+            Ok(Async::Ready(())) => true,
+            Ok(Async::NotReady) => false,
+            Err(e) => {
+                error!("endpoint timer failed: {:?}", e);
+                false
+            }
+        }
+    }
+
+    fn route(&mut self, route: Route) {
+        match route {
+            Route::Add(cid, addr, sender) => {
+                if cid.len == 0 {
+                    self.addresses.insert(addr, sender);
+                } else {
+                    self.connections.insert(cid, sender);
+                }
+            }
+            Route::Issued(cid, existing) => {
+                let sender = match self.connections.get(&existing) {
+                    Some(sender) => sender.clone(),
+                    None => {
+                        debug!("not routing {:?} for unknown connection {:?}", cid, existing);
+                        return;
+                    }
+                };
+                match self.connections.entry(cid) {
+                    Entry::Occupied(_) => debug!("issued connection ID {:?} already in use", cid),
+                    Entry::Vacant(entry) => {
+                        entry.insert(sender);
+                    }
+                }
+            }
+            Route::Retired(cid) => {
+                self.connections.remove(&cid);
+            }
+        }
+    }
+}
+
+impl Future for Driver {
+    type Item = ();
+    type Error = QuicError;
+
+    fn poll(&mut self) -> Poll<(), QuicError> {
+        let mut waiting;
+        loop {
+            waiting = true;
+            while let Ok(Async::Ready(Some(route))) = self.routes.1.poll() {
+                self.route(route);
+            }
+            if self.poll_timers() {
+                waiting = false;
+            }
+
+            match self.socket.poll_recv_batch(&mut self.in_bufs, &mut self.in_meta) {
+                Ok(Async::Ready(count)) => {
+                    waiting = false;
+                    let mut bufs = mem::replace(&mut self.in_bufs, Vec::new());
+                    for (i, buf) in bufs.iter_mut().enumerate().take(count) {
+                        let meta = self.in_meta[i];
+                        for datagram in buf[..meta.len].chunks_mut(cmp::max(meta.stride, 1)) {
+                            self.handle_datagram(meta.source, meta.ecn, datagram);
+                        }
+                    }
+                    self.in_bufs = bufs;
+                }
+                Ok(Async::NotReady) => {}
+                Err(e) => error!("endpoint receive error: {:?}", e),
+            }
+
+            while self.outgoing.len() < SEND_BATCH_SIZE {
+                match self.send_queue.1.poll() {
+                    Ok(Async::Ready(Some((destination, ecn, contents)))) => {
+                        self.outgoing.push(Transmit {
+                            destination,
+                            ecn,
+                            contents,
+                        });
+                    }
+                    Ok(Async::Ready(None)) | Ok(Async::NotReady) => break,
+                    Err(e) => {
+                        error!("error polling send queue: {:?}", e);
+                        break;
+                    }
+                }
+            }
+
+            if !self.outgoing.is_empty() {
+                match self.socket.poll_send_batch(&self.outgoing) {
+                    Ok(Async::Ready(sent)) => {
+                        waiting = false;
+                        for transmit in self.outgoing.drain(..sent) {
+                            self.pool.put(transmit.contents);
+                        }
+                    }
+                    Ok(Async::NotReady) => {}
+                    Err(e) => {
+                        // Drop the datagram that failed so the rest can go out
+                        error!("endpoint send error {:?}", e);
+                        waiting = false;
+                        let transmit = self.outgoing.remove(0);
+                        self.pool.put(transmit.contents);
+                    }
+                }
+            }
+
+            if waiting {
+                break;
+            }
+        }
+        Ok(Async::NotReady)
+    }
+}
+
+// Unless IPV6_V6ONLY is set, which we never do, these accept IPv4 traffic too
+fn is_dual_stack(addr: &SocketAddr) -> bool {
+    addr.is_ipv6() && addr.ip().is_unspecified()
+}
+
+fn bind(addr: &SocketAddr) -> QuicResult<Box<Socket>> {
+    let udp = UdpSocket::bind(addr)?;
+    if let Err(e) = socket::enable_ecn(&udp) {
+        debug!("not reading ECN marks on {:?}: {:?}", addr, e);
+    }
+    if let Err(e) = socket::enable_gro(&udp) {
+        debug!("not coalescing received datagrams on {:?}: {:?}", addr, e);
+    }
+    Ok(Box::new(udp))
+}
+
+fn forward_packet(
+    sink: &mut Sender<(SocketAddr, Option<EcnCodepoint>, Vec<u8>)>,
+    msg: (SocketAddr, Option<EcnCodepoint>, Vec<u8>),
+) -> QuicResult<()> {
+    match sink.start_send(msg) {
+        Ok(AsyncSink::Ready) => {}
+        Ok(AsyncSink::NotReady(msg)) => error!("discarding message: {:?}", msg),
+        Err(e) => {
+            return Err(QuicError::General(format!(
+                "error while starting channel send: {:?}",
+                e
+            )));
+        }
+    }
+    match sink.poll_complete() {
+        Ok(Async::Ready(())) => {}
+        Ok(Async::NotReady) => {}
+        Err(e) => {
+            return Err(QuicError::General(format!(
+                "error while polling channel complete: {:?}",
+                e
+            )));
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Driver, Endpoint, EndpointConfig, Incoming, Resources, Usage};
+    use futures::{future, Future, Stream};
+    use resolver::{Resolution, Resolver};
+    use sim::{Network, NetworkConfig};
+    use tls::tests::{client_config, server_config};
+    use tokio;
+    use tokio::net::UdpSocket;
+    // Connection timers and the simulated network's latency need the runtime's timer
+    use tokio::runtime::current_thread::Runtime;
+    use tokio::timer::Delay;
+    use {ConnectError, QuicError};
+
+    use std::cell::RefCell;
+    use std::net::SocketAddr;
+    use std::rc::Rc;
+    use std::sync::atomic::Ordering;
+    use std::sync::Arc;
+    use std::time::{Duration, Instant};
+
+    #[test]
+    fn test_endpoint_connect() {
+        let socket = UdpSocket::bind(&"127.0.0.1:0".parse().unwrap()).unwrap();
+        let server_addr = socket.local_addr().unwrap();
+        let (_, server_driver, incoming) =
+            Endpoint::listen_with_socket(Box::new(socket), server_config(), Default::default())
+                .unwrap();
+        let (mut client, client_driver) = Endpoint::new(&"127.0.0.1:0".parse().unwrap()).unwrap();
+        client.set_client_config(client_config());
+
+        let mut exec = Runtime::new().unwrap();
+        exec.spawn(server_driver.map_err(|_| ()));
+        exec.spawn(client_driver.map_err(|_| ()));
+
+        let conn = exec.block_on(future::lazy(|| {
+            client.connect(&server_addr, "Localhost").unwrap()
+        })).unwrap();
+        assert_eq!(conn.remote_address(), server_addr);
+
+        let (accepted, _) = exec.block_on(incoming.into_future().map_err(|(e, _)| e))
+            .unwrap();
+        assert!(accepted.is_some());
+    }
+
+    #[test]
+    fn test_accept_backlog() {
+        let config = EndpointConfig::default()
+            .accept_backlog(1)
+            .admission(|_, server_name| server_name == Some("Localhost"));
+        let EndpointPair {
+            mut exec,
+            server_addr,
+            incoming,
+            client,
+            ..
+        } = endpoint_pair(config, Default::default());
+
+        exec.block_on(future::lazy(|| client.connect(&server_addr, "Localhost").unwrap()))
+            .unwrap();
+        // The first connection hasn't been accepted yet, so there's no room for another
+        assert!(
+            exec.block_on(future::lazy(|| client.connect(&server_addr, "Localhost").unwrap()))
+                .is_err()
+        );
+
+        let (accepted, _) = exec.block_on(incoming.into_future().map_err(|(e, _)| e))
+            .unwrap();
+        assert!(accepted.is_some());
+        exec.block_on(future::lazy(|| client.connect(&server_addr, "Localhost").unwrap()))
+            .unwrap();
+    }
+
+    #[test]
+    fn test_resource_usage() {
+        let config = EndpointConfig::default()
+            .max_connections(2)
+            .max_handshakes(1)
+            .max_buffered_bytes(100);
+        let resources = Arc::new(Resources::default());
+        assert_eq!(resources.exhausted(&config), None);
+
+        let mut first = Usage::new(&resources);
+        assert_eq!(resources.exhausted(&config), Some("handshake limit"));
+        first.handshake_done();
+        first.set_buffered(150);
+        assert_eq!(resources.exhausted(&config), Some("buffer limit"));
+        first.set_buffered(50);
+        assert_eq!(resources.exhausted(&config), None);
+
+        let mut second = Usage::new(&resources);
+        second.handshake_done();
+        assert_eq!(resources.exhausted(&config), Some("connection limit"));
+        drop(first);
+        drop(second);
+        assert_eq!(resources.exhausted(&config), None);
+        assert_eq!(resources.connections.load(Ordering::SeqCst), 0);
+        assert_eq!(resources.buffered.load(Ordering::SeqCst), 0);
+    }
+
+    #[test]
+    fn test_connection_limit() {
+        let config = EndpointConfig::default().max_connections(1);
+        let EndpointPair {
+            mut exec,
+            server_addr,
+            incoming,
+            client,
+            ..
+        } = endpoint_pair(config, Default::default());
+
+        exec.block_on(future::lazy(|| client.connect(&server_addr, "Localhost").unwrap()))
+            .unwrap();
+        let (accepted, _) = exec.block_on(incoming.into_future().map_err(|(e, _)| e))
+            .unwrap();
+        assert!(accepted.is_some());
+        // Unlike the accept backlog, taking the connection doesn't make room for another
+        assert!(
+            exec.block_on(future::lazy(|| client.connect(&server_addr, "Localhost").unwrap()))
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn test_stateless_retry() {
+        let config = EndpointConfig::default().stateless_retry(true);
+        let EndpointPair {
+            net,
+            mut exec,
+            server_addr,
+            incoming,
+            client,
+            ..
+        } = endpoint_pair(config, Default::default());
+
+        // The client has to come back with the token from the Retry before it gets anywhere
+        let conn = exec
+            .block_on(future::lazy(|| client.connect(&server_addr, "Localhost").unwrap()))
+            .unwrap();
+        assert_eq!(conn.remote_address(), server_addr);
+        let (accepted, _) = exec.block_on(incoming.into_future().map_err(|(e, _)| e))
+            .unwrap();
+        assert!(accepted.is_some());
+        assert_eq!(net.stats().retries, 1);
+    }
+
+    #[test]
+    fn test_handshake_timeout() {
+        let timeout = Duration::from_millis(100);
+        let config = EndpointConfig::default().handshake_timeout(timeout);
+        let EndpointPair { mut exec, client, .. } = endpoint_pair(Default::default(), config);
+
+        // Nothing listens at this address, so only the timer can end the attempt
+        let addr = "10.0.0.3:4433".parse().unwrap();
+        let started = Instant::now();
+        match exec.block_on(future::lazy(|| client.connect(&addr, "Localhost").unwrap())) {
+            Err(QuicError::Connect(ConnectError::TimedOut)) => {}
+            res => panic!("unexpected result {:?}", res.map(|_| ())),
+        }
+        assert!(started.elapsed() >= timeout);
+    }
+
+    #[test]
+    fn test_dual_stack_connect() {
+        let config = EndpointConfig::default().connection_attempt_delay(Duration::from_millis(20));
+        let EndpointPair {
+            net,
+            mut exec,
+            server_addr: v4,
+            incoming: _incoming,
+            client,
+            ..
+        } = endpoint_pair(Default::default(), config);
+        let v6: SocketAddr = "[2001:db8::1]:4433".parse().unwrap();
+
+        // Nothing answers over IPv6, so the connection comes up over IPv4 once it gets its turn
+        let conn = exec.block_on(client.connect_racing(&[v4, v6], "Localhost")).unwrap();
+        assert_eq!(conn.remote_address(), v4);
+
+        // With a server on both, IPv6 wins well before IPv4 is tried
+        let (_incoming, _) = listen(&net, &mut exec, v6, Default::default());
+        let config = EndpointConfig::default().connection_attempt_delay(Duration::from_secs(10));
+        let (client, _) = client_at(&net, &mut exec, "10.0.0.4:5000".parse().unwrap(), config);
+        let conn = exec.block_on(client.connect_racing(&[v4, v6], "Localhost")).unwrap();
+        assert_eq!(conn.remote_address(), v6);
+
+        // connect_dual gets the addresses from the configured resolver
+        let config = EndpointConfig::default()
+            .resolver(Arc::new(StaticResolver(vec![v4, v6])))
+            .connection_attempt_delay(Duration::from_secs(10));
+        let (client, _) = client_at(&net, &mut exec, "10.0.0.5:5000".parse().unwrap(), config);
+        let conn = exec.block_on(client.connect_dual("Localhost", 4433)).unwrap();
+        assert_eq!(conn.remote_address(), v6);
+    }
+
+    #[test]
+    fn test_dual_stack_socket_address() {
+        let net = Network::new(NetworkConfig::default());
+        let socket = Box::new(net.bind("[2001:db8::2]:5000".parse().unwrap()));
+        let (mut endpoint, _driver) = Endpoint::with_socket(socket, Default::default()).unwrap();
+        let v4: SocketAddr = "10.0.0.1:4433".parse().unwrap();
+        let v6: SocketAddr = "[2001:db8::1]:4433".parse().unwrap();
+        assert_eq!(endpoint.socket_address(&v4), v4);
+
+        endpoint.dual_stack = true;
+        let mapped: SocketAddr = "[::ffff:10.0.0.1]:4433".parse().unwrap();
+        assert_eq!(endpoint.socket_address(&v4), mapped);
+        assert_eq!(endpoint.socket_address(&v6), v6);
+
+        assert!(super::is_dual_stack(&"[::]:0".parse().unwrap()));
+        assert!(!super::is_dual_stack(&"[::1]:0".parse().unwrap()));
+        assert!(!super::is_dual_stack(&"0.0.0.0:0".parse().unwrap()));
+    }
+
+    struct StaticResolver(Vec<SocketAddr>);
+
+    impl Resolver for StaticResolver {
+        fn resolve(&self, host: &str, port: u16) -> Resolution {
+            assert_eq!((host, port), ("Localhost", 4433));
+            Box::new(future::ok(self.0.clone()))
+        }
+    }
+
+    struct FailingResolver;
+
+    impl Resolver for FailingResolver {
+        fn resolve(&self, host: &str, _: u16) -> Resolution {
+            Box::new(future::err(QuicError::General(format!("no such host {}", host))))
+        }
+    }
+
+    #[test]
+    fn test_connect_host() {
+        // Nothing answers at the first address, so its handshake times out before the second
+        // one is tried
+        let server_addr: SocketAddr = "10.0.0.1:4433".parse().unwrap();
+        let addrs = vec!["10.0.0.3:4433".parse().unwrap(), server_addr];
+        let config = EndpointConfig::default()
+            .resolver(Arc::new(StaticResolver(addrs)))
+            .handshake_timeout(Duration::from_millis(100));
+        let EndpointPair {
+            net,
+            mut exec,
+            incoming: _incoming,
+            client,
+            ..
+        } = endpoint_pair(Default::default(), config);
+
+        let conn = exec.block_on(client.connect_host("Localhost", 4433)).unwrap();
+        assert_eq!(conn.remote_address(), server_addr);
+
+        let config = EndpointConfig::default().resolver(Arc::new(StaticResolver(Vec::new())));
+        let (client, _) = client_at(&net, &mut exec, "10.0.0.4:5000".parse().unwrap(), config);
+        assert!(exec.block_on(client.connect_host("Localhost", 4433)).is_err());
+
+        // Lookup failures come back from the connection attempt
+        let config = EndpointConfig::default().resolver(Arc::new(FailingResolver));
+        let (client, _) = client_at(&net, &mut exec, "10.0.0.5:5000".parse().unwrap(), config);
+        match exec.block_on(client.connect_host("Localhost", 4433)) {
+            Err(QuicError::General(ref reason)) if reason == "no such host Localhost" => {}
+            res => panic!("unexpected result {:?}", res.map(|_| ())),
+        }
+    }
+
+    #[test]
+    fn test_zero_length_cids() {
+        // Both connections share the client's empty CID, so only the address tells them apart
+        let config = EndpointConfig::default().connection_id_length(0);
+        let EndpointPair {
+            net,
+            mut exec,
+            server_addr,
+            incoming,
+            client,
+            ..
+        } = endpoint_pair(Default::default(), config);
+        let other_addr = "10.0.0.3:4433".parse().unwrap();
+        let (other_incoming, _) = listen(&net, &mut exec, other_addr, Default::default());
+        let servers = vec![(server_addr, incoming), (other_addr, other_incoming)];
+
+        let mut conns = Vec::new();
+        for (addr, incoming) in servers {
+            let conn = exec.block_on(future::lazy(|| client.connect(&addr, "Localhost").unwrap()))
+                .unwrap();
+            assert_eq!(conn.remote_address(), addr);
+            let (accepted, _) = exec.block_on(incoming.into_future().map_err(|(e, _)| e))
+                .unwrap();
+            conns.push((conn, accepted.unwrap()));
+        }
+
+        for (i, (conn, accepted)) in conns.into_iter().enumerate() {
+            exec.spawn(
+                conn.open_uni()
+                    .and_then(move |send| {
+                        tokio::io::write_all(send, vec![i as u8; 10]).map_err(QuicError::from)
+                    })
+                    .and_then(|(send, _)| send.finish())
+                    .map_err(|e| panic!("sending failed: {:?}", e)),
+            );
+            let read = accepted
+                .accept_uni()
+                .and_then(|recv| tokio::io::read_to_end(recv, Vec::new()).map_err(QuicError::from));
+            let (_, received) = exec.block_on(read).unwrap();
+            assert_eq!(received, vec![i as u8; 10]);
+        }
+    }
+
+    #[test]
+    fn test_rotated_connection_id() {
+        let EndpointPair {
+            mut exec,
+            server_addr,
+            incoming,
+            client,
+            server_driver,
+            client_driver,
+            ..
+        } = endpoint_pair(Default::default(), Default::default());
+
+        let conn = exec.block_on(future::lazy(|| {
+            client.connect(&server_addr, "Localhost").unwrap()
+        })).unwrap();
+        let (accepted, _) = exec.block_on(incoming.into_future().map_err(|(e, _)| e))
+            .unwrap();
+        let accepted = accepted.unwrap();
+
+        // A round trip from the server delivers its NEW_CONNECTION_ID frames to the client
+        exec.spawn(
+            accepted
+                .open_uni()
+                .and_then(|send| tokio::io::write_all(send, b"hi").map_err(QuicError::from))
+                .and_then(|(send, _)| send.finish())
+                .map_err(|e| panic!("sending failed: {:?}", e)),
+        );
+        exec.block_on(conn.accept_uni()).unwrap();
+
+        // Only the endpoint's routing table can get packets for the new CID to the connection
+        conn.rotate_connection_id().unwrap();
+        exec.spawn(
+            conn.open_uni()
+                .and_then(|send| tokio::io::write_all(send, b"rotated").map_err(QuicError::from))
+                .and_then(|(send, _)| send.finish())
+                .map_err(|e| panic!("sending failed: {:?}", e)),
+        );
+        let read = accepted
+            .accept_uni()
+            .and_then(|recv| tokio::io::read_to_end(recv, Vec::new()).map_err(QuicError::from));
+        let (_, received) = exec.block_on(read).unwrap();
+        assert_eq!(received, b"rotated");
+
+        // Every CID routed to the connections goes with them, including the one the client
+        // picked for its Initial
+        exec.block_on(conn.close(0, "done").join(accepted.close(0, "done")))
+            .unwrap();
+        exec.block_on(Delay::new(Instant::now() + Duration::from_millis(50)))
+            .unwrap();
+        assert!(server_driver.borrow().connections.is_empty());
+        assert!(client_driver.borrow().connections.is_empty());
+    }
+
+    // A server at 10.0.0.1:4433 and a client at 10.0.0.2:5000 on a simulated network, with
+    // both endpoints being driven
+    struct EndpointPair {
+        net: Network,
+        exec: Runtime,
+        server_addr: SocketAddr,
+        incoming: Incoming,
+        client: Endpoint,
+        server_driver: Rc<RefCell<Driver>>,
+        client_driver: Rc<RefCell<Driver>>,
+    }
+
+    fn endpoint_pair(server: EndpointConfig, client: EndpointConfig) -> EndpointPair {
+        let net = Network::new(NetworkConfig::default());
+        let mut exec = Runtime::new().unwrap();
+        let server_addr = "10.0.0.1:4433".parse().unwrap();
+        let (incoming, server_driver) = listen(&net, &mut exec, server_addr, server);
+        let client_addr = "10.0.0.2:5000".parse().unwrap();
+        let (client, client_driver) = client_at(&net, &mut exec, client_addr, client);
+        EndpointPair {
+            net,
+            exec,
+            server_addr,
+            incoming,
+            client,
+            server_driver,
+            client_driver,
+        }
+    }
+
+    fn listen(
+        net: &Network,
+        exec: &mut Runtime,
+        addr: SocketAddr,
+        config: EndpointConfig,
+    ) -> (Incoming, Rc<RefCell<Driver>>) {
+        let socket = Box::new(net.bind(addr));
+        let (_, driver, incoming) =
+            Endpoint::listen_with_socket(socket, server_config(), config).unwrap();
+        (incoming, drive(exec, driver))
+    }
+
+    fn client_at(
+        net: &Network,
+        exec: &mut Runtime,
+        addr: SocketAddr,
+        config: EndpointConfig,
+    ) -> (Endpoint, Rc<RefCell<Driver>>) {
+        let socket = Box::new(net.bind(addr));
+        let (mut client, driver) = Endpoint::with_socket(socket, config).unwrap();
+        client.set_client_config(client_config());
+        (client, drive(exec, driver))
+    }
+
+    // Drivers stay reachable, so tests can look at their routing tables
+    fn drive(exec: &mut Runtime, driver: Driver) -> Rc<RefCell<Driver>> {
+        let driver = Rc::new(RefCell::new(driver));
+        let polled = driver.clone();
+        exec.spawn(future::poll_fn(move || polled.borrow_mut().poll()).map_err(|_| ()));
+        driver
+    }
+}
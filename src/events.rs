@@ -0,0 +1,187 @@
+use futures::{task, Async, Poll, Stream};
+
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+
+use super::{QuicError, QuicResult};
+use streams::StreamLimits;
+use types::StreamId;
+
+#[derive(Debug)]
+pub enum Event {
+    HandshakeComplete,
+    StreamOpened(StreamId),
+    StreamReadable(StreamId),
+    DatagramReceived,
+    ConnectionLost { error: QuicError },
+    MaxStreamsChanged(StreamLimits),
+    PeerStreamsBlocked(StreamId),
+    // Streams that had data in 0-RTT packets the server threw away
+    EarlyDataRejected(Vec<StreamId>),
+}
+
+#[derive(Clone)]
+pub struct Events {
+    inner: Arc<Mutex<Inner>>,
+}
+
+impl Events {
+    pub fn new() -> Self {
+        Self {
+            inner: Arc::new(Mutex::new(Inner {
+                task: None,
+                queue: VecDeque::new(),
+                listening: false,
+                connected: false,
+                lost: false,
+            })),
+        }
+    }
+
+    pub fn push(&self, event: Event) {
+        let mut me = self.inner.lock().unwrap();
+        match event {
+            Event::HandshakeComplete => me.connected = true,
+            Event::ConnectionLost { .. } => me.lost = true,
+            _ => {}
+        }
+        // Nobody is going to read them, so there's no point holding on to them
+        if !me.listening {
+            return;
+        }
+        // A listener that stopped polling shouldn't make the connection hold on to everything
+        if me.queue.len() >= MAX_QUEUED {
+            me.queue.pop_front();
+        }
+        me.queue.push_back(event);
+        if let Some(task) = me.task.take() {
+            task.notify();
+        }
+    }
+
+    // Events are handed out once, so there can only be one listener at a time
+    pub fn listen(&self) -> QuicResult<ConnectionEvents> {
+        let mut me = self.inner.lock().unwrap();
+        if me.listening {
+            return Err(QuicError::General(
+                "connection events already have a listener".into(),
+            ));
+        }
+        // Listeners that show up late still learn that the handshake is done
+        if me.connected {
+            me.queue.push_back(Event::HandshakeComplete);
+        }
+        me.listening = true;
+        Ok(ConnectionEvents {
+            inner: self.inner.clone(),
+        })
+    }
+}
+
+pub struct ConnectionEvents {
+    inner: Arc<Mutex<Inner>>,
+}
+
+impl Stream for ConnectionEvents {
+    type Item = Event;
+    type Error = QuicError;
+
+    fn poll(&mut self) -> Poll<Option<Event>, QuicError> {
+        let mut me = self.inner.lock().unwrap();
+        match me.queue.pop_front() {
+            Some(event) => Ok(Async::Ready(Some(event))),
+            None if me.lost => Ok(Async::Ready(None)),
+            None => {
+                me.task = Some(task::current());
+                Ok(Async::NotReady)
+            }
+        }
+    }
+}
+
+impl Drop for ConnectionEvents {
+    fn drop(&mut self) {
+        let mut me = self.inner.lock().unwrap();
+        me.listening = false;
+        me.task = None;
+        me.queue.clear();
+    }
+}
+
+struct Inner {
+    task: Option<task::Task>,
+    queue: VecDeque<Event>,
+    listening: bool,
+    connected: bool,
+    lost: bool,
+}
+
+const MAX_QUEUED: usize = 1024;
+
+#[cfg(test)]
+mod tests {
+    use super::{Event, Events, MAX_QUEUED};
+    use futures::{Async, Stream};
+    use types::StreamId;
+    use QuicError;
+
+    #[test]
+    fn test_events() {
+        let events = Events::new();
+        events.push(Event::StreamOpened(StreamId(0)));
+        events.push(Event::HandshakeComplete);
+
+        let mut listener = events.listen().unwrap();
+        match listener.poll().unwrap() {
+            Async::Ready(Some(Event::HandshakeComplete)) => {}
+            event => panic!("unexpected event {:?}", event),
+        }
+        events.push(Event::StreamReadable(StreamId(4)));
+        events.push(Event::ConnectionLost {
+            error: QuicError::General("gone".into()),
+        });
+        match listener.poll().unwrap() {
+            Async::Ready(Some(Event::StreamReadable(id))) => assert_eq!(id, StreamId(4)),
+            event => panic!("unexpected event {:?}", event),
+        }
+        match listener.poll().unwrap() {
+            Async::Ready(Some(Event::ConnectionLost { .. })) => {}
+            event => panic!("unexpected event {:?}", event),
+        }
+        match listener.poll().unwrap() {
+            Async::Ready(None) => {}
+            event => panic!("unexpected event {:?}", event),
+        }
+    }
+
+    #[test]
+    fn test_single_listener() {
+        let events = Events::new();
+        let listener = events.listen().unwrap();
+        assert!(events.listen().is_err());
+
+        // Events queue up again only once there is a new listener
+        drop(listener);
+        events.push(Event::StreamOpened(StreamId(0)));
+        let mut listener = events.listen().unwrap();
+        events.push(Event::StreamOpened(StreamId(4)));
+        match listener.poll().unwrap() {
+            Async::Ready(Some(Event::StreamOpened(id))) => assert_eq!(id, StreamId(4)),
+            event => panic!("unexpected event {:?}", event),
+        }
+    }
+
+    #[test]
+    fn test_queue_bounded() {
+        let events = Events::new();
+        let mut listener = events.listen().unwrap();
+        for i in 0..MAX_QUEUED as u64 + 1 {
+            events.push(Event::StreamOpened(StreamId(4 * i)));
+        }
+        // The oldest event made room for the newest
+        match listener.poll().unwrap() {
+            Async::Ready(Some(Event::StreamOpened(id))) => assert_eq!(id, StreamId(4)),
+            event => panic!("unexpected event {:?}", event),
+        }
+    }
+}
@@ -0,0 +1,178 @@
+use std::cmp;
+use std::time::{Duration, Instant};
+
+use super::{QuicError, QuicResult, TransportError};
+
+pub struct FlowControl {
+    send_max: u64,
+    sent: u64,
+    blocked_at: Option<u64>,
+    recv_window: u64,
+    recv_max: u64,
+    received: u64,
+    consumed: u64,
+    // Largest window auto-tuning may grow to, and when the current window was last extended
+    max_window: Option<u64>,
+    window_start: Option<Instant>,
+}
+
+impl FlowControl {
+    pub fn new(send_max: u64, recv_window: u64) -> Self {
+        Self {
+            send_max,
+            sent: 0,
+            blocked_at: None,
+            recv_window,
+            recv_max: recv_window,
+            received: 0,
+            consumed: 0,
+            max_window: None,
+            window_start: None,
+        }
+    }
+
+    pub fn send_credit(&self) -> u64 {
+        self.send_max.saturating_sub(self.sent)
+    }
+
+    pub fn on_sent(&mut self, len: u64) {
+        debug_assert!(len <= self.send_credit());
+        self.sent += len;
+    }
+
+    pub fn update_send_max(&mut self, max: u64) -> bool {
+        if max <= self.send_max {
+            return false;
+        }
+        self.send_max = max;
+        true
+    }
+
+    pub fn blocked(&mut self) -> Option<u64> {
+        if self.send_credit() > 0 || self.blocked_at == Some(self.send_max) {
+            return None;
+        }
+        self.blocked_at = Some(self.send_max);
+        self.blocked_at
+    }
+
+    pub fn set_recv_window(&mut self, window: u64) {
+        self.recv_window = window;
+        self.recv_max = cmp::max(self.recv_max, self.consumed + window);
+    }
+
+    pub fn set_autotune(&mut self, max_window: u64) {
+        self.max_window = Some(cmp::max(max_window, self.recv_window));
+    }
+
+    pub fn recv_window(&self) -> u64 {
+        self.recv_window
+    }
+
+    pub fn recv_max(&self) -> u64 {
+        self.recv_max
+    }
+
+    pub fn received(&self) -> u64 {
+        self.received
+    }
+
+    pub fn on_received(&mut self, end: u64) -> QuicResult<u64> {
+        if end > self.recv_max {
+            return Err(QuicError::Transport(
+                TransportError::FlowControlError,
+                format!(
+                    "flow control limit {} exceeded (received up to {})",
+                    self.recv_max, end
+                ),
+            ));
+        }
+        let new = end.saturating_sub(self.received);
+        self.received += new;
+        Ok(new)
+    }
+
+    pub fn on_consumed(&mut self, len: u64) -> Option<u64> {
+        self.consumed += len;
+        if self.recv_max - self.consumed > self.recv_window / 2 {
+            return None;
+        }
+        self.recv_max = self.consumed + self.recv_window;
+        Some(self.recv_max)
+    }
+
+    // Like TCP receive window auto-tuning: a reader that gets through half a window in less than
+    // two round trips would leave the sender waiting for credit, so the window doubles
+    pub fn on_consumed_at(&mut self, len: u64, now: Instant, rtt: Duration) -> Option<u64> {
+        if let Some(max_window) = self.max_window {
+            if self.recv_max - (self.consumed + len) <= self.recv_window / 2 {
+                let fast = self.window_start
+                    .map_or(false, |start| now.duration_since(start) < rtt * 2);
+                if fast {
+                    self.recv_window = cmp::min(self.recv_window * 2, max_window);
+                }
+                self.window_start = Some(now);
+            }
+        }
+        self.on_consumed(len)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::FlowControl;
+    use std::time::{Duration, Instant};
+
+    #[test]
+    fn test_send_credit() {
+        let mut flow = FlowControl::new(100, 0);
+        assert_eq!(flow.blocked(), None);
+        flow.on_sent(100);
+        assert_eq!(flow.send_credit(), 0);
+        assert_eq!(flow.blocked(), Some(100));
+        assert_eq!(flow.blocked(), None);
+
+        assert!(!flow.update_send_max(50));
+        assert!(flow.update_send_max(150));
+        assert_eq!(flow.send_credit(), 50);
+    }
+
+    #[test]
+    fn test_receive_window() {
+        let mut flow = FlowControl::new(0, 100);
+        assert_eq!(flow.on_received(60).unwrap(), 60);
+        assert_eq!(flow.on_received(40).unwrap(), 0);
+        assert!(flow.on_received(101).is_err());
+
+        assert_eq!(flow.on_consumed(40), None);
+        assert_eq!(flow.on_consumed(20), Some(160));
+        assert_eq!(flow.on_received(160).unwrap(), 100);
+    }
+
+    #[test]
+    fn test_window_autotune() {
+        let (start, rtt) = (Instant::now(), Duration::from_millis(100));
+        let mut flow = FlowControl::new(0, 100);
+        flow.set_autotune(400);
+        flow.on_received(100).unwrap();
+        assert_eq!(flow.on_consumed_at(60, start, rtt), Some(160));
+        assert_eq!(flow.recv_window(), 100);
+
+        // Half a window went by within two round trips, so the sender needs more room
+        flow.on_received(160).unwrap();
+        let now = start + Duration::from_millis(50);
+        assert_eq!(flow.on_consumed_at(60, now, rtt), Some(320));
+        assert_eq!(flow.recv_window(), 200);
+
+        flow.on_received(320).unwrap();
+        let now = now + Duration::from_millis(100);
+        assert_eq!(flow.on_consumed_at(200, now, rtt), Some(720));
+        assert_eq!(flow.recv_window(), 400);
+
+        // A slow reader leaves the window where it is
+        flow.on_received(720).unwrap();
+        let now = now + Duration::from_secs(10);
+        assert_eq!(flow.on_consumed_at(400, now, rtt), Some(1120));
+        assert_eq!(flow.recv_window(), 400);
+    }
+}
@@ -1,33 +1,108 @@
-use bytes::{Buf, BufMut};
+use bytes::{Buf, BufMut, Bytes, BytesMut};
 
-use codec::{BufLen, Codec, VarLen};
+use super::{QuicError, QuicResult};
+use codec::{BufExt, BufLen, Codec, VarLen};
+use crypto::EncryptionLevel;
+use types::{ConnectionId, StreamId};
 
-use std::str;
+use std::cmp;
+use std::io::Cursor;
 
 #[derive(Debug, PartialEq)]
 pub enum Frame {
     Ack(AckFrame),
     ApplicationClose(CloseFrame),
+    Blocked(BlockedFrame),
     ConnectionClose(CloseFrame),
+    Crypto(CryptoFrame),
+    Datagram(DatagramFrame),
+    MaxData(MaxDataFrame),
+    MaxStreamData(MaxStreamDataFrame),
+    MaxStreamId(MaxStreamIdFrame),
+    NewConnectionId(NewConnectionIdFrame),
+    NewToken(NewTokenFrame),
     Padding(PaddingFrame),
     PathChallenge(PathFrame),
     PathResponse(PathFrame),
     Ping,
+    RetireConnectionId(RetireConnectionIdFrame),
+    RstStream(RstStreamFrame),
+    StopSending(StopSendingFrame),
     Stream(StreamFrame),
+    StreamBlocked(StreamBlockedFrame),
     StreamIdBlocked(StreamIdBlockedFrame),
 }
 
+impl Frame {
+    // Acknowledging packets that only acknowledge would bounce ACKs back and forth forever
+    pub fn is_ack_eliciting(&self) -> bool {
+        match self {
+            Frame::Ack(_) | Frame::Padding(_) => false,
+            _ => true,
+        }
+    }
+
+    pub fn is_0rtt_allowed(&self) -> bool {
+        match self {
+            Frame::Ack(_) | Frame::Crypto(_) | Frame::NewToken(_) | Frame::PathResponse(_) => {
+                false
+            }
+            _ => true,
+        }
+    }
+
+    // Initial and Handshake packets only carry what it takes to finish or abandon the handshake
+    pub fn is_allowed_at(&self, level: EncryptionLevel) -> bool {
+        match level {
+            EncryptionLevel::Initial | EncryptionLevel::Handshake => match self {
+                Frame::Ack(_)
+                | Frame::ApplicationClose(_)
+                | Frame::ConnectionClose(_)
+                | Frame::Crypto(_)
+                | Frame::Padding(_)
+                | Frame::Ping => true,
+                _ => false,
+            },
+            EncryptionLevel::ZeroRtt => self.is_0rtt_allowed(),
+            EncryptionLevel::OneRtt => true,
+        }
+    }
+
+    // Packets carrying only these may be probing a new path, so they don't move the connection
+    pub fn is_probing(&self) -> bool {
+        match self {
+            Frame::PathChallenge(_)
+            | Frame::PathResponse(_)
+            | Frame::NewConnectionId(_)
+            | Frame::Padding(_) => true,
+            _ => false,
+        }
+    }
+}
+
 impl BufLen for Frame {
     fn buf_len(&self) -> usize {
         match self {
             Frame::Ack(f) => f.buf_len(),
             Frame::ApplicationClose(f) => 1 + f.buf_len(),
+            Frame::Blocked(f) => 1 + f.buf_len(),
             Frame::ConnectionClose(f) => 1 + f.buf_len(),
+            Frame::Crypto(f) => 1 + f.buf_len(),
+            Frame::Datagram(f) => 1 + f.buf_len(),
+            Frame::MaxData(f) => 1 + f.buf_len(),
+            Frame::MaxStreamData(f) => 1 + f.buf_len(),
+            Frame::MaxStreamId(f) => 1 + f.buf_len(),
+            Frame::NewConnectionId(f) => 1 + f.buf_len(),
+            Frame::NewToken(f) => 1 + f.buf_len(),
             Frame::Padding(f) => f.buf_len(),
             Frame::PathChallenge(f) => 1 + f.buf_len(),
             Frame::PathResponse(f) => 1 + f.buf_len(),
             Frame::Ping => 1,
+            Frame::RetireConnectionId(f) => 1 + f.buf_len(),
+            Frame::RstStream(f) => 1 + f.buf_len(),
+            Frame::StopSending(f) => 1 + f.buf_len(),
             Frame::Stream(f) => f.buf_len(),
+            Frame::StreamBlocked(f) => 1 + f.buf_len(),
             Frame::StreamIdBlocked(f) => 1 + f.buf_len(),
         }
     }
@@ -41,10 +116,42 @@ impl Codec for Frame {
                 buf.put_u8(0x03);
                 f.encode(buf)
             }
+            Frame::Blocked(f) => {
+                buf.put_u8(0x08);
+                f.encode(buf)
+            }
             Frame::ConnectionClose(f) => {
                 buf.put_u8(0x02);
                 f.encode(buf)
             }
+            Frame::Crypto(f) => {
+                buf.put_u8(0x18);
+                f.encode(buf)
+            }
+            Frame::Datagram(f) => {
+                buf.put_u8(0x31);
+                f.encode(buf)
+            }
+            Frame::MaxData(f) => {
+                buf.put_u8(0x04);
+                f.encode(buf)
+            }
+            Frame::MaxStreamData(f) => {
+                buf.put_u8(0x05);
+                f.encode(buf)
+            }
+            Frame::MaxStreamId(f) => {
+                buf.put_u8(0x06);
+                f.encode(buf)
+            }
+            Frame::NewConnectionId(f) => {
+                buf.put_u8(0x0b);
+                f.encode(buf)
+            }
+            Frame::NewToken(f) => {
+                buf.put_u8(0x19);
+                f.encode(buf)
+            }
             Frame::Padding(f) => f.encode(buf),
             Frame::PathChallenge(f) => {
                 buf.put_u8(0x0e);
@@ -55,7 +162,23 @@ impl Codec for Frame {
                 f.encode(buf)
             }
             Frame::Ping => buf.put_u8(0x07),
+            Frame::RetireConnectionId(f) => {
+                buf.put_u8(0x1b);
+                f.encode(buf)
+            }
+            Frame::RstStream(f) => {
+                buf.put_u8(0x01);
+                f.encode(buf)
+            }
+            Frame::StopSending(f) => {
+                buf.put_u8(0x0c);
+                f.encode(buf)
+            }
             Frame::Stream(f) => f.encode(buf),
+            Frame::StreamBlocked(f) => {
+                buf.put_u8(0x09);
+                f.encode(buf)
+            }
             Frame::StreamIdBlocked(f) => {
                 buf.put_u8(0x0a);
                 f.encode(buf)
@@ -63,56 +186,193 @@ impl Codec for Frame {
         }
     }
 
-    fn decode<T: Buf>(buf: &mut T) -> Self {
-        match buf.bytes()[0] {
-            v if v >= 0x10 => Frame::Stream(StreamFrame::decode(buf)),
+    fn decode<T: Buf>(buf: &mut T) -> QuicResult<Self> {
+        if !buf.has_remaining() {
+            return Err(QuicError::UnexpectedEnd);
+        }
+        Ok(match buf.bytes()[0] {
+            0x10..=0x17 => Frame::Stream(StreamFrame::decode(buf)?),
+            0x01 => Frame::RstStream({
+                buf.get_u8();
+                RstStreamFrame::decode(buf)?
+            }),
             0x02 => Frame::ConnectionClose({
                 buf.get_u8();
-                CloseFrame::decode(buf)
+                CloseFrame::decode(buf)?
             }),
             0x03 => Frame::ApplicationClose({
                 buf.get_u8();
-                CloseFrame::decode(buf)
+                CloseFrame::decode(buf)?
+            }),
+            0x04 => Frame::MaxData({
+                buf.get_u8();
+                MaxDataFrame::decode(buf)?
+            }),
+            0x05 => Frame::MaxStreamData({
+                buf.get_u8();
+                MaxStreamDataFrame::decode(buf)?
+            }),
+            0x06 => Frame::MaxStreamId({
+                buf.get_u8();
+                MaxStreamIdFrame::decode(buf)?
             }),
             0x07 => {
                 buf.get_u8();
                 Frame::Ping
             }
+            0x08 => Frame::Blocked({
+                buf.get_u8();
+                BlockedFrame::decode(buf)?
+            }),
+            0x09 => Frame::StreamBlocked({
+                buf.get_u8();
+                StreamBlockedFrame::decode(buf)?
+            }),
             0x0a => Frame::StreamIdBlocked({
                 buf.get_u8();
-                StreamIdBlockedFrame::decode(buf)
+                StreamIdBlockedFrame::decode(buf)?
             }),
-            0x0d => Frame::Ack(AckFrame::decode(buf)),
+            0x0b => Frame::NewConnectionId({
+                buf.get_u8();
+                NewConnectionIdFrame::decode(buf)?
+            }),
+            0x0c => Frame::StopSending({
+                buf.get_u8();
+                StopSendingFrame::decode(buf)?
+            }),
+            0x0d | 0x1a => Frame::Ack(AckFrame::decode(buf)?),
             0x0e => Frame::PathChallenge({
                 buf.get_u8();
-                PathFrame::decode(buf)
+                PathFrame::decode(buf)?
             }),
             0x0f => Frame::PathResponse({
                 buf.get_u8();
-                PathFrame::decode(buf)
+                PathFrame::decode(buf)?
+            }),
+            0x18 => Frame::Crypto({
+                buf.get_u8();
+                CryptoFrame::decode(buf)?
+            }),
+            0x19 => Frame::NewToken({
+                buf.get_u8();
+                NewTokenFrame::decode(buf)?
+            }),
+            // 0x0d would collide with ACK, which keeps its old type here
+            0x1b => Frame::RetireConnectionId({
+                buf.get_u8();
+                RetireConnectionIdFrame::decode(buf)?
             }),
-            0 => Frame::Padding(PaddingFrame::decode(buf)),
-            v => panic!("unimplemented decoding for frame type {}", v),
+            0x30 => Frame::Datagram({
+                buf.get_u8();
+                let mut data = vec![0; buf.remaining()];
+                buf.copy_to_slice(&mut data);
+                DatagramFrame(data.into())
+            }),
+            0x31 => Frame::Datagram({
+                buf.get_u8();
+                DatagramFrame::decode(buf)?
+            }),
+            0 => Frame::Padding(PaddingFrame::decode(buf)?),
+            v => return Err(QuicError::UnknownFrameType(v)),
+        })
+    }
+}
+
+pub struct FrameDecoder {
+    buf: Vec<u8>,
+    pos: usize,
+    complete: bool,
+}
+
+impl FrameDecoder {
+    pub fn new() -> Self {
+        Self {
+            buf: Vec::new(),
+            pos: 0,
+            complete: false,
+        }
+    }
+
+    pub fn feed(&mut self, data: &[u8]) {
+        if self.pos > 0 {
+            self.buf.drain(..self.pos);
+            self.pos = 0;
+        }
+        self.buf.extend_from_slice(data);
+    }
+
+    pub fn finish(&mut self) {
+        self.complete = true;
+    }
+
+    #[cfg_attr(feature = "cargo-clippy", allow(should_implement_trait))]
+    pub fn next(&mut self) -> QuicResult<Decoded> {
+        let pending = &self.buf[self.pos..];
+        if pending.is_empty() {
+            return Ok(Decoded::NeedMoreData);
+        }
+
+        if !self.complete {
+            // Frames without an explicit length run to the end of the packet, and trailing
+            // padding may continue in the next chunk, so wait until the input is complete.
+            let first = pending[0];
+            let open_stream = first >= 0x10 && first <= 0x17 && first & 0x02 == 0;
+            let open_padding = pending.iter().all(|b| *b == 0);
+            if open_stream || open_padding || first == 0x30 {
+                return Ok(Decoded::NeedMoreData);
+            }
+        }
+
+        let mut read = Cursor::new(pending);
+        match Frame::decode(&mut read) {
+            Ok(frame) => {
+                self.pos += read.position() as usize;
+                Ok(Decoded::Frame(frame))
+            }
+            Err(QuicError::UnexpectedEnd) if !self.complete => Ok(Decoded::NeedMoreData),
+            Err(e) => Err(e),
         }
     }
 }
 
+#[derive(Debug, PartialEq)]
+pub enum Decoded {
+    Frame(Frame),
+    NeedMoreData,
+}
+
+impl BufLen for StreamId {
+    fn buf_len(&self) -> usize {
+        VarLen::bounded(self.0).buf_len()
+    }
+}
+
+impl Codec for StreamId {
+    fn encode<T: BufMut>(&self, buf: &mut T) {
+        VarLen::bounded(self.0).encode(buf)
+    }
+
+    fn decode<T: Buf>(buf: &mut T) -> QuicResult<Self> {
+        Ok(StreamId(VarLen::decode(buf)?.value()))
+    }
+}
+
 #[derive(Debug, PartialEq)]
 pub struct StreamFrame {
-    pub id: u64,
+    pub id: StreamId,
     pub fin: bool,
     pub offset: u64,
     pub len: Option<u64>,
-    pub data: Vec<u8>,
+    pub data: Bytes,
 }
 
 impl BufLen for StreamFrame {
     fn buf_len(&self) -> usize {
-        1 + VarLen(self.id).buf_len() + if self.offset > 0 {
-            VarLen(self.offset).buf_len()
+        1 + self.id.buf_len() + if self.offset > 0 {
+            VarLen::bounded(self.offset).buf_len()
         } else {
             0
-        } + self.len.map(VarLen).buf_len() + self.data.len()
+        } + self.len.map(VarLen::bounded).buf_len() + self.data.len()
     }
 }
 
@@ -122,93 +382,242 @@ impl Codec for StreamFrame {
         let has_len = if self.len.is_some() { 0x02 } else { 0 };
         let is_fin = if self.fin { 0x01 } else { 0 };
         buf.put_u8(0x10 | has_offset | has_len | is_fin);
-        VarLen(self.id).encode(buf);
+        self.id.encode(buf);
         if self.offset > 0 {
-            VarLen(self.offset).encode(buf);
+            VarLen::bounded(self.offset).encode(buf);
         }
         if let Some(len) = self.len {
-            VarLen(len).encode(buf);
+            VarLen::bounded(len).encode(buf);
         }
         buf.put_slice(&self.data);
     }
 
-    fn decode<T: Buf>(buf: &mut T) -> Self {
-        let first = buf.get_u8();
-        let id = VarLen::decode(buf).0;
+    fn decode<T: Buf>(buf: &mut T) -> QuicResult<Self> {
+        let (mut frame, len) = StreamFrame::decode_header(buf)?;
+        let mut data = vec![0u8; len];
+        buf.copy_to_slice(&mut data);
+        frame.data = data.into();
+        Ok(frame)
+    }
+}
+
+impl StreamFrame {
+    pub fn split_off(&mut self, at: usize) -> StreamFrame {
+        let data = self.data.split_off(at);
+        let rest = StreamFrame {
+            id: self.id,
+            fin: self.fin,
+            offset: self.offset + at as u64,
+            len: Some(data.len() as u64),
+            data,
+        };
+        self.fin = false;
+        self.len = Some(at as u64);
+        rest
+    }
+
+    // Extends the frame with data that directly follows it
+    pub fn append(&mut self, data: &[u8]) {
+        let mut buf = BytesMut::with_capacity(self.data.len() + data.len());
+        buf.extend_from_slice(&self.data);
+        buf.extend_from_slice(data);
+        self.data = buf.freeze();
+        self.len = Some(self.data.len() as u64);
+    }
+
+    fn decode_header<T: Buf>(buf: &mut T) -> QuicResult<(Self, usize)> {
+        let first = buf.try_get_u8()?;
+        let id = StreamId::decode(buf)?;
         let offset = if first & 0x04 > 0 {
-            VarLen::decode(buf).0
+            VarLen::decode(buf)?.value()
         } else {
             0
         };
 
         let len = if first & 0x02 > 0 {
-            VarLen::decode(buf).0
+            VarLen::decode(buf)?.value()
         } else {
             buf.remaining() as u64
         };
-        let mut data = vec![0u8; len as usize];
-        buf.copy_to_slice(&mut data);
+        buf.check_remaining(len as usize)?;
 
-        StreamFrame {
+        let frame = StreamFrame {
             id,
             fin: first & 0x01 > 0,
             offset,
             len: if first & 0x02 > 0 { Some(len) } else { None },
-            data,
-        }
+            data: Bytes::new(),
+        };
+        Ok((frame, len as usize))
+    }
+}
+
+// Stream data is sliced out of the payload rather than copied into each frame
+pub fn decode_all(payload: &Bytes) -> QuicResult<Vec<Frame>> {
+    let mut read = Cursor::new(&payload[..]);
+    let mut frames = Vec::new();
+    while read.has_remaining() {
+        let frame = match payload[read.position() as usize] {
+            0x10..=0x17 => {
+                let (mut frame, len) = StreamFrame::decode_header(&mut read)?;
+                let start = read.position() as usize;
+                frame.data = payload.slice(start, start + len);
+                read.advance(len);
+                Frame::Stream(frame)
+            }
+            0x18 => {
+                read.get_u8();
+                let offset = VarLen::decode(&mut read)?.value();
+                let len = VarLen::decode(&mut read)?.value() as usize;
+                read.check_remaining(len)?;
+                let start = read.position() as usize;
+                read.advance(len);
+                Frame::Crypto(CryptoFrame {
+                    offset,
+                    data: payload.slice(start, start + len),
+                })
+            }
+            0x30 | 0x31 => {
+                let len = if read.get_u8() == 0x31 {
+                    VarLen::decode(&mut read)?.value() as usize
+                } else {
+                    read.remaining()
+                };
+                read.check_remaining(len)?;
+                let start = read.position() as usize;
+                read.advance(len);
+                Frame::Datagram(DatagramFrame(payload.slice(start, start + len)))
+            }
+            _ => Frame::decode(&mut read)?,
+        };
+        frames.push(frame);
     }
+    Ok(frames)
 }
 
 #[derive(Debug, PartialEq)]
 pub struct AckFrame {
-    pub largest: u32,
+    pub largest: u64,
     pub ack_delay: u64,
     pub blocks: Vec<Ack>,
+    pub ecn: Option<EcnCounts>,
+}
+
+impl AckFrame {
+    pub fn ranges(&self) -> Vec<(u64, u64)> {
+        let mut ranges = Vec::with_capacity(self.blocks.len() / 2 + 1);
+        let mut largest = Some(self.largest);
+        for block in &self.blocks {
+            let top = match largest {
+                Some(top) => top,
+                None => break,
+            };
+            match *block {
+                Ack::Ack(len) => {
+                    let smallest = top.saturating_sub(len);
+                    ranges.push((smallest, top));
+                    largest = smallest.checked_sub(1);
+                }
+                Ack::Gap(len) => {
+                    largest = top.checked_sub(len + 1);
+                }
+            }
+        }
+        ranges
+    }
 }
 
 impl BufLen for AckFrame {
     fn buf_len(&self) -> usize {
-        1 + VarLen(u64::from(self.largest)).buf_len() + VarLen(self.ack_delay).buf_len()
-            + VarLen((self.blocks.len() - 1) as u64).buf_len()
+        1 + VarLen::bounded(self.largest).buf_len() + VarLen::bounded(self.ack_delay).buf_len()
+            + VarLen::bounded((self.blocks.len() - 1) as u64).buf_len()
             + self.blocks
                 .iter()
-                .map(|v| VarLen(v.value()).buf_len())
-                .sum::<usize>()
+                .map(|v| VarLen::bounded(v.value()).buf_len())
+                .sum::<usize>() + self.ecn.buf_len()
     }
 }
 
 impl Codec for AckFrame {
     fn encode<T: BufMut>(&self, buf: &mut T) {
-        buf.put_u8(0x0d);
-        VarLen(u64::from(self.largest)).encode(buf);
-        VarLen(self.ack_delay).encode(buf);
-        VarLen((self.blocks.len() - 1) as u64).encode(buf);
+        buf.put_u8(if self.ecn.is_some() { 0x1a } else { 0x0d });
+        VarLen::bounded(self.largest).encode(buf);
+        VarLen::bounded(self.ack_delay).encode(buf);
+        VarLen::bounded((self.blocks.len() - 1) as u64).encode(buf);
         for ack in &self.blocks {
-            VarLen(ack.value()).encode(buf);
+            VarLen::bounded(ack.value()).encode(buf);
+        }
+        if let Some(ref ecn) = self.ecn {
+            ecn.encode(buf);
         }
     }
 
-    fn decode<T: Buf>(buf: &mut T) -> Self {
-        let _ = buf.get_u8();
-        let largest = VarLen::decode(buf).0 as u32;
-        let ack_delay = VarLen::decode(buf).0;
-        let count = VarLen::decode(buf).0;
-        debug_assert_eq!(count % 2, 0);
+    fn decode<T: Buf>(buf: &mut T) -> QuicResult<Self> {
+        let ftype = buf.try_get_u8()?;
+        let largest = VarLen::decode(buf)?.value();
+        let ack_delay = VarLen::decode(buf)?.value();
+        let count = VarLen::decode(buf)?.value();
+        if count % 2 != 0 {
+            return Err(QuicError::InvalidEncoding(format!(
+                "odd ACK block count {}",
+                count
+            )));
+        }
+        // Every block takes at least a byte, so don't trust counts the buffer can't back up
+        buf.check_remaining(count as usize + 1)?;
 
         let mut blocks = vec![];
         for i in 0..count + 1 {
             blocks.push(if i % 2 == 0 {
-                Ack::Ack(VarLen::decode(buf).0)
+                Ack::Ack(VarLen::decode(buf)?.value())
             } else {
-                Ack::Gap(VarLen::decode(buf).0)
+                Ack::Gap(VarLen::decode(buf)?.value())
             });
         }
 
-        AckFrame {
+        let ecn = if ftype == 0x1a {
+            Some(EcnCounts::decode(buf)?)
+        } else {
+            None
+        };
+
+        Ok(AckFrame {
             largest,
             ack_delay,
             blocks,
-        }
+            ecn,
+        })
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct EcnCounts {
+    pub ect0: u64,
+    pub ect1: u64,
+    pub ce: u64,
+}
+
+impl BufLen for EcnCounts {
+    fn buf_len(&self) -> usize {
+        VarLen::bounded(self.ect0).buf_len()
+            + VarLen::bounded(self.ect1).buf_len()
+            + VarLen::bounded(self.ce).buf_len()
+    }
+}
+
+impl Codec for EcnCounts {
+    fn encode<T: BufMut>(&self, buf: &mut T) {
+        VarLen::bounded(self.ect0).encode(buf);
+        VarLen::bounded(self.ect1).encode(buf);
+        VarLen::bounded(self.ce).encode(buf);
+    }
+
+    fn decode<T: Buf>(buf: &mut T) -> QuicResult<Self> {
+        Ok(EcnCounts {
+            ect0: VarLen::decode(buf)?.value(),
+            ect1: VarLen::decode(buf)?.value(),
+            ce: VarLen::decode(buf)?.value(),
+        })
     }
 }
 
@@ -235,26 +644,223 @@ pub struct CloseFrame {
 
 impl BufLen for CloseFrame {
     fn buf_len(&self) -> usize {
-        2 + VarLen(self.reason.len() as u64).buf_len() + self.reason.len()
+        2 + VarLen::bounded(self.reason.len() as u64).buf_len() + self.reason.len()
     }
 }
 
 impl Codec for CloseFrame {
     fn encode<T: BufMut>(&self, buf: &mut T) {
         buf.put_u16_be(self.code);
-        VarLen(self.reason.len() as u64).encode(buf);
+        VarLen::bounded(self.reason.len() as u64).encode(buf);
         buf.put_slice(self.reason.as_bytes());
     }
 
-    fn decode<T: Buf>(buf: &mut T) -> Self {
-        let code = buf.get_u16_be();
-        let len = VarLen::decode(buf).0 as usize;
-        let reason = {
-            let bytes = buf.bytes();
-            str::from_utf8(&bytes[..len]).unwrap()
-        }.to_string();
-        buf.advance(len);
-        CloseFrame { code, reason }
+    fn decode<T: Buf>(buf: &mut T) -> QuicResult<Self> {
+        let code = buf.try_get_u16_be()?;
+        let len = VarLen::decode(buf)?.value() as usize;
+        buf.check_remaining(len)?;
+        let mut bytes = vec![0; len];
+        buf.copy_to_slice(&mut bytes);
+        let reason = String::from_utf8(bytes)
+            .map_err(|_| QuicError::InvalidEncoding("close reason is not valid UTF-8".into()))?;
+        Ok(CloseFrame { code, reason })
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub struct RstStreamFrame {
+    pub id: StreamId,
+    pub error_code: u16,
+    pub final_offset: u64,
+}
+
+impl BufLen for RstStreamFrame {
+    fn buf_len(&self) -> usize {
+        self.id.buf_len() + 2 + VarLen::bounded(self.final_offset).buf_len()
+    }
+}
+
+impl Codec for RstStreamFrame {
+    fn encode<T: BufMut>(&self, buf: &mut T) {
+        self.id.encode(buf);
+        buf.put_u16_be(self.error_code);
+        VarLen::bounded(self.final_offset).encode(buf);
+    }
+
+    fn decode<T: Buf>(buf: &mut T) -> QuicResult<Self> {
+        Ok(RstStreamFrame {
+            id: StreamId::decode(buf)?,
+            error_code: buf.try_get_u16_be()?,
+            final_offset: VarLen::decode(buf)?.value(),
+        })
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub struct NewConnectionIdFrame {
+    pub sequence: u64,
+    pub id: ConnectionId,
+    pub reset_token: [u8; 16],
+}
+
+impl BufLen for NewConnectionIdFrame {
+    fn buf_len(&self) -> usize {
+        VarLen::bounded(self.sequence).buf_len() + 1 + self.id.len as usize + 16
+    }
+}
+
+impl Codec for NewConnectionIdFrame {
+    fn encode<T: BufMut>(&self, buf: &mut T) {
+        VarLen::bounded(self.sequence).encode(buf);
+        buf.put_u8(self.id.len);
+        buf.put_slice(&self.id);
+        buf.put_slice(&self.reset_token);
+    }
+
+    fn decode<T: Buf>(buf: &mut T) -> QuicResult<Self> {
+        let sequence = VarLen::decode(buf)?.value();
+        let len = buf.try_get_u8()? as usize;
+        if len < 4 || len > 18 {
+            return Err(QuicError::InvalidEncoding(format!(
+                "invalid connection ID length {}",
+                len
+            )));
+        }
+        let mut bytes = [0; 18];
+        buf.try_copy_to_slice(&mut bytes[..len])?;
+        let mut reset_token = [0; 16];
+        buf.try_copy_to_slice(&mut reset_token)?;
+        Ok(NewConnectionIdFrame {
+            sequence,
+            id: ConnectionId::new(&bytes[..len]),
+            reset_token,
+        })
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub struct RetireConnectionIdFrame(pub u64);
+
+impl BufLen for RetireConnectionIdFrame {
+    fn buf_len(&self) -> usize {
+        VarLen::bounded(self.0).buf_len()
+    }
+}
+
+impl Codec for RetireConnectionIdFrame {
+    fn encode<T: BufMut>(&self, buf: &mut T) {
+        VarLen::bounded(self.0).encode(buf)
+    }
+
+    fn decode<T: Buf>(buf: &mut T) -> QuicResult<Self> {
+        Ok(RetireConnectionIdFrame(VarLen::decode(buf)?.value()))
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub struct NewTokenFrame(pub Vec<u8>);
+
+impl BufLen for NewTokenFrame {
+    fn buf_len(&self) -> usize {
+        VarLen::bounded(self.0.len() as u64).buf_len() + self.0.len()
+    }
+}
+
+impl Codec for NewTokenFrame {
+    fn encode<T: BufMut>(&self, buf: &mut T) {
+        VarLen::bounded(self.0.len() as u64).encode(buf);
+        buf.put_slice(&self.0);
+    }
+
+    fn decode<T: Buf>(buf: &mut T) -> QuicResult<Self> {
+        let len = VarLen::decode(buf)?.value() as usize;
+        buf.check_remaining(len)?;
+        let mut token = vec![0; len];
+        buf.copy_to_slice(&mut token);
+        Ok(NewTokenFrame(token))
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub struct CryptoFrame {
+    pub offset: u64,
+    pub data: Bytes,
+}
+
+impl BufLen for CryptoFrame {
+    fn buf_len(&self) -> usize {
+        VarLen::bounded(self.offset).buf_len() + VarLen::bounded(self.data.len() as u64).buf_len()
+            + self.data.len()
+    }
+}
+
+impl Codec for CryptoFrame {
+    fn encode<T: BufMut>(&self, buf: &mut T) {
+        VarLen::bounded(self.offset).encode(buf);
+        VarLen::bounded(self.data.len() as u64).encode(buf);
+        buf.put_slice(&self.data);
+    }
+
+    fn decode<T: Buf>(buf: &mut T) -> QuicResult<Self> {
+        let offset = VarLen::decode(buf)?.value();
+        let len = VarLen::decode(buf)?.value() as usize;
+        buf.check_remaining(len)?;
+        let mut data = vec![0; len];
+        buf.copy_to_slice(&mut data);
+        Ok(CryptoFrame {
+            offset,
+            data: data.into(),
+        })
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub struct DatagramFrame(pub Bytes);
+
+impl BufLen for DatagramFrame {
+    fn buf_len(&self) -> usize {
+        VarLen::bounded(self.0.len() as u64).buf_len() + self.0.len()
+    }
+}
+
+impl Codec for DatagramFrame {
+    fn encode<T: BufMut>(&self, buf: &mut T) {
+        VarLen::bounded(self.0.len() as u64).encode(buf);
+        buf.put_slice(&self.0);
+    }
+
+    fn decode<T: Buf>(buf: &mut T) -> QuicResult<Self> {
+        let len = VarLen::decode(buf)?.value() as usize;
+        buf.check_remaining(len)?;
+        let mut data = vec![0; len];
+        buf.copy_to_slice(&mut data);
+        Ok(DatagramFrame(data.into()))
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub struct StopSendingFrame {
+    pub id: StreamId,
+    pub error_code: u16,
+}
+
+impl BufLen for StopSendingFrame {
+    fn buf_len(&self) -> usize {
+        self.id.buf_len() + 2
+    }
+}
+
+impl Codec for StopSendingFrame {
+    fn encode<T: BufMut>(&self, buf: &mut T) {
+        self.id.encode(buf);
+        buf.put_u16_be(self.error_code);
+    }
+
+    fn decode<T: Buf>(buf: &mut T) -> QuicResult<Self> {
+        Ok(StopSendingFrame {
+            id: StreamId::decode(buf)?,
+            error_code: buf.try_get_u16_be()?,
+        })
     }
 }
 
@@ -272,35 +878,147 @@ impl Codec for PathFrame {
         buf.put_slice(&self.0);
     }
 
-    fn decode<T: Buf>(buf: &mut T) -> Self {
+    fn decode<T: Buf>(buf: &mut T) -> QuicResult<Self> {
         let mut bytes = [0; 8];
-        buf.copy_to_slice(&mut bytes);
-        PathFrame(bytes)
+        buf.try_copy_to_slice(&mut bytes)?;
+        Ok(PathFrame(bytes))
     }
 }
 
 #[derive(Debug, PartialEq)]
-pub struct StreamIdBlockedFrame(pub u64);
+pub struct MaxDataFrame(pub u64);
+
+impl BufLen for MaxDataFrame {
+    fn buf_len(&self) -> usize {
+        VarLen::bounded(self.0).buf_len()
+    }
+}
+
+impl Codec for MaxDataFrame {
+    fn encode<T: BufMut>(&self, buf: &mut T) {
+        VarLen::bounded(self.0).encode(buf)
+    }
+
+    fn decode<T: Buf>(buf: &mut T) -> QuicResult<Self> {
+        Ok(MaxDataFrame(VarLen::decode(buf)?.value()))
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub struct MaxStreamDataFrame {
+    pub id: StreamId,
+    pub max: u64,
+}
+
+impl BufLen for MaxStreamDataFrame {
+    fn buf_len(&self) -> usize {
+        self.id.buf_len() + VarLen::bounded(self.max).buf_len()
+    }
+}
+
+impl Codec for MaxStreamDataFrame {
+    fn encode<T: BufMut>(&self, buf: &mut T) {
+        self.id.encode(buf);
+        VarLen::bounded(self.max).encode(buf);
+    }
+
+    fn decode<T: Buf>(buf: &mut T) -> QuicResult<Self> {
+        Ok(MaxStreamDataFrame {
+            id: StreamId::decode(buf)?,
+            max: VarLen::decode(buf)?.value(),
+        })
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub struct MaxStreamIdFrame(pub StreamId);
+
+impl BufLen for MaxStreamIdFrame {
+    fn buf_len(&self) -> usize {
+        self.0.buf_len()
+    }
+}
+
+impl Codec for MaxStreamIdFrame {
+    fn encode<T: BufMut>(&self, buf: &mut T) {
+        self.0.encode(buf)
+    }
+
+    fn decode<T: Buf>(buf: &mut T) -> QuicResult<Self> {
+        Ok(MaxStreamIdFrame(StreamId::decode(buf)?))
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub struct BlockedFrame(pub u64);
+
+impl BufLen for BlockedFrame {
+    fn buf_len(&self) -> usize {
+        VarLen::bounded(self.0).buf_len()
+    }
+}
+
+impl Codec for BlockedFrame {
+    fn encode<T: BufMut>(&self, buf: &mut T) {
+        VarLen::bounded(self.0).encode(buf)
+    }
+
+    fn decode<T: Buf>(buf: &mut T) -> QuicResult<Self> {
+        Ok(BlockedFrame(VarLen::decode(buf)?.value()))
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub struct StreamBlockedFrame {
+    pub id: StreamId,
+    pub offset: u64,
+}
+
+impl BufLen for StreamBlockedFrame {
+    fn buf_len(&self) -> usize {
+        self.id.buf_len() + VarLen::bounded(self.offset).buf_len()
+    }
+}
+
+impl Codec for StreamBlockedFrame {
+    fn encode<T: BufMut>(&self, buf: &mut T) {
+        self.id.encode(buf);
+        VarLen::bounded(self.offset).encode(buf);
+    }
+
+    fn decode<T: Buf>(buf: &mut T) -> QuicResult<Self> {
+        Ok(StreamBlockedFrame {
+            id: StreamId::decode(buf)?,
+            offset: VarLen::decode(buf)?.value(),
+        })
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub struct StreamIdBlockedFrame(pub StreamId);
 
 impl BufLen for StreamIdBlockedFrame {
     fn buf_len(&self) -> usize {
-        VarLen(self.0).buf_len()
+        self.0.buf_len()
     }
 }
 
 impl Codec for StreamIdBlockedFrame {
     fn encode<T: BufMut>(&self, buf: &mut T) {
-        VarLen(self.0).encode(buf)
+        self.0.encode(buf)
     }
 
-    fn decode<T: Buf>(buf: &mut T) -> Self {
-        StreamIdBlockedFrame(VarLen::decode(buf).0)
+    fn decode<T: Buf>(buf: &mut T) -> QuicResult<Self> {
+        Ok(StreamIdBlockedFrame(StreamId::decode(buf)?))
     }
 }
 
 #[derive(Debug, PartialEq)]
 pub struct PaddingFrame(pub usize);
 
+// Padding is written in chunks from here rather than from a freshly allocated buffer
+const ZEROS: [u8; 256] = [0; 256];
+
 impl BufLen for PaddingFrame {
     fn buf_len(&self) -> usize {
         self.0
@@ -309,29 +1027,269 @@ impl BufLen for PaddingFrame {
 
 impl Codec for PaddingFrame {
     fn encode<T: BufMut>(&self, buf: &mut T) {
-        let padding = vec![0; self.0];
-        buf.put_slice(&padding);
+        let mut remaining = self.0;
+        while remaining > 0 {
+            let len = cmp::min(remaining, ZEROS.len());
+            buf.put_slice(&ZEROS[..len]);
+            remaining -= len;
+        }
     }
 
-    fn decode<T: Buf>(buf: &mut T) -> Self {
+    fn decode<T: Buf>(buf: &mut T) -> QuicResult<Self> {
         let size = buf.bytes().iter().take_while(|b| **b == 0).count();
         buf.advance(size);
-        PaddingFrame(size)
+        Ok(PaddingFrame(size))
     }
 }
 
 #[cfg(test)]
 mod tests {
-    use bytes::Buf;
+    use super::{decode_all, Ack, AckFrame, BlockedFrame, CloseFrame, CryptoFrame, DatagramFrame,
+                EcnCounts, Frame, MaxDataFrame, MaxStreamDataFrame, MaxStreamIdFrame,
+                NewConnectionIdFrame, NewTokenFrame, PaddingFrame, PathFrame,
+                RetireConnectionIdFrame, RstStreamFrame, StopSendingFrame, StreamBlockedFrame,
+                StreamFrame, StreamIdBlockedFrame};
+    use bytes::{Buf, Bytes};
     use codec::{BufLen, Codec};
+    use crypto::EncryptionLevel;
+    use proptest::prelude::*;
     use std::io::Cursor;
+    use types::{ConnectionId, StreamId};
+    use QuicError;
+
+    fn var() -> impl Strategy<Value = u64> {
+        0..1u64 << 62
+    }
+
+    fn stream_id() -> impl Strategy<Value = StreamId> {
+        var().prop_map(StreamId)
+    }
+
+    fn data() -> impl Strategy<Value = Bytes> {
+        prop::collection::vec(any::<u8>(), 0..64).prop_map(Bytes::from)
+    }
+
+    // Kept in groups, since prop_oneof! only takes so many alternatives
+    fn frame() -> impl Strategy<Value = Frame> {
+        prop_oneof![stream_frames(), control_frames(), other_frames()]
+    }
+
+    fn stream_frames() -> BoxedStrategy<Frame> {
+        prop_oneof![
+            (stream_id(), any::<bool>(), var(), any::<bool>(), data()).prop_map(
+                |(id, fin, offset, explicit_len, data)| {
+                    // Without a length the frame runs to the end of the packet
+                    let len = if explicit_len {
+                        Some(data.len() as u64)
+                    } else {
+                        None
+                    };
+                    Frame::Stream(StreamFrame {
+                        id,
+                        fin,
+                        offset,
+                        len,
+                        data,
+                    })
+                }
+            ),
+            (stream_id(), any::<u16>(), var()).prop_map(|(id, error_code, final_offset)| {
+                Frame::RstStream(RstStreamFrame {
+                    id,
+                    error_code,
+                    final_offset,
+                })
+            }),
+            (stream_id(), any::<u16>()).prop_map(|(id, error_code)| {
+                Frame::StopSending(StopSendingFrame { id, error_code })
+            }),
+            (stream_id(), var()).prop_map(|(id, max)| {
+                Frame::MaxStreamData(MaxStreamDataFrame { id, max })
+            }),
+            stream_id().prop_map(|id| Frame::MaxStreamId(MaxStreamIdFrame(id))),
+            (stream_id(), var()).prop_map(|(id, offset)| {
+                Frame::StreamBlocked(StreamBlockedFrame { id, offset })
+            }),
+            stream_id().prop_map(|id| Frame::StreamIdBlocked(StreamIdBlockedFrame(id))),
+        ].boxed()
+    }
+
+    fn control_frames() -> BoxedStrategy<Frame> {
+        prop_oneof![
+            (
+                var(),
+                var(),
+                prop::collection::vec(var(), 0..8),
+                prop::option::of((var(), var(), var())),
+            ).prop_map(|(largest, ack_delay, mut values, ecn)| {
+                // Blocks alternate between ranges and gaps, and start and end with a range
+                if values.len() % 2 == 0 {
+                    values.push(0);
+                }
+                let blocks = values
+                    .into_iter()
+                    .enumerate()
+                    .map(|(i, v)| if i % 2 == 0 { Ack::Ack(v) } else { Ack::Gap(v) })
+                    .collect();
+                let ecn = ecn.map(|(ect0, ect1, ce)| EcnCounts { ect0, ect1, ce });
+                Frame::Ack(AckFrame {
+                    largest,
+                    ack_delay,
+                    blocks,
+                    ecn,
+                })
+            }),
+            (any::<bool>(), any::<u16>(), ".{0,32}").prop_map(|(application, code, reason)| {
+                let frame = CloseFrame { code, reason };
+                if application {
+                    Frame::ApplicationClose(frame)
+                } else {
+                    Frame::ConnectionClose(frame)
+                }
+            }),
+            (var(), data()).prop_map(|(offset, data)| Frame::Crypto(CryptoFrame { offset, data })),
+            var().prop_map(|max| Frame::MaxData(MaxDataFrame(max))),
+            var().prop_map(|offset| Frame::Blocked(BlockedFrame(offset))),
+            Just(Frame::Ping),
+            (1usize..1500).prop_map(|len| Frame::Padding(PaddingFrame(len))),
+        ].boxed()
+    }
+
+    fn other_frames() -> BoxedStrategy<Frame> {
+        prop_oneof![
+            (
+                var(),
+                prop::collection::vec(any::<u8>(), 4..19),
+                any::<[u8; 16]>(),
+            ).prop_map(|(sequence, id, reset_token)| {
+                Frame::NewConnectionId(NewConnectionIdFrame {
+                    sequence,
+                    id: ConnectionId::new(&id),
+                    reset_token,
+                })
+            }),
+            var().prop_map(|sequence| {
+                Frame::RetireConnectionId(RetireConnectionIdFrame(sequence))
+            }),
+            prop::collection::vec(any::<u8>(), 0..64)
+                .prop_map(|token| Frame::NewToken(NewTokenFrame(token))),
+            (any::<bool>(), any::<[u8; 8]>()).prop_map(|(challenge, token)| if challenge {
+                Frame::PathChallenge(PathFrame(token))
+            } else {
+                Frame::PathResponse(PathFrame(token))
+            }),
+            data().prop_map(|data| Frame::Datagram(DatagramFrame(data))),
+        ].boxed()
+    }
+
+    proptest! {
+        #[test]
+        fn test_frame_codec(frame in frame()) {
+            let mut buf = Vec::new();
+            frame.encode(&mut buf);
+            prop_assert_eq!(frame.buf_len(), buf.len());
+
+            let mut read = Cursor::new(&buf);
+            prop_assert_eq!(&Frame::decode(&mut read).unwrap(), &frame);
+            prop_assert_eq!(read.position() as usize, buf.len());
+            prop_assert_eq!(decode_all(&Bytes::from(buf)).unwrap(), vec![frame]);
+        }
+    }
+
+    #[test]
+    fn test_0rtt_allowed() {
+        let stream = |id| {
+            super::Frame::Stream(super::StreamFrame {
+                id,
+                fin: false,
+                offset: 0,
+                len: None,
+                data: Bytes::new(),
+            })
+        };
+        assert!(stream(StreamId(0)).is_0rtt_allowed());
+        assert!(stream(StreamId(4)).is_0rtt_allowed());
+        let crypto = super::Frame::Crypto(super::CryptoFrame {
+            offset: 0,
+            data: Bytes::from_static(b"hello"),
+        });
+        assert!(!crypto.is_0rtt_allowed());
+        assert!(super::Frame::Ping.is_0rtt_allowed());
+        assert!(super::Frame::Ping.is_ack_eliciting());
+        assert!(!super::Frame::Padding(super::PaddingFrame(1)).is_ack_eliciting());
+        assert!(!super::Frame::NewToken(super::NewTokenFrame(vec![1])).is_0rtt_allowed());
+    }
+
+    #[test]
+    fn test_allowed_levels() {
+        let stream = Frame::Stream(StreamFrame {
+            id: StreamId(0),
+            fin: false,
+            offset: 0,
+            len: None,
+            data: Bytes::new(),
+        });
+        assert!(!stream.is_allowed_at(EncryptionLevel::Initial));
+        assert!(!stream.is_allowed_at(EncryptionLevel::Handshake));
+        assert!(stream.is_allowed_at(EncryptionLevel::ZeroRtt));
+        assert!(stream.is_allowed_at(EncryptionLevel::OneRtt));
+        let ack = Frame::Ack(AckFrame {
+            largest: 0,
+            ack_delay: 0,
+            blocks: vec![Ack::Ack(0)],
+            ecn: None,
+        });
+        assert!(ack.is_allowed_at(EncryptionLevel::Handshake));
+        assert!(!ack.is_allowed_at(EncryptionLevel::ZeroRtt));
+        let challenge = Frame::PathChallenge(PathFrame([0; 8]));
+        assert!(!challenge.is_allowed_at(EncryptionLevel::Initial));
+    }
+
+    #[test]
+    fn test_decode_all_slices_stream_data() {
+        let frames = vec![
+            super::Frame::Ping,
+            super::Frame::Datagram(super::DatagramFrame(Bytes::from_static(b"unreliable"))),
+            super::Frame::Crypto(super::CryptoFrame {
+                offset: 1200,
+                data: Bytes::from_static(b"handshake"),
+            }),
+            super::Frame::Stream(super::StreamFrame {
+                id: StreamId(4),
+                fin: false,
+                offset: 10,
+                len: Some(5),
+                data: Bytes::from_static(b"hello"),
+            }),
+            super::Frame::Stream(super::StreamFrame {
+                id: StreamId(8),
+                fin: true,
+                offset: 0,
+                len: None,
+                data: Bytes::from(vec![7; 64]),
+            }),
+        ];
+        let mut buf = Vec::new();
+        for frame in &frames {
+            frame.encode(&mut buf);
+        }
+        let payload = Bytes::from(buf);
+        let decoded = super::decode_all(&payload).unwrap();
+        assert_eq!(decoded, frames);
+        match decoded[4] {
+            super::Frame::Stream(ref f) => {
+                assert_eq!(f.data.as_ptr(), payload[payload.len() - 64..].as_ptr());
+            }
+            _ => panic!("expected stream frame"),
+        }
+    }
 
     #[test]
     fn test_padding_roundtrip() {
         let bytes = b"\x00\x00\x00\x00\x01";
         let frame = {
             let mut read = Cursor::new(&bytes);
-            let frame = super::Frame::decode(&mut read);
+            let frame = super::Frame::decode(&mut read).unwrap();
             assert_eq!(read.bytes(), b"\x01");
             frame
         };
@@ -340,6 +1298,10 @@ mod tests {
         let mut buf = vec![0u8; 16];
         frame.encode(&mut buf);
         assert_eq!(&bytes[..4], &buf[..4]);
+
+        let mut buf = Vec::new();
+        super::PaddingFrame(1000).encode(&mut buf);
+        assert_eq!(buf, vec![0; 1000]);
     }
 
     #[test]
@@ -348,6 +1310,7 @@ mod tests {
             largest: 485971334,
             ack_delay: 0,
             blocks: vec![super::Ack::Ack(0)],
+            ecn: None,
         });
         let bytes = b"\x0d\x9c\xf7\x55\x86\x00\x00\x00";
         assert_eq!(obj.buf_len(), bytes.len());
@@ -357,7 +1320,212 @@ mod tests {
         assert_eq!(&buf, bytes);
 
         let mut read = Cursor::new(bytes);
-        let decoded = super::Frame::decode(&mut read);
+        let decoded = super::Frame::decode(&mut read).unwrap();
+        assert_eq!(decoded, obj);
+    }
+
+    #[test]
+    fn test_rst_stream_round_trip() {
+        let obj = super::Frame::RstStream(super::RstStreamFrame {
+            id: StreamId(4),
+            error_code: 0x0102,
+            final_offset: 1024,
+        });
+        let bytes = b"\x01\x04\x01\x02\x44\x00";
+        assert_eq!(obj.buf_len(), bytes.len());
+
+        let mut buf = Vec::with_capacity(64);
+        obj.encode(&mut buf);
+        assert_eq!(&buf, bytes);
+
+        let mut read = Cursor::new(bytes);
+        let decoded = super::Frame::decode(&mut read).unwrap();
+        assert_eq!(decoded, obj);
+    }
+
+    #[test]
+    fn test_crypto_round_trip() {
+        let obj = super::Frame::Crypto(super::CryptoFrame {
+            offset: 64,
+            data: Bytes::from_static(b"abc"),
+        });
+        let bytes = b"\x18\x40\x40\x03abc";
+        assert_eq!(obj.buf_len(), bytes.len());
+
+        let mut buf = Vec::with_capacity(64);
+        obj.encode(&mut buf);
+        assert_eq!(&buf, bytes);
+
+        let mut read = Cursor::new(bytes);
+        let decoded = super::Frame::decode(&mut read).unwrap();
+        assert_eq!(decoded, obj);
+    }
+
+    #[test]
+    fn test_max_stream_data_round_trip() {
+        let obj = super::Frame::MaxStreamData(super::MaxStreamDataFrame {
+            id: StreamId(8),
+            max: 65536,
+        });
+        let bytes = b"\x05\x08\x80\x01\x00\x00";
+        assert_eq!(obj.buf_len(), bytes.len());
+
+        let mut buf = Vec::with_capacity(64);
+        obj.encode(&mut buf);
+        assert_eq!(&buf, bytes);
+
+        let mut read = Cursor::new(bytes);
+        let decoded = super::Frame::decode(&mut read).unwrap();
+        assert_eq!(decoded, obj);
+    }
+
+    #[test]
+    fn test_new_connection_id_round_trip() {
+        let obj = super::Frame::NewConnectionId(super::NewConnectionIdFrame {
+            sequence: 1,
+            id: ConnectionId::new(&[1, 2, 3, 4, 5, 6, 7, 8]),
+            reset_token: [9; 16],
+        });
+        let mut buf = Vec::with_capacity(64);
+        obj.encode(&mut buf);
+        assert_eq!(obj.buf_len(), buf.len());
+        assert_eq!(&buf[..11], b"\x0b\x01\x08\x01\x02\x03\x04\x05\x06\x07\x08");
+
+        let mut read = Cursor::new(&buf);
+        let decoded = super::Frame::decode(&mut read).unwrap();
+        assert_eq!(decoded, obj);
+    }
+
+    #[test]
+    fn test_retire_connection_id_round_trip() {
+        let obj = super::Frame::RetireConnectionId(super::RetireConnectionIdFrame(300));
+        let bytes = b"\x1b\x41\x2c";
+        assert_eq!(obj.buf_len(), bytes.len());
+
+        let mut buf = Vec::with_capacity(64);
+        obj.encode(&mut buf);
+        assert_eq!(&buf, bytes);
+
+        let mut read = Cursor::new(bytes);
+        let decoded = super::Frame::decode(&mut read).unwrap();
+        assert_eq!(decoded, obj);
+    }
+
+    #[test]
+    fn test_decode_errors() {
+        let mut read = Cursor::new(b"\x05\x08");
+        match super::Frame::decode(&mut read) {
+            Err(QuicError::UnexpectedEnd) => {}
+            v => panic!("unexpected result {:?}", v),
+        }
+
+        let mut read = Cursor::new(b"\x1f");
+        match super::Frame::decode(&mut read) {
+            Err(QuicError::UnexpectedEnd) => {}
+            v => panic!("unexpected result {:?}", v),
+        }
+
+        let mut read = Cursor::new(b"\x02\x00\x00\x01\xff");
+        match super::Frame::decode(&mut read) {
+            Err(QuicError::InvalidEncoding(_)) => {}
+            v => panic!("unexpected result {:?}", v),
+        }
+
+        let mut read = Cursor::new(b"\x0d\x00\x00\x80\x10\x00\x00\x00");
+        match super::Frame::decode(&mut read) {
+            Err(QuicError::UnexpectedEnd) => {}
+            v => panic!("unexpected result {:?}", v),
+        }
+    }
+
+    #[test]
+    fn test_truncated_frames() {
+        let frames = vec![
+            super::Frame::Ack(super::AckFrame {
+                largest: 1000,
+                ack_delay: 12,
+                blocks: vec![super::Ack::Ack(3), super::Ack::Gap(1), super::Ack::Ack(2)],
+                ecn: None,
+            }),
+            super::Frame::Stream(super::StreamFrame {
+                id: StreamId(4),
+                fin: true,
+                offset: 1 << 20,
+                len: Some(5),
+                data: Bytes::from_static(b"hello"),
+            }),
+            super::Frame::ConnectionClose(super::CloseFrame {
+                code: 7,
+                reason: "bad".into(),
+            }),
+            super::Frame::Crypto(super::CryptoFrame {
+                offset: 100,
+                data: Bytes::from_static(b"hello"),
+            }),
+            super::Frame::NewConnectionId(super::NewConnectionIdFrame {
+                sequence: 1,
+                id: ConnectionId::new(&[1, 2, 3, 4, 5, 6, 7, 8]),
+                reset_token: [9; 16],
+            }),
+        ];
+        for frame in frames {
+            let mut buf = Vec::new();
+            frame.encode(&mut buf);
+            for len in 0..buf.len() {
+                let mut read = Cursor::new(&buf[..len]);
+                assert!(super::Frame::decode(&mut read).is_err());
+            }
+        }
+    }
+
+    #[test]
+    fn test_ack_ecn_round_trip() {
+        let obj = super::Frame::Ack(super::AckFrame {
+            largest: 1 << 40,
+            ack_delay: 12,
+            blocks: vec![super::Ack::Ack(3)],
+            ecn: Some(super::EcnCounts {
+                ect0: 5,
+                ect1: 0,
+                ce: 1,
+            }),
+        });
+        let bytes = b"\x1a\xc0\x00\x01\x00\x00\x00\x00\x00\x0c\x00\x03\x05\x00\x01";
+        assert_eq!(obj.buf_len(), bytes.len());
+
+        let mut buf = Vec::with_capacity(64);
+        obj.encode(&mut buf);
+        assert_eq!(&buf, bytes);
+
+        let mut read = Cursor::new(bytes);
+        let decoded = super::Frame::decode(&mut read).unwrap();
         assert_eq!(decoded, obj);
     }
+
+    #[test]
+    fn test_frame_decoder_partial() {
+        let mut decoder = super::FrameDecoder::new();
+        decoder.feed(b"\x07\x05\x08");
+        assert_eq!(
+            decoder.next().unwrap(),
+            super::Decoded::Frame(super::Frame::Ping)
+        );
+        assert_eq!(decoder.next().unwrap(), super::Decoded::NeedMoreData);
+
+        decoder.feed(b"\x80\x01\x00\x00\x00\x00");
+        assert_eq!(
+            decoder.next().unwrap(),
+            super::Decoded::Frame(super::Frame::MaxStreamData(super::MaxStreamDataFrame {
+                id: StreamId(8),
+                max: 65536,
+            }))
+        );
+        assert_eq!(decoder.next().unwrap(), super::Decoded::NeedMoreData);
+
+        decoder.finish();
+        assert_eq!(
+            decoder.next().unwrap(),
+            super::Decoded::Frame(super::Frame::Padding(super::PaddingFrame(2)))
+        );
+    }
 }
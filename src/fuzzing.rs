@@ -0,0 +1,6 @@
+// The wire format as seen by the fuzz targets in fuzz/; none of this is a stable API
+pub use codec::{Codec, VarLen};
+pub use frame::*;
+pub use packet::{Header, LongType, Packet, ShortType};
+pub use parameters::{ClientTransportParameters, ServerTransportParameters, TransportParameters};
+pub use types::ConnectionId;
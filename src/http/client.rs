@@ -2,8 +2,9 @@ use futures::future::Future;
 
 use QuicError;
 use streams::Streams;
+use types::StreamId;
 
 pub fn start(streams: Streams) -> impl Future<Item = Streams, Error = QuicError> {
     println!("REQUEST STREAM 2");
-    streams.request_stream(2)
+    streams.request_stream(StreamId(2))
 }
@@ -1,6 +1,7 @@
 use bytes::{Buf, BufMut};
 
-use codec::{BufLen, Codec, VarLen};
+use codec::{BufExt, BufLen, Codec, VarLen};
+use {QuicError, QuicResult};
 
 // On the wire:
 // len: VarLen
@@ -23,11 +24,11 @@ impl Codec for HttpFrame {
         }
     }
 
-    fn decode<T: Buf>(buf: &mut T) -> Self {
-        let len = VarLen::decode(buf).0 as usize;
-        match buf.get_u8() {
-            0x4 => HttpFrame::Settings(SettingsFrame::decode(&mut buf.take(1 + len))),
-            v => panic!("unsupported HTTP frame type {}", v),
+    fn decode<T: Buf>(buf: &mut T) -> QuicResult<Self> {
+        let len = VarLen::decode(buf)?.value() as usize;
+        match buf.try_get_u8()? {
+            0x4 => Ok(HttpFrame::Settings(SettingsFrame::decode(&mut buf.take(1 + len))?)),
+            v => Err(QuicError::General(format!("unsupported HTTP frame type {}", v))),
         }
     }
 }
@@ -39,43 +40,50 @@ impl Codec for SettingsFrame {
     fn encode<T: BufMut>(&self, buf: &mut T) {
         buf.put_u8(0);
         buf.put_u16_be(0x1);
-        let encoded = VarLen(self.0.header_table_size as u64);
+        let encoded = VarLen::from(self.0.header_table_size);
         let encoded_len = encoded.buf_len();
         debug_assert!(encoded_len < 64);
-        VarLen(encoded_len as u64).encode(buf);
+        VarLen::from(encoded_len as u32).encode(buf);
         encoded.encode(buf);
 
         buf.put_u16_be(0x6);
-        let encoded = VarLen(self.0.max_header_list_size as u64);
+        let encoded = VarLen::from(self.0.max_header_list_size);
         let encoded_len = encoded.buf_len();
         debug_assert!(encoded_len < 64);
-        VarLen(encoded_len as u64).encode(buf);
+        VarLen::from(encoded_len as u32).encode(buf);
         encoded.encode(buf);
     }
 
-    fn decode<T: Buf>(buf: &mut T) -> SettingsFrame {
-        assert_eq!(buf.get_u8(), 0); // Flags
+    fn decode<T: Buf>(buf: &mut T) -> QuicResult<SettingsFrame> {
+        if buf.try_get_u8()? != 0 {
+            return Err(QuicError::InvalidEncoding("invalid SETTINGS flags".into()));
+        }
         let mut settings = Settings::default();
         while buf.has_remaining() {
-            let tag = buf.get_u16_be();
-            assert!(tag == 0x1 || tag == 0x6);
-            VarLen::decode(buf);
-            let val = VarLen::decode(buf);
+            let tag = buf.try_get_u16_be()?;
+            if tag != 0x1 && tag != 0x6 {
+                return Err(QuicError::InvalidEncoding(format!(
+                    "unsupported setting {}",
+                    tag
+                )));
+            }
+            VarLen::decode(buf)?;
+            let val = VarLen::decode(buf)?;
             if tag == 0x1 {
-                settings.header_table_size = val.0 as u32;
+                settings.header_table_size = val.value() as u32;
             } else if tag == 0x6 {
-                settings.max_header_list_size = val.0 as u32;
+                settings.max_header_list_size = val.value() as u32;
             }
         }
-        SettingsFrame(settings)
+        Ok(SettingsFrame(settings))
     }
 }
 
 impl FrameHeader for SettingsFrame {
     fn len(&self) -> VarLen {
-        VarLen(
-            (6 + VarLen(self.0.header_table_size as u64).buf_len()
-                + VarLen(self.0.max_header_list_size as u64).buf_len()) as u64,
+        VarLen::from(
+            (6 + VarLen::from(self.0.header_table_size).buf_len()
+                + VarLen::from(self.0.max_header_list_size).buf_len()) as u32,
         )
     }
     fn flags(&self) -> u8 {
@@ -92,7 +100,7 @@ where
 {
     fn buf_len(&self) -> usize {
         let len = self.len();
-        2 + VarLen(len.0).buf_len() + (len.0 as usize)
+        2 + len.buf_len() + (len.value() as usize)
     }
 }
 
@@ -143,7 +151,7 @@ mod tests {
         );
 
         let mut read = Cursor::new(&buf);
-        let decoded = super::HttpFrame::decode(&mut read);
+        let decoded = super::HttpFrame::decode(&mut read).unwrap();
         assert_eq!(decoded, frame);
     }
 }
@@ -4,8 +4,15 @@ extern crate failure;
 extern crate failure_derive;
 #[macro_use]
 extern crate futures;
+#[cfg(target_os = "linux")]
+extern crate libc;
 #[macro_use]
 extern crate log;
+#[cfg(target_os = "linux")]
+extern crate mio;
+#[cfg(test)]
+#[macro_use]
+extern crate proptest;
 extern crate rand;
 extern crate ring;
 extern crate rustls;
@@ -14,19 +21,66 @@ extern crate webpki;
 extern crate webpki_roots;
 
 pub use client::Client;
+pub use clock::{Clock, MockClock, SystemClock};
+pub use congestion::Algorithm;
+pub use conn_ids::{ConnectionIdGenerator, RandomConnectionIdGenerator, ServerIdGenerator};
+pub use conn_state::{ConnectionStats, EarlyData, HandshakeData};
+pub use crypto::{AeadAlgorithm, AeadKey, CryptoProvider, HashAlgorithm, HeaderKey, RingProvider,
+                 HEADER_MASK_LEN};
+pub use connection::{CloseFuture, Connection, HandshakeFuture, KeyingMaterial};
+pub use datagrams::RecvDatagrams;
+pub use endpoint::{ConnectingFuture, Driver, DualConnectingFuture, Endpoint, EndpointConfig,
+                   HostConnectingFuture, Incoming};
+pub use events::{ConnectionEvents, Event};
+pub use parameters::{TransportConfig, TransportParameters};
+pub use resolver::{Resolution, Resolver, SystemResolver};
 pub use server::Server;
+pub use session::{LruSessionCache, SessionCache};
+pub use socket::{EcnCodepoint, RecvMeta, Socket, Transmit};
+pub use streams::{AcceptUni, IncomingStreams, NewStream, OpenBi, OpenBiBatch, OpenOptions,
+                  OpenStream, OpenUni, ReadToEnd, RecvStream, SendStream, StreamLimits, StreamRef,
+                  Streams, WriteAll};
+pub use timers::Timeout;
+pub use token::{HmacTokenStore, TokenStore};
+pub use types::{ConnectionId, Side, StreamId};
 
+mod acks;
+mod assembler;
+mod ciphers;
 mod client;
+mod clock;
 mod codec;
+mod congestion;
+mod conn_ids;
 mod conn_state;
+mod connection;
 mod crypto;
+mod datagrams;
+mod endpoint;
+mod events;
+mod flow_control;
 mod frame;
+#[cfg(feature = "fuzzing")]
+pub mod fuzzing;
 pub mod http;
+mod mtu;
 mod packet;
+mod packetizer;
 mod parameters;
+mod pn;
+mod pool;
+mod qlog;
+mod recovery;
+mod resolver;
 mod server;
+mod session;
+#[cfg(any(test, feature = "test-util"))]
+pub mod sim;
+mod socket;
 mod streams;
+mod timers;
 pub mod tls;
+mod token;
 mod types;
 
 #[derive(Debug, Fail)]
@@ -37,20 +91,61 @@ pub enum QuicError {
     AllocationError(usize, usize),
     #[fail(display = "application close ({}): '{}'", _0, _1)]
     ApplicationClose(u16, String),
+    #[fail(display = "{}", _0)]
+    Connect(#[cause] ConnectError),
     #[fail(display = "connection close ({}): '{}'", _0, _1)]
-    ConnectionClose(u16, String),
+    ConnectionClose(TransportError, String),
     #[fail(display = "")]
     DecryptError,
     #[fail(display = "")]
     EncryptError,
     #[fail(display = "{}", _0)]
     General(String),
+    #[fail(display = "needed {} bytes, found {}", _0, _1)]
+    InsufficientSpace(usize, usize),
     #[fail(display = "{}", _0)]
     InvalidDnsName(String),
+    #[fail(display = "invalid encoding: {}", _0)]
+    InvalidEncoding(String),
     #[fail(display = "{}", _0)]
     Io(#[cause] std::io::Error),
+    #[fail(display = "no streams left under the peer's limit")]
+    StreamLimitReached,
+    #[fail(display = "stream {} reset by peer ({})", _0, _1)]
+    StreamReset(StreamId, u16),
+    #[fail(display = "stream data exceeds limit of {} bytes", _0)]
+    StreamTooLong(usize),
+    #[fail(display = "timed out")]
+    TimedOut,
     #[fail(display = "{}", _0)]
     Tls(#[cause] rustls::TLSError),
+    #[fail(display = "{}: {}", _0, _1)]
+    Transport(TransportError, String),
+    #[fail(display = "unexpected end of buffer")]
+    UnexpectedEnd,
+    #[fail(display = "unknown frame type {}", _0)]
+    UnknownFrameType(u8),
+}
+
+// Why a handshake failed, so clients can tell which failures are worth retrying
+#[derive(Clone, Debug, Fail, PartialEq)]
+pub enum ConnectError {
+    #[fail(display = "connection closed during handshake: {}", _0)]
+    Closed(String),
+    #[fail(display = "handshake failed with TLS alert {}: '{}'", _0, _1)]
+    TlsAlert(u8, String),
+    #[fail(display = "server kept sending Retry packets")]
+    RetryLoop,
+    #[fail(display = "handshake timed out")]
+    TimedOut,
+    #[fail(display = "no version in common with server (supports {:?})", _0)]
+    VersionMismatch(Vec<u32>),
+}
+
+impl From<ConnectError> for QuicError {
+    fn from(e: ConnectError) -> QuicError {
+        QuicError::Connect(e)
+    }
 }
 
 impl From<std::io::Error> for QuicError {
@@ -59,6 +154,21 @@ impl From<std::io::Error> for QuicError {
     }
 }
 
+impl From<QuicError> for std::io::Error {
+    fn from(e: QuicError) -> std::io::Error {
+        match e {
+            QuicError::Io(e) => e,
+            QuicError::StreamReset(..) => {
+                std::io::Error::new(std::io::ErrorKind::ConnectionReset, e.to_string())
+            }
+            QuicError::TimedOut | QuicError::Connect(ConnectError::TimedOut) => {
+                std::io::Error::new(std::io::ErrorKind::TimedOut, e.to_string())
+            }
+            e => std::io::Error::new(std::io::ErrorKind::Other, e.to_string()),
+        }
+    }
+}
+
 impl From<std::net::AddrParseError> for QuicError {
     fn from(e: std::net::AddrParseError) -> QuicError {
         QuicError::AddrParse(e)
@@ -71,6 +181,88 @@ impl From<rustls::TLSError> for QuicError {
     }
 }
 
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum TransportError {
+    NoError,
+    InternalError,
+    ServerBusy,
+    FlowControlError,
+    StreamIdError,
+    StreamStateError,
+    FinalOffsetError,
+    FrameEncodingError,
+    TransportParameterError,
+    VersionNegotiationError,
+    ProtocolViolation,
+    UnsolicitedPathResponse,
+    Crypto(u8),
+    Unknown(u16),
+}
+
+impl TransportError {
+    pub fn code(&self) -> u16 {
+        match *self {
+            TransportError::NoError => 0x0,
+            TransportError::InternalError => 0x1,
+            TransportError::ServerBusy => 0x2,
+            TransportError::FlowControlError => 0x3,
+            TransportError::StreamIdError => 0x4,
+            TransportError::StreamStateError => 0x5,
+            TransportError::FinalOffsetError => 0x6,
+            TransportError::FrameEncodingError => 0x7,
+            TransportError::TransportParameterError => 0x8,
+            TransportError::VersionNegotiationError => 0x9,
+            TransportError::ProtocolViolation => 0xa,
+            TransportError::UnsolicitedPathResponse => 0xb,
+            TransportError::Crypto(alert) => 0x100 | u16::from(alert),
+            TransportError::Unknown(code) => code,
+        }
+    }
+}
+
+impl From<u16> for TransportError {
+    fn from(code: u16) -> Self {
+        match code {
+            0x0 => TransportError::NoError,
+            0x1 => TransportError::InternalError,
+            0x2 => TransportError::ServerBusy,
+            0x3 => TransportError::FlowControlError,
+            0x4 => TransportError::StreamIdError,
+            0x5 => TransportError::StreamStateError,
+            0x6 => TransportError::FinalOffsetError,
+            0x7 => TransportError::FrameEncodingError,
+            0x8 => TransportError::TransportParameterError,
+            0x9 => TransportError::VersionNegotiationError,
+            0xa => TransportError::ProtocolViolation,
+            0xb => TransportError::UnsolicitedPathResponse,
+            0x100..=0x1ff => TransportError::Crypto(code as u8),
+            code => TransportError::Unknown(code),
+        }
+    }
+}
+
+impl std::fmt::Display for TransportError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let name = match *self {
+            TransportError::NoError => "NO_ERROR",
+            TransportError::InternalError => "INTERNAL_ERROR",
+            TransportError::ServerBusy => "SERVER_BUSY",
+            TransportError::FlowControlError => "FLOW_CONTROL_ERROR",
+            TransportError::StreamIdError => "STREAM_ID_ERROR",
+            TransportError::StreamStateError => "STREAM_STATE_ERROR",
+            TransportError::FinalOffsetError => "FINAL_OFFSET_ERROR",
+            TransportError::FrameEncodingError => "FRAME_ENCODING_ERROR",
+            TransportError::TransportParameterError => "TRANSPORT_PARAMETER_ERROR",
+            TransportError::VersionNegotiationError => "VERSION_NEGOTIATION_ERROR",
+            TransportError::ProtocolViolation => "PROTOCOL_VIOLATION",
+            TransportError::UnsolicitedPathResponse => "UNSOLICITED_PATH_RESPONSE",
+            TransportError::Crypto(alert) => return write!(f, "CRYPTO_ERROR(0x{:x})", alert),
+            TransportError::Unknown(code) => return write!(f, "0x{:x}", code),
+        };
+        f.write_str(name)
+    }
+}
+
 pub type QuicResult<O> = std::result::Result<O, QuicError>;
 
 pub const QUIC_VERSION: u32 = 0xff00_000b;
@@ -0,0 +1,154 @@
+use std::cmp;
+use std::time::{Duration, Instant};
+
+pub struct MtuDiscovery {
+    current: u16,
+    low: u16,
+    high: u16,
+    max: u16,
+    in_flight: Option<(u64, u16)>,
+    failures: u8,
+    next_search: Option<Instant>,
+}
+
+impl MtuDiscovery {
+    pub fn new(max: u16) -> Self {
+        let max = cmp::max(cmp::min(max, MAX_PROBE_MTU), BASE_MTU);
+        Self {
+            current: BASE_MTU,
+            low: BASE_MTU,
+            high: max,
+            max,
+            in_flight: None,
+            failures: 0,
+            next_search: None,
+        }
+    }
+
+    pub fn current(&self) -> usize {
+        self.current as usize
+    }
+
+    pub fn set_peer_max(&mut self, max: u16) {
+        self.max = cmp::max(cmp::min(self.max, max), BASE_MTU);
+        self.high = cmp::min(self.high, self.max);
+        self.current = cmp::min(self.current, self.max);
+        self.low = cmp::min(self.low, self.max);
+    }
+
+    // A new path starts over from the base MTU
+    pub fn reset(&mut self, start: Instant) {
+        self.current = BASE_MTU;
+        self.low = BASE_MTU;
+        self.high = self.max;
+        self.in_flight = None;
+        self.failures = 0;
+        self.next_search = Some(start);
+    }
+
+    pub fn deadline(&self) -> Option<Instant> {
+        match self.in_flight {
+            Some(_) => None,
+            None => self.next_search,
+        }
+    }
+
+    pub fn next_probe(&self, now: Instant) -> Option<u16> {
+        if self.in_flight.is_some() || self.next_search.map_or(true, |start| now < start) {
+            return None;
+        }
+        if self.high < self.low + SEARCH_GRANULARITY {
+            return None;
+        }
+        Some(self.low + (self.high - self.low + 1) / 2)
+    }
+
+    pub fn on_probe_sent(&mut self, number: u64, size: u16) {
+        self.in_flight = Some((number, size));
+    }
+
+    pub fn on_acked(&mut self, number: u64, now: Instant) {
+        match self.in_flight {
+            Some((probe, size)) if probe == number => {
+                self.current = size;
+                self.low = size;
+                self.failures = 0;
+                self.in_flight = None;
+                self.on_probe_done(now);
+            }
+            _ => {}
+        }
+    }
+
+    pub fn on_lost(&mut self, number: u64, now: Instant) {
+        match self.in_flight {
+            Some((probe, size)) if probe == number => {
+                self.in_flight = None;
+                self.failures += 1;
+                if self.failures >= MAX_PROBES {
+                    self.high = size - 1;
+                    self.failures = 0;
+                }
+                self.on_probe_done(now);
+            }
+            _ => {}
+        }
+    }
+
+    fn on_probe_done(&mut self, now: Instant) {
+        // Once the search converges, try raising the MTU again much later on
+        if self.high < self.low + SEARCH_GRANULARITY {
+            self.high = self.max;
+            self.next_search = Some(now + Duration::from_secs(RAISE_INTERVAL_SECS));
+        }
+    }
+}
+
+pub const BASE_MTU: u16 = 1200;
+// The largest UDP payload fitting in a 1500 byte Ethernet frame over IPv6
+pub const MAX_PROBE_MTU: u16 = 1452;
+const SEARCH_GRANULARITY: u16 = 20;
+const MAX_PROBES: u8 = 3;
+const RAISE_INTERVAL_SECS: u64 = 600;
+
+#[cfg(test)]
+mod tests {
+    use super::{MtuDiscovery, BASE_MTU, MAX_PROBE_MTU};
+    use std::time::Instant;
+
+    #[test]
+    fn test_binary_search() {
+        let now = Instant::now();
+        let mut mtu = MtuDiscovery::new(65527);
+        assert_eq!(mtu.next_probe(now), None);
+        mtu.reset(now);
+
+        let mut number = 0;
+        while let Some(size) = mtu.next_probe(now) {
+            mtu.on_probe_sent(number, size);
+            assert_eq!(mtu.next_probe(now), None);
+            if size <= 1400 {
+                mtu.on_acked(number, now);
+            } else {
+                mtu.on_lost(number, now);
+            }
+            number += 1;
+        }
+        assert!(mtu.current() <= 1400 && mtu.current() > 1380);
+        assert!(mtu.deadline().unwrap() > now);
+    }
+
+    #[test]
+    fn test_peer_limit() {
+        let now = Instant::now();
+        let mut mtu = MtuDiscovery::new(65527);
+        mtu.set_peer_max(1000);
+        mtu.reset(now);
+        assert_eq!(mtu.current(), BASE_MTU as usize);
+        assert_eq!(mtu.next_probe(now), None);
+
+        let mut mtu = MtuDiscovery::new(65527);
+        mtu.reset(now);
+        assert_eq!(mtu.next_probe(now), Some((BASE_MTU + MAX_PROBE_MTU + 1) / 2));
+    }
+}
@@ -1,11 +1,12 @@
-use bytes::{Buf, BufMut};
+use bytes::{Buf, BufMut, Bytes};
 
 use super::{QuicError, QuicResult};
-use codec::{BufLen, Codec, VarLen};
-use crypto::PacketKey;
-use frame::Frame;
+use codec::{BufExt, BufLen, Codec, VarLen};
+use crypto::{EncryptionLevel, KeyChain, HEADER_MASK_LEN, HEADER_SAMPLE_LEN};
+use frame::{self, Frame};
 use types::{ConnectionId, GENERATED_CID_LENGTH};
 
+use std::cmp;
 use std::io::Cursor;
 
 #[derive(Debug, PartialEq)]
@@ -15,63 +16,106 @@ pub struct Packet {
 }
 
 impl Packet {
-    pub fn number(&self) -> u32 {
+    pub fn number(&self) -> u64 {
         self.header.number()
     }
 
-    pub fn encode(&self, key: &PacketKey, buf: &mut [u8]) -> QuicResult<usize> {
-        let tag_len = key.algorithm().tag_len();
+    pub fn encode(&self, keys: &KeyChain, buf: &mut [u8]) -> QuicResult<usize> {
+        let level = EncryptionLevel::of(&self.header);
+        let tag_len = keys.tag_len(level)?;
         let len = self.buf_len() + tag_len;
         if len > buf.len() {
             return Err(QuicError::AllocationError(len, buf.len()));
         }
 
-        let (header_len, msg_len, buf) = {
-            let mut write = Cursor::new(buf);
-            self.header.encode(&mut write);
-            let header_len = write.position() as usize;
-            debug_assert_eq!(header_len, self.header.buf_len());
-
-            let mut expected = header_len;
+        // The frames have to leave room for the AEAD tag
+        let capacity = buf.len() - tag_len;
+        let (header_len, msg_len) = {
+            let mut write = Cursor::new(&mut buf[..capacity]);
+            let header_len = self.header.try_encode(&mut write)?;
             for frame in &self.payload {
-                frame.encode(&mut write);
-                expected += frame.buf_len();
+                frame.try_encode(&mut write)?;
             }
-            debug_assert_eq!(expected, write.position() as usize);
-            let msg_len = write.position() as usize;
-            (header_len, msg_len, write.into_inner())
+            (header_len, write.position() as usize)
         };
 
         let out_len = {
             let (header_buf, mut payload) = buf.split_at_mut(header_len);
             let mut in_out = &mut payload[..msg_len - header_len + tag_len];
-            key.encrypt(self.header.number(), &header_buf, in_out, tag_len)?
+            keys.seal(level, self.header.number(), &header_buf, in_out, tag_len)?
         };
 
         if let Header::Long { len, .. } = self.header {
             debug_assert_eq!(len, out_len as u64);
         }
+
+        let pn_len = self.header.pn_len();
+        if pn_len > 0 {
+            let pn_offset = header_len - pn_len;
+            let sample = pn_offset + 4;
+            if header_len + out_len < sample + HEADER_SAMPLE_LEN {
+                return Err(QuicError::General(
+                    "packet too short for header protection".into(),
+                ));
+            }
+            let mask =
+                keys.local_header_mask(level, &buf[sample..sample + HEADER_SAMPLE_LEN])?;
+            if let Header::Short { .. } = self.header {
+                buf[0] ^= mask[0] & SHORT_PROTECTED_BITS;
+            }
+            apply_pn_mask(&mut buf[pn_offset..header_len], &mask);
+        }
         Ok(header_len + out_len)
     }
 
-    pub fn start_decode(buf: &mut [u8]) -> PartialDecode {
-        let (header, header_len) = {
-            let mut read = Cursor::new(&buf);
-            let header = Header::decode(&mut read);
-            (header, read.position() as usize)
+    // Short headers don't encode the CID length, so the receiver has to know its own
+    pub fn start_decode(buf: &mut [u8], cid_len: usize) -> QuicResult<PartialDecode> {
+        let short = buf.first().map_or(false, |first| first & 128 == 0);
+        let (header, header_len) = if short {
+            // Flags and packet number are only parsed once protection is removed
+            let pn_offset = 1 + cid_len;
+            if buf.len() < pn_offset {
+                return Err(QuicError::UnexpectedEnd);
+            }
+            let header = Header::Short {
+                key_phase: false,
+                spin: false,
+                ptype: ShortType::Four,
+                dst_cid: ConnectionId::new(&buf[1..pn_offset]),
+                number: 0,
+            };
+            (header, pn_offset)
+        } else {
+            decode_header(buf, cid_len)?
         };
-        PartialDecode {
+        Ok(PartialDecode {
             header,
             header_len,
+            cid_len,
             buf,
-        }
+            protected: true,
+        })
+    }
+}
+
+fn decode_header(buf: &[u8], cid_len: usize) -> QuicResult<(Header, usize)> {
+    let mut read = Cursor::new(buf);
+    let header = Header::decode_with_cid_len(&mut read, cid_len)?;
+    Ok((header, read.position() as usize))
+}
+
+fn apply_pn_mask(pn: &mut [u8], mask: &[u8; HEADER_MASK_LEN]) {
+    for (byte, mask) in pn.iter_mut().zip(&mask[1..]) {
+        *byte ^= mask;
     }
 }
 
 pub struct PartialDecode<'a> {
     pub(crate) header: Header,
     header_len: usize,
+    cid_len: usize,
     buf: &'a mut [u8],
+    protected: bool,
 }
 
 impl<'a> PartialDecode<'a> {
@@ -79,22 +123,92 @@ impl<'a> PartialDecode<'a> {
         self.header.dst_cid()
     }
 
-    pub fn finish(self, key: &PacketKey) -> QuicResult<Packet> {
+    pub fn size(&self) -> usize {
+        self.buf.len()
+    }
+
+    pub fn split_coalesced(self) -> QuicResult<(PartialDecode<'a>, &'a mut [u8])> {
         let PartialDecode {
             header,
             header_len,
+            cid_len,
             buf,
+            protected,
         } = self;
-        let (header_buf, payload_buf) = buf.split_at_mut(header_len);
-        let decrypted = key.decrypt(header.number(), &header_buf, payload_buf)?;
-        let mut read = Cursor::new(decrypted);
+        let end = match header {
+            Header::Long { len, .. } if len > (buf.len() - header_len) as u64 => {
+                return Err(QuicError::UnexpectedEnd);
+            }
+            Header::Long { len, .. } => header_len + len as usize,
+            _ => buf.len(),
+        };
+        let (buf, rest) = buf.split_at_mut(end);
+        let partial = PartialDecode {
+            header,
+            header_len,
+            cid_len,
+            buf,
+            protected,
+        };
+        Ok((partial, rest))
+    }
 
-        let mut payload = Vec::new();
-        while read.has_remaining() {
-            let frame = Frame::decode(&mut read);
-            payload.push(frame);
+    pub fn remove_protection(&mut self, keys: &KeyChain) -> QuicResult<()> {
+        if !self.protected {
+            return Ok(());
         }
+        let pn_offset = match self.header {
+            Header::Long { .. } => self.header_len - 4,
+            Header::Retry { .. } => {
+                self.protected = false;
+                return Ok(());
+            }
+            Header::Short { .. } => self.header_len,
+        };
 
+        let sample = pn_offset + 4;
+        if self.buf.len() < sample + HEADER_SAMPLE_LEN {
+            return Err(QuicError::UnexpectedEnd);
+        }
+        let level = EncryptionLevel::of(&self.header);
+        let mask = keys.remote_header_mask(level, &self.buf[sample..sample + HEADER_SAMPLE_LEN])?;
+        let pn_len = match self.header {
+            Header::Short { .. } => {
+                self.buf[0] ^= mask[0] & SHORT_PROTECTED_BITS;
+                ShortType::from_byte(self.buf[0] & 3)?.buf_len()
+            }
+            _ => 4,
+        };
+        apply_pn_mask(&mut self.buf[pn_offset..pn_offset + pn_len], &mask);
+
+        let (header, header_len) = decode_header(self.buf, self.cid_len)?;
+        self.header = header;
+        self.header_len = header_len;
+        self.protected = false;
+        Ok(())
+    }
+
+    pub fn finish(self, keys: &mut KeyChain) -> QuicResult<Packet> {
+        let PartialDecode {
+            header,
+            header_len,
+            buf,
+            protected,
+            ..
+        } = self;
+        debug_assert!(!protected);
+        let (header_buf, payload_buf) = buf.split_at_mut(header_len);
+        let decrypted = match header {
+            Header::Short {
+                key_phase, number, ..
+            } => keys.open_1rtt(key_phase, number, &header_buf, payload_buf)?,
+            _ => {
+                let level = EncryptionLevel::of(&header);
+                keys.open(level, header.number(), &header_buf, payload_buf)?
+            }
+        };
+        // One copy of the plaintext, which the stream frames then share
+        let payload = frame::decode_all(&Bytes::from(&decrypted[..]))?;
         Ok(Packet { header, payload })
     }
 }
@@ -112,14 +226,23 @@ pub enum Header {
         version: u32,
         dst_cid: ConnectionId,
         src_cid: ConnectionId,
+        token: Vec<u8>,
         len: u64,
-        number: u32,
+        number: u64,
+    },
+    Retry {
+        version: u32,
+        dst_cid: ConnectionId,
+        src_cid: ConnectionId,
+        orig_dst_cid: ConnectionId,
+        token: Vec<u8>,
     },
     Short {
         key_phase: bool,
+        spin: bool,
         ptype: ShortType,
         dst_cid: ConnectionId,
-        number: u32,
+        number: u64,
     },
 }
 
@@ -127,39 +250,114 @@ impl Header {
     pub fn ptype(&self) -> Option<LongType> {
         match *self {
             Header::Long { ptype, .. } => Some(ptype),
+            Header::Retry { .. } => Some(LongType::Retry),
             Header::Short { .. } => None,
         }
     }
 
-    fn dst_cid(&self) -> ConnectionId {
+    pub fn dst_cid(&self) -> ConnectionId {
         match *self {
             Header::Long { dst_cid, .. } => dst_cid,
+            Header::Retry { dst_cid, .. } => dst_cid,
             Header::Short { dst_cid, .. } => dst_cid,
         }
     }
 
-    fn number(&self) -> u32 {
+    // The full packet number once a received header has been expanded; only the low bits given
+    // by pn_len() go on the wire
+    pub fn number(&self) -> u64 {
         match *self {
             Header::Long { number, .. } => number,
+            Header::Retry { .. } => 0,
             Header::Short { number, .. } => number,
         }
     }
+
+    pub fn pn_len(&self) -> usize {
+        match *self {
+            Header::Long { .. } => 4,
+            Header::Retry { .. } => 0,
+            Header::Short { ptype, .. } => ptype.buf_len(),
+        }
+    }
+
+    pub fn set_number(&mut self, new: u64) {
+        match *self {
+            Header::Long { ref mut number, .. } => *number = new,
+            Header::Retry { .. } => {}
+            Header::Short { ref mut number, .. } => *number = new,
+        }
+    }
+}
+
+// Version negotiation packets have a long header with a zero version, followed by the
+// versions the server does support. Returns the destination and source CIDs along with them.
+pub fn version_negotiation(buf: &[u8]) -> Option<(ConnectionId, ConnectionId, Vec<u32>)> {
+    let mut read = Cursor::new(buf);
+    let first = read.try_get_u8().ok()?;
+    if first & 128 == 0 || read.try_get_u32_be().ok()? != 0 {
+        return None;
+    }
+    let cils = read.try_get_u8().ok()?;
+    let dst_cid = read_cid(&mut read, cils >> 4)?;
+    let src_cid = read_cid(&mut read, cils & 15)?;
+    if read.remaining() == 0 || read.remaining() % 4 != 0 {
+        return None;
+    }
+    let mut versions = Vec::new();
+    while read.has_remaining() {
+        versions.push(read.get_u32_be());
+    }
+    Some((dst_cid, src_cid, versions))
+}
+
+fn read_cid<T: Buf>(read: &mut T, cil: u8) -> Option<ConnectionId> {
+    let len = if cil > 0 { cil as usize + 3 } else { 0 };
+    let mut bytes = [0; 18];
+    read.try_copy_to_slice(&mut bytes[..len]).ok()?;
+    Some(ConnectionId::new(&bytes[..len]))
 }
 
 impl BufLen for Header {
     fn buf_len(&self) -> usize {
         match *self {
             Header::Long {
+                ptype,
                 dst_cid,
                 src_cid,
+                ref token,
                 len,
                 ..
-            } => 10 + (dst_cid.len as usize + src_cid.len as usize) + VarLen(len).buf_len(),
+            } => {
+                let token_len = if ptype == LongType::Initial {
+                    VarLen::bounded(token.len() as u64).buf_len() + token.len()
+                } else {
+                    0
+                };
+                10 + (dst_cid.len as usize + src_cid.len as usize) + token_len
+                    + length_field_len(len)
+            }
+            Header::Retry {
+                dst_cid,
+                src_cid,
+                orig_dst_cid,
+                ref token,
+                ..
+            } => {
+                7 + (dst_cid.len as usize + src_cid.len as usize + orig_dst_cid.len as usize)
+                    + token.len()
+            }
             Header::Short { ptype, dst_cid, .. } => 1 + (dst_cid.len as usize) + ptype.buf_len(),
         }
     }
 }
 
+// The payload length always gets the two bytes reserved for it while the payload is built,
+// so the header doesn't shrink under a small payload
+fn length_field_len(len: u64) -> usize {
+    cmp::max(2, VarLen::bounded(len).buf_len())
+}
+
 impl Codec for Header {
     fn encode<T: BufMut>(&self, buf: &mut T) {
         match *self {
@@ -168,6 +366,7 @@ impl Codec for Header {
                 version,
                 dst_cid,
                 src_cid,
+                ref token,
                 len,
                 number,
             } => {
@@ -176,83 +375,154 @@ impl Codec for Header {
                 buf.put_u8((dst_cid.cil() << 4) | src_cid.cil());
                 buf.put_slice(&dst_cid);
                 buf.put_slice(&src_cid);
-                VarLen(len).encode(buf);
-                buf.put_u32_be(number);
+                if ptype == LongType::Initial {
+                    VarLen::bounded(token.len() as u64).encode(buf);
+                    buf.put_slice(token);
+                }
+                VarLen::bounded(len)
+                    .encode_with_len(buf, length_field_len(len))
+                    .expect("length field sized to fit");
+                buf.put_u32_be(number as u32);
+            }
+            Header::Retry {
+                version,
+                dst_cid,
+                src_cid,
+                orig_dst_cid,
+                ref token,
+            } => {
+                buf.put_u8(128 | LongType::Retry.to_byte());
+                buf.put_u32_be(version);
+                buf.put_u8((dst_cid.cil() << 4) | src_cid.cil());
+                buf.put_slice(&dst_cid);
+                buf.put_slice(&src_cid);
+                buf.put_u8(orig_dst_cid.len);
+                buf.put_slice(&orig_dst_cid);
+                buf.put_slice(token);
             }
             Header::Short {
                 key_phase,
+                spin,
                 ptype,
                 dst_cid,
                 number,
             } => {
                 let key_phase_bit = if key_phase { 0x40 } else { 0 };
+                // The spin bit stays outside header protection so observers can read it
+                let spin_bit = if spin { SPIN_BIT } else { 0 };
 
-                buf.put_u8(key_phase_bit | 0x20 | 0x10 | ptype.to_byte());
+                buf.put_u8(key_phase_bit | 0x20 | 0x10 | spin_bit | ptype.to_byte());
                 buf.put_slice(&dst_cid);
                 match ptype {
                     ShortType::One => buf.put_u8(number as u8),
                     ShortType::Two => buf.put_u16_be(number as u16),
-                    ShortType::Four => buf.put_u32_be(number),
+                    ShortType::Four => buf.put_u32_be(number as u32),
                 }
             }
         }
     }
 
-    fn decode<T: Buf>(buf: &mut T) -> Self {
-        let first = buf.get_u8();
+    fn decode<T: Buf>(buf: &mut T) -> QuicResult<Self> {
+        Header::decode_with_cid_len(buf, GENERATED_CID_LENGTH as usize)
+    }
+}
+
+impl Header {
+    pub fn decode_with_cid_len<T: Buf>(buf: &mut T, cid_len: usize) -> QuicResult<Self> {
+        let first = buf.try_get_u8()?;
         if first & 128 == 128 {
-            let version = buf.get_u32_be();
-            let cils = buf.get_u8();
+            let version = buf.try_get_u32_be()?;
+            let cils = buf.try_get_u8()?;
 
-            let (dst_cid, src_cid, used) = {
-                let (mut dcil, mut scil) = ((cils >> 4) as usize, (cils & 15) as usize);
-                if dcil > 0 {
-                    dcil += 3;
-                }
-                if scil > 0 {
-                    scil += 3;
+            let (mut dcil, mut scil) = ((cils >> 4) as usize, (cils & 15) as usize);
+            if dcil > 0 {
+                dcil += 3;
+            }
+            if scil > 0 {
+                scil += 3;
+            }
+            // Copied rather than sliced, since buffers need not be contiguous
+            let mut bytes = [0; 18];
+            buf.try_copy_to_slice(&mut bytes[..dcil])?;
+            let dst_cid = ConnectionId::new(&bytes[..dcil]);
+            buf.try_copy_to_slice(&mut bytes[..scil])?;
+            let src_cid = ConnectionId::new(&bytes[..scil]);
+
+            let ptype = LongType::from_byte(first ^ 128)?;
+            if ptype == LongType::Retry {
+                let odcil = buf.try_get_u8()? as usize;
+                if odcil != 0 && (odcil < 4 || odcil > 18) {
+                    return Err(QuicError::InvalidEncoding(format!(
+                        "invalid original connection ID length {}",
+                        odcil
+                    )));
                 }
+                let mut odcid = [0; 18];
+                buf.try_copy_to_slice(&mut odcid[..odcil])?;
+                let mut token = vec![0; buf.remaining()];
+                buf.copy_to_slice(&mut token);
+                return Ok(Header::Retry {
+                    version,
+                    dst_cid,
+                    src_cid,
+                    orig_dst_cid: ConnectionId::new(&odcid[..odcil]),
+                    token,
+                });
+            }
 
-                let bytes = buf.bytes();
-                let dst_cid = ConnectionId::new(&bytes[..dcil]);
-                let src_cid = ConnectionId::new(&bytes[dcil..dcil + scil]);
-                (dst_cid, src_cid, dcil + scil)
+            let token = if ptype == LongType::Initial {
+                let len = VarLen::decode(buf)?.value() as usize;
+                buf.check_remaining(len)?;
+                let mut token = vec![0; len];
+                buf.copy_to_slice(&mut token);
+                token
+            } else {
+                Vec::new()
             };
 
-            buf.advance(used);
-            Header::Long {
-                ptype: LongType::from_byte(first ^ 128),
+            Ok(Header::Long {
+                ptype,
                 version,
                 dst_cid,
                 src_cid,
-                len: VarLen::decode(buf).0,
-                number: buf.get_u32_be(),
-            }
+                token,
+                len: VarLen::decode(buf)?.value(),
+                number: u64::from(buf.try_get_u32_be()?),
+            })
         } else {
             let key_phase = first & 0x40 == 0x40;
-            let dst_cid = {
-                let bytes = buf.bytes();
-                ConnectionId::new(&bytes[..GENERATED_CID_LENGTH as usize])
-            };
-            buf.advance(GENERATED_CID_LENGTH as usize);
+            let spin = first & SPIN_BIT == SPIN_BIT;
+            let mut bytes = [0; 18];
+            if cid_len > bytes.len() {
+                return Err(QuicError::InvalidEncoding(format!(
+                    "invalid connection ID length {}",
+                    cid_len
+                )));
+            }
+            buf.try_copy_to_slice(&mut bytes[..cid_len])?;
+            let dst_cid = ConnectionId::new(&bytes[..cid_len]);
 
-            let ptype = ShortType::from_byte(first & 3);
+            let ptype = ShortType::from_byte(first & 3)?;
             let number = match ptype {
-                ShortType::One => u32::from(buf.get_u8()),
-                ShortType::Two => u32::from(buf.get_u16_be()),
-                ShortType::Four => buf.get_u32_be(),
+                ShortType::One => u64::from(buf.try_get_u8()?),
+                ShortType::Two => u64::from(buf.try_get_u16_be()?),
+                ShortType::Four => u64::from(buf.try_get_u32_be()?),
             };
 
-            Header::Short {
+            Ok(Header::Short {
                 key_phase,
+                spin,
                 ptype,
                 dst_cid,
                 number,
-            }
+            })
         }
     }
 }
 
+const SHORT_PROTECTED_BITS: u8 = 0x43;
+const SPIN_BIT: u8 = 0x04;
+
 #[derive(Clone, Debug, PartialEq)]
 pub enum LongType {
     Initial = 0x7f,
@@ -273,14 +543,17 @@ impl LongType {
             Protected => 0x7c,
         }
     }
-    pub fn from_byte(v: u8) -> Self {
+    pub fn from_byte(v: u8) -> QuicResult<Self> {
         use self::LongType::*;
         match v {
-            0x7f => Initial,
-            0x7e => Retry,
-            0x7d => Handshake,
-            0x7c => Protected,
-            _ => panic!("invalid long packet type {}", v),
+            0x7f => Ok(Initial),
+            0x7e => Ok(Retry),
+            0x7d => Ok(Handshake),
+            0x7c => Ok(Protected),
+            _ => Err(QuicError::InvalidEncoding(format!(
+                "invalid long packet type {}",
+                v
+            ))),
         }
     }
 }
@@ -306,6 +579,15 @@ impl BufLen for ShortType {
 }
 
 impl ShortType {
+    pub fn from_len(len: usize) -> Self {
+        use self::ShortType::*;
+        match len {
+            1 => One,
+            2 => Two,
+            _ => Four,
+        }
+    }
+
     pub fn to_byte(&self) -> u8 {
         use self::ShortType::*;
         match self {
@@ -314,13 +596,261 @@ impl ShortType {
             Four => 2,
         }
     }
-    pub fn from_byte(v: u8) -> Self {
+    pub fn from_byte(v: u8) -> QuicResult<Self> {
         use self::ShortType::*;
         match v {
-            0 => One,
-            1 => Two,
-            2 => Four,
-            _ => panic!("invalid short packet type {}", v),
+            0 => Ok(One),
+            1 => Ok(Two),
+            2 => Ok(Four),
+            _ => Err(QuicError::InvalidEncoding(format!(
+                "invalid short packet type {}",
+                v
+            ))),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Header, LongType, ShortType};
+    use bytes::Buf;
+    use codec::{BufLen, Codec};
+    use proptest::prelude::*;
+    use std::io::Cursor;
+    use types::ConnectionId;
+    use QuicError;
+
+    fn cid() -> impl Strategy<Value = ConnectionId> {
+        prop_oneof![Just(Vec::new()), prop::collection::vec(any::<u8>(), 4..19)]
+            .prop_map(|id| ConnectionId::new(&id))
+    }
+
+    fn token() -> impl Strategy<Value = Vec<u8>> {
+        prop::collection::vec(any::<u8>(), 0..32)
+    }
+
+    fn header() -> impl Strategy<Value = Header> {
+        let long_type = prop_oneof![
+            Just(LongType::Initial),
+            Just(LongType::Handshake),
+            Just(LongType::Protected),
+        ];
+        let short_type = prop_oneof![
+            Just(ShortType::One),
+            Just(ShortType::Two),
+            Just(ShortType::Four),
+        ];
+        prop_oneof![
+            (
+                long_type,
+                any::<u32>(),
+                cid(),
+                cid(),
+                token(),
+                0..1u64 << 62,
+                any::<u32>(),
+            ).prop_map(|(ptype, version, dst_cid, src_cid, token, len, number)| {
+                // Only Initial packets carry a token
+                let token = if ptype == LongType::Initial {
+                    token
+                } else {
+                    Vec::new()
+                };
+                Header::Long {
+                    ptype,
+                    version,
+                    dst_cid,
+                    src_cid,
+                    token,
+                    len,
+                    number: u64::from(number),
+                }
+            }),
+            (any::<u32>(), cid(), cid(), cid(), token()).prop_map(
+                |(version, dst_cid, src_cid, orig_dst_cid, token)| Header::Retry {
+                    version,
+                    dst_cid,
+                    src_cid,
+                    orig_dst_cid,
+                    token,
+                }
+            ),
+            (any::<bool>(), any::<bool>(), short_type, cid(), any::<u32>()).prop_map(
+                |(key_phase, spin, ptype, dst_cid, number)| {
+                    let number = u64::from(match ptype {
+                        ShortType::One => number & 0xff,
+                        ShortType::Two => number & 0xffff,
+                        ShortType::Four => number,
+                    });
+                    Header::Short {
+                        key_phase,
+                        spin,
+                        ptype,
+                        dst_cid,
+                        number,
+                    }
+                }
+            ),
+        ]
+    }
+
+    proptest! {
+        #[test]
+        fn test_header_codec(header in header()) {
+            let mut buf = Vec::new();
+            header.encode(&mut buf);
+            prop_assert_eq!(header.buf_len(), buf.len());
+
+            let cid_len = header.dst_cid().len as usize;
+            let mut read = Cursor::new(&buf);
+            prop_assert_eq!(Header::decode_with_cid_len(&mut read, cid_len).unwrap(), header);
+            prop_assert_eq!(read.position() as usize, buf.len());
+        }
+    }
+
+    fn round_trip(header: Header) {
+        let mut buf = Vec::new();
+        header.encode(&mut buf);
+        assert_eq!(header.buf_len(), buf.len());
+
+        let mut read = Cursor::new(&buf);
+        assert_eq!(Header::decode(&mut read).unwrap(), header);
+        assert_eq!(read.position() as usize, buf.len());
+    }
+
+    #[test]
+    fn test_long_header_round_trip() {
+        for ptype in &[
+            LongType::Initial,
+            LongType::Handshake,
+            LongType::Protected,
+        ] {
+            round_trip(Header::Long {
+                ptype: *ptype,
+                version: 0xff00_000b,
+                dst_cid: ConnectionId::new(&[1, 2, 3, 4, 5, 6, 7, 8]),
+                src_cid: ConnectionId::new(&[8, 7, 6, 5, 4]),
+                token: if *ptype == LongType::Initial {
+                    vec![1, 2, 3]
+                } else {
+                    Vec::new()
+                },
+                len: 1200,
+                number: 0x0102_0304,
+            });
+        }
+    }
+
+    #[test]
+    fn test_retry_header_round_trip() {
+        round_trip(Header::Retry {
+            version: 0xff00_000b,
+            dst_cid: ConnectionId::new(&[1, 2, 3, 4, 5, 6, 7, 8]),
+            src_cid: ConnectionId::new(&[8, 7, 6, 5, 4, 3, 2, 1]),
+            orig_dst_cid: ConnectionId::new(&[9, 9, 9, 9, 9, 9, 9, 9]),
+            token: vec![0xaa; 24],
+        });
+    }
+
+    #[test]
+    fn test_short_header_round_trip() {
+        for ptype in &[ShortType::One, ShortType::Two, ShortType::Four] {
+            round_trip(Header::Short {
+                key_phase: true,
+                spin: true,
+                ptype: *ptype,
+                dst_cid: ConnectionId::new(&[1, 2, 3, 4, 5, 6, 7, 8]),
+                number: 0x42,
+            });
+        }
+    }
+
+    #[test]
+    fn test_number_truncated_on_encode() {
+        let mut header = Header::Short {
+            key_phase: false,
+            spin: false,
+            ptype: ShortType::Two,
+            dst_cid: ConnectionId::new(&[1, 2, 3, 4]),
+            number: 0x1_0000_0142,
+        };
+        let mut buf = Vec::new();
+        header.encode(&mut buf);
+        assert_eq!(&buf[5..], &[0x01, 0x42]);
+
+        let mut read = Cursor::new(&buf);
+        let mut decoded = Header::decode_with_cid_len(&mut read, 4).unwrap();
+        assert_eq!(decoded.number(), 0x0142);
+        decoded.set_number(0x1_0000_0142);
+        assert_eq!(decoded, header);
+
+        header.set_number(0x2_0304_0506);
+        let mut buf = Vec::new();
+        Header::Long {
+            ptype: LongType::Handshake,
+            version: 0xff00_000b,
+            dst_cid: ConnectionId::new(&[]),
+            src_cid: ConnectionId::new(&[]),
+            token: Vec::new(),
+            len: 20,
+            number: header.number(),
+        }.encode(&mut buf);
+        assert_eq!(&buf[buf.len() - 4..], &[3, 4, 5, 6]);
+        // A short payload's length still takes the two bytes reserved for it
+        assert_eq!(&buf[6..8], &[0x40, 20]);
+    }
+
+    #[test]
+    fn test_zero_length_cid() {
+        let header = Header::Short {
+            key_phase: false,
+            spin: false,
+            ptype: ShortType::Two,
+            dst_cid: ConnectionId::new(&[]),
+            number: 0x0102,
+        };
+        let mut buf = Vec::new();
+        header.encode(&mut buf);
+        assert_eq!(buf.len(), 3);
+        let mut read = Cursor::new(&buf);
+        assert_eq!(Header::decode_with_cid_len(&mut read, 0).unwrap(), header);
+
+        round_trip(Header::Long {
+            ptype: LongType::Handshake,
+            version: 0xff00_000b,
+            dst_cid: ConnectionId::new(&[]),
+            src_cid: ConnectionId::new(&[1, 2, 3, 4]),
+            token: Vec::new(),
+            len: 20,
+            number: 1,
+        });
+    }
+
+    #[test]
+    fn test_split_and_truncated_headers() {
+        let header = Header::Long {
+            ptype: LongType::Handshake,
+            version: 0xff00_000b,
+            dst_cid: ConnectionId::new(&[1, 2, 3, 4, 5, 6, 7, 8]),
+            src_cid: ConnectionId::new(&[8, 7, 6, 5, 4]),
+            token: Vec::new(),
+            len: 1200,
+            number: 7,
+        };
+        let mut buf = Vec::new();
+        header.encode(&mut buf);
+
+        // Connection IDs straddling the two halves
+        let (head, tail) = buf.split_at(8);
+        let mut read = Cursor::new(head).chain(Cursor::new(tail));
+        assert_eq!(Header::decode(&mut read).unwrap(), header);
+
+        for len in 0..buf.len() {
+            match Header::decode(&mut Cursor::new(&buf[..len])) {
+                Err(QuicError::UnexpectedEnd) => {}
+                res => panic!("unexpected result {:?} for {} bytes", res, len),
+            }
         }
+        assert!(Header::decode_with_cid_len(&mut Cursor::new(&[0x30; 32]), 19).is_err());
     }
 }
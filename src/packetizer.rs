@@ -0,0 +1,200 @@
+use bytes::BufMut;
+
+use std::collections::VecDeque;
+use std::io::Cursor;
+
+use codec::{BufLen, Codec};
+use frame::{Frame, PaddingFrame};
+use streams::Streams;
+
+pub struct Packetizer {
+    max_payload: usize,
+    budget: usize,
+    // Frames are encoded here as they're packed, so a frame only counts as fitting once it has
+    // actually been written within the payload's bounds
+    scratch: Vec<u8>,
+}
+
+impl Packetizer {
+    pub fn new(max_payload: usize, budget: usize) -> Self {
+        Self {
+            max_payload,
+            budget,
+            scratch: vec![0; max_payload],
+        }
+    }
+
+    pub fn next_packet(
+        &mut self,
+        control: &mut VecDeque<Frame>,
+        streams: &mut Streams,
+    ) -> Option<Vec<Frame>> {
+        if self.budget == 0 {
+            return None;
+        }
+
+        // Control frames (including retransmissions) go ahead of fresh stream data
+        let mut payload = Vec::new();
+        let mut oversized = false;
+        let mut write = Cursor::new(&mut self.scratch[..]);
+        while let Some(frame) = control.pop_front() {
+            match fit(frame, &mut write) {
+                Fit::Whole(frame) => payload.push(frame),
+                Fit::Split(head, tail) => {
+                    payload.push(head);
+                    control.push_front(tail);
+                    break;
+                }
+                // Frames that can't be split get a packet of their own rather than getting stuck
+                Fit::None(frame) if payload.is_empty() => {
+                    payload.push(frame);
+                    oversized = true;
+                    break;
+                }
+                Fit::None(frame) => {
+                    control.push_front(frame);
+                    break;
+                }
+            }
+        }
+
+        if !oversized {
+            while let Some(frame) = streams.queued() {
+                match fit(frame, &mut write) {
+                    Fit::Whole(frame) => payload.push(frame),
+                    Fit::Split(head, tail) => {
+                        payload.push(head);
+                        streams.requeue(tail);
+                        break;
+                    }
+                    Fit::None(frame) => {
+                        streams.requeue(frame);
+                        break;
+                    }
+                }
+            }
+        }
+
+        if payload.is_empty() {
+            return None;
+        }
+        let used = if oversized {
+            self.max_payload
+        } else {
+            write.position() as usize
+        };
+        self.budget = self.budget.saturating_sub(used);
+        Some(payload)
+    }
+}
+
+// Appends padding so the frames encode to at least `len` bytes
+pub fn pad_to(payload: &mut Vec<Frame>, len: usize) {
+    let current = payload.buf_len();
+    if current < len {
+        payload.push(Frame::Padding(PaddingFrame(len - current)));
+    }
+}
+
+enum Fit {
+    Whole(Frame),
+    Split(Frame, Frame),
+    None(Frame),
+}
+
+// Only stream frames can be cut down to what's left of the packet
+fn fit<T: BufMut>(frame: Frame, buf: &mut T) -> Fit {
+    if frame.try_encode(buf).is_ok() {
+        return Fit::Whole(frame);
+    }
+    let f = match frame {
+        Frame::Stream(f) => f,
+        frame => return Fit::None(frame),
+    };
+
+    let space = buf.remaining_mut();
+    let overhead = f.buf_len() - f.data.len();
+    if space <= overhead {
+        return Fit::None(Frame::Stream(f));
+    }
+    let mut head = f;
+    let tail = head.split_off(space - overhead);
+    let head = Frame::Stream(head);
+    // Shortening the data can shrink the length field, but never grow it
+    head.try_encode(buf).expect("split stream frame fits");
+    Fit::Split(head, Frame::Stream(tail))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{pad_to, Packetizer};
+    use codec::BufLen;
+    use frame::{Frame, MaxDataFrame, PaddingFrame, StreamFrame};
+    use streams::{Dir, Streams};
+    use types::{Side, StreamId};
+
+    use std::collections::VecDeque;
+
+    #[test]
+    fn test_packs_control_first_and_splits_streams() {
+        let mut streams = Streams::new(Side::Client);
+        streams.update_max_id(StreamId(0));
+        streams.update_max_id(StreamId(4));
+        streams.set_send_limits(1 << 20, 1 << 20);
+        let mut stream = streams.init_send(Dir::Bidi).unwrap();
+        assert_eq!(stream.write(&[7; 300]).unwrap(), 300);
+
+        let mut control = VecDeque::new();
+        control.push_back(Frame::MaxData(MaxDataFrame(1000)));
+
+        let mut packetizer = Packetizer::new(200, 1 << 20);
+        let first = packetizer.next_packet(&mut control, &mut streams).unwrap();
+        assert_eq!(first[0], Frame::MaxData(MaxDataFrame(1000)));
+        assert_eq!(first.buf_len(), 200);
+        let sent = match first[1] {
+            Frame::Stream(StreamFrame { ref data, fin, .. }) => {
+                assert!(!fin);
+                data.len()
+            }
+            _ => panic!("expected stream frame"),
+        };
+
+        let second = packetizer.next_packet(&mut control, &mut streams).unwrap();
+        match second[0] {
+            Frame::Stream(StreamFrame {
+                offset, ref data, ..
+            }) => {
+                assert_eq!(offset, sent as u64);
+                assert_eq!(sent + data.len(), 300);
+            }
+            _ => panic!("expected stream frame"),
+        }
+        assert_eq!(packetizer.next_packet(&mut control, &mut streams), None);
+    }
+
+    #[test]
+    fn test_congestion_budget() {
+        let mut streams = Streams::new(Side::Client);
+        let mut control = VecDeque::new();
+        for _ in 0..10 {
+            control.push_back(Frame::Ping);
+        }
+
+        let mut packetizer = Packetizer::new(4, 6);
+        assert_eq!(packetizer.next_packet(&mut control, &mut streams).unwrap().len(), 4);
+        assert_eq!(packetizer.next_packet(&mut control, &mut streams).unwrap().len(), 4);
+        assert_eq!(packetizer.next_packet(&mut control, &mut streams), None);
+        assert_eq!(control.len(), 2);
+    }
+
+    #[test]
+    fn test_pad_to() {
+        let mut payload = vec![Frame::Ping];
+        pad_to(&mut payload, 1200);
+        assert_eq!(payload, vec![Frame::Ping, Frame::Padding(PaddingFrame(1199))]);
+        assert_eq!(payload.buf_len(), 1200);
+
+        pad_to(&mut payload, 100);
+        assert_eq!(payload.len(), 2);
+    }
+}
@@ -1,7 +1,12 @@
 use bytes::{Buf, BufMut};
 
-use super::QUIC_VERSION;
-use codec::Codec;
+use std::cmp;
+use std::time::Duration;
+
+use super::{QuicError, QuicResult, QUIC_VERSION};
+use acks::DEFAULT_ACK_THRESHOLD;
+use codec::{BufExt, Codec};
+use recovery::DEFAULT_MAX_ACK_DELAY;
 
 #[derive(Clone, Debug, PartialEq)]
 pub struct ClientTransportParameters {
@@ -24,11 +29,11 @@ impl Codec for ClientTransportParameters {
         self.parameters.encode(buf);
     }
 
-    fn decode<T: Buf>(buf: &mut T) -> Self {
-        ClientTransportParameters {
-            initial_version: buf.get_u32_be(),
-            parameters: TransportParameters::decode(buf),
-        }
+    fn decode<T: Buf>(buf: &mut T) -> QuicResult<Self> {
+        Ok(ClientTransportParameters {
+            initial_version: buf.try_get_u32_be()?,
+            parameters: TransportParameters::decode(buf)?,
+        })
     }
 }
 
@@ -59,20 +64,21 @@ impl Codec for ServerTransportParameters {
         self.parameters.encode(buf);
     }
 
-    fn decode<T: Buf>(buf: &mut T) -> Self {
-        ServerTransportParameters {
-            negotiated_version: buf.get_u32_be(),
+    fn decode<T: Buf>(buf: &mut T) -> QuicResult<Self> {
+        Ok(ServerTransportParameters {
+            negotiated_version: buf.try_get_u32_be()?,
             supported_versions: {
                 let mut supported_versions = vec![];
-                let supported_bytes = buf.get_u8() as usize;
+                let supported_bytes = buf.try_get_u8()? as usize;
+                buf.check_remaining(supported_bytes)?;
                 let mut sub = buf.take(supported_bytes);
                 while sub.has_remaining() {
-                    supported_versions.push(sub.get_u32_be());
+                    supported_versions.push(sub.try_get_u32_be()?);
                 }
                 supported_versions
             },
-            parameters: TransportParameters::decode(buf),
-        }
+            parameters: TransportParameters::decode(buf)?,
+        })
     }
 }
 
@@ -99,13 +105,13 @@ impl Codec for TransportParameters {
         tmp.append(&mut val);
         val.truncate(0);
 
-        if self.max_streams_bidi > 0 {
-            tmp.put_u16_be(2);
-            val.put_u16_be(self.max_streams_bidi);
-            tmp.put_u16_be(val.len() as u16);
-            tmp.append(&mut val);
-            val.truncate(0);
-        }
+        // Stream limits always go out, since a peer missing them falls back to its own defaults
+        // rather than to zero
+        tmp.put_u16_be(2);
+        val.put_u16_be(self.max_streams_bidi);
+        tmp.put_u16_be(val.len() as u16);
+        tmp.append(&mut val);
+        val.truncate(0);
 
         if self.max_packet_size != 65527 {
             tmp.put_u16_be(5);
@@ -115,7 +121,7 @@ impl Codec for TransportParameters {
             val.truncate(0);
         }
 
-        if self.ack_delay_exponent != 3 {
+        if self.ack_delay_exponent != DEFAULT_ACK_DELAY_EXPONENT {
             tmp.put_u16_be(7);
             val.put_u8(self.ack_delay_exponent);
             tmp.put_u16_be(val.len() as u16);
@@ -123,9 +129,23 @@ impl Codec for TransportParameters {
             val.truncate(0);
         }
 
-        if self.max_stream_id_uni > 0 {
-            tmp.put_u16_be(8);
-            val.put_u16_be(self.max_stream_id_uni);
+        if u64::from(self.max_ack_delay) != DEFAULT_MAX_ACK_DELAY {
+            tmp.put_u16_be(0xb);
+            val.put_u8(self.max_ack_delay);
+            tmp.put_u16_be(val.len() as u16);
+            tmp.append(&mut val);
+            val.truncate(0);
+        }
+
+        tmp.put_u16_be(8);
+        val.put_u16_be(self.max_stream_id_uni);
+        tmp.put_u16_be(val.len() as u16);
+        tmp.append(&mut val);
+        val.truncate(0);
+
+        if self.max_datagram_frame_size > 0 {
+            tmp.put_u16_be(0x20);
+            val.put_u16_be(self.max_datagram_frame_size);
             tmp.put_u16_be(val.len() as u16);
             tmp.append(&mut val);
             val.truncate(0);
@@ -141,52 +161,77 @@ impl Codec for TransportParameters {
         buf.put_slice(&tmp);
     }
 
-    fn decode<T: Buf>(buf: &mut T) -> Self {
+    fn decode<T: Buf>(buf: &mut T) -> QuicResult<Self> {
         let mut params = TransportParameters::default();
-        let num = buf.get_u16_be();
+        let num = buf.try_get_u16_be()?;
+        buf.check_remaining(num as usize)?;
         let mut sub = buf.take(num as usize);
         while sub.has_remaining() {
-            let tag = sub.get_u16_be();
-            let size = sub.get_u16_be();
+            let tag = sub.try_get_u16_be()?;
+            let size = sub.try_get_u16_be()?;
+            let expected = match tag {
+                0 | 1 => 4,
+                2 | 3 | 5 | 8 | 0x20 => 2,
+                6 => 16,
+                7 | 0xb => 1,
+                _ => size,
+            };
+            if size != expected {
+                return Err(QuicError::InvalidEncoding(format!(
+                    "invalid size {} for transport parameter {}",
+                    size, tag
+                )));
+            }
+            sub.check_remaining(size as usize)?;
             match tag {
                 0 => {
-                    debug_assert_eq!(size, 4);
                     params.max_stream_data = sub.get_u32_be();
                 }
                 1 => {
-                    debug_assert_eq!(size, 4);
                     params.max_data = sub.get_u32_be();
                 }
                 2 => {
-                    debug_assert_eq!(size, 2);
                     params.max_streams_bidi = sub.get_u16_be();
                 }
                 3 => {
-                    debug_assert_eq!(size, 2);
                     params.idle_timeout = sub.get_u16_be();
                 }
                 5 => {
-                    debug_assert_eq!(size, 2);
                     params.max_packet_size = sub.get_u16_be();
+                    if params.max_packet_size < 1200 {
+                        return Err(QuicError::InvalidEncoding(format!(
+                            "invalid maximum packet size {}",
+                            params.max_packet_size
+                        )));
+                    }
                 }
                 6 => {
-                    debug_assert_eq!(size, 16);
                     let mut token = [0; 16];
                     sub.copy_to_slice(&mut token);
                     params.stateless_reset_token = Some(token);
                 }
                 7 => {
-                    debug_assert_eq!(size, 1);
                     params.ack_delay_exponent = sub.get_u8();
+                    if params.ack_delay_exponent > 20 {
+                        return Err(QuicError::InvalidEncoding(format!(
+                            "invalid ACK delay exponent {}",
+                            params.ack_delay_exponent
+                        )));
+                    }
                 }
                 8 => {
-                    debug_assert_eq!(size, 2);
                     params.max_stream_id_uni = sub.get_u16_be();
                 }
-                t => panic!("invalid transport parameter tag {}", t),
+                0xb => {
+                    params.max_ack_delay = sub.get_u8();
+                }
+                0x20 => {
+                    params.max_datagram_frame_size = sub.get_u16_be();
+                }
+                _ => sub.advance(size as usize),
             }
         }
-        params
+        Ok(params)
     }
 }
 
@@ -200,6 +245,8 @@ pub struct TransportParameters {
     pub stateless_reset_token: Option<[u8; 16]>, // 0x06
     pub ack_delay_exponent: u8,                  // 0x07
     pub max_stream_id_uni: u16,                  // 0x08
+    pub max_ack_delay: u8,                       // 0x0b
+    pub max_datagram_frame_size: u16,            // 0x20
 }
 
 impl Default for TransportParameters {
@@ -211,18 +258,206 @@ impl Default for TransportParameters {
             idle_timeout: 300,
             max_packet_size: 65_527,
             stateless_reset_token: None,
-            ack_delay_exponent: 3,
+            ack_delay_exponent: DEFAULT_ACK_DELAY_EXPONENT,
             max_stream_id_uni: 20,
+            max_ack_delay: DEFAULT_MAX_ACK_DELAY as u8,
+            max_datagram_frame_size: 0,
         }
     }
 }
 
+// Per-connection transport settings; most of them are advertised to the peer as transport
+// parameters
+#[derive(Clone, Debug)]
+pub struct TransportConfig {
+    params: TransportParameters,
+    keep_alive: Option<Duration>,
+    ack_threshold: usize,
+    stream_window_limit: Option<u64>,
+    handshake_timeout: Duration,
+}
+
+impl Default for TransportConfig {
+    fn default() -> Self {
+        Self {
+            params: TransportParameters::default(),
+            keep_alive: None,
+            ack_threshold: DEFAULT_ACK_THRESHOLD,
+            stream_window_limit: None,
+            handshake_timeout: Duration::from_secs(DEFAULT_HANDSHAKE_TIMEOUT),
+        }
+    }
+}
+
+impl TransportConfig {
+    pub fn idle_timeout(mut self, timeout: Duration) -> Self {
+        self.params.idle_timeout =
+            cmp::min(timeout.as_secs(), u64::from(u16::max_value())) as u16;
+        self
+    }
+
+    // Connections that haven't completed the handshake by then are aborted
+    pub fn handshake_timeout(mut self, timeout: Duration) -> Self {
+        self.handshake_timeout = timeout;
+        self
+    }
+
+    pub fn keep_alive_interval(mut self, interval: Duration) -> Self {
+        self.keep_alive = Some(interval);
+        self
+    }
+
+    pub fn max_stream_data(mut self, bytes: u32) -> Self {
+        self.params.max_stream_data = bytes;
+        self
+    }
+
+    // Lets stream windows start at max_stream_data and double, up to this size, whenever the
+    // application reads half a window within two round trips
+    pub fn max_stream_data_autotune(mut self, bytes: u64) -> Self {
+        self.stream_window_limit = Some(bytes);
+        self
+    }
+
+    pub fn max_data(mut self, bytes: u32) -> Self {
+        self.params.max_data = bytes;
+        self
+    }
+
+    pub fn max_concurrent_bidi_streams(mut self, count: u16) -> Self {
+        self.params.max_streams_bidi = count;
+        self
+    }
+
+    pub fn max_concurrent_uni_streams(mut self, count: u16) -> Self {
+        self.params.max_stream_id_uni = count;
+        self
+    }
+
+    pub fn ack_delay_exponent(mut self, exponent: u8) -> Self {
+        assert!(exponent <= 20, "invalid ACK delay exponent {}", exponent);
+        self.params.ack_delay_exponent = exponent;
+        self
+    }
+
+    // How long we may hold back acknowledgements, which the peer allows for in its RTT samples
+    pub fn max_ack_delay(mut self, delay: Duration) -> Self {
+        let millis = delay.as_secs() * 1000 + u64::from(delay.subsec_millis());
+        self.params.max_ack_delay = cmp::min(millis, u64::from(u8::max_value())) as u8;
+        self
+    }
+
+    // Every QUIC path has to carry 1200-byte datagrams, so anything smaller can't be honored
+    pub fn max_udp_payload_size(mut self, bytes: u16) -> Self {
+        assert!(bytes >= 1200, "invalid maximum UDP payload size {}", bytes);
+        self.params.max_packet_size = bytes;
+        self
+    }
+
+    // Acknowledge after this many ack-eliciting packets instead of waiting for the ACK delay
+    pub fn ack_frequency(mut self, packets: usize) -> Self {
+        assert!(packets > 0, "ACK frequency must be at least one packet");
+        self.ack_threshold = packets;
+        self
+    }
+
+    pub fn max_datagram_frame_size(mut self, bytes: u16) -> Self {
+        self.params.max_datagram_frame_size = bytes;
+        self
+    }
+
+    pub(crate) fn parameters(&self) -> TransportParameters {
+        self.params.clone()
+    }
+
+    pub(crate) fn keep_alive(&self) -> Option<Duration> {
+        self.keep_alive
+    }
+
+    pub(crate) fn ack_threshold(&self) -> usize {
+        self.ack_threshold
+    }
+
+    pub(crate) fn stream_window_limit(&self) -> Option<u64> {
+        self.stream_window_limit
+    }
+
+    pub(crate) fn handshake_timeout(&self) -> Duration {
+        self.handshake_timeout
+    }
+}
+
+pub const DEFAULT_ACK_DELAY_EXPONENT: u8 = 3;
+const DEFAULT_HANDSHAKE_TIMEOUT: u64 = 10;
+
 #[cfg(test)]
 mod tests {
-    use super::TransportParameters;
+    use super::{TransportConfig, TransportParameters};
     use super::{ClientTransportParameters, Codec, ServerTransportParameters};
+    use proptest::prelude::*;
     use std::fmt::Debug;
     use std::io::Cursor;
+    use std::time::Duration;
+
+    // Zero stream limits are drawn often, rather than once in 65536 cases, since they differ
+    // from the decoder's defaults
+    fn stream_limit() -> impl Strategy<Value = u16> {
+        prop_oneof![Just(0), any::<u16>()]
+    }
+
+    fn parameters() -> impl Strategy<Value = TransportParameters> {
+        (
+            any::<u32>(),
+            any::<u32>(),
+            stream_limit(),
+            any::<u16>(),
+            1200..u16::max_value(),
+            prop::option::of(any::<[u8; 16]>()),
+            0..21u8,
+            stream_limit(),
+            any::<u16>(),
+            any::<u8>(),
+        ).prop_map(|params| TransportParameters {
+            max_stream_data: params.0,
+            max_data: params.1,
+            max_streams_bidi: params.2,
+            idle_timeout: params.3,
+            max_packet_size: params.4,
+            stateless_reset_token: params.5,
+            ack_delay_exponent: params.6,
+            max_stream_id_uni: params.7,
+            max_datagram_frame_size: params.8,
+            max_ack_delay: params.9,
+        })
+    }
+
+    proptest! {
+        #[test]
+        fn test_transport_parameters_codec(
+            version in any::<u32>(),
+            supported_versions in prop::collection::vec(any::<u32>(), 0..8),
+            parameters in parameters(),
+        ) {
+            let mut buf = Vec::new();
+            let client = ClientTransportParameters {
+                initial_version: version,
+                parameters: parameters.clone(),
+            };
+            client.encode(&mut buf);
+            let decoded = ClientTransportParameters::decode(&mut Cursor::new(&buf)).unwrap();
+            prop_assert_eq!(decoded, client);
+
+            buf.clear();
+            let server = ServerTransportParameters {
+                negotiated_version: version,
+                supported_versions,
+                parameters,
+            };
+            server.encode(&mut buf);
+            let decoded = ServerTransportParameters::decode(&mut Cursor::new(&buf)).unwrap();
+            prop_assert_eq!(decoded, server);
+        }
+    }
 
     fn round_trip<T: Codec + PartialEq + Debug>(t: T) {
         let buf = {
@@ -231,7 +466,7 @@ mod tests {
             ret
         };
         let mut read = Cursor::new(&buf);
-        assert_eq!(t, T::decode(&mut read));
+        assert_eq!(t, T::decode(&mut read).unwrap());
     }
 
     #[test]
@@ -242,11 +477,66 @@ mod tests {
                 max_stream_data: 0,
                 max_data: 1234,
                 idle_timeout: 26,
+                max_datagram_frame_size: 1200,
                 ..Default::default()
             },
         });
     }
 
+    #[test]
+    fn test_no_peer_streams() {
+        let params = TransportConfig::default()
+            .max_concurrent_bidi_streams(0)
+            .max_concurrent_uni_streams(0)
+            .parameters();
+        let mut buf = Vec::new();
+        params.encode(&mut buf);
+        let decoded = TransportParameters::decode(&mut Cursor::new(&buf)).unwrap();
+        assert_eq!(decoded.max_streams_bidi, 0);
+        assert_eq!(decoded.max_stream_id_uni, 0);
+        round_trip(params);
+    }
+
+    #[test]
+    fn test_transport_config() {
+        let config = TransportConfig::default()
+            .idle_timeout(Duration::from_secs(30))
+            .max_data(1 << 24)
+            .max_concurrent_uni_streams(0)
+            .max_udp_payload_size(1452)
+            .keep_alive_interval(Duration::from_secs(10))
+            .ack_frequency(8)
+            .max_ack_delay(Duration::from_millis(40))
+            .max_stream_data_autotune(1 << 24);
+        let params = config.parameters();
+        assert_eq!(params.idle_timeout, 30);
+        assert_eq!(params.max_data, 1 << 24);
+        assert_eq!(params.max_stream_id_uni, 0);
+        assert_eq!(params.max_packet_size, 1452);
+        assert_eq!(params.max_ack_delay, 40);
+        assert_eq!(params.max_stream_data, TransportParameters::default().max_stream_data);
+        assert_eq!(config.keep_alive(), Some(Duration::from_secs(10)));
+        assert_eq!(config.ack_threshold(), 8);
+        assert_eq!(config.stream_window_limit(), Some(1 << 24));
+    }
+
+    #[test]
+    fn test_invalid_transport_parameters() {
+        let mut buf = Vec::new();
+        TransportParameters {
+            ack_delay_exponent: 21,
+            ..Default::default()
+        }.encode(&mut buf);
+        assert!(TransportParameters::decode(&mut Cursor::new(&buf)).is_err());
+
+        buf.clear();
+        TransportParameters {
+            max_packet_size: 1000,
+            ..Default::default()
+        }.encode(&mut buf);
+        assert!(TransportParameters::decode(&mut Cursor::new(&buf)).is_err());
+    }
+
     #[test]
     fn test_server_transport_parameters() {
         round_trip(ServerTransportParameters {
@@ -0,0 +1,167 @@
+pub struct PacketNumberSpace {
+    next: u64,
+    largest_acked: Option<u64>,
+    largest_received: Option<u64>,
+    // Bit n is set once largest_received - n has arrived
+    window: u64,
+}
+
+impl PacketNumberSpace {
+    pub fn new(initial: u64) -> Self {
+        Self {
+            next: initial,
+            largest_acked: None,
+            largest_received: None,
+            window: 0,
+        }
+    }
+
+    pub fn take_next(&mut self) -> u64 {
+        let number = self.next;
+        self.next += 1;
+        number
+    }
+
+    pub fn peek_next(&self) -> u64 {
+        self.next
+    }
+
+    pub fn on_ack(&mut self, largest: u64) {
+        if largest < self.next && self.largest_acked.map_or(true, |acked| largest > acked) {
+            self.largest_acked = Some(largest);
+        }
+    }
+
+    pub fn on_receive(&mut self, number: u64) {
+        match self.largest_received {
+            Some(largest) if number <= largest => {
+                if largest - number < REPLAY_WINDOW {
+                    self.window |= 1 << (largest - number);
+                }
+            }
+            Some(largest) => {
+                let shift = number - largest;
+                self.window = if shift < REPLAY_WINDOW {
+                    self.window << shift | 1
+                } else {
+                    1
+                };
+                self.largest_received = Some(number);
+            }
+            None => {
+                self.window = 1;
+                self.largest_received = Some(number);
+            }
+        }
+    }
+
+    // Anything older than the window can't be told apart from a replay, so it's treated as one
+    pub fn is_duplicate(&self, number: u64) -> bool {
+        let largest = match self.largest_received {
+            Some(largest) if number <= largest => largest,
+            _ => return false,
+        };
+        let age = largest - number;
+        age >= REPLAY_WINDOW || self.window & (1 << age) != 0
+    }
+
+    pub fn largest_received(&self) -> Option<u64> {
+        self.largest_received
+    }
+
+    pub fn encoded_len(&self, number: u64) -> usize {
+        match self.largest_acked {
+            Some(acked) => encoded_len(number - acked),
+            None => 4,
+        }
+    }
+
+    pub fn expand(&self, truncated: u64, len: usize) -> u64 {
+        let expected = self.largest_received.map_or(0, |largest| largest + 1);
+        expand(expected, truncated, len * 8)
+    }
+}
+
+const REPLAY_WINDOW: u64 = 64;
+
+pub fn encoded_len(unacked: u64) -> usize {
+    // The encoding must cover more than twice the number of packets in flight
+    if unacked < 1 << 7 {
+        1
+    } else if unacked < 1 << 15 {
+        2
+    } else {
+        4
+    }
+}
+
+pub fn expand(expected: u64, truncated: u64, bits: usize) -> u64 {
+    let win = 1u64 << bits;
+    let hwin = win / 2;
+    let mask = win - 1;
+    let candidate = (expected & !mask) | truncated;
+    if candidate + hwin <= expected {
+        candidate + win
+    } else if candidate > expected + hwin && candidate >= win {
+        candidate - win
+    } else {
+        candidate
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{expand, PacketNumberSpace};
+
+    #[test]
+    fn test_expand() {
+        assert_eq!(expand(0xa82f_30eb, 0x9b32, 16), 0xa82f_9b32);
+        assert_eq!(expand(0x1_0000_00ff, 0x01, 8), 0x1_0000_0101);
+        assert_eq!(expand(0x1_0000_0001, 0xff, 8), 0xffff_ffff);
+        assert_eq!(expand(0, 0x1234_5678, 32), 0x1234_5678);
+    }
+
+    #[test]
+    fn test_replay_window() {
+        let mut space = PacketNumberSpace::new(0);
+        assert!(!space.is_duplicate(5));
+        for number in &[5, 3, 7] {
+            space.on_receive(*number);
+        }
+        assert!(space.is_duplicate(5));
+        assert!(space.is_duplicate(7));
+        assert!(!space.is_duplicate(4));
+        assert!(!space.is_duplicate(6));
+        assert!(!space.is_duplicate(8));
+
+        space.on_receive(70);
+        assert!(space.is_duplicate(7));
+        assert!(!space.is_duplicate(8));
+        // Too old to remember either way
+        assert!(space.is_duplicate(6));
+
+        space.on_receive(1000);
+        assert!(space.is_duplicate(70));
+        assert!(!space.is_duplicate(999));
+    }
+
+    #[test]
+    fn test_truncation_round_trip() {
+        let mut sender = PacketNumberSpace::new(0x00ff_fff0);
+        let mut receiver = PacketNumberSpace::new(0);
+        let first = sender.take_next();
+        receiver.on_receive(first);
+        sender.on_ack(first);
+
+        for _ in 0..1000 {
+            let number = sender.take_next();
+            let len = sender.encoded_len(number);
+            let truncated = number & ((1 << (len * 8)) - 1);
+            assert_eq!(receiver.expand(truncated, len), number);
+            receiver.on_receive(number);
+            if number % 200 == 0 {
+                sender.on_ack(number);
+            }
+        }
+    }
+}
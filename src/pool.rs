@@ -0,0 +1,78 @@
+use std::sync::{Arc, Mutex};
+
+// Enough for a full send and receive batch to be in flight at once
+pub const DEFAULT_POOL_SIZE: usize = 256;
+
+// Recycles datagram buffers so that steady-state packet processing doesn't allocate
+#[derive(Clone)]
+pub struct BufferPool {
+    buffers: Arc<Mutex<Vec<Vec<u8>>>>,
+    max_buffers: usize,
+}
+
+impl BufferPool {
+    pub fn new(max_buffers: usize) -> Self {
+        Self {
+            buffers: Arc::new(Mutex::new(Vec::with_capacity(max_buffers))),
+            max_buffers,
+        }
+    }
+
+    pub fn get(&self, len: usize) -> Vec<u8> {
+        let mut buf = self.buffers.lock().unwrap().pop().unwrap_or_default();
+        buf.clear();
+        buf.resize(len, 0);
+        buf
+    }
+
+    pub fn copy_of(&self, data: &[u8]) -> Vec<u8> {
+        let mut buf = self.buffers.lock().unwrap().pop().unwrap_or_default();
+        buf.clear();
+        buf.extend_from_slice(data);
+        buf
+    }
+
+    pub fn put(&self, buf: Vec<u8>) {
+        let mut buffers = self.buffers.lock().unwrap();
+        if buffers.len() < self.max_buffers {
+            buffers.push(buf);
+        }
+    }
+}
+
+impl Default for BufferPool {
+    fn default() -> Self {
+        Self::new(DEFAULT_POOL_SIZE)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::BufferPool;
+
+    #[test]
+    fn test_reuse() {
+        let pool = BufferPool::new(1);
+        let buf = pool.get(1200);
+        let ptr = buf.as_ptr();
+        pool.put(buf);
+
+        let buf = pool.copy_of(b"hello");
+        assert_eq!(buf.as_ptr(), ptr);
+        assert_eq!(&buf[..], b"hello");
+        pool.put(buf);
+
+        let buf = pool.get(3);
+        assert_eq!(buf.as_ptr(), ptr);
+        assert_eq!(buf, vec![0; 3]);
+    }
+
+    #[test]
+    fn test_limit() {
+        let pool = BufferPool::new(1);
+        let (first, second) = (pool.get(10), pool.get(10));
+        pool.put(first);
+        pool.put(second);
+        assert_eq!(pool.buffers.lock().unwrap().len(), 1);
+    }
+}
@@ -0,0 +1,344 @@
+use std::fmt::Write as FmtWrite;
+use std::io::Write;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use codec::BufLen;
+use frame::Frame;
+use packet::{Header, LongType};
+use recovery::Recovery;
+use types::{ConnectionId, Side};
+
+pub type QlogFactory = Arc<Fn(Side, &[u8]) -> Option<Box<Write + Send>> + Send + Sync>;
+
+// Writes a qlog trace in the JSON-SEQ serialization, which qvis can load directly
+pub struct Qlog {
+    out: Box<Write + Send>,
+    start: Instant,
+    metrics: Option<Metrics>,
+    failed: bool,
+}
+
+impl Qlog {
+    pub fn new(out: Box<Write + Send>, side: Side, cid: &ConnectionId) -> Self {
+        let mut qlog = Self {
+            out,
+            start: Instant::now(),
+            metrics: None,
+            failed: false,
+        };
+        let vantage = match side {
+            Side::Client => "client",
+            Side::Server => "server",
+        };
+        let header = format!(
+            "{{\"qlog_version\":\"0.3\",\"qlog_format\":\"JSON-SEQ\",\"trace\":{{\
+             \"vantage_point\":{{\"type\":\"{}\"}},\"common_fields\":{{\"group_id\":\"{}\"}}}}}}",
+            vantage,
+            hex(cid)
+        );
+        qlog.record(&header);
+        qlog
+    }
+
+    pub fn packet_sent(&mut self, header: &Header, size: usize, frames: &[Frame]) {
+        let data = packet(header, size, frames);
+        self.event("transport:packet_sent", &data);
+    }
+
+    pub fn packet_received(&mut self, header: &Header, size: usize, frames: &[Frame]) {
+        let data = packet(header, size, frames);
+        self.event("transport:packet_received", &data);
+    }
+
+    pub fn packet_lost(&mut self, ptype: Option<LongType>, number: u64) {
+        let data = format!(
+            "{{\"header\":{{\"packet_type\":\"{}\",\"packet_number\":{}}}}}",
+            packet_type(ptype),
+            number
+        );
+        self.event("recovery:packet_lost", &data);
+    }
+
+    pub fn metrics_updated(&mut self, recovery: &Recovery) {
+        let metrics = Metrics {
+            window: recovery.window(),
+            bytes_in_flight: recovery.bytes_in_flight(),
+            smoothed_rtt: recovery.rtt(),
+            latest_rtt: recovery.latest_rtt(),
+            rtt_variance: recovery.rtt_var(),
+        };
+        // Only changes are interesting, and acks often leave everything as it was
+        if self.metrics.as_ref() == Some(&metrics) {
+            return;
+        }
+        let data = format!(
+            "{{\"congestion_window\":{},\"bytes_in_flight\":{},\"smoothed_rtt\":{},\
+             \"latest_rtt\":{},\"rtt_variance\":{}}}",
+            metrics.window,
+            metrics.bytes_in_flight,
+            millis(metrics.smoothed_rtt),
+            millis(metrics.latest_rtt),
+            millis(metrics.rtt_variance)
+        );
+        self.metrics = Some(metrics);
+        self.event("recovery:metrics_updated", &data);
+    }
+
+    fn event(&mut self, name: &str, data: &str) {
+        let event = format!(
+            "{{\"time\":{},\"name\":\"{}\",\"data\":{}}}",
+            millis(self.start.elapsed()),
+            name,
+            data
+        );
+        self.record(&event);
+    }
+
+    fn record(&mut self, json: &str) {
+        if self.failed {
+            return;
+        }
+        // Each JSON-SEQ record starts with an ASCII record separator
+        let res = self.out
+            .write_all(b"\x1e")
+            .and_then(|_| self.out.write_all(json.as_bytes()))
+            .and_then(|_| self.out.write_all(b"\n"));
+        if let Err(e) = res {
+            debug!("disabling qlog after write failure: {}", e);
+            self.failed = true;
+        }
+    }
+}
+
+#[derive(Debug, PartialEq)]
+struct Metrics {
+    window: usize,
+    bytes_in_flight: usize,
+    smoothed_rtt: Duration,
+    latest_rtt: Duration,
+    rtt_variance: Duration,
+}
+
+fn packet(header: &Header, size: usize, frames: &[Frame]) -> String {
+    let mut out = format!(
+        "{{\"header\":{{\"packet_type\":\"{}\",\"packet_number\":{}}},\
+         \"raw\":{{\"length\":{}}},\"frames\":[",
+        packet_type(header.ptype()),
+        header.number(),
+        size
+    );
+    for (i, frame) in frames.iter().enumerate() {
+        if i > 0 {
+            out.push(',');
+        }
+        out.push_str(&self::frame(frame));
+    }
+    out.push_str("]}");
+    out
+}
+
+fn packet_type(ptype: Option<LongType>) -> &'static str {
+    match ptype {
+        Some(LongType::Initial) => "initial",
+        Some(LongType::Retry) => "retry",
+        Some(LongType::Handshake) => "handshake",
+        Some(LongType::Protected) => "0RTT",
+        None => "1RTT",
+    }
+}
+
+fn frame(frame: &Frame) -> String {
+    match frame {
+        Frame::Ack(f) => {
+            let ranges = f.ranges()
+                .iter()
+                .map(|&(smallest, largest)| format!("[{},{}]", smallest, largest))
+                .collect::<Vec<_>>()
+                .join(",");
+            format!(
+                "{{\"frame_type\":\"ack\",\"ack_delay\":{},\"acked_ranges\":[{}]}}",
+                f.ack_delay, ranges
+            )
+        }
+        Frame::ApplicationClose(f) => format!(
+            "{{\"frame_type\":\"connection_close\",\"error_space\":\"application\",\
+             \"error_code\":{},\"reason\":{}}}",
+            f.code,
+            string(&f.reason)
+        ),
+        Frame::ConnectionClose(f) => format!(
+            "{{\"frame_type\":\"connection_close\",\"error_space\":\"transport\",\
+             \"error_code\":{},\"reason\":{}}}",
+            f.code,
+            string(&f.reason)
+        ),
+        Frame::Crypto(f) => format!(
+            "{{\"frame_type\":\"crypto\",\"offset\":{},\"length\":{}}}",
+            f.offset,
+            f.data.len()
+        ),
+        Frame::Datagram(f) => format!(
+            "{{\"frame_type\":\"datagram\",\"length\":{}}}",
+            f.0.len()
+        ),
+        Frame::MaxData(f) => format!("{{\"frame_type\":\"max_data\",\"maximum\":{}}}", f.0),
+        Frame::MaxStreamData(f) => format!(
+            "{{\"frame_type\":\"max_stream_data\",\"stream_id\":{},\"maximum\":{}}}",
+            f.id, f.max
+        ),
+        Frame::MaxStreamId(f) => format!(
+            "{{\"frame_type\":\"max_stream_id\",\"maximum\":{}}}",
+            f.0
+        ),
+        Frame::Padding(f) => format!(
+            "{{\"frame_type\":\"padding\",\"length\":{}}}",
+            f.buf_len()
+        ),
+        Frame::RetireConnectionId(f) => format!(
+            "{{\"frame_type\":\"retire_connection_id\",\"sequence_number\":{}}}",
+            f.0
+        ),
+        Frame::RstStream(f) => format!(
+            "{{\"frame_type\":\"reset_stream\",\"stream_id\":{},\"error_code\":{},\
+             \"final_size\":{}}}",
+            f.id, f.error_code, f.final_offset
+        ),
+        Frame::StopSending(f) => format!(
+            "{{\"frame_type\":\"stop_sending\",\"stream_id\":{},\"error_code\":{}}}",
+            f.id, f.error_code
+        ),
+        Frame::Stream(f) => format!(
+            "{{\"frame_type\":\"stream\",\"stream_id\":{},\"offset\":{},\"length\":{},\
+             \"fin\":{}}}",
+            f.id,
+            f.offset,
+            f.data.len(),
+            f.fin
+        ),
+        frame => format!("{{\"frame_type\":\"{}\"}}", frame_type(frame)),
+    }
+}
+
+fn frame_type(frame: &Frame) -> &'static str {
+    match frame {
+        Frame::Ack(_) => "ack",
+        Frame::ApplicationClose(_) | Frame::ConnectionClose(_) => "connection_close",
+        Frame::Blocked(_) => "data_blocked",
+        Frame::Crypto(_) => "crypto",
+        Frame::Datagram(_) => "datagram",
+        Frame::MaxData(_) => "max_data",
+        Frame::MaxStreamData(_) => "max_stream_data",
+        Frame::MaxStreamId(_) => "max_stream_id",
+        Frame::NewConnectionId(_) => "new_connection_id",
+        Frame::NewToken(_) => "new_token",
+        Frame::Padding(_) => "padding",
+        Frame::PathChallenge(_) => "path_challenge",
+        Frame::PathResponse(_) => "path_response",
+        Frame::Ping => "ping",
+        Frame::RetireConnectionId(_) => "retire_connection_id",
+        Frame::RstStream(_) => "reset_stream",
+        Frame::StopSending(_) => "stop_sending",
+        Frame::Stream(_) => "stream",
+        Frame::StreamBlocked(_) => "stream_data_blocked",
+        Frame::StreamIdBlocked(_) => "stream_id_blocked",
+    }
+}
+
+fn millis(duration: Duration) -> f64 {
+    duration.as_secs() as f64 * 1000.0 + f64::from(duration.subsec_nanos()) / 1_000_000.0
+}
+
+fn hex(bytes: &[u8]) -> String {
+    let mut out = String::with_capacity(bytes.len() * 2);
+    for b in bytes {
+        write!(out, "{:02x}", b).unwrap();
+    }
+    out
+}
+
+fn string(s: &str) -> String {
+    let mut out = String::with_capacity(s.len() + 2);
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if (c as u32) < 0x20 => write!(out, "\\u{:04x}", c as u32).unwrap(),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Qlog;
+    use bytes::Bytes;
+    use frame::{CloseFrame, Frame, StreamFrame};
+    use packet::{Header, ShortType};
+    use types::{ConnectionId, Side, StreamId};
+
+    use std::io::{self, Write};
+    use std::sync::{Arc, Mutex};
+
+    #[derive(Clone)]
+    struct Shared(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for Shared {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_packet_sent() {
+        let out = Shared(Arc::new(Mutex::new(Vec::new())));
+        let cid = ConnectionId::new(&[1, 2, 3, 4]);
+        let mut qlog = Qlog::new(Box::new(out.clone()), Side::Client, &cid);
+        let header = Header::Short {
+            key_phase: false,
+            spin: false,
+            ptype: ShortType::Two,
+            dst_cid: cid,
+            number: 7,
+        };
+        let frames = vec![
+            Frame::Stream(StreamFrame {
+                id: StreamId(4),
+                fin: true,
+                offset: 0,
+                len: None,
+                data: Bytes::from_static(b"hello"),
+            }),
+            Frame::ConnectionClose(CloseFrame {
+                code: 0,
+                reason: "said \"bye\"".into(),
+            }),
+        ];
+        qlog.packet_sent(&header, 42, &frames);
+
+        let written = String::from_utf8(out.0.lock().unwrap().clone()).unwrap();
+        let records = written.split('\x1e').skip(1).collect::<Vec<_>>();
+        assert_eq!(records.len(), 2);
+        assert!(records[0].contains("\"vantage_point\":{\"type\":\"client\"}"));
+        assert!(records[0].contains("\"group_id\":\"01020304\""));
+        assert!(records[1].contains("\"name\":\"transport:packet_sent\""));
+        assert!(records[1].contains(
+            "\"header\":{\"packet_type\":\"1RTT\",\"packet_number\":7},\"raw\":{\"length\":42}"
+        ));
+        assert!(records[1].contains(
+            "{\"frame_type\":\"stream\",\"stream_id\":4,\"offset\":0,\"length\":5,\"fin\":true}"
+        ));
+        assert!(records[1].contains("\"reason\":\"said \\\"bye\\\"\""));
+        assert!(records.iter().all(|record| record.ends_with('\n')));
+    }
+}
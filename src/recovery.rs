@@ -0,0 +1,644 @@
+use std::cmp;
+use std::collections::BTreeMap;
+use std::mem;
+use std::time::{Duration, Instant};
+
+use congestion::{nanos, CongestionController, NANOS_PER_SEC};
+use frame::{AckFrame, EcnCounts, Frame};
+use packet::LongType;
+use parameters::DEFAULT_ACK_DELAY_EXPONENT;
+use socket::EcnCodepoint;
+
+pub struct Recovery {
+    sent: BTreeMap<u64, SentPacket>,
+    largest_acked: Option<u64>,
+    rtt: RttEstimator,
+    max_ack_delay: Duration,
+    ack_delay_exponent: u8,
+    loss_time: Option<Instant>,
+    last_ack_eliciting: Option<Instant>,
+    pto_count: u32,
+    bytes_in_flight: usize,
+    congestion: Box<CongestionController>,
+    prev_path: Option<(Box<CongestionController>, RttEstimator)>,
+    ecn: EcnState,
+    ecn_counts: EcnCounts,
+}
+
+impl Recovery {
+    pub fn new(congestion: Box<CongestionController>) -> Self {
+        Self {
+            sent: BTreeMap::new(),
+            largest_acked: None,
+            rtt: RttEstimator::new(),
+            max_ack_delay: Duration::from_millis(DEFAULT_MAX_ACK_DELAY),
+            ack_delay_exponent: DEFAULT_ACK_DELAY_EXPONENT,
+            loss_time: None,
+            last_ack_eliciting: None,
+            pto_count: 0,
+            bytes_in_flight: 0,
+            congestion,
+            prev_path: None,
+            ecn: EcnState::Testing,
+            ecn_counts: EcnCounts::default(),
+        }
+    }
+
+    // Both come from the peer's transport parameters, since it's the one delaying its ACKs
+    pub fn set_ack_delay(&mut self, exponent: u8, max: Duration) {
+        self.ack_delay_exponent = exponent;
+        self.max_ack_delay = max;
+    }
+
+    pub fn can_send(&self) -> bool {
+        self.bytes_in_flight < self.congestion.window()
+    }
+
+    pub fn send_budget(&self) -> usize {
+        self.congestion.window().saturating_sub(self.bytes_in_flight)
+    }
+
+    pub fn pacing_rate(&self) -> u64 {
+        self.congestion.pacing_rate().unwrap_or_else(|| {
+            let rtt = cmp::max(nanos(self.rtt()), 1);
+            self.congestion.window() as u64 * 5 / 4 * NANOS_PER_SEC / rtt
+        })
+    }
+
+    pub fn bytes_in_flight(&self) -> usize {
+        self.bytes_in_flight
+    }
+
+    pub fn rtt(&self) -> Duration {
+        self.rtt.smoothed.unwrap_or_else(|| Duration::from_millis(INITIAL_RTT))
+    }
+
+    pub fn latest_rtt(&self) -> Duration {
+        self.rtt.latest
+    }
+
+    pub fn rtt_var(&self) -> Duration {
+        self.rtt.var
+    }
+
+    pub fn window(&self) -> usize {
+        self.congestion.window()
+    }
+
+    pub fn in_flight(&self) -> usize {
+        self.sent.len()
+    }
+
+    // Packets stay ECN-capable unless the path turned out to mangle the marks
+    pub fn ecn_codepoint(&self) -> Option<EcnCodepoint> {
+        match self.ecn {
+            EcnState::Failed => None,
+            EcnState::Testing | EcnState::Capable => Some(EcnCodepoint::Ect0),
+        }
+    }
+
+    pub fn on_packet_sent(&mut self, number: u64, mut packet: SentPacket) {
+        packet.ecn = self.ecn_codepoint().is_some();
+        if packet.ack_eliciting {
+            self.last_ack_eliciting = Some(packet.time);
+            self.bytes_in_flight += packet.size;
+            self.congestion.on_packet_sent(packet.time, packet.size);
+        }
+        self.sent.insert(number, packet);
+    }
+
+    // Probes are sized to find out whether the path drops them, so losing one says nothing
+    // about congestion
+    pub fn on_mtu_probe_sent(&mut self, number: u64) {
+        if let Some(packet) = self.sent.get_mut(&number) {
+            packet.mtu_probe = true;
+        }
+    }
+
+    pub fn on_ack_received(
+        &mut self,
+        ack: &AckFrame,
+        now: Instant,
+    ) -> (Vec<SentPacket>, Vec<SentPacket>) {
+        if self.largest_acked.map_or(true, |largest| ack.largest > largest) {
+            self.largest_acked = Some(ack.largest);
+            if let Some(packet) = self.sent.get(&ack.largest) {
+                if packet.ack_eliciting {
+                    let micros = ack.ack_delay.saturating_mul(1 << self.ack_delay_exponent);
+                    let ack_delay = Duration::from_micros(micros);
+                    self.rtt
+                        .update(now - packet.time, cmp::min(ack_delay, self.max_ack_delay));
+                }
+            }
+        }
+
+        let mut newly_acked = Vec::new();
+        for (smallest, largest) in ack.ranges() {
+            let acked = self.sent
+                .range(smallest..=largest)
+                .map(|(&number, _)| number)
+                .collect::<Vec<_>>();
+            for number in acked {
+                if let Some(packet) = self.sent.remove(&number) {
+                    if packet.ack_eliciting {
+                        self.bytes_in_flight -= packet.size;
+                        let rtt = self.rtt();
+                        self.congestion.on_ack(now, packet.time, packet.size, rtt);
+                    }
+                    newly_acked.push(packet);
+                }
+            }
+        }
+
+        if !newly_acked.is_empty() {
+            self.pto_count = 0;
+        }
+        self.process_ecn(ack.ecn.as_ref(), &newly_acked, now);
+        (newly_acked, self.detect_lost(now))
+    }
+
+    fn process_ecn(
+        &mut self,
+        counts: Option<&EcnCounts>,
+        newly_acked: &[SentPacket],
+        now: Instant,
+    ) {
+        let ect_acked = newly_acked.iter().filter(|packet| packet.ecn).count() as u64;
+        if self.ecn == EcnState::Failed || ect_acked == 0 {
+            return;
+        }
+        // Every newly acknowledged ECT packet has to show up in the peer's counts, or
+        // something on the path is clearing or rewriting the marks
+        let counts = match counts {
+            Some(counts) => *counts,
+            None => {
+                debug!("disabling ECN: acknowledgement without ECN counts");
+                self.ecn = EcnState::Failed;
+                return;
+            }
+        };
+        let (old, new) = (self.ecn_counts, counts);
+        if new.ect0 < old.ect0 || new.ce < old.ce
+            || (new.ect0 - old.ect0) + (new.ce - old.ce) < ect_acked
+        {
+            debug!("disabling ECN: counts {:?} don't cover {} acked packets", new, ect_acked);
+            self.ecn = EcnState::Failed;
+            return;
+        }
+        self.ecn = EcnState::Capable;
+        self.ecn_counts = new;
+
+        // CE marks signal congestion just like loss does, without the retransmissions
+        if new.ce > old.ce {
+            let sent = newly_acked
+                .iter()
+                .max_by_key(|packet| packet.number)
+                .map(|packet| packet.time);
+            if let Some(sent) = sent {
+                self.congestion.on_loss(now, sent, 0);
+            }
+        }
+    }
+
+    // Whether an ACK covers any 0-RTT or 1-RTT packets we still know about
+    pub fn acks_application_data(&self, ack: &AckFrame) -> bool {
+        ack.ranges().into_iter().any(|(smallest, largest)| {
+            self.sent
+                .range(smallest..=largest)
+                .any(|(_, packet)| match packet.ptype {
+                    None | Some(LongType::Protected) => true,
+                    _ => false,
+                })
+        })
+    }
+
+    // Probing without anything in flight keeps a handshaking client from waiting forever on a
+    // server that is held back by the amplification limit
+    pub fn timeout(&self, keep_probing: bool) -> Option<Instant> {
+        if self.loss_time.is_some() {
+            return self.loss_time;
+        }
+        if !keep_probing && !self.sent.values().any(|packet| packet.ack_eliciting) {
+            return None;
+        }
+        self.last_ack_eliciting
+            .map(|sent| sent + self.pto() * 2u32.pow(cmp::min(self.pto_count, MAX_PTO_BACKOFF)))
+    }
+
+    pub fn pto_count(&self) -> u32 {
+        self.pto_count
+    }
+
+    pub fn on_timeout(&mut self, now: Instant) -> Vec<SentPacket> {
+        if self.loss_time.map_or(false, |time| time <= now) {
+            return self.detect_lost(now);
+        }
+
+        // Probe with the oldest outstanding data; if it wasn't lost after all,
+        // the peer just receives it twice
+        self.pto_count += 1;
+        let oldest = self.sent
+            .iter()
+            .find(|&(_, packet)| packet.ack_eliciting)
+            .map(|(&number, _)| number);
+        let probe = oldest.and_then(|number| self.sent.remove(&number));
+        if let Some(ref packet) = probe {
+            self.bytes_in_flight -= packet.size;
+        }
+        probe.into_iter().collect()
+    }
+
+    // For packets the peer will never acknowledge, such as rejected 0-RTT; unlike losses, these
+    // say nothing about congestion
+    pub fn discard(&mut self, ptype: LongType) -> Vec<SentPacket> {
+        let numbers = self.sent
+            .iter()
+            .filter(|&(_, packet)| packet.ptype == Some(ptype))
+            .map(|(&number, _)| number)
+            .collect::<Vec<_>>();
+        let discarded = numbers
+            .into_iter()
+            .filter_map(|number| self.sent.remove(&number))
+            .collect::<Vec<_>>();
+        for packet in discarded.iter().filter(|packet| packet.ack_eliciting) {
+            self.bytes_in_flight -= packet.size;
+        }
+        discarded
+    }
+
+    // Nothing learned about the old path says anything about the new one, but it's kept around
+    // in case the new path fails validation
+    pub fn new_path(&mut self, congestion: Box<CongestionController>) {
+        let congestion = mem::replace(&mut self.congestion, congestion);
+        let rtt = mem::replace(&mut self.rtt, RttEstimator::new());
+        if self.prev_path.is_none() {
+            self.prev_path = Some((congestion, rtt));
+        }
+    }
+
+    pub fn path_validated(&mut self) {
+        self.prev_path = None;
+    }
+
+    pub fn restore_path(&mut self) {
+        if let Some((congestion, rtt)) = self.prev_path.take() {
+            self.congestion = congestion;
+            self.rtt = rtt;
+        }
+    }
+
+    pub fn pto(&self) -> Duration {
+        let var = cmp::max(self.rtt.var * 4, Duration::from_millis(GRANULARITY));
+        self.rtt() + var + self.max_ack_delay
+    }
+
+    fn detect_lost(&mut self, now: Instant) -> Vec<SentPacket> {
+        self.loss_time = None;
+        let largest_acked = match self.largest_acked {
+            Some(largest) => largest,
+            None => return Vec::new(),
+        };
+
+        let rtt = cmp::max(self.rtt.latest, self.rtt());
+        let loss_delay = cmp::max(rtt * 9 / 8, Duration::from_millis(GRANULARITY));
+
+        let mut lost = Vec::new();
+        for (&number, packet) in self.sent.range(..largest_acked) {
+            if number + PACKET_THRESHOLD <= largest_acked || packet.time + loss_delay <= now {
+                lost.push(number);
+            } else {
+                let time = packet.time + loss_delay;
+                if self.loss_time.map_or(true, |loss_time| time < loss_time) {
+                    self.loss_time = Some(time);
+                }
+            }
+        }
+
+        let lost = lost.into_iter()
+            .filter_map(|number| self.sent.remove(&number))
+            .collect::<Vec<_>>();
+        for packet in lost.iter().filter(|packet| packet.ack_eliciting) {
+            self.bytes_in_flight -= packet.size;
+            if !packet.mtu_probe {
+                self.congestion.on_loss(now, packet.time, packet.size);
+            }
+        }
+        lost
+    }
+}
+
+pub struct SentPacket {
+    pub number: u64,
+    pub ptype: Option<LongType>,
+    pub time: Instant,
+    pub size: usize,
+    pub ack_eliciting: bool,
+    pub ecn: bool,
+    pub mtu_probe: bool,
+    pub frames: Vec<Frame>,
+}
+
+impl SentPacket {
+    pub fn new(
+        number: u64,
+        ptype: Option<LongType>,
+        time: Instant,
+        size: usize,
+        payload: Vec<Frame>,
+    ) -> Self {
+        let ack_eliciting = payload.iter().any(Frame::is_ack_eliciting);
+        let frames = payload
+            .into_iter()
+            .filter(|frame| match frame {
+                Frame::Ack(_) | Frame::Padding(_) | Frame::Ping => false,
+                _ => true,
+            })
+            .collect();
+        Self {
+            number,
+            ptype,
+            time,
+            size,
+            ack_eliciting,
+            ecn: false,
+            mtu_probe: false,
+            frames,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum EcnState {
+    Testing,
+    Capable,
+    Failed,
+}
+
+struct RttEstimator {
+    latest: Duration,
+    smoothed: Option<Duration>,
+    var: Duration,
+    min: Option<Duration>,
+}
+
+impl RttEstimator {
+    fn new() -> Self {
+        Self {
+            latest: Duration::from_millis(INITIAL_RTT),
+            smoothed: None,
+            var: Duration::from_millis(INITIAL_RTT / 2),
+            min: None,
+        }
+    }
+
+    fn update(&mut self, sample: Duration, ack_delay: Duration) {
+        self.latest = sample;
+        let min = cmp::min(self.min.unwrap_or(sample), sample);
+        self.min = Some(min);
+
+        // Only subtract the peer's delay if that doesn't take us below the minimum
+        let adjusted = if sample > min + ack_delay {
+            sample - ack_delay
+        } else {
+            sample
+        };
+
+        match self.smoothed {
+            None => {
+                self.smoothed = Some(adjusted);
+                self.var = adjusted / 2;
+            }
+            Some(smoothed) => {
+                let diff = if smoothed > adjusted {
+                    smoothed - adjusted
+                } else {
+                    adjusted - smoothed
+                };
+                self.var = (self.var * 3 + diff) / 4;
+                self.smoothed = Some((smoothed * 7 + adjusted) / 8);
+            }
+        }
+    }
+}
+
+const PACKET_THRESHOLD: u64 = 3;
+const GRANULARITY: u64 = 1;
+const INITIAL_RTT: u64 = 100;
+pub const DEFAULT_MAX_ACK_DELAY: u64 = 25;
+const MAX_PTO_BACKOFF: u32 = 16;
+
+#[cfg(test)]
+mod tests {
+    use super::{Recovery, SentPacket, INITIAL_RTT};
+    use congestion::NewReno;
+    use frame::{Ack, AckFrame, EcnCounts, Frame, PaddingFrame};
+    use packet::LongType;
+    use socket::EcnCodepoint;
+    use std::time::{Duration, Instant};
+
+    fn ack(largest: u64, blocks: Vec<Ack>) -> AckFrame {
+        AckFrame {
+            largest,
+            ack_delay: 0,
+            blocks,
+            ecn: None,
+        }
+    }
+
+    #[test]
+    fn test_rtt_sample() {
+        let start = Instant::now();
+        let mut recovery = Recovery::new(Box::new(NewReno::new()));
+        recovery.on_packet_sent(0, SentPacket::new(0, None, start, 100, vec![Frame::Ping]));
+        let (acked, lost) = recovery.on_ack_received(
+            &ack(0, vec![Ack::Ack(0)]),
+            start + Duration::from_millis(40),
+        );
+        assert_eq!(acked.len(), 1);
+        assert!(lost.is_empty());
+        assert_eq!(recovery.rtt(), Duration::from_millis(40));
+        assert_eq!(recovery.in_flight(), 0);
+        assert_eq!(recovery.bytes_in_flight(), 0);
+        assert_eq!(recovery.timeout(false), None);
+    }
+
+    #[test]
+    fn test_ack_delay() {
+        let start = Instant::now();
+        let mut recovery = Recovery::new(Box::new(NewReno::new()));
+        recovery.set_ack_delay(3, Duration::from_millis(25));
+        recovery.on_packet_sent(0, SentPacket::new(0, None, start, 100, vec![Frame::Ping]));
+        recovery.on_packet_sent(1, SentPacket::new(1, None, start, 100, vec![Frame::Ping]));
+
+        // The first sample only sets the minimum, which the delay never counts against
+        let mut frame = ack(0, vec![Ack::Ack(0)]);
+        recovery.on_ack_received(&frame, start + Duration::from_millis(40));
+        assert_eq!(recovery.rtt(), Duration::from_millis(40));
+
+        // 2500 units of 8us is 20ms of delay
+        frame = ack(1, vec![Ack::Ack(1)]);
+        frame.ack_delay = 2500;
+        recovery.on_ack_received(&frame, start + Duration::from_millis(100));
+        assert_eq!(recovery.rtt(), Duration::from_millis(45));
+
+        // Delays beyond what the peer promised are its own fault, and still count
+        recovery.on_packet_sent(2, SentPacket::new(2, None, start, 100, vec![Frame::Ping]));
+        frame = ack(2, vec![Ack::Ack(2)]);
+        frame.ack_delay = 12500;
+        recovery.on_ack_received(&frame, start + Duration::from_millis(166));
+        assert_eq!(recovery.rtt(), Duration::from_millis(57));
+    }
+
+    #[test]
+    fn test_packet_threshold_loss() {
+        let start = Instant::now();
+        let mut recovery = Recovery::new(Box::new(NewReno::new()));
+        for number in 0..5 {
+            let payload = vec![Frame::Ping, Frame::Padding(PaddingFrame(10))];
+            recovery.on_packet_sent(number, SentPacket::new(number, None, start, 100, payload));
+        }
+
+        let (acked, lost) = recovery.on_ack_received(
+            &ack(4, vec![Ack::Ack(0), Ack::Gap(1), Ack::Ack(0)]),
+            start + Duration::from_millis(10),
+        );
+        assert_eq!(acked.len(), 2);
+        assert_eq!(lost.len(), 1);
+        assert!(lost[0].frames.is_empty());
+        assert_eq!(recovery.in_flight(), 2);
+        assert!(recovery.timeout(false).is_some());
+    }
+
+    #[test]
+    fn test_lost_mtu_probe() {
+        let start = Instant::now();
+        let mut recovery = Recovery::new(Box::new(NewReno::new()));
+        recovery.on_packet_sent(0, SentPacket::new(0, None, start, 1400, vec![Frame::Ping]));
+        recovery.on_mtu_probe_sent(0);
+        for number in 1..4 {
+            recovery.on_packet_sent(number, SentPacket::new(number, None, start, 100, vec![]));
+        }
+        let window = recovery.window();
+
+        let (_, lost) = recovery.on_ack_received(
+            &ack(3, vec![Ack::Ack(2)]),
+            start + Duration::from_millis(10),
+        );
+        assert_eq!(lost.len(), 1);
+        assert!(lost[0].mtu_probe);
+        assert_eq!(recovery.bytes_in_flight(), 0);
+        assert_eq!(recovery.window(), window);
+    }
+
+    #[test]
+    fn test_discard() {
+        let start = Instant::now();
+        let mut recovery = Recovery::new(Box::new(NewReno::new()));
+        let early = Some(LongType::Protected);
+        recovery.on_packet_sent(0, SentPacket::new(0, early, start, 100, vec![Frame::Ping]));
+        recovery.on_packet_sent(1, SentPacket::new(1, None, start, 100, vec![Frame::Ping]));
+        recovery.on_packet_sent(2, SentPacket::new(2, early, start, 100, vec![Frame::Ping]));
+
+        let discarded = recovery.discard(LongType::Protected);
+        assert_eq!(discarded.iter().map(|p| p.number).collect::<Vec<_>>(), vec![0, 2]);
+        assert_eq!(recovery.in_flight(), 1);
+        assert_eq!(recovery.bytes_in_flight(), 100);
+    }
+
+    #[test]
+    fn test_new_path() {
+        let start = Instant::now();
+        let mut recovery = Recovery::new(Box::new(NewReno::new()));
+        recovery.on_packet_sent(0, SentPacket::new(0, None, start, 100, vec![Frame::Ping]));
+        recovery.on_ack_received(&ack(0, vec![Ack::Ack(0)]), start + Duration::from_millis(40));
+        let initial_rtt = Duration::from_millis(INITIAL_RTT);
+
+        recovery.new_path(Box::new(NewReno::new()));
+        assert_eq!(recovery.rtt(), initial_rtt);
+        recovery.restore_path();
+        assert_eq!(recovery.rtt(), Duration::from_millis(40));
+
+        recovery.new_path(Box::new(NewReno::new()));
+        recovery.path_validated();
+        recovery.restore_path();
+        assert_eq!(recovery.rtt(), initial_rtt);
+    }
+
+    #[test]
+    fn test_acks_application_data() {
+        let start = Instant::now();
+        let mut recovery = Recovery::new(Box::new(NewReno::new()));
+        let handshake = Some(LongType::Handshake);
+        recovery.on_packet_sent(0, SentPacket::new(0, handshake, start, 100, vec![Frame::Ping]));
+        recovery.on_packet_sent(1, SentPacket::new(1, None, start, 100, vec![Frame::Ping]));
+        assert!(!recovery.acks_application_data(&ack(0, vec![Ack::Ack(0)])));
+        assert!(recovery.acks_application_data(&ack(1, vec![Ack::Ack(1)])));
+    }
+
+    #[test]
+    fn test_pto_probes_oldest() {
+        let start = Instant::now();
+        let mut recovery = Recovery::new(Box::new(NewReno::new()));
+        recovery.on_packet_sent(0, SentPacket::new(0, None, start, 100, vec![Frame::Ping]));
+        recovery.on_packet_sent(1, SentPacket::new(1, None, start, 100, vec![Frame::Ping]));
+
+        let first = recovery.timeout(false).unwrap();
+        assert_eq!(recovery.on_timeout(first).len(), 1);
+        assert_eq!(recovery.timeout(false), Some(start + (first - start) * 2));
+    }
+
+    #[test]
+    fn test_pto_without_packets_in_flight() {
+        let start = Instant::now();
+        let mut recovery = Recovery::new(Box::new(NewReno::new()));
+        recovery.on_packet_sent(0, SentPacket::new(0, None, start, 100, vec![Frame::Ping]));
+        recovery.on_ack_received(&ack(0, vec![Ack::Ack(0)]), start + Duration::from_millis(40));
+        assert_eq!(recovery.timeout(false), None);
+
+        let first = recovery.timeout(true).unwrap();
+        assert!(recovery.on_timeout(first).is_empty());
+        assert_eq!(recovery.pto_count(), 1);
+        assert_eq!(recovery.timeout(true), Some(start + (first - start) * 2));
+    }
+
+    #[test]
+    fn test_ecn_validation() {
+        let start = Instant::now();
+        let mut recovery = Recovery::new(Box::new(NewReno::new()));
+        assert_eq!(recovery.ecn_codepoint(), Some(EcnCodepoint::Ect0));
+        for number in 0..3 {
+            recovery.on_packet_sent(number, SentPacket::new(number, None, start, 100, vec![]));
+        }
+        let window = recovery.window();
+
+        let mut frame = ack(1, vec![Ack::Ack(1)]);
+        frame.ecn = Some(EcnCounts {
+            ect0: 2,
+            ect1: 0,
+            ce: 0,
+        });
+        recovery.on_ack_received(&frame, start + Duration::from_millis(10));
+        assert_eq!(recovery.window(), window);
+
+        let mut frame = ack(2, vec![Ack::Ack(2)]);
+        frame.ecn = Some(EcnCounts {
+            ect0: 2,
+            ect1: 0,
+            ce: 1,
+        });
+        recovery.on_ack_received(&frame, start + Duration::from_millis(20));
+        assert!(recovery.window() < window);
+        assert_eq!(recovery.ecn_codepoint(), Some(EcnCodepoint::Ect0));
+
+        // A peer that stops echoing the marks means they're lost somewhere on the path
+        recovery.on_packet_sent(3, SentPacket::new(3, None, start, 100, vec![]));
+        recovery.on_ack_received(&ack(3, vec![Ack::Ack(0)]), start + Duration::from_millis(30));
+        assert_eq!(recovery.ecn_codepoint(), None);
+    }
+
+    #[test]
+    fn test_ack_ranges() {
+        let frame = ack(10, vec![Ack::Ack(2), Ack::Gap(1), Ack::Ack(1)]);
+        assert_eq!(frame.ranges(), vec![(8, 10), (4, 5)]);
+    }
+}
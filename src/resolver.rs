@@ -0,0 +1,46 @@
+use futures::sync::oneshot;
+use futures::Future;
+
+use std::net::{SocketAddr, ToSocketAddrs};
+use std::thread;
+
+use super::QuicError;
+
+pub type Resolution = Box<Future<Item = Vec<SocketAddr>, Error = QuicError>>;
+
+// Looks up the addresses to try for a host, in the order they should be tried
+pub trait Resolver: Send + Sync {
+    fn resolve(&self, host: &str, port: u16) -> Resolution;
+}
+
+// The operating system's resolver. Lookups block, so each one gets a thread of its own.
+pub struct SystemResolver;
+
+impl Resolver for SystemResolver {
+    fn resolve(&self, host: &str, port: u16) -> Resolution {
+        let (tx, rx) = oneshot::channel();
+        let host = host.to_owned();
+        thread::spawn(move || {
+            let addrs = (&host[..], port)
+                .to_socket_addrs()
+                .map(|addrs| addrs.collect::<Vec<_>>());
+            let _ = tx.send(addrs);
+        });
+        Box::new(
+            rx.map_err(|_| QuicError::General("resolver thread went away".into()))
+                .and_then(|addrs| addrs.map_err(QuicError::from)),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Resolver, SystemResolver};
+    use futures::Future;
+
+    #[test]
+    fn test_system_resolver() {
+        let addrs = SystemResolver.resolve("127.0.0.1", 4433).wait().unwrap();
+        assert_eq!(addrs, vec!["127.0.0.1:4433".parse().unwrap()]);
+    }
+}
@@ -1,28 +1,18 @@
-use futures::{Async, AsyncSink, Future, Poll, Sink, Stream, sync::mpsc::{self, Receiver, Sender}};
+use futures::{Async, Future, Poll, Stream};
 
 use super::{QuicError, QuicResult};
-use conn_state::ConnectionState;
-use crypto::Secret;
-use packet::{LongType, Packet};
-use parameters::ServerTransportParameters;
+use connection::Connection;
+use endpoint::{Driver, Endpoint, Incoming};
 use tls;
-use types::ConnectionId;
 
-use std::collections::{HashMap, hash_map::Entry};
-use std::net::{SocketAddr, ToSocketAddrs};
-use std::sync::Arc;
+use std::net::ToSocketAddrs;
 
-use tokio::{self, net::UdpSocket};
+use tokio::runtime::current_thread::Runtime;
 
 pub struct Server {
-    socket: UdpSocket,
-    tls_config: Arc<tls::ServerConfig>,
-    in_buf: Vec<u8>,
-    connections: HashMap<ConnectionId, Sender<Vec<u8>>>,
-    send_queue: (
-        Sender<(SocketAddr, Vec<u8>)>,
-        Receiver<(SocketAddr, Vec<u8>)>,
-    ),
+    driver: Driver,
+    incoming: Incoming,
+    connections: Vec<Connection>,
 }
 
 impl Server {
@@ -31,17 +21,17 @@ impl Server {
             .to_socket_addrs()?
             .next()
             .ok_or_else(|| QuicError::General("no address found for host".into()))?;
+        let (_, driver, incoming) = Endpoint::listen(&addr, tls_config)?;
         Ok(Server {
-            socket: UdpSocket::bind(&addr)?,
-            tls_config: Arc::new(tls_config),
-            in_buf: vec![0u8; 65536],
-            connections: HashMap::new(),
-            send_queue: mpsc::channel(5),
+            driver,
+            incoming,
+            connections: Vec::new(),
         })
     }
 
+    // Accepted connections get their drivers spawned onto the executor running the server
     pub fn run(&mut self) -> QuicResult<()> {
-        self.wait()
+        Runtime::new()?.block_on(self)
     }
 }
 
@@ -50,175 +40,12 @@ impl Future for Server {
     type Error = QuicError;
 
     fn poll(&mut self) -> Poll<(), QuicError> {
-        let mut waiting;
-        loop {
-            waiting = true;
-            match self.socket.poll_recv_from(&mut self.in_buf) {
-                Ok(Async::Ready((len, addr))) => {
-                    waiting = false;
-                    let connections = &mut self.connections;
-
-                    let cid = {
-                        let partial = Packet::start_decode(&mut self.in_buf[..len]);
-                        debug!("incoming packet: {:?} {:?}", addr, partial.header);
-                        let dst_cid = partial.dst_cid();
-                        if partial.header.ptype() == Some(LongType::Initial) {
-                            let mut state = ConnectionState::new(
-                                tls::server_session(
-                                    &self.tls_config,
-                                    &ServerTransportParameters::default(),
-                                ),
-                                Some(Secret::Handshake(dst_cid)),
-                            );
-
-                            let cid = state.pick_unused_cid(|cid| connections.contains_key(&cid));
-                            let (recv_tx, recv_rx) = mpsc::channel(5);
-                            tokio::executor::current_thread::spawn(
-                                Box::new(Connection::new(
-                                    addr,
-                                    state,
-                                    self.send_queue.0.clone(),
-                                    recv_rx,
-                                )).map_err(|e| {
-                                    error!("error spawning connection: {:?}", e);
-                                }),
-                            );
-                            connections.insert(cid, recv_tx);
-                            cid
-                        } else {
-                            dst_cid
-                        }
-                    };
-
-                    let msg = self.in_buf[..len].to_vec();
-                    match connections.entry(cid) {
-                        Entry::Occupied(mut inner) => {
-                            let mut sink = inner.get_mut();
-                            forward_packet(sink, msg)?;
-                        }
-                        Entry::Vacant(_) => debug!("connection ID {:?} unknown", cid),
-                    }
-                }
-                Ok(Async::NotReady) => {}
-                Err(e) => error!("Server RECV ERROR: {:?}", e),
-            }
-
-            match self.send_queue.1.poll() {
-                Ok(Async::Ready(Some((addr, msg)))) => {
-                    waiting = false;
-                    match self.socket.poll_send_to(&msg, &addr) {
-                        Ok(Async::Ready(_)) => {}
-                        Ok(Async::NotReady) => {}
-                        Err(e) => error!("Server poll_send_to ERROR {:?}", e),
-                    }
-                }
-                Ok(Async::Ready(None)) => {}
-                Ok(Async::NotReady) => {}
-                Err(e) => {
-                    error!("error polling send queue: {:?}", e);
-                }
-            }
-
-            if waiting {
-                break;
-            }
-        }
-        Ok(Async::NotReady)
-    }
-}
-
-fn forward_packet(sink: &mut Sender<Vec<u8>>, msg: Vec<u8>) -> QuicResult<()> {
-    match sink.start_send(msg) {
-        Ok(AsyncSink::Ready) => {}
-        Ok(AsyncSink::NotReady(msg)) => error!("discarding message: {:?}", msg),
-        Err(e) => {
-            return Err(QuicError::General(format!(
-                "error while starting channel send: {:?}",
-                e
-            )));
-        }
-    }
-    match sink.poll_complete() {
-        Ok(Async::Ready(())) => {}
-        Ok(Async::NotReady) => {}
-        Err(e) => {
-            return Err(QuicError::General(format!(
-                "error while polling channel complete: {:?}",
-                e
-            )));
-        }
-    }
-    Ok(())
-}
-
-struct Connection {
-    addr: SocketAddr,
-    state: ConnectionState<tls::ServerSession>,
-    send: Sender<(SocketAddr, Vec<u8>)>,
-    recv: Receiver<Vec<u8>>,
-}
-
-impl Connection {
-    fn new(
-        addr: SocketAddr,
-        state: ConnectionState<tls::ServerSession>,
-        send: Sender<(SocketAddr, Vec<u8>)>,
-        recv: Receiver<Vec<u8>>,
-    ) -> Self {
-        Self {
-            addr,
-            state,
-            send,
-            recv,
-        }
-    }
-}
-
-impl Future for Connection {
-    type Item = ();
-    type Error = ();
-    fn poll(&mut self) -> Poll<(), ()> {
-        loop {
-            let mut received = false;
-            match self.recv.poll() {
-                Ok(Async::Ready(Some(ref mut msg))) => {
-                    self.state.handle(msg).unwrap();
-                    received = true;
-                }
-                Ok(Async::Ready(None)) => {}
-                Ok(Async::NotReady) => {}
-                Err(e) => error!("error from server: {:?}", e),
-            }
-
-            let mut sent = false;
-            match self.state.queued() {
-                Ok(Some(msg)) => match self.send.start_send((self.addr.clone(), msg.clone())) {
-                    Ok(AsyncSink::Ready) => {
-                        sent = true;
-                    }
-                    Ok(AsyncSink::NotReady(msg)) => {
-                        error!("start send not ready: {:?}", msg);
-                    }
-                    Err(e) => error!("error sending: {:?}", e),
-                },
-                Ok(None) => {}
-                Err(e) => error!("error from connection state: {:?}", e),
-            }
-            if sent {
-                self.state.pop_queue();
-            }
-
-            let flushed = false;
-            match self.send.poll_complete() {
-                Ok(Async::Ready(())) => {}
-                Ok(Async::NotReady) => {}
-                Err(e) => error!("error from flushing sender: {:?}", e),
-            }
-
-            if !(received || sent || flushed) {
-                break;
-            }
+        // Connections left waiting in Incoming fill up the backlog until new ones are refused
+        while let Async::Ready(Some(conn)) = self.incoming.poll()? {
+            debug!("accepted connection from {}", conn.remote_address());
+            self.connections.push(conn);
         }
-        Ok(Async::NotReady)
+        self.connections.retain(|conn| conn.close_reason().is_none());
+        self.driver.poll()
     }
 }
@@ -0,0 +1,102 @@
+use rustls::StoresClientSessions;
+
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, Mutex};
+
+pub trait SessionCache: Send + Sync {
+    fn get(&self, server_name: &[u8]) -> Option<Vec<u8>>;
+    fn put(&self, server_name: Vec<u8>, value: Vec<u8>) -> bool;
+}
+
+pub struct LruSessionCache {
+    capacity: usize,
+    inner: Mutex<LruInner>,
+}
+
+struct LruInner {
+    entries: HashMap<Vec<u8>, Vec<u8>>,
+    order: VecDeque<Vec<u8>>,
+}
+
+impl LruSessionCache {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            inner: Mutex::new(LruInner {
+                entries: HashMap::new(),
+                order: VecDeque::new(),
+            }),
+        }
+    }
+}
+
+impl LruInner {
+    fn touch(&mut self, key: &[u8]) {
+        if let Some(pos) = self.order.iter().position(|k| &k[..] == key) {
+            let key = self.order.remove(pos).unwrap();
+            self.order.push_back(key);
+        }
+    }
+}
+
+impl SessionCache for LruSessionCache {
+    fn get(&self, server_name: &[u8]) -> Option<Vec<u8>> {
+        let mut inner = self.inner.lock().unwrap();
+        let value = inner.entries.get(server_name).cloned()?;
+        inner.touch(server_name);
+        Some(value)
+    }
+
+    fn put(&self, server_name: Vec<u8>, value: Vec<u8>) -> bool {
+        if self.capacity == 0 {
+            return false;
+        }
+        let mut inner = self.inner.lock().unwrap();
+        if inner.entries.insert(server_name.clone(), value).is_some() {
+            inner.touch(&server_name);
+            return true;
+        }
+        inner.order.push_back(server_name);
+        while inner.order.len() > self.capacity {
+            if let Some(oldest) = inner.order.pop_front() {
+                inner.entries.remove(&oldest);
+            }
+        }
+        true
+    }
+}
+
+pub(crate) struct SessionStore(pub Arc<SessionCache>);
+
+impl StoresClientSessions for SessionStore {
+    fn put(&self, key: Vec<u8>, value: Vec<u8>) -> bool {
+        self.0.put(key, value)
+    }
+
+    fn get(&self, key: &[u8]) -> Option<Vec<u8>> {
+        self.0.get(key)
+    }
+}
+
+pub const DEFAULT_SESSION_CACHE_SIZE: usize = 32;
+
+#[cfg(test)]
+mod tests {
+    use super::{LruSessionCache, SessionCache};
+
+    #[test]
+    fn test_lru_eviction() {
+        let cache = LruSessionCache::new(2);
+        assert!(cache.put(b"a".to_vec(), vec![1]));
+        assert!(cache.put(b"b".to_vec(), vec![2]));
+        assert_eq!(cache.get(b"a"), Some(vec![1]));
+
+        assert!(cache.put(b"c".to_vec(), vec![3]));
+        assert_eq!(cache.get(b"b"), None);
+        assert_eq!(cache.get(b"a"), Some(vec![1]));
+        assert_eq!(cache.get(b"c"), Some(vec![3]));
+
+        assert!(cache.put(b"a".to_vec(), vec![4]));
+        assert_eq!(cache.get(b"a"), Some(vec![4]));
+    }
+}
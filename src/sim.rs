@@ -0,0 +1,527 @@
+use futures::{task, Async, Future, Poll};
+use rand::prng::XorShiftRng;
+use rand::{Rng, SeedableRng};
+use tokio::timer::Delay;
+
+use std::collections::HashMap;
+use std::io;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use packet::LongType;
+use socket::{EcnCodepoint, Socket};
+
+#[derive(Clone, Debug)]
+pub struct NetworkConfig {
+    loss: f64,
+    latency: Duration,
+    jitter: Duration,
+    seed: u64,
+    ce_marking: f64,
+    bleach_ecn: bool,
+}
+
+impl Default for NetworkConfig {
+    fn default() -> Self {
+        Self {
+            loss: 0.0,
+            latency: Duration::from_millis(0),
+            jitter: Duration::from_millis(0),
+            seed: 0,
+            ce_marking: 0.0,
+            bleach_ecn: false,
+        }
+    }
+}
+
+impl NetworkConfig {
+    pub fn loss(mut self, probability: f64) -> Self {
+        self.loss = probability;
+        self
+    }
+
+    pub fn latency(mut self, latency: Duration) -> Self {
+        self.latency = latency;
+        self
+    }
+
+    // Each packet is held back by a random extra delay up to this, which reorders them
+    pub fn jitter(mut self, jitter: Duration) -> Self {
+        self.jitter = jitter;
+        self
+    }
+
+    pub fn seed(mut self, seed: u64) -> Self {
+        self.seed = seed;
+        self
+    }
+
+    // Like a congested router with AQM, which marks ECN-capable packets instead of dropping them
+    pub fn ce_marking(mut self, probability: f64) -> Self {
+        self.ce_marking = probability;
+        self
+    }
+
+    // Some middleboxes clear the ECN field, which ECN validation has to detect
+    pub fn bleach_ecn(mut self, bleach: bool) -> Self {
+        self.bleach_ecn = bleach;
+        self
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct NetworkStats {
+    pub sent: u64,
+    pub dropped: u64,
+    pub delivered: u64,
+    // Retry packets are sent on their own, so the first byte gives them away
+    pub retries: u64,
+}
+
+#[derive(Clone)]
+pub struct Network {
+    inner: Arc<Mutex<Inner>>,
+}
+
+impl Network {
+    pub fn new(config: NetworkConfig) -> Self {
+        let rng = rng(config.seed);
+        Self {
+            inner: Arc::new(Mutex::new(Inner {
+                config,
+                rng,
+                queues: HashMap::new(),
+                rebound: HashMap::new(),
+                next_seq: 0,
+                stats: NetworkStats::default(),
+            })),
+        }
+    }
+
+    pub fn bind(&self, addr: SocketAddr) -> SimSocket {
+        let mut me = self.inner.lock().unwrap();
+        me.queues.insert(addr, Queue::default());
+        SimSocket {
+            addr,
+            network: self.inner.clone(),
+            timer: None,
+        }
+    }
+
+    // Conditions can change mid-test, e.g. to only start dropping packets after the handshake
+    pub fn set_config(&self, config: NetworkConfig) {
+        let mut me = self.inner.lock().unwrap();
+        me.rng = rng(config.seed);
+        me.config = config;
+    }
+
+    // Like a NAT picking a new mapping: the socket bound to `addr` now sends from `public`, and
+    // anything still sent to its old address is lost
+    pub fn rebind(&self, addr: SocketAddr, public: SocketAddr) {
+        let mut me = self.inner.lock().unwrap();
+        me.rebound.insert(addr, public);
+    }
+
+    pub fn stats(&self) -> NetworkStats {
+        let me = self.inner.lock().unwrap();
+        me.stats
+    }
+}
+
+pub struct SimSocket {
+    addr: SocketAddr,
+    network: Arc<Mutex<Inner>>,
+    timer: Option<Delay>,
+}
+
+impl SimSocket {
+    pub fn local_addr(&self) -> SocketAddr {
+        self.addr
+    }
+}
+
+impl Socket for SimSocket {
+    fn poll_recv_from(&mut self, buf: &mut [u8]) -> Poll<(usize, SocketAddr), io::Error> {
+        let (len, addr, _) = try_ready!(self.poll_recv_ecn(buf));
+        Ok(Async::Ready((len, addr)))
+    }
+
+    fn poll_send_to(&mut self, buf: &[u8], addr: &SocketAddr) -> Poll<usize, io::Error> {
+        self.poll_send_ecn(buf, addr, None)
+    }
+
+    fn poll_recv_ecn(
+        &mut self,
+        buf: &mut [u8],
+    ) -> Poll<(usize, SocketAddr, Option<EcnCodepoint>), io::Error> {
+        loop {
+            let next = {
+                let mut me = self.network.lock().unwrap();
+                let now = Instant::now();
+                let received = {
+                    let queue = me.queues.get_mut(&self.addr).unwrap();
+                    let next = queue
+                        .packets
+                        .iter()
+                        .enumerate()
+                        .min_by_key(|&(_, packet)| (packet.deliver_at, packet.seq))
+                        .map(|(i, packet)| (i, packet.deliver_at));
+                    match next {
+                        Some((i, deliver_at)) if deliver_at <= now => {
+                            let packet = queue.packets.swap_remove(i);
+                            let len = packet.data.len().min(buf.len());
+                            buf[..len].copy_from_slice(&packet.data[..len]);
+                            Ok((len, packet.from, packet.ecn))
+                        }
+                        next => {
+                            queue.task = Some(task::current());
+                            Err(next.map(|(_, deliver_at)| deliver_at))
+                        }
+                    }
+                };
+                match received {
+                    Ok(received) => {
+                        me.stats.delivered += 1;
+                        return Ok(Async::Ready(received));
+                    }
+                    Err(next) => next,
+                }
+            };
+
+            let deadline = match next {
+                Some(deadline) => deadline,
+                None => {
+                    self.timer = None;
+                    return Ok(Async::NotReady);
+                }
+            };
+            let timer = self.timer.get_or_insert_with(|| Delay::new(deadline));
+            if timer.deadline() != deadline {
+                timer.reset(deadline);
+            }
+            match timer.poll() {
+                Ok(Async::Ready(())) => continue,
+                Ok(Async::NotReady) => return Ok(Async::NotReady),
+                Err(e) => return Err(io::Error::new(io::ErrorKind::Other, e)),
+            }
+        }
+    }
+
+    fn poll_send_ecn(
+        &mut self,
+        buf: &[u8],
+        addr: &SocketAddr,
+        ecn: Option<EcnCodepoint>,
+    ) -> Poll<usize, io::Error> {
+        let mut me = self.network.lock().unwrap();
+        me.stats.sent += 1;
+        if buf.first() == Some(&(0x80 | LongType::Retry.to_byte())) {
+            me.stats.retries += 1;
+        }
+        let (loss, latency, jitter) = (me.config.loss, me.config.latency, me.config.jitter);
+        let lost = loss > 0.0 && me.rng.gen::<f64>() < loss;
+        let destination = match me.destination(addr) {
+            Some(destination) if !lost => destination,
+            _ => {
+                me.stats.dropped += 1;
+                return Ok(Async::Ready(buf.len()));
+            }
+        };
+        let ce_marking = me.config.ce_marking;
+        let ecn = match ecn {
+            _ if me.config.bleach_ecn => None,
+            Some(_) if ce_marking > 0.0 && me.rng.gen::<f64>() < ce_marking => {
+                Some(EcnCodepoint::Ce)
+            }
+            ecn => ecn,
+        };
+
+        let extra = jitter * me.rng.gen_range(0, 1001) / 1000;
+        let seq = me.next_seq;
+        me.next_seq += 1;
+        let from = me.rebound.get(&self.addr).cloned().unwrap_or(self.addr);
+        let queue = me.queues.get_mut(&destination).unwrap();
+        queue.packets.push(InFlight {
+            deliver_at: Instant::now() + latency + extra,
+            seq,
+            from,
+            ecn,
+            data: buf.to_vec(),
+        });
+        if let Some(task) = queue.task.take() {
+            task.notify();
+        }
+        Ok(Async::Ready(buf.len()))
+    }
+}
+
+struct Inner {
+    config: NetworkConfig,
+    rng: XorShiftRng,
+    queues: HashMap<SocketAddr, Queue>,
+    rebound: HashMap<SocketAddr, SocketAddr>,
+    next_seq: u64,
+    stats: NetworkStats,
+}
+
+impl Inner {
+    fn destination(&self, addr: &SocketAddr) -> Option<SocketAddr> {
+        if let Some((&local, _)) = self.rebound.iter().find(|&(_, public)| public == addr) {
+            return Some(local);
+        }
+        if self.rebound.contains_key(addr) || !self.queues.contains_key(addr) {
+            return None;
+        }
+        Some(*addr)
+    }
+}
+
+#[derive(Default)]
+struct Queue {
+    packets: Vec<InFlight>,
+    task: Option<task::Task>,
+}
+
+struct InFlight {
+    deliver_at: Instant,
+    seq: u64,
+    from: SocketAddr,
+    ecn: Option<EcnCodepoint>,
+    data: Vec<u8>,
+}
+
+fn rng(seed: u64) -> XorShiftRng {
+    // XorShift can't start from all zeroes, which mixing in the index rules out
+    let mut bytes = [0; 16];
+    for (i, b) in bytes.iter_mut().enumerate() {
+        *b = (seed >> (8 * (i % 8))) as u8 ^ i as u8;
+    }
+    XorShiftRng::from_seed(bytes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Network, NetworkConfig};
+    use endpoint::Endpoint;
+    use socket::{EcnCodepoint, Socket};
+    use tls::tests::{client_config, server_config};
+    use QuicError;
+
+    use futures::{future, Async, Future, Stream};
+    use tokio;
+    // Unlike a bare CurrentThread executor, this comes with the timer that delayed deliveries
+    // wait on
+    use tokio::runtime::current_thread::Runtime;
+
+    use std::net::SocketAddr;
+    use std::time::{Duration, Instant};
+
+    #[test]
+    fn test_loss_and_stats() {
+        let net = Network::new(NetworkConfig::default().loss(1.0));
+        let (a, b): (SocketAddr, SocketAddr) =
+            ("10.0.0.1:1".parse().unwrap(), "10.0.0.2:1".parse().unwrap());
+        let mut sa = net.bind(a);
+        let mut sb = net.bind(b);
+        future::lazy(move || {
+            assert_eq!(sa.poll_send_to(b"lost", &b).unwrap(), Async::Ready(4));
+            net.set_config(NetworkConfig::default());
+            assert_eq!(sa.poll_send_to(b"kept", &b).unwrap(), Async::Ready(4));
+
+            let mut buf = [0; 16];
+            assert_eq!(sb.poll_recv_from(&mut buf).unwrap(), Async::Ready((4, a)));
+            assert_eq!(&buf[..4], b"kept");
+            assert!(sb.poll_recv_from(&mut buf).unwrap().is_not_ready());
+
+            let stats = net.stats();
+            assert_eq!((stats.sent, stats.dropped, stats.delivered), (2, 1, 1));
+            Ok::<_, ()>(())
+        }).wait()
+            .unwrap();
+    }
+
+    #[test]
+    fn test_ecn_marks() {
+        let net = Network::new(NetworkConfig::default());
+        let (a, b): (SocketAddr, SocketAddr) =
+            ("10.0.0.1:1".parse().unwrap(), "10.0.0.2:1".parse().unwrap());
+        let mut sa = net.bind(a);
+        let mut sb = net.bind(b);
+        future::lazy(move || {
+            let mut buf = [0; 16];
+            let configs = vec![
+                (NetworkConfig::default(), Some(EcnCodepoint::Ect0)),
+                (NetworkConfig::default().ce_marking(1.0), Some(EcnCodepoint::Ce)),
+                (NetworkConfig::default().bleach_ecn(true), None),
+            ];
+            for (config, expected) in configs {
+                net.set_config(config);
+                sa.poll_send_ecn(b"mark", &b, Some(EcnCodepoint::Ect0)).unwrap();
+                assert_eq!(sb.poll_recv_ecn(&mut buf).unwrap(), Async::Ready((4, a, expected)));
+            }
+            // Routers only mark packets that claim to be ECN-capable
+            net.set_config(NetworkConfig::default().ce_marking(1.0));
+            sa.poll_send_ecn(b"mark", &b, None).unwrap();
+            assert_eq!(sb.poll_recv_ecn(&mut buf).unwrap(), Async::Ready((4, a, None)));
+            Ok::<_, ()>(())
+        }).wait()
+            .unwrap();
+    }
+
+    #[test]
+    fn test_latency() {
+        let latency = Duration::from_millis(20);
+        let net = Network::new(NetworkConfig::default().latency(latency));
+        let (a, b): (SocketAddr, SocketAddr) =
+            ("10.0.0.1:1".parse().unwrap(), "10.0.0.2:1".parse().unwrap());
+        let mut sa = net.bind(a);
+        let mut sb = net.bind(b);
+
+        let sent = Instant::now();
+        let received = Runtime::new()
+            .unwrap()
+            .block_on(future::lazy(move || {
+                sa.poll_send_to(b"late", &b).unwrap();
+                let mut buf = [0; 16];
+                future::poll_fn(move || sb.poll_recv_from(&mut buf))
+            }))
+            .unwrap();
+        assert_eq!(received, (4, a));
+        assert!(sent.elapsed() >= latency);
+    }
+
+    #[test]
+    fn test_rebind() {
+        let net = Network::new(NetworkConfig::default());
+        let (a, b, public): (SocketAddr, SocketAddr, SocketAddr) = (
+            "10.0.0.1:1".parse().unwrap(),
+            "10.0.0.2:1".parse().unwrap(),
+            "10.0.0.1:2".parse().unwrap(),
+        );
+        let mut sa = net.bind(a);
+        let mut sb = net.bind(b);
+        net.rebind(a, public);
+        future::lazy(move || {
+            let mut buf = [0; 16];
+            sa.poll_send_to(b"out", &b).unwrap();
+            assert_eq!(sb.poll_recv_from(&mut buf).unwrap(), Async::Ready((3, public)));
+            sb.poll_send_to(b"old", &a).unwrap();
+            sb.poll_send_to(b"new", &public).unwrap();
+            assert_eq!(sa.poll_recv_from(&mut buf).unwrap(), Async::Ready((3, b)));
+            assert_eq!(&buf[..3], b"new");
+            assert_eq!(net.stats().dropped, 1);
+            Ok::<_, ()>(())
+        }).wait()
+            .unwrap();
+    }
+
+    #[test]
+    fn test_nat_rebinding_mid_transfer() {
+        transfer_with_rebinding("10.0.0.2:5001".parse().unwrap());
+    }
+
+    #[test]
+    fn test_address_change_mid_transfer() {
+        transfer_with_rebinding("10.0.0.3:5000".parse().unwrap());
+    }
+
+    fn transfer_with_rebinding(public: SocketAddr) {
+        let net = Network::new(NetworkConfig::default().latency(Duration::from_millis(5)));
+        let server_addr = "10.0.0.1:4433".parse().unwrap();
+        let client_addr = "10.0.0.2:5000".parse().unwrap();
+        let (_, server_driver, incoming) = Endpoint::listen_with_socket(
+            Box::new(net.bind(server_addr)),
+            server_config(),
+            Default::default(),
+        ).unwrap();
+        let (mut client, client_driver) =
+            Endpoint::with_socket(Box::new(net.bind(client_addr)), Default::default()).unwrap();
+        client.set_client_config(client_config());
+
+        let mut exec = Runtime::new().unwrap();
+        exec.spawn(server_driver.map_err(|_| ()));
+        exec.spawn(client_driver.map_err(|_| ()));
+
+        let conn = exec.block_on(future::lazy(|| {
+            client.connect(&server_addr, "Localhost").unwrap()
+        })).unwrap();
+        let (accepted, _) = exec.block_on(incoming.into_future().map_err(|(e, _)| e))
+            .unwrap();
+        let accepted = accepted.unwrap();
+        assert_eq!(accepted.remote_address(), client_addr);
+
+        // Sending this much takes several round trips, so the address changes while it's going
+        let data = (0..256 * 1024).map(|i| i as u8).collect::<Vec<_>>();
+        let expected = data.clone();
+        exec.spawn(
+            conn.open_uni()
+                .and_then(move |send| tokio::io::write_all(send, data).map_err(QuicError::from))
+                .and_then(|(send, _)| send.finish())
+                .map_err(|e| panic!("sending failed: {:?}", e)),
+        );
+        let recv = exec.block_on(accepted.accept_uni()).unwrap();
+        let (recv, first) = exec.block_on(
+            tokio::io::read_exact(recv, vec![0; 16 * 1024]).map_err(QuicError::from),
+        ).unwrap();
+
+        net.rebind(client_addr, public);
+        let (_, rest) = exec.block_on(
+            tokio::io::read_to_end(recv, Vec::new()).map_err(QuicError::from),
+        ).unwrap();
+        assert_eq!([first, rest].concat(), expected);
+        assert_eq!(accepted.remote_address(), public);
+    }
+
+    #[test]
+    fn test_transfer_with_loss_and_reordering() {
+        let net = Network::new(NetworkConfig::default().latency(Duration::from_millis(5)));
+        let server_addr = "10.0.0.1:4433".parse().unwrap();
+        let client_addr = "10.0.0.2:5000".parse().unwrap();
+        let (_, server_driver, incoming) = Endpoint::listen_with_socket(
+            Box::new(net.bind(server_addr)),
+            server_config(),
+            Default::default(),
+        ).unwrap();
+        let (mut client, client_driver) =
+            Endpoint::with_socket(Box::new(net.bind(client_addr)), Default::default()).unwrap();
+        client.set_client_config(client_config());
+
+        let mut exec = Runtime::new().unwrap();
+        exec.spawn(server_driver.map_err(|_| ()));
+        exec.spawn(client_driver.map_err(|_| ()));
+
+        let conn = exec.block_on(future::lazy(|| {
+            client.connect(&server_addr, "Localhost").unwrap()
+        })).unwrap();
+        let (accepted, _) = exec.block_on(incoming.into_future().map_err(|(e, _)| e))
+            .unwrap();
+        let accepted = accepted.unwrap();
+        assert_eq!(accepted.remote_address(), client_addr);
+
+        // Keep the handshake on a clean link so the test exercises stream recovery only
+        net.set_config(
+            NetworkConfig::default()
+                .latency(Duration::from_millis(5))
+                .jitter(Duration::from_millis(10))
+                .loss(0.1)
+                .seed(42),
+        );
+
+        let data = (0..32 * 1024).map(|i| i as u8).collect::<Vec<_>>();
+        let expected = data.clone();
+        exec.spawn(
+            conn.open_uni()
+                .and_then(move |send| tokio::io::write_all(send, data).map_err(QuicError::from))
+                .and_then(|(send, _)| send.finish())
+                .map_err(|e| panic!("sending failed: {:?}", e)),
+        );
+        let (_, received) = exec.block_on(
+            accepted
+                .accept_uni()
+                .and_then(|recv| tokio::io::read_to_end(recv, Vec::new()).map_err(QuicError::from)),
+        ).unwrap();
+        assert_eq!(received, expected);
+        assert!(net.stats().sent > net.stats().dropped);
+    }
+}
@@ -0,0 +1,759 @@
+use futures::{Async, Poll};
+use tokio::net::UdpSocket;
+
+use std::io;
+use std::net::SocketAddr;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum EcnCodepoint {
+    Ect0,
+    Ect1,
+    Ce,
+}
+
+impl EcnCodepoint {
+    pub fn from_bits(bits: u8) -> Option<Self> {
+        match bits & 0b11 {
+            0b10 => Some(EcnCodepoint::Ect0),
+            0b01 => Some(EcnCodepoint::Ect1),
+            0b11 => Some(EcnCodepoint::Ce),
+            _ => None,
+        }
+    }
+
+    pub fn bits(self) -> u8 {
+        match self {
+            EcnCodepoint::Ect0 => 0b10,
+            EcnCodepoint::Ect1 => 0b01,
+            EcnCodepoint::Ce => 0b11,
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Transmit {
+    pub destination: SocketAddr,
+    pub ecn: Option<EcnCodepoint>,
+    pub contents: Vec<u8>,
+}
+
+// A received buffer may hold several datagrams from the same source, each `stride` bytes long
+// except for the last
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct RecvMeta {
+    pub source: SocketAddr,
+    pub len: usize,
+    pub stride: usize,
+    pub ecn: Option<EcnCodepoint>,
+}
+
+pub trait Socket {
+    fn poll_recv_from(&mut self, buf: &mut [u8]) -> Poll<(usize, SocketAddr), io::Error>;
+    fn poll_send_to(&mut self, buf: &[u8], addr: &SocketAddr) -> Poll<usize, io::Error>;
+
+    // Sockets that can't get at the IP header just don't report or set ECN marks
+    fn poll_recv_ecn(
+        &mut self,
+        buf: &mut [u8],
+    ) -> Poll<(usize, SocketAddr, Option<EcnCodepoint>), io::Error> {
+        let (len, addr) = try_ready!(self.poll_recv_from(buf));
+        Ok(Async::Ready((len, addr, None)))
+    }
+
+    fn poll_send_ecn(
+        &mut self,
+        buf: &[u8],
+        addr: &SocketAddr,
+        _ecn: Option<EcnCodepoint>,
+    ) -> Poll<usize, io::Error> {
+        self.poll_send_to(buf, addr)
+    }
+
+    // Sends a prefix of the batch, returning how many datagrams went out; the fallback
+    // just pays for one syscall per datagram
+    fn poll_send_batch(&mut self, transmits: &[Transmit]) -> Poll<usize, io::Error> {
+        let mut sent = 0;
+        for transmit in transmits {
+            match self.poll_send_ecn(&transmit.contents, &transmit.destination, transmit.ecn) {
+                Ok(Async::Ready(_)) => sent += 1,
+                Ok(Async::NotReady) | Err(_) if sent > 0 => break,
+                Ok(Async::NotReady) => return Ok(Async::NotReady),
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(Async::Ready(sent))
+    }
+
+    // Fills a prefix of `bufs`, returning how many were filled and describing each in `meta`
+    fn poll_recv_batch(
+        &mut self,
+        bufs: &mut [Vec<u8>],
+        meta: &mut [RecvMeta],
+    ) -> Poll<usize, io::Error> {
+        if bufs.is_empty() || meta.is_empty() {
+            return Ok(Async::Ready(0));
+        }
+        let (len, source, ecn) = try_ready!(self.poll_recv_ecn(&mut bufs[0]));
+        meta[0] = RecvMeta {
+            source,
+            len,
+            stride: len,
+            ecn,
+        };
+        Ok(Async::Ready(1))
+    }
+}
+
+impl Socket for UdpSocket {
+    fn poll_recv_from(&mut self, buf: &mut [u8]) -> Poll<(usize, SocketAddr), io::Error> {
+        UdpSocket::poll_recv_from(self, buf)
+    }
+
+    fn poll_send_to(&mut self, buf: &[u8], addr: &SocketAddr) -> Poll<usize, io::Error> {
+        UdpSocket::poll_send_to(self, buf, addr)
+    }
+
+    #[cfg(target_os = "linux")]
+    fn poll_recv_ecn(
+        &mut self,
+        buf: &mut [u8],
+    ) -> Poll<(usize, SocketAddr, Option<EcnCodepoint>), io::Error> {
+        linux::poll_recv(self, buf)
+    }
+
+    #[cfg(target_os = "linux")]
+    fn poll_send_ecn(
+        &mut self,
+        buf: &[u8],
+        addr: &SocketAddr,
+        ecn: Option<EcnCodepoint>,
+    ) -> Poll<usize, io::Error> {
+        linux::poll_send(self, buf, addr, ecn)
+    }
+
+    #[cfg(target_os = "linux")]
+    fn poll_send_batch(&mut self, transmits: &[Transmit]) -> Poll<usize, io::Error> {
+        linux::poll_send_batch(self, transmits)
+    }
+
+    #[cfg(target_os = "linux")]
+    fn poll_recv_batch(
+        &mut self,
+        bufs: &mut [Vec<u8>],
+        meta: &mut [RecvMeta],
+    ) -> Poll<usize, io::Error> {
+        linux::poll_recv_batch(self, bufs, meta)
+    }
+}
+
+// Asks the kernel to hand us the ECN bits of incoming packets
+#[cfg(target_os = "linux")]
+pub(crate) fn enable_ecn(socket: &UdpSocket) -> io::Result<()> {
+    linux::enable_ecn(socket)
+}
+
+#[cfg(not(target_os = "linux"))]
+pub(crate) fn enable_ecn(_: &UdpSocket) -> io::Result<()> {
+    Ok(())
+}
+
+// Lets the kernel coalesce datagrams from one source; only for sockets read via poll_recv_batch
+#[cfg(target_os = "linux")]
+pub(crate) fn enable_gro(socket: &UdpSocket) -> io::Result<()> {
+    linux::enable_gro(socket)
+}
+
+#[cfg(not(target_os = "linux"))]
+pub(crate) fn enable_gro(_: &UdpSocket) -> io::Result<()> {
+    Ok(())
+}
+
+#[cfg(target_os = "linux")]
+mod linux {
+    use futures::{Async, Poll};
+    use libc;
+    use mio::Ready;
+    use tokio::net::UdpSocket;
+
+    use std::cmp;
+    use std::io;
+    use std::mem;
+    use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, SocketAddrV6};
+    use std::os::unix::io::AsRawFd;
+    use std::ptr;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use super::{EcnCodepoint, RecvMeta, Transmit};
+
+    const CMSG_LEN: usize = 64;
+    const BATCH_SIZE: usize = 32;
+    // Not in libc yet; kernels before 4.18 reject it
+    const UDP_SEGMENT: libc::c_int = 103;
+    // Likewise, from 5.0
+    const UDP_GRO: libc::c_int = 104;
+
+    const GSO_UNKNOWN: usize = 0;
+    const GSO_UNSUPPORTED: usize = 1;
+    const GSO_SUPPORTED: usize = 2;
+    static GSO: AtomicUsize = AtomicUsize::new(GSO_UNKNOWN);
+
+    pub fn enable_ecn(socket: &UdpSocket) -> io::Result<()> {
+        let (level, name) = if socket.local_addr()?.is_ipv4() {
+            (libc::IPPROTO_IP, libc::IP_RECVTOS)
+        } else {
+            (libc::IPPROTO_IPV6, libc::IPV6_RECVTCLASS)
+        };
+        let on: libc::c_int = 1;
+        let rc = unsafe {
+            libc::setsockopt(
+                socket.as_raw_fd(),
+                level,
+                name,
+                &on as *const _ as *const libc::c_void,
+                mem::size_of::<libc::c_int>() as libc::socklen_t,
+            )
+        };
+        if rc == -1 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+
+    pub fn enable_gro(socket: &UdpSocket) -> io::Result<()> {
+        let on: libc::c_int = 1;
+        let rc = unsafe {
+            libc::setsockopt(
+                socket.as_raw_fd(),
+                libc::IPPROTO_UDP,
+                UDP_GRO,
+                &on as *const _ as *const libc::c_void,
+                mem::size_of::<libc::c_int>() as libc::socklen_t,
+            )
+        };
+        if rc == -1 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+
+    pub fn poll_recv(
+        socket: &mut UdpSocket,
+        buf: &mut [u8],
+    ) -> Poll<(usize, SocketAddr, Option<EcnCodepoint>), io::Error> {
+        try_ready!(socket.poll_read_ready(Ready::readable()));
+        match recv(socket.as_raw_fd(), buf) {
+            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
+                socket.clear_read_ready(Ready::readable())?;
+                Ok(Async::NotReady)
+            }
+            res => res.map(Async::Ready),
+        }
+    }
+
+    pub fn poll_send(
+        socket: &mut UdpSocket,
+        buf: &[u8],
+        addr: &SocketAddr,
+        ecn: Option<EcnCodepoint>,
+    ) -> Poll<usize, io::Error> {
+        try_ready!(socket.poll_write_ready());
+        match send(socket.as_raw_fd(), buf, addr, ecn) {
+            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
+                socket.clear_write_ready()?;
+                Ok(Async::NotReady)
+            }
+            res => res.map(Async::Ready),
+        }
+    }
+
+    pub fn poll_recv_batch(
+        socket: &mut UdpSocket,
+        bufs: &mut [Vec<u8>],
+        meta: &mut [RecvMeta],
+    ) -> Poll<usize, io::Error> {
+        try_ready!(socket.poll_read_ready(Ready::readable()));
+        match recv_batch(socket.as_raw_fd(), bufs, meta) {
+            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
+                socket.clear_read_ready(Ready::readable())?;
+                Ok(Async::NotReady)
+            }
+            res => res.map(Async::Ready),
+        }
+    }
+
+    pub fn poll_send_batch(
+        socket: &mut UdpSocket,
+        transmits: &[Transmit],
+    ) -> Poll<usize, io::Error> {
+        try_ready!(socket.poll_write_ready());
+        let transmits = &transmits[..cmp::min(transmits.len(), BATCH_SIZE)];
+        loop {
+            let gso = gso_supported(socket.as_raw_fd());
+            match send_batch(socket.as_raw_fd(), transmits, gso) {
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
+                    socket.clear_write_ready()?;
+                    return Ok(Async::NotReady);
+                }
+                // Devices without checksum offload can't segment, which only shows up here
+                Err(ref e) if gso && e.raw_os_error() == Some(libc::EIO) => {
+                    debug!("disabling UDP GSO after send failure: {:?}", e);
+                    GSO.store(GSO_UNSUPPORTED, Ordering::Relaxed);
+                }
+                res => return res.map(Async::Ready),
+            }
+        }
+    }
+
+    fn gso_supported(fd: libc::c_int) -> bool {
+        match GSO.load(Ordering::Relaxed) {
+            GSO_UNKNOWN => {
+                let mut value: libc::c_int = 0;
+                let mut len = mem::size_of::<libc::c_int>() as libc::socklen_t;
+                let rc = unsafe {
+                    libc::getsockopt(
+                        fd,
+                        libc::IPPROTO_UDP,
+                        UDP_SEGMENT,
+                        &mut value as *mut _ as *mut libc::c_void,
+                        &mut len,
+                    )
+                };
+                let state = if rc == -1 {
+                    GSO_UNSUPPORTED
+                } else {
+                    GSO_SUPPORTED
+                };
+                GSO.store(state, Ordering::Relaxed);
+                state == GSO_SUPPORTED
+            }
+            state => state == GSO_SUPPORTED,
+        }
+    }
+
+    pub fn send_batch(fd: libc::c_int, transmits: &[Transmit], gso: bool) -> io::Result<usize> {
+        // A GSO send splits one buffer into equal-sized datagrams for the same destination,
+        // of which only the last may be shorter
+        let mut groups = Vec::new();
+        let mut start = 0;
+        while start < transmits.len() {
+            let first = &transmits[start];
+            let mut end = start + 1;
+            while gso && end < transmits.len() {
+                let (prev, next) = (&transmits[end - 1], &transmits[end]);
+                if next.destination != first.destination || next.ecn != first.ecn
+                    || prev.contents.len() != first.contents.len()
+                    || next.contents.len() > first.contents.len()
+                {
+                    break;
+                }
+                end += 1;
+            }
+            groups.push((start, end));
+            start = end;
+        }
+
+        // The kernel gathers each group's iovecs into the buffer it then segments
+        let mut iovs = transmits
+            .iter()
+            .map(|transmit| libc::iovec {
+                iov_base: transmit.contents.as_ptr() as *mut libc::c_void,
+                iov_len: transmit.contents.len(),
+            })
+            .collect::<Vec<_>>();
+        let mut names = vec![unsafe { mem::zeroed::<libc::sockaddr_storage>() }; groups.len()];
+        let mut controls = vec![[0u64; CMSG_LEN / 8]; groups.len()];
+        let mut msgs = Vec::with_capacity(groups.len());
+        for (i, &(start, end)) in groups.iter().enumerate() {
+            let transmit = &transmits[start];
+            let (name, name_len) = encode_addr(&transmit.destination);
+            names[i] = name;
+            let segment = if end - start > 1 {
+                Some(transmit.contents.len() as u16)
+            } else {
+                None
+            };
+            let control_len = unsafe {
+                write_cmsgs(&mut controls[i], &transmit.destination, transmit.ecn, segment)
+            };
+
+            let mut hdr: libc::msghdr = unsafe { mem::zeroed() };
+            hdr.msg_name = &mut names[i] as *mut _ as *mut libc::c_void;
+            hdr.msg_namelen = name_len;
+            hdr.msg_iov = &mut iovs[start] as *mut libc::iovec;
+            hdr.msg_iovlen = (end - start) as _;
+            hdr.msg_control = controls[i].as_mut_ptr() as *mut libc::c_void;
+            hdr.msg_controllen = control_len as _;
+            msgs.push(libc::mmsghdr {
+                msg_hdr: hdr,
+                msg_len: 0,
+            });
+        }
+
+        let sent = unsafe { libc::sendmmsg(fd, msgs.as_mut_ptr(), msgs.len() as _, 0) };
+        if sent == -1 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(groups[..sent as usize].last().map_or(0, |&(_, end)| end))
+    }
+
+    fn recv(
+        fd: libc::c_int,
+        buf: &mut [u8],
+    ) -> io::Result<(usize, SocketAddr, Option<EcnCodepoint>)> {
+        let mut name: libc::sockaddr_storage = unsafe { mem::zeroed() };
+        let mut iov = libc::iovec {
+            iov_base: buf.as_mut_ptr() as *mut libc::c_void,
+            iov_len: buf.len(),
+        };
+        let mut control = [0u64; CMSG_LEN / 8];
+        let mut hdr: libc::msghdr = unsafe { mem::zeroed() };
+        hdr.msg_name = &mut name as *mut _ as *mut libc::c_void;
+        hdr.msg_namelen = mem::size_of::<libc::sockaddr_storage>() as libc::socklen_t;
+        hdr.msg_iov = &mut iov;
+        hdr.msg_iovlen = 1;
+        hdr.msg_control = control.as_mut_ptr() as *mut libc::c_void;
+        hdr.msg_controllen = CMSG_LEN as _;
+
+        let len = unsafe { libc::recvmsg(fd, &mut hdr, 0) };
+        if len == -1 {
+            return Err(io::Error::last_os_error());
+        }
+        let addr = decode_addr(&name)?;
+        let (ecn, _) = decode_cmsgs(&hdr);
+        Ok((len as usize, addr, ecn))
+    }
+
+    pub fn recv_batch(
+        fd: libc::c_int,
+        bufs: &mut [Vec<u8>],
+        meta: &mut [RecvMeta],
+    ) -> io::Result<usize> {
+        let count = cmp::min(cmp::min(bufs.len(), meta.len()), BATCH_SIZE);
+        let mut names = vec![unsafe { mem::zeroed::<libc::sockaddr_storage>() }; count];
+        let mut controls = vec![[0u64; CMSG_LEN / 8]; count];
+        let mut iovs = bufs[..count]
+            .iter_mut()
+            .map(|buf| libc::iovec {
+                iov_base: buf.as_mut_ptr() as *mut libc::c_void,
+                iov_len: buf.len(),
+            })
+            .collect::<Vec<_>>();
+        let mut msgs = Vec::with_capacity(count);
+        for i in 0..count {
+            let mut hdr: libc::msghdr = unsafe { mem::zeroed() };
+            hdr.msg_name = &mut names[i] as *mut _ as *mut libc::c_void;
+            hdr.msg_namelen = mem::size_of::<libc::sockaddr_storage>() as libc::socklen_t;
+            hdr.msg_iov = &mut iovs[i] as *mut libc::iovec;
+            hdr.msg_iovlen = 1;
+            hdr.msg_control = controls[i].as_mut_ptr() as *mut libc::c_void;
+            hdr.msg_controllen = CMSG_LEN as _;
+            msgs.push(libc::mmsghdr {
+                msg_hdr: hdr,
+                msg_len: 0,
+            });
+        }
+
+        // On a non-blocking socket this returns whatever is queued, up to `count`
+        let received = unsafe {
+            libc::recvmmsg(fd, msgs.as_mut_ptr(), count as _, 0, ptr::null_mut())
+        };
+        if received == -1 {
+            return Err(io::Error::last_os_error());
+        }
+        for (i, msg) in msgs[..received as usize].iter().enumerate() {
+            let len = msg.msg_len as usize;
+            let (ecn, stride) = decode_cmsgs(&msg.msg_hdr);
+            meta[i] = RecvMeta {
+                source: decode_addr(&names[i])?,
+                len,
+                stride: stride.unwrap_or(len),
+                ecn,
+            };
+        }
+        Ok(received as usize)
+    }
+
+    fn decode_cmsgs(hdr: &libc::msghdr) -> (Option<EcnCodepoint>, Option<usize>) {
+        let (mut ecn, mut stride) = (None, None);
+        let mut cmsg = unsafe { first_cmsg(hdr) };
+        while let Some(msg) = cmsg {
+            let data = unsafe { (msg as *const libc::cmsghdr).offset(1) as *const u8 };
+            match (msg.cmsg_level, msg.cmsg_type) {
+                // IPv4 reports the TOS byte on its own, IPv6 widens the traffic class to an int
+                (libc::IPPROTO_IP, libc::IP_TOS) => {
+                    ecn = EcnCodepoint::from_bits(unsafe { *data });
+                }
+                (libc::IPPROTO_IPV6, libc::IPV6_TCLASS) => {
+                    let tclass = unsafe { ptr::read_unaligned(data as *const libc::c_int) };
+                    ecn = EcnCodepoint::from_bits(tclass as u8);
+                }
+                (libc::IPPROTO_UDP, UDP_GRO) => {
+                    let size = unsafe { ptr::read_unaligned(data as *const libc::c_int) };
+                    stride = Some(size as usize);
+                }
+                _ => {}
+            }
+            cmsg = unsafe { next_cmsg(hdr, msg) };
+        }
+        (ecn, stride)
+    }
+
+    fn send(
+        fd: libc::c_int,
+        buf: &[u8],
+        addr: &SocketAddr,
+        ecn: Option<EcnCodepoint>,
+    ) -> io::Result<usize> {
+        let (mut name, name_len) = encode_addr(addr);
+        let mut iov = libc::iovec {
+            iov_base: buf.as_ptr() as *mut libc::c_void,
+            iov_len: buf.len(),
+        };
+        let mut control = [0u64; CMSG_LEN / 8];
+        let mut hdr: libc::msghdr = unsafe { mem::zeroed() };
+        hdr.msg_name = &mut name as *mut _ as *mut libc::c_void;
+        hdr.msg_namelen = name_len;
+        hdr.msg_iov = &mut iov;
+        hdr.msg_iovlen = 1;
+
+        let control_len = unsafe { write_cmsgs(&mut control, addr, ecn, None) };
+        hdr.msg_control = control.as_mut_ptr() as *mut libc::c_void;
+        hdr.msg_controllen = control_len as _;
+
+        let len = unsafe { libc::sendmsg(fd, &hdr, 0) };
+        if len == -1 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(len as usize)
+    }
+
+    unsafe fn write_cmsgs(
+        control: &mut [u64; CMSG_LEN / 8],
+        addr: &SocketAddr,
+        ecn: Option<EcnCodepoint>,
+        segment: Option<u16>,
+    ) -> usize {
+        // Without a mark, the packet goes out as Not-ECT
+        let tos = libc::c_int::from(ecn.map_or(0, EcnCodepoint::bits));
+        let (level, kind) = match *addr {
+            SocketAddr::V4(_) => (libc::IPPROTO_IP, libc::IP_TOS),
+            SocketAddr::V6(_) => (libc::IPPROTO_IPV6, libc::IPV6_TCLASS),
+        };
+        let buf = control.as_mut_ptr() as *mut u8;
+        let mut len = write_cmsg(buf, level, kind, tos);
+        if let Some(segment) = segment {
+            len += write_cmsg(buf.offset(len as isize), libc::IPPROTO_UDP, UDP_SEGMENT, segment);
+        }
+        len
+    }
+
+    unsafe fn write_cmsg<T>(
+        buf: *mut u8,
+        level: libc::c_int,
+        kind: libc::c_int,
+        value: T,
+    ) -> usize {
+        let hdr_len = cmsg_align(mem::size_of::<libc::cmsghdr>());
+        let msg = buf as *mut libc::cmsghdr;
+        (*msg).cmsg_level = level;
+        (*msg).cmsg_type = kind;
+        (*msg).cmsg_len = (hdr_len + mem::size_of::<T>()) as _;
+        ptr::write_unaligned(buf.offset(hdr_len as isize) as *mut T, value);
+        hdr_len + cmsg_align(mem::size_of::<T>())
+    }
+
+    fn cmsg_align(len: usize) -> usize {
+        let align = mem::size_of::<usize>();
+        (len + align - 1) & !(align - 1)
+    }
+
+    unsafe fn first_cmsg(hdr: &libc::msghdr) -> Option<&libc::cmsghdr> {
+        if (hdr.msg_controllen as usize) < mem::size_of::<libc::cmsghdr>() {
+            return None;
+        }
+        Some(&*(hdr.msg_control as *const libc::cmsghdr))
+    }
+
+    unsafe fn next_cmsg<'a>(
+        hdr: &'a libc::msghdr,
+        msg: &'a libc::cmsghdr,
+    ) -> Option<&'a libc::cmsghdr> {
+        let start = hdr.msg_control as usize;
+        let next = msg as *const _ as usize + cmsg_align(msg.cmsg_len as usize);
+        let end = start + hdr.msg_controllen as usize;
+        if msg.cmsg_len == 0 || next + mem::size_of::<libc::cmsghdr>() > end {
+            return None;
+        }
+        Some(&*(next as *const libc::cmsghdr))
+    }
+
+    fn decode_addr(name: &libc::sockaddr_storage) -> io::Result<SocketAddr> {
+        match libc::c_int::from(name.ss_family) {
+            libc::AF_INET => {
+                let addr = unsafe { &*(name as *const _ as *const libc::sockaddr_in) };
+                let ip = Ipv4Addr::from(u32::from_be(addr.sin_addr.s_addr));
+                Ok(SocketAddr::V4(SocketAddrV4::new(ip, u16::from_be(addr.sin_port))))
+            }
+            libc::AF_INET6 => {
+                let addr = unsafe { &*(name as *const _ as *const libc::sockaddr_in6) };
+                Ok(SocketAddr::V6(SocketAddrV6::new(
+                    Ipv6Addr::from(addr.sin6_addr.s6_addr),
+                    u16::from_be(addr.sin6_port),
+                    addr.sin6_flowinfo,
+                    addr.sin6_scope_id,
+                )))
+            }
+            family => Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("unexpected address family {}", family),
+            )),
+        }
+    }
+
+    fn encode_addr(addr: &SocketAddr) -> (libc::sockaddr_storage, libc::socklen_t) {
+        let mut name: libc::sockaddr_storage = unsafe { mem::zeroed() };
+        let len = match *addr {
+            SocketAddr::V4(ref addr) => {
+                let out = unsafe { &mut *(&mut name as *mut _ as *mut libc::sockaddr_in) };
+                out.sin_family = libc::AF_INET as libc::sa_family_t;
+                out.sin_port = addr.port().to_be();
+                out.sin_addr.s_addr = u32::from(*addr.ip()).to_be();
+                mem::size_of::<libc::sockaddr_in>()
+            }
+            SocketAddr::V6(ref addr) => {
+                let out = unsafe { &mut *(&mut name as *mut _ as *mut libc::sockaddr_in6) };
+                out.sin6_family = libc::AF_INET6 as libc::sa_family_t;
+                out.sin6_port = addr.port().to_be();
+                out.sin6_addr.s6_addr = addr.ip().octets();
+                out.sin6_flowinfo = addr.flowinfo();
+                out.sin6_scope_id = addr.scope_id();
+                mem::size_of::<libc::sockaddr_in6>()
+            }
+        };
+        (name, len as libc::socklen_t)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{EcnCodepoint, RecvMeta, Socket, Transmit};
+    use futures::{Async, Poll};
+
+    use std::io;
+    use std::net::SocketAddr;
+
+    struct Limited {
+        sent: Vec<Vec<u8>>,
+        capacity: usize,
+    }
+
+    impl Socket for Limited {
+        fn poll_recv_from(&mut self, _: &mut [u8]) -> Poll<(usize, SocketAddr), io::Error> {
+            Ok(Async::NotReady)
+        }
+
+        fn poll_send_to(&mut self, buf: &[u8], _: &SocketAddr) -> Poll<usize, io::Error> {
+            if self.sent.len() == self.capacity {
+                return Ok(Async::NotReady);
+            }
+            self.sent.push(buf.to_vec());
+            Ok(Async::Ready(buf.len()))
+        }
+    }
+
+    fn transmits(sizes: &[usize], destination: SocketAddr) -> Vec<Transmit> {
+        sizes
+            .iter()
+            .enumerate()
+            .map(|(i, &size)| Transmit {
+                destination,
+                ecn: Some(EcnCodepoint::Ect0),
+                contents: vec![i as u8; size],
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_send_batch_fallback() {
+        let batch = transmits(&[10, 10, 10], "127.0.0.1:4433".parse().unwrap());
+        let mut socket = Limited {
+            sent: Vec::new(),
+            capacity: 2,
+        };
+        assert_eq!(socket.poll_send_batch(&batch).unwrap(), Async::Ready(2));
+        assert_eq!(socket.sent, vec![vec![0; 10], vec![1; 10]]);
+        assert_eq!(socket.poll_send_batch(&batch[2..]).unwrap(), Async::NotReady);
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_send_batch_segments() {
+        use std::net::UdpSocket;
+        use std::os::unix::io::AsRawFd;
+
+        let receiver = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let sender = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let batch = transmits(&[100, 100, 60], receiver.local_addr().unwrap());
+
+        // Whether or not the kernel segments, the peer sees the original datagrams
+        for &gso in &[false, true] {
+            match super::linux::send_batch(sender.as_raw_fd(), &batch, gso) {
+                Ok(sent) => assert_eq!(sent, 3),
+                Err(_) if gso => continue,
+                Err(e) => panic!("send failed: {:?}", e),
+            }
+            let mut buf = [0; 1500];
+            for (i, &size) in [100, 100, 60].iter().enumerate() {
+                let (len, _) = receiver.recv_from(&mut buf).unwrap();
+                assert_eq!(&buf[..len], &vec![i as u8; size][..]);
+            }
+        }
+    }
+
+    #[test]
+    fn test_ecn_bits() {
+        for ecn in &[EcnCodepoint::Ect0, EcnCodepoint::Ect1, EcnCodepoint::Ce] {
+            assert_eq!(EcnCodepoint::from_bits(ecn.bits()), Some(*ecn));
+        }
+        // Only the two low bits of the TOS byte carry ECN
+        assert_eq!(EcnCodepoint::from_bits(0xb8 | 0b10), Some(EcnCodepoint::Ect0));
+        assert_eq!(EcnCodepoint::from_bits(0xb8), None);
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_recv_batch() {
+        use std::net::UdpSocket;
+        use std::os::unix::io::AsRawFd;
+
+        let receiver = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let sender = UdpSocket::bind("127.0.0.1:0").unwrap();
+        for &size in &[100, 60, 100] {
+            sender
+                .send_to(&vec![size as u8; size], receiver.local_addr().unwrap())
+                .unwrap();
+        }
+        receiver.set_nonblocking(true).unwrap();
+
+        let mut bufs = vec![vec![0; 1500]; 4];
+        let mut meta = vec![
+            RecvMeta {
+                source: sender.local_addr().unwrap(),
+                len: 0,
+                stride: 0,
+                ecn: None,
+            };
+            4
+        ];
+        let received = super::linux::recv_batch(receiver.as_raw_fd(), &mut bufs, &mut meta);
+        assert_eq!(received.unwrap(), 3);
+        for (i, &size) in [100, 60, 100].iter().enumerate() {
+            assert_eq!(meta[i].source, sender.local_addr().unwrap());
+            assert_eq!((meta[i].len, meta[i].stride), (size, size));
+            assert_eq!(&bufs[i][..size], &vec![size as u8; size][..]);
+        }
+
+        let received = super::linux::recv_batch(receiver.as_raw_fd(), &mut bufs, &mut meta);
+        assert_eq!(received.unwrap_err().kind(), io::ErrorKind::WouldBlock);
+    }
+}
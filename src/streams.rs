@@ -1,14 +1,34 @@
+use bytes::Bytes;
 use futures::future::{self, Future};
 use futures::sync::oneshot;
-use futures::task;
+use futures::{task, Async, Poll, Stream as FuturesStream};
 
+use std::cmp;
 use std::collections::{HashMap, VecDeque};
+use std::io::{self, Read, Write};
+use std::mem;
 use std::sync::{Arc, Mutex};
+use std::time::Duration;
 
-use super::QuicError;
-use frame::{Frame, StreamIdBlockedFrame};
-use types::Side;
+use tokio::io::{AsyncRead, AsyncWrite};
 
+use super::{QuicError, QuicResult, TransportError};
+use assembler::Assembler;
+use clock::{Clock, SystemClock};
+use events::{Event, Events};
+use flow_control::FlowControl;
+use frame::{BlockedFrame, Frame, MaxDataFrame, MaxStreamDataFrame, MaxStreamIdFrame,
+            RstStreamFrame, StopSendingFrame, StreamBlockedFrame, StreamFrame,
+            StreamIdBlockedFrame};
+use parameters::TransportConfig;
+use timers::{Timeout, Timers};
+use types::{Side, StreamId};
+
+// Connection-wide state (flow control, scheduling, stream bookkeeping) sits behind one lock and
+// each stream's state behind its own, shared with the stream's handles. Anything that needs both
+// takes the connection lock first. Handles do their per-stream work, such as taking data out of
+// the reassembly buffer or copying writes, holding only their stream's lock, and take the
+// connection lock just long enough to update credit and scheduling.
 #[derive(Clone)]
 pub struct Streams {
     inner: Arc<Mutex<Inner>>,
@@ -22,88 +42,673 @@ impl Streams {
             OpenStreams::new(),
             OpenStreams::new(),
         ];
-        if let Side::Client = side {
-            open[0].next = Some(0);
+        for &dir in &[Dir::Bidi, Dir::Uni] {
+            open[stype(side, dir)].next = Some(StreamId::new(side, dir, 0));
         }
 
         Self {
             inner: Arc::new(Mutex::new(Inner {
                 side,
-                task: None,
+                conn_tasks: Wakers::default(),
                 queue: VecDeque::new(),
                 streams: HashMap::new(),
                 open,
+                incoming: VecDeque::new(),
+                incoming_tasks: Wakers::default(),
+                sending: VecDeque::new(),
+                flow: FlowControl::new(0, 0),
+                flow_blocked: Vec::new(),
+                initial_max_stream_data: 0,
+                stream_window: 0,
+                window_limit: None,
+                rtt: Duration::from_millis(0),
+                clock: Arc::new(SystemClock),
+                timers: None,
+                buffer_limit: DEFAULT_RECEIVE_BUFFER,
+                send_buffer: DEFAULT_SEND_BUFFER,
+                coalesce: Some(0),
+                reset_code: 0,
+                buffered: 0,
+                max_buffered: DEFAULT_CONNECTION_BUFFER,
+                credit_held: false,
+                peer_blocked: 0,
+                events: Events::new(),
             })),
         }
     }
 
+    // Streams with the limits we advertise to the peer already in place
+    pub fn with_config(side: Side, config: &TransportConfig) -> Self {
+        let params = config.parameters();
+        let peer = side.other();
+        let mut streams = Self::new(side);
+        let max_bidi = u64::from(params.max_streams_bidi);
+        streams.update_max_id(StreamId::new(peer, Dir::Bidi, max_bidi));
+        let max_uni = u64::from(params.max_stream_id_uni);
+        streams.update_max_id(StreamId::new(peer, Dir::Uni, max_uni));
+        {
+            let mut me = streams.inner.lock().unwrap();
+            me.open[stype(peer, Dir::Bidi)].window = max_bidi + 1;
+            me.open[stype(peer, Dir::Uni)].window = max_uni + 1;
+        }
+        streams.set_receive_windows(
+            u64::from(params.max_data),
+            u64::from(params.max_stream_data),
+        );
+        if let Some(limit) = config.stream_window_limit() {
+            streams.set_window_autotune(limit);
+        }
+        streams
+    }
+
     pub fn set_task(&mut self, task: task::Task) {
         let mut me = self.inner.lock().unwrap();
-        me.task = Some(task);
+        me.conn_tasks.register(task);
+    }
+
+    pub fn set_events(&mut self, events: Events) {
+        let mut me = self.inner.lock().unwrap();
+        me.events = events;
     }
 
     pub fn queued(&mut self) -> Option<Frame> {
         let mut me = self.inner.lock().unwrap();
-        me.queue.pop_front()
+        match me.queue.pop_front() {
+            Some(frame) => Some(frame),
+            None => me.next_stream_frame().map(Frame::Stream),
+        }
     }
 
-    pub fn init_send(&mut self, dir: Dir) -> Option<StreamRef> {
+    pub fn requeue(&mut self, frame: Frame) {
         let mut me = self.inner.lock().unwrap();
-        let stype = (me.side.to_bit() + dir.to_bit()) as usize;
-        let next = me.open[stype].next;
+        match frame {
+            Frame::Stream(f) => me.requeue_stream(f),
+            frame => me.queue.push_front(frame),
+        }
+    }
 
-        if let Some(id) = next {
-            me.open[stype].next = if id + 4 < me.open[stype].max {
-                Some(id + 4)
-            } else {
-                None
-            };
+    pub(crate) fn init_send(&mut self, dir: Dir) -> Option<StreamRef> {
+        self.init_send_batch(dir, 1).and_then(|mut streams| streams.pop())
+    }
+
+    // Opens consecutive streams under a single lock; either all of them fit under the peer's
+    // limit or none are opened
+    pub(crate) fn init_send_batch(&mut self, dir: Dir, count: usize) -> Option<Vec<StreamRef>> {
+        if count == 0 {
+            return Some(Vec::new());
+        }
+        let mut me = self.inner.lock().unwrap();
+        let stype = stype(me.side, dir);
+        let first = me.open[stype].next?;
+        let last = StreamId(first.0 + 4 * (count as u64 - 1));
+        if last > me.open[stype].max {
+            return None;
         }
+        me.open[stype].next = Some(last.next());
 
-        next.map(|id| {
-            me.streams.insert(id, Stream::new());
-            StreamRef {
+        let mut streams = Vec::with_capacity(count);
+        let mut id = first;
+        for _ in 0..count {
+            let stream = Arc::new(Mutex::new(me.new_stream()));
+            me.streams.insert(id, stream.clone());
+            streams.push(StreamRef {
                 inner: self.inner.clone(),
+                stream,
                 id,
+            });
+            id = id.next();
+        }
+        Some(streams)
+    }
+
+    // The peer's initial limit is also how many of our streams it lets us have open at once
+    pub fn set_send_window(&mut self, dir: Dir, window: u64) {
+        let mut me = self.inner.lock().unwrap();
+        let stype = stype(me.side, dir);
+        me.open[stype].window = window;
+    }
+
+    fn exceeds_send_window(&self, dir: Dir, count: usize) -> bool {
+        let me = self.inner.lock().unwrap();
+        let window = me.open[stype(me.side, dir)].window;
+        window > 0 && count as u64 > window
+    }
+
+    pub fn update_max_id(&mut self, id: StreamId) {
+        let mut me = self.inner.lock().unwrap();
+        {
+            let open = &mut me.open[stype(id.initiator(), id.dir())];
+            if id < open.max {
+                return;
+            }
+            open.max = id;
+
+            let (ready, waiting) = open.updates.drain(..).partition(|&(wanted, _)| wanted <= id);
+            open.updates = waiting;
+            for (_, sender) in ready {
+                let _ = sender.send(id);
+            }
+        }
+        if id.initiator() == me.side {
+            me.events.push(Event::MaxStreamsChanged(me.limits()));
+        }
+    }
+
+    // The peer wants to open a stream past our limit. It gets a new limit if it missed an update,
+    // or if fewer of its streams are open than configured; anything else is left blocked.
+    pub fn stream_id_blocked_received(&mut self, id: StreamId) -> QuicResult<()> {
+        let mut guard = self.inner.lock().unwrap();
+        let me = &mut *guard;
+        if id.initiator() == me.side {
+            return Err(QuicError::Transport(
+                TransportError::StreamStateError,
+                format!("peer blocked on locally-initiated stream {}", id),
+            ));
+        }
+        let live = me.streams
+            .keys()
+            .filter(|other| other.initiator() == id.initiator() && other.dir() == id.dir())
+            .count() as u64;
+        let open = &mut me.open[stype(id.initiator(), id.dir())];
+        let allowed = open.remote + open.window.saturating_sub(live);
+        if id > open.max && allowed > 0 {
+            open.max = cmp::max(open.max, StreamId::new(id.initiator(), id.dir(), allowed - 1));
+        }
+        if id <= open.max {
+            open.blocked = 0;
+            me.queue
+                .push_back(Frame::MaxStreamId(MaxStreamIdFrame(open.max)));
+            me.conn_tasks.wake();
+            return Ok(());
+        }
+
+        open.blocked += 1;
+        me.peer_blocked += 1;
+        if open.blocked == PERSISTENTLY_BLOCKED {
+            me.events.push(Event::PeerStreamsBlocked(id));
+        }
+        Ok(())
+    }
+
+    // How often the peer said it was blocked on a stream limit we kept in place
+    pub fn peer_blocked(&self) -> u64 {
+        self.inner.lock().unwrap().peer_blocked
+    }
+
+    // Received data that hasn't been read yet, plus written data that hasn't been acknowledged
+    pub fn buffered(&self) -> usize {
+        let me = self.inner.lock().unwrap();
+        let unacked = me.streams
+            .values()
+            .map(|stream| {
+                let stream = stream.lock().unwrap();
+                (stream.offset - stream.acked) as usize
+            })
+            .sum::<usize>();
+        me.buffered + unacked
+    }
+
+    pub fn received(&mut self, id: StreamId) -> Option<StreamRef> {
+        let mut me = self.inner.lock().unwrap();
+        if !me.streams.contains_key(&id) {
+            if me.is_closed(id) || me.check_peer_stream(id).is_err() {
+                return None;
             }
+            me.open_stream(id);
+        }
+        Some(StreamRef {
+            inner: self.inner.clone(),
+            stream: me.streams.get(&id)?.clone(),
+            id,
         })
     }
 
-    pub fn update_max_id(&mut self, id: u64) {
+    pub fn limits(&self) -> StreamLimits {
+        self.inner.lock().unwrap().limits()
+    }
+
+    pub fn incoming(&self) -> IncomingStreams {
+        IncomingStreams {
+            inner: self.inner.clone(),
+        }
+    }
+
+    pub fn open(&self, dir: Dir) -> OpenStream {
+        self.open_with(dir, OpenOptions::default())
+    }
+
+    pub fn open_with(&self, dir: Dir, options: OpenOptions) -> OpenStream {
+        self.open_batch(dir, 1, options)
+    }
+
+    fn open_batch(&self, dir: Dir, count: usize, options: OpenOptions) -> OpenStream {
+        OpenStream {
+            streams: self.clone(),
+            dir,
+            count,
+            options,
+            waiting: None,
+        }
+    }
+
+    pub fn accept_uni(&self) -> AcceptUni {
+        AcceptUni {
+            inner: self.inner.clone(),
+        }
+    }
+
+    fn wait_for_open(&self, dir: Dir, count: usize) -> oneshot::Receiver<StreamId> {
+        let mut me = self.inner.lock().unwrap();
+        let stype = stype(me.side, dir);
+        let (p, c) = oneshot::channel();
+        let next = match me.open[stype].next {
+            Some(next) => StreamId(next.0 + 4 * (count as u64 - 1)),
+            None => return c,
+        };
+        if next <= me.open[stype].max {
+            // The limit was raised since we last looked
+            let _ = p.send(next);
+            return c;
+        }
+        me.open[stype].updates.push((next, p));
+        me.queue
+            .push_back(Frame::StreamIdBlocked(StreamIdBlockedFrame(next)));
+        me.conn_tasks.wake();
+        c
+    }
+
+    pub fn set_send_limits(&mut self, max_data: u64, max_stream_data: u64) {
         let mut me = self.inner.lock().unwrap();
-        me.open[(id % 4) as usize].max = id;
+        me.flow.update_send_max(max_data);
+        me.initial_max_stream_data = max_stream_data;
+        for stream in me.streams.values() {
+            stream.lock().unwrap().flow.update_send_max(max_stream_data);
+        }
     }
 
-    pub fn received(&mut self, id: u64) -> Option<StreamRef> {
+    pub fn set_receive_windows(&mut self, max_data: u64, max_stream_data: u64) {
         let mut me = self.inner.lock().unwrap();
-        match me.streams.get(&id) {
-            Some(_) => Some(StreamRef {
-                inner: self.inner.clone(),
-                id,
-            }),
-            None => {
-                let stype = (id % 4) as usize;
-                if id > me.open[stype].max {
-                    None
+        me.flow.set_recv_window(max_data);
+        me.stream_window = max_stream_data;
+        for stream in me.streams.values() {
+            stream.lock().unwrap().flow.set_recv_window(max_stream_data);
+        }
+    }
+
+    // Per-stream windows may grow up to this when readers keep up with the peer
+    pub fn set_window_autotune(&mut self, limit: u64) {
+        let mut me = self.inner.lock().unwrap();
+        me.window_limit = Some(limit);
+        let limit = cmp::min(limit, me.buffer_limit as u64);
+        for stream in me.streams.values() {
+            stream.lock().unwrap().flow.set_autotune(limit);
+        }
+    }
+
+    pub fn set_rtt(&mut self, rtt: Duration) {
+        let mut me = self.inner.lock().unwrap();
+        me.rtt = rtt;
+    }
+
+    pub fn set_clock(&mut self, clock: Arc<Clock>) {
+        let mut me = self.inner.lock().unwrap();
+        me.clock = clock;
+    }
+
+    pub(crate) fn set_timers(&mut self, timers: Timers) {
+        let mut me = self.inner.lock().unwrap();
+        me.timers = Some(timers);
+    }
+
+    pub fn timeout<F>(&self, future: F, after: Duration) -> Timeout<F> {
+        self.inner.lock().unwrap().timeout(future, after)
+    }
+
+    pub fn set_receive_buffer(&mut self, limit: usize) {
+        let mut me = self.inner.lock().unwrap();
+        me.buffer_limit = limit;
+        for stream in me.streams.values() {
+            stream.lock().unwrap().received.set_limit(limit);
+        }
+    }
+
+    pub fn set_connection_buffer(&mut self, limit: usize) {
+        let mut me = self.inner.lock().unwrap();
+        me.max_buffered = limit;
+    }
+
+    pub fn set_send_buffer(&mut self, limit: usize) {
+        let mut me = self.inner.lock().unwrap();
+        me.send_buffer = limit;
+        for stream in me.streams.values() {
+            stream.lock().unwrap().send_limit = limit;
+        }
+    }
+
+    // None sends every write as it comes; otherwise small writes share frames, and are held
+    // back while earlier data is unacknowledged until at least that many bytes are waiting
+    pub fn set_write_coalescing(&mut self, coalesce: Option<usize>) {
+        let mut me = self.inner.lock().unwrap();
+        me.coalesce = coalesce;
+        for stream in me.streams.values() {
+            stream.lock().unwrap().coalesce = coalesce;
+        }
+    }
+
+    pub fn set_reset_code(&mut self, error_code: u16) {
+        let mut me = self.inner.lock().unwrap();
+        me.reset_code = error_code;
+    }
+
+    pub fn update_max_data(&mut self, max: u64) {
+        let mut guard = self.inner.lock().unwrap();
+        let me = &mut *guard;
+        if me.flow.update_send_max(max) {
+            for id in me.flow_blocked.drain(..) {
+                if let Some(stream) = me.streams.get(&id) {
+                    stream.lock().unwrap().notify_writer();
+                }
+            }
+            me.conn_tasks.wake();
+        }
+    }
+
+    pub fn update_max_stream_data(&mut self, id: StreamId, max: u64) -> QuicResult<()> {
+        let mut me = self.inner.lock().unwrap();
+        if !me.can_send(id) {
+            return Err(QuicError::Transport(
+                TransportError::StreamStateError,
+                format!("max stream data received for receive-only stream {}", id),
+            ));
+        }
+        let updated = match me.streams.get(&id) {
+            Some(stream) => {
+                let mut stream = stream.lock().unwrap();
+                if stream.flow.update_send_max(max) {
+                    stream.notify_writer();
+                    true
                 } else {
-                    me.streams.insert(id, Stream::new());
-                    Some(StreamRef {
-                        inner: self.inner.clone(),
-                        id,
-                    })
+                    false
+                }
+            }
+            None => false,
+        };
+        if updated {
+            me.conn_tasks.wake();
+        }
+        Ok(())
+    }
+
+    pub fn reset(&mut self, id: StreamId, error_code: u16, final_offset: u64) -> QuicResult<()> {
+        let mut guard = self.inner.lock().unwrap();
+        let me = &mut *guard;
+        if !me.can_recv(id) {
+            return Err(QuicError::Transport(
+                TransportError::StreamStateError,
+                format!("reset received for send-only stream {}", id),
+            ));
+        }
+        if !me.streams.contains_key(&id) {
+            if me.is_closed(id) {
+                return Ok(());
+            }
+            // A peer can reset a stream before we've seen any of its data
+            me.check_peer_stream(id)?;
+            me.open_stream(id);
+        }
+        {
+            let mut stream = me.streams[&id].lock().unwrap();
+            if stream.final_offset.map_or(false, |known| known != final_offset) {
+                return Err(QuicError::Transport(
+                    TransportError::FinalOffsetError,
+                    format!("reset changed the final offset of stream {}", id),
+                ));
+            }
+            if final_offset < stream.flow.received() {
+                return Err(QuicError::Transport(
+                    TransportError::FinalOffsetError,
+                    format!(
+                        "final offset {} for stream {} lower than received data",
+                        final_offset, id
+                    ),
+                ));
+            }
+            let new = stream.flow.on_received(final_offset)?;
+            let total = me.flow.received() + new;
+            me.flow.on_received(total)?;
+            stream.final_offset = Some(final_offset);
+            if stream.recv != RecvState::DataRead {
+                stream.recv = RecvState::ResetRecvd(error_code);
+                me.buffered -= stream.received.buffered();
+                stream.received.clear();
+                stream.notify_reader();
+            }
+        }
+        me.close_if_done(id);
+        me.issue_credit(None);
+        me.conn_tasks.wake();
+        Ok(())
+    }
+
+    pub fn stop_sending_received(&mut self, id: StreamId, error_code: u16) -> QuicResult<()> {
+        let mut me = self.inner.lock().unwrap();
+        if !me.can_send(id) {
+            return Err(QuicError::Transport(
+                TransportError::StreamStateError,
+                format!("stop sending received for receive-only stream {}", id),
+            ));
+        }
+        if me.is_closed(id) {
+            return Ok(());
+        }
+        let final_offset = {
+            let stream = me.streams.get(&id).ok_or_else(|| {
+                QuicError::Transport(
+                    TransportError::StreamStateError,
+                    format!("stop sending received for unknown stream {}", id),
+                )
+            })?;
+            let mut stream = stream.lock().unwrap();
+            if stream.send.is_closed() {
+                return Ok(());
+            }
+            stream.send = SendState::Stopped(error_code);
+            stream.queued.clear();
+            stream.notify_writer();
+            stream.offset
+        };
+
+        me.queue.push_back(Frame::RstStream(RstStreamFrame {
+            id,
+            error_code,
+            final_offset,
+        }));
+        me.close_if_done(id);
+        me.conn_tasks.wake();
+        Ok(())
+    }
+
+    // The server never saw what these streams sent in 0-RTT, so their writers find out on the
+    // next write and the server learns they're gone
+    pub fn early_data_rejected(&mut self, ids: &[StreamId]) {
+        let mut me = self.inner.lock().unwrap();
+        let error_code = me.reset_code;
+        for &id in ids {
+            let final_offset = {
+                let stream = match me.streams.get(&id) {
+                    Some(stream) => stream,
+                    None => continue,
+                };
+                let mut stream = stream.lock().unwrap();
+                if stream.send.is_closed() {
+                    continue;
+                }
+                stream.send = SendState::Stopped(error_code);
+                stream.queued.clear();
+                stream.notify_writer();
+                stream.offset
+            };
+            me.queue.push_back(Frame::RstStream(RstStreamFrame {
+                id,
+                error_code,
+                final_offset,
+            }));
+            me.close_if_done(id);
+        }
+        me.conn_tasks.wake();
+    }
+
+    pub fn on_stream_acked(&mut self, frame: &StreamFrame) {
+        let mut me = self.inner.lock().unwrap();
+        let release = match me.streams.get(&frame.id) {
+            Some(stream) => {
+                let mut stream = stream.lock().unwrap();
+                stream.acked += frame.data.len() as u64;
+                if frame.fin {
+                    stream.fin_acked = true;
+                }
+                if stream.send == SendState::DataSent && stream.fin_acked
+                    && stream.acked >= stream.offset
+                {
+                    stream.send = SendState::DataRecvd;
+                }
+                // Either room in the send buffer or a finished stream may unblock the writer
+                stream.notify_writer();
+                !stream.queued.is_empty() && !stream.holds_back()
+            }
+            None => false,
+        };
+        // Writes held back for coalescing go out once the data ahead of them is acknowledged
+        if release {
+            me.schedule(frame.id);
+            me.conn_tasks.wake();
+        }
+        me.close_if_done(frame.id);
+    }
+
+    // Decides what to send in place of a frame from a lost packet
+    pub fn regenerate(&mut self, frame: Frame) -> Option<Frame> {
+        let mut me = self.inner.lock().unwrap();
+        match frame {
+            Frame::Stream(f) => {
+                let stream = me.streams.get(&f.id)?.lock().unwrap();
+                match stream.send {
+                    SendState::ResetSent(_) | SendState::Stopped(_) => return None,
+                    _ => {}
+                }
+                Some(Frame::Stream(f))
+            }
+            Frame::MaxData(_) => {
+                if me.buffered >= me.max_buffered {
+                    me.credit_held = true;
+                    return None;
+                }
+                Some(Frame::MaxData(MaxDataFrame(me.flow.recv_max())))
+            }
+            Frame::MaxStreamId(MaxStreamIdFrame(id)) => {
+                let open = &me.open[stype(id.initiator(), id.dir())];
+                Some(Frame::MaxStreamId(MaxStreamIdFrame(open.max)))
+            }
+            Frame::MaxStreamData(f) => {
+                let stream = me.streams.get(&f.id)?.lock().unwrap();
+                if stream.recv != RecvState::Recv {
+                    return None;
                 }
+                Some(Frame::MaxStreamData(MaxStreamDataFrame {
+                    id: f.id,
+                    max: stream.flow.recv_max(),
+                }))
             }
+            // Datagrams are unreliable by design
+            Frame::Datagram(_) => None,
+            // Acknowledgements are rebuilt from what has been received since, and padding and
+            // PINGs (as in an MTU probe) only mattered to the packet that carried them
+            Frame::Ack(_) | Frame::Padding(_) | Frame::Ping => None,
+            // Path validation needs a fresh challenge from the connection, and responses are
+            // never resent
+            Frame::PathChallenge(_) | Frame::PathResponse(_) => None,
+            frame => Some(frame),
         }
     }
 
-    pub fn request_stream(self, id: u64) -> Box<Future<Item = Streams, Error = QuicError>> {
+    pub fn received_frame(&mut self, frame: &StreamFrame) -> QuicResult<()> {
+        let mut guard = self.inner.lock().unwrap();
+        let me = &mut *guard;
+        if !me.can_recv(frame.id) {
+            return Err(QuicError::Transport(
+                TransportError::StreamStateError,
+                format!("data received for send-only stream {}", frame.id),
+            ));
+        }
+        if !me.streams.contains_key(&frame.id) {
+            // Retransmissions can still arrive for a stream we've closed
+            if me.is_closed(frame.id) {
+                return Ok(());
+            }
+            me.check_peer_stream(frame.id)?;
+            me.open_stream(frame.id);
+        }
+
+        {
+            let mut stream = me.streams[&frame.id].lock().unwrap();
+            let end = frame.offset + frame.data.len() as u64;
+            if let Some(final_offset) = stream.final_offset {
+                if end > final_offset || (frame.fin && end != final_offset) {
+                    return Err(QuicError::Transport(
+                        TransportError::FinalOffsetError,
+                        format!(
+                            "data for stream {} beyond its final offset {}",
+                            frame.id, final_offset
+                        ),
+                    ));
+                }
+            }
+            if frame.fin {
+                if end < stream.flow.received() {
+                    return Err(QuicError::Transport(
+                        TransportError::FinalOffsetError,
+                        format!(
+                            "final offset {} for stream {} lower than received data",
+                            end, frame.id
+                        ),
+                    ));
+                }
+                stream.final_offset = Some(end);
+                if stream.recv == RecvState::Recv {
+                    stream.recv = RecvState::SizeKnown;
+                }
+            }
+
+            let new = stream.flow.on_received(end)?;
+            let total = me.flow.received() + new;
+            me.flow.on_received(total)?;
+
+            match stream.recv {
+                RecvState::Recv | RecvState::SizeKnown => {
+                    let before = stream.received.buffered();
+                    stream.received.insert(frame.offset, &frame.data)?;
+                    me.buffered += stream.received.buffered() - before;
+                    stream.check_all_read();
+                    if !frame.data.is_empty() || frame.fin {
+                        stream.notify_reader();
+                        me.events.push(Event::StreamReadable(frame.id));
+                    }
+                }
+                _ => {}
+            }
+        }
+        me.close_if_done(frame.id);
+        Ok(())
+    }
+
+    pub fn request_stream(self, id: StreamId) -> Box<Future<Item = Streams, Error = QuicError>> {
         let consumer = {
             let mut me = self.inner.lock().unwrap();
             let consumer = {
-                let open = me.open.get_mut((id % 4) as usize).unwrap();
+                let open = &mut me.open[stype(id.initiator(), id.dir())];
                 if id > open.max {
-                    let (p, c) = oneshot::channel::<u64>();
-                    open.updates.push(p);
+                    let (p, c) = oneshot::channel::<StreamId>();
+                    open.updates.push((id, p));
                     Some(c)
                 } else {
                     None
@@ -112,9 +717,7 @@ impl Streams {
             if consumer.is_some() {
                 me.queue
                     .push_back(Frame::StreamIdBlocked(StreamIdBlockedFrame(id)));
-                if let Some(ref mut task) = me.task {
-                    task.notify();
-                }
+                me.conn_tasks.wake();
             }
             consumer
         };
@@ -129,75 +732,2059 @@ impl Streams {
     }
 }
 
+#[derive(Clone)]
 pub struct StreamRef {
     inner: Arc<Mutex<Inner>>,
-    id: u64,
+    stream: Arc<Mutex<Stream>>,
+    id: StreamId,
 }
 
 impl StreamRef {
+    pub fn id(&self) -> StreamId {
+        self.id
+    }
+
+    // Fails the future with QuicError::TimedOut if it isn't done within the given time
+    pub fn timeout<F>(&self, future: F, after: Duration) -> Timeout<F> {
+        self.inner.lock().unwrap().timeout(future, after)
+    }
+
+    pub fn split(self) -> (SendStream, RecvStream) {
+        let send = SendStream {
+            stream: self.clone(),
+        };
+        let recv = RecvStream {
+            stream: self,
+            buf: Bytes::new(),
+            offset: 0,
+        };
+        (send, recv)
+    }
+
+    pub fn set_priority(&mut self, priority: u8) {
+        self.stream.lock().unwrap().priority = priority;
+    }
+
     pub fn get_offset(&self) -> u64 {
-        let me = self.inner.lock().unwrap();
-        me.streams[&self.id].offset
+        self.stream.lock().unwrap().offset
     }
 
     pub fn set_offset(&mut self, new: u64) {
-        let mut me = self.inner.lock().unwrap();
-        let stream = me.streams.get_mut(&self.id).unwrap();
-        stream.offset = new;
+        self.stream.lock().unwrap().offset = new;
     }
-}
 
-struct Inner {
-    side: Side,
-    task: Option<task::Task>,
-    queue: VecDeque<Frame>,
-    streams: HashMap<u64, Stream>,
-    open: [OpenStreams; 4],
-}
-
-struct Stream {
-    offset: u64,
-    queued: VecDeque<Vec<u8>>,
-    received: VecDeque<Vec<u8>>,
-}
+    pub fn reserve_send(&mut self, len: u64) -> (u64, u64) {
+        let mut guard = self.inner.lock().unwrap();
+        let me = &mut *guard;
+        let mut stream = self.stream.lock().unwrap();
+        let conn_credit = me.flow.send_credit();
+        let allowed = cmp::min(len, cmp::min(stream.flow.send_credit(), conn_credit));
+        if conn_credit < len && !me.flow_blocked.contains(&self.id) {
+            me.flow_blocked.push(self.id);
+        }
+        let offset = stream.offset;
+        stream.offset += allowed;
+        stream.flow.on_sent(allowed);
+        me.flow.on_sent(allowed);
 
-impl Stream {
-    fn new() -> Self {
-        Self {
-            offset: 0,
-            queued: VecDeque::new(),
-            received: VecDeque::new(),
+        if allowed < len {
+            if let Some(limit) = stream.flow.blocked() {
+                me.queue.push_back(Frame::StreamBlocked(StreamBlockedFrame {
+                    id: self.id,
+                    offset: limit,
+                }));
+            }
+            if let Some(limit) = me.flow.blocked() {
+                me.queue.push_back(Frame::Blocked(BlockedFrame(limit)));
+            }
         }
+        (offset, allowed)
     }
-}
 
-struct OpenStreams {
-    next: Option<u64>,
-    max: u64,
-    updates: Vec<oneshot::Sender<u64>>,
-}
+    pub fn write(&mut self, data: &[u8]) -> QuicResult<usize> {
+        self.write_with(data.len(), true, |allowed| Some(Bytes::from(&data[..allowed])))
+    }
 
-impl OpenStreams {
-    fn new() -> Self {
-        Self {
-            next: None,
-            max: 0,
-            updates: Vec::new(),
+    // Hands the buffer over without copying; only the part that fits is queued. Merging with an
+    // earlier write would mean a copy, so each buffer goes out in frames of its own
+    pub fn write_bytes(&mut self, data: &Bytes) -> QuicResult<usize> {
+        self.write_with(data.len(), false, |allowed| Some(data.slice_to(allowed)))
+    }
+
+    // Queues the buffers back to back without concatenating them, each in frames of its own;
+    // returns how many bytes fit, taken from the front
+    pub fn write_chunks(&mut self, chunks: &[Bytes]) -> QuicResult<usize> {
+        let len = chunks.iter().map(|chunk| chunk.len()).sum();
+        self.write_with(len, false, |allowed| {
+            let mut left = allowed;
+            chunks
+                .iter()
+                .map(move |chunk| {
+                    let len = cmp::min(left, chunk.len());
+                    left -= len;
+                    chunk.slice_to(len)
+                })
+                .filter(|data| !data.is_empty())
+        })
+    }
+
+    fn write_with<F, I>(&mut self, len: usize, merge: bool, data: F) -> QuicResult<usize>
+    where
+        F: FnOnce(usize) -> I,
+        I: IntoIterator<Item = Bytes>,
+    {
+        if len == 0 {
+            return Ok(0);
+        }
+        let len = {
+            let stream = self.stream.lock().unwrap();
+            stream.check_writable(self.id)?;
+            // Data is buffered until it's acknowledged, so slow peers push back on writers
+            let buffered = (stream.offset - stream.acked) as usize;
+            let room = stream.send_limit.saturating_sub(buffered);
+            cmp::min(len, room)
+        };
+
+        let (offset, allowed) = self.reserve_send(len as u64);
+        if allowed == 0 {
+            return Err(io::Error::from(io::ErrorKind::WouldBlock).into());
+        }
+
+        // Copy outside of any lock, so other streams aren't held up by a large write
+        let chunks = data(allowed as usize);
+        let held = {
+            let mut stream = self.stream.lock().unwrap();
+            stream.check_writable(self.id)?;
+            let merge = merge && stream.coalesce.is_some();
+            let mut offset = offset;
+            for data in chunks {
+                let len = data.len() as u64;
+                if !merge || !stream.merge_write(offset, &data) {
+                    stream.queued.push_back(StreamFrame {
+                        id: self.id,
+                        fin: false,
+                        offset,
+                        len: Some(len),
+                        data,
+                    });
+                }
+                offset += len;
+            }
+            stream.holds_back()
+        };
+
+        if !held {
+            let mut me = self.inner.lock().unwrap();
+            me.schedule(self.id);
+            me.conn_tasks.wake();
         }
+        Ok(allowed as usize)
     }
-}
 
-#[derive(Clone, Copy, Debug, PartialEq)]
-pub enum Dir {
-    Bidi,
-    Uni,
-}
+    // Sends whatever is being held back for coalescing without waiting for more writes
+    pub fn flush(&mut self) {
+        if self.stream.lock().unwrap().queued.is_empty() {
+            return;
+        }
+        let mut me = self.inner.lock().unwrap();
+        me.schedule(self.id);
+        me.conn_tasks.wake();
+    }
 
-impl Dir {
-    fn to_bit(&self) -> u64 {
-        match self {
-            Dir::Bidi => 0,
-            Dir::Uni => 2,
+    pub fn poll_write(&mut self, data: &[u8]) -> Poll<usize, QuicError> {
+        self.set_write_task();
+        poll_would_block(self.write(data))
+    }
+
+    pub fn poll_write_bytes(&mut self, data: &Bytes) -> Poll<usize, QuicError> {
+        self.set_write_task();
+        poll_would_block(self.write_bytes(data))
+    }
+
+    pub fn poll_write_chunks(&mut self, chunks: &[Bytes]) -> Poll<usize, QuicError> {
+        self.set_write_task();
+        poll_would_block(self.write_chunks(chunks))
+    }
+
+    fn set_write_task(&mut self) {
+        self.stream.lock().unwrap().write_task = Some(task::current());
+    }
+
+    pub fn finish(&mut self) {
+        {
+            let mut stream = self.stream.lock().unwrap();
+            if stream.send != SendState::Ready {
+                return;
+            }
+            stream.send = SendState::DataSent;
+
+            // Piggyback on the last frame for this stream if it hasn't gone out yet
+            let offset = stream.offset;
+            let piggybacked = match stream.queued.back_mut() {
+                Some(ref mut f) if f.offset + f.data.len() as u64 == offset => {
+                    f.fin = true;
+                    true
+                }
+                _ => false,
+            };
+            if !piggybacked {
+                stream.queued.push_back(StreamFrame {
+                    id: self.id,
+                    fin: true,
+                    offset,
+                    len: Some(0),
+                    data: Bytes::new(),
+                });
+            }
+        }
+        let mut me = self.inner.lock().unwrap();
+        me.schedule(self.id);
+        me.conn_tasks.wake();
+    }
+
+    pub fn poll_finish(&mut self) -> Poll<(), QuicError> {
+        let mut stream = self.stream.lock().unwrap();
+        match stream.send {
+            SendState::DataRecvd => Ok(Async::Ready(())),
+            SendState::Stopped(code) => Err(QuicError::StreamReset(self.id, code)),
+            SendState::ResetSent(_) => Err(QuicError::Transport(
+                TransportError::StreamStateError,
+                format!("finish of reset stream {}", self.id),
+            )),
+            SendState::Ready | SendState::DataSent => {
+                stream.write_task = Some(task::current());
+                Ok(Async::NotReady)
+            }
+        }
+    }
+
+    pub fn reset(&mut self, error_code: u16) {
+        let mut me = self.inner.lock().unwrap();
+        if !me.can_send(self.id) {
+            return;
+        }
+        let final_offset = {
+            let mut stream = self.stream.lock().unwrap();
+            if stream.send.is_closed() {
+                return;
+            }
+            stream.send = SendState::ResetSent(error_code);
+            stream.queued.clear();
+            stream.offset
+        };
+
+        me.queue.push_back(Frame::RstStream(RstStreamFrame {
+            id: self.id,
+            error_code,
+            final_offset,
+        }));
+        me.close_if_done(self.id);
+        me.conn_tasks.wake();
+    }
+
+    pub fn poll_read(&mut self) -> Poll<Option<Bytes>, QuicError> {
+        let chunk = try_ready!(self.poll_chunk(true));
+        Ok(Async::Ready(chunk.map(|(_, data)| data)))
+    }
+
+    // Data along with the offset it belongs at, as soon as it arrives rather than once
+    // everything before it is in
+    pub fn poll_read_unordered(&mut self) -> Poll<Option<(u64, Bytes)>, QuicError> {
+        self.poll_chunk(false)
+    }
+
+    fn poll_chunk(&mut self, ordered: bool) -> Poll<Option<(u64, Bytes)>, QuicError> {
+        self.stream.lock().unwrap().read_task = Some(task::current());
+        match self.read_chunk(ordered)? {
+            Some(chunk) => Ok(Async::Ready(Some(chunk))),
+            None => {
+                let stream = self.stream.lock().unwrap();
+                if stream.recv == RecvState::DataRead || stream.recv == RecvState::Stopped {
+                    Ok(Async::Ready(None))
+                } else {
+                    Ok(Async::NotReady)
+                }
+            }
+        }
+    }
+
+    pub fn read(&mut self) -> QuicResult<Option<Bytes>> {
+        Ok(self.read_chunk(true)?.map(|(_, data)| data))
+    }
+
+    pub fn read_unordered(&mut self) -> QuicResult<Option<(u64, Bytes)>> {
+        self.read_chunk(false)
+    }
+
+    fn read_chunk(&mut self, ordered: bool) -> QuicResult<Option<(u64, Bytes)>> {
+        let (now, rtt) = {
+            let me = self.inner.lock().unwrap();
+            (me.clock.now(), me.rtt)
+        };
+        let (offset, data, stream_max) = {
+            let mut stream = self.stream.lock().unwrap();
+            match stream.recv {
+                RecvState::ResetRecvd(code) => return Err(QuicError::StreamReset(self.id, code)),
+                RecvState::Stopped | RecvState::DataRead => return Ok(None),
+                RecvState::Recv | RecvState::SizeKnown => {}
+            }
+            let chunk = if ordered {
+                let offset = stream.received.offset();
+                stream.received.read().map(|data| (offset, data))
+            } else {
+                stream.received.read_unordered()
+            };
+            let (offset, data) = match chunk {
+                Some(chunk) => chunk,
+                None => return Ok(None),
+            };
+            stream.check_all_read();
+            // Once the final offset is known the peer needs no more credit
+            let max = stream.flow.on_consumed_at(data.len() as u64, now, rtt);
+            let max = max.filter(|_| stream.recv == RecvState::Recv);
+            (offset, data, max)
+        };
+
+        // Only the credit updates need the connection
+        let mut me = self.inner.lock().unwrap();
+        let mut credited = false;
+        if let Some(max) = stream_max {
+            me.queue.push_back(Frame::MaxStreamData(MaxStreamDataFrame {
+                id: self.id,
+                max,
+            }));
+            credited = true;
+        }
+        me.close_if_done(self.id);
+
+        me.buffered -= data.len();
+        let max = me.flow.on_consumed(data.len() as u64);
+        credited |= me.issue_credit(max);
+        if credited {
+            me.conn_tasks.wake();
+        }
+        Ok(Some((offset, data)))
+    }
+
+    pub fn stop_sending(&mut self, error_code: u16) {
+        let mut guard = self.inner.lock().unwrap();
+        let me = &mut *guard;
+        if !me.can_recv(self.id) {
+            return;
+        }
+        {
+            let mut stream = self.stream.lock().unwrap();
+            match stream.recv {
+                RecvState::Recv | RecvState::SizeKnown => {}
+                _ => return,
+            }
+            stream.recv = RecvState::Stopped;
+            me.buffered -= stream.received.buffered();
+            stream.received.clear();
+        }
+
+        me.queue.push_back(Frame::StopSending(StopSendingFrame {
+            id: self.id,
+            error_code,
+        }));
+        me.close_if_done(self.id);
+        me.issue_credit(None);
+        me.conn_tasks.wake();
+    }
+}
+
+fn poll_would_block(result: QuicResult<usize>) -> Poll<usize, QuicError> {
+    match result {
+        Ok(len) => Ok(Async::Ready(len)),
+        Err(QuicError::Io(ref e)) if e.kind() == io::ErrorKind::WouldBlock => Ok(Async::NotReady),
+        Err(e) => Err(e),
+    }
+}
+
+struct Inner {
+    side: Side,
+    conn_tasks: Wakers,
+    queue: VecDeque<Frame>,
+    streams: HashMap<StreamId, Arc<Mutex<Stream>>>,
+    open: [OpenStreams; 4],
+    incoming: VecDeque<(StreamId, Arc<Mutex<Stream>>)>,
+    incoming_tasks: Wakers,
+    sending: VecDeque<StreamId>,
+    flow: FlowControl,
+    // Streams whose writers ran out of connection-level credit
+    flow_blocked: Vec<StreamId>,
+    initial_max_stream_data: u64,
+    stream_window: u64,
+    window_limit: Option<u64>,
+    rtt: Duration,
+    clock: Arc<Clock>,
+    timers: Option<Timers>,
+    buffer_limit: usize,
+    send_buffer: usize,
+    coalesce: Option<usize>,
+    reset_code: u16,
+    // Data received but not yet read, across all streams
+    buffered: usize,
+    max_buffered: usize,
+    // Whether connection credit was held back while too much data was buffered
+    credit_held: bool,
+    peer_blocked: u64,
+    events: Events,
+}
+
+impl Inner {
+    fn timeout<F>(&self, future: F, after: Duration) -> Timeout<F> {
+        let deadline = self.clock.now() + after;
+        Timeout::new(future, deadline, self.clock.clone(), self.timers.as_ref())
+    }
+
+    fn limits(&self) -> StreamLimits {
+        let (local, remote) = (self.side, self.side.other());
+        StreamLimits {
+            max_bidi: self.open[stype(local, Dir::Bidi)].max,
+            max_uni: self.open[stype(local, Dir::Uni)].max,
+            peer_max_bidi: self.open[stype(remote, Dir::Bidi)].max,
+            peer_max_uni: self.open[stype(remote, Dir::Uni)].max,
+        }
+    }
+
+    fn new_stream(&self) -> Stream {
+        let mut stream = Stream::new(
+            self.initial_max_stream_data,
+            self.stream_window,
+            self.buffer_limit,
+            self.send_buffer,
+        );
+        stream.coalesce = self.coalesce;
+        // The window can't usefully grow past what the reassembly buffer will hold
+        if let Some(limit) = self.window_limit {
+            let limit = cmp::min(limit, self.buffer_limit as u64);
+            stream.flow.set_autotune(limit);
+        }
+        stream
+    }
+
+    // Peers may only open streams of their own, and only up to the limit we've given them
+    fn check_peer_stream(&self, id: StreamId) -> QuicResult<()> {
+        if id.initiator() == self.side {
+            return Err(QuicError::Transport(
+                TransportError::StreamStateError,
+                format!("peer referenced locally-initiated stream {} before it was opened", id),
+            ));
+        }
+        if id > self.open[stype(id.initiator(), id.dir())].max {
+            return Err(QuicError::Transport(
+                TransportError::StreamIdError,
+                format!("stream {} exceeds the stream limit", id),
+            ));
+        }
+        Ok(())
+    }
+
+    // Whether a stream that isn't in the map was opened before, and so has since closed
+    fn is_closed(&self, id: StreamId) -> bool {
+        let open = &self.open[stype(id.initiator(), id.dir())];
+        if id.initiator() == self.side {
+            open.next.map_or(false, |next| id < next)
+        } else {
+            id.index() < open.remote
+        }
+    }
+
+    fn open_stream(&mut self, id: StreamId) {
+        let stype = stype(id.initiator(), id.dir());
+        // Opening a stream implicitly opens all lower streams of the same type
+        let mut next = StreamId::new(id.initiator(), id.dir(), self.open[stype].remote);
+        while next <= id {
+            let stream = Arc::new(Mutex::new(self.new_stream()));
+            self.streams.insert(next, stream.clone());
+            self.incoming.push_back((next, stream));
+            self.events.push(Event::StreamOpened(next));
+            next = next.next();
+        }
+        self.open[stype].remote = cmp::max(self.open[stype].remote, id.index() + 1);
+        self.incoming_tasks.wake();
+    }
+
+    fn schedule(&mut self, id: StreamId) {
+        if !self.sending.contains(&id) {
+            self.sending.push_back(id);
+        }
+    }
+
+    // Deficit round robin: each turn a stream may send a quantum of data scaled by its priority
+    fn next_stream_frame(&mut self) -> Option<StreamFrame> {
+        while let Some(id) = self.sending.pop_front() {
+            let (frame, again) = {
+                let mut stream = match self.streams.get(&id) {
+                    Some(stream) => stream.lock().unwrap(),
+                    None => continue,
+                };
+                let mut frame = match stream.queued.pop_front() {
+                    Some(frame) => frame,
+                    None => {
+                        stream.deficit = 0;
+                        continue;
+                    }
+                };
+                if stream.deficit == 0 {
+                    stream.deficit = (usize::from(stream.priority) + 1) * SEND_QUANTUM;
+                }
+                if frame.data.len() > stream.deficit {
+                    let rest = frame.split_off(stream.deficit);
+                    stream.queued.push_front(rest);
+                }
+                stream.deficit -= frame.data.len();
+                if stream.queued.is_empty() {
+                    stream.deficit = 0;
+                    (frame, None)
+                } else {
+                    (frame, Some(stream.deficit > 0))
+                }
+            };
+            match again {
+                Some(true) => self.sending.push_front(id),
+                Some(false) => self.sending.push_back(id),
+                None => {}
+            }
+            return Some(frame);
+        }
+        None
+    }
+
+    fn requeue_stream(&mut self, frame: StreamFrame) {
+        let id = frame.id;
+        match self.streams.get(&id) {
+            Some(stream) => {
+                let mut stream = stream.lock().unwrap();
+                stream.deficit += frame.data.len();
+                stream.queued.push_front(frame);
+            }
+            None => return,
+        }
+        // The stream keeps its turn for what didn't fit in the last packet
+        self.sending.retain(|&other| other != id);
+        self.sending.push_front(id);
+    }
+
+    // No connection credit goes out while too much data sits unread, so the peer has to wait
+    // for the application to catch up
+    fn issue_credit(&mut self, max: Option<u64>) -> bool {
+        if self.buffered >= self.max_buffered {
+            self.credit_held |= max.is_some();
+            return false;
+        }
+        if max.is_none() && !self.credit_held {
+            return false;
+        }
+        self.credit_held = false;
+        let max = self.flow.recv_max();
+        self.queue.push_back(Frame::MaxData(MaxDataFrame(max)));
+        true
+    }
+
+    fn wait_incoming(&mut self) {
+        self.incoming_tasks.register(task::current());
+    }
+
+    fn can_send(&self, id: StreamId) -> bool {
+        id.dir() == Dir::Bidi || id.initiator() == self.side
+    }
+
+    fn can_recv(&self, id: StreamId) -> bool {
+        id.dir() == Dir::Bidi || id.initiator() != self.side
+    }
+
+    // A stream is forgotten once both of its halves are closed; handles keep their own reference.
+    // Every peer-initiated stream that closes makes room for the peer to open another.
+    fn close_if_done(&mut self, id: StreamId) {
+        let done = match self.streams.get(&id) {
+            Some(stream) => {
+                let stream = stream.lock().unwrap();
+                (!self.can_send(id) || stream.send.is_closed())
+                    && (!self.can_recv(id) || stream.recv.is_closed())
+            }
+            None => return,
+        };
+        if !done {
+            return;
+        }
+        self.streams.remove(&id);
+        self.sending.retain(|&other| other != id);
+        self.flow_blocked.retain(|&other| other != id);
+        if id.initiator() == self.side {
+            return;
+        }
+
+        let open = &mut self.open[stype(id.initiator(), id.dir())];
+        open.max = open.max.next();
+        open.blocked = 0;
+        self.queue
+            .push_back(Frame::MaxStreamId(MaxStreamIdFrame(open.max)));
+        self.conn_tasks.wake();
+    }
+}
+
+struct Stream {
+    offset: u64,
+    queued: VecDeque<StreamFrame>,
+    send_limit: usize,
+    coalesce: Option<usize>,
+    priority: u8,
+    deficit: usize,
+    send: SendState,
+    acked: u64,
+    fin_acked: bool,
+    recv: RecvState,
+    received: Assembler,
+    final_offset: Option<u64>,
+    flow: FlowControl,
+    read_task: Option<task::Task>,
+    write_task: Option<task::Task>,
+}
+
+impl Stream {
+    fn new(max_data: u64, window: u64, buffer_limit: usize, send_limit: usize) -> Self {
+        Self {
+            offset: 0,
+            queued: VecDeque::new(),
+            send_limit,
+            coalesce: Some(0),
+            priority: 0,
+            deficit: 0,
+            send: SendState::Ready,
+            acked: 0,
+            fin_acked: false,
+            recv: RecvState::Recv,
+            received: Assembler::new(buffer_limit),
+            final_offset: None,
+            flow: FlowControl::new(max_data, window),
+            read_task: None,
+            write_task: None,
+        }
+    }
+
+    // Appends a small write to the last one if that hasn't gone out yet
+    fn merge_write(&mut self, offset: u64, data: &[u8]) -> bool {
+        if let Some(last) = self.queued.back_mut() {
+            if !last.fin && last.offset + last.data.len() as u64 == offset
+                && last.data.len() + data.len() <= SEND_QUANTUM
+            {
+                last.append(data);
+                return true;
+            }
+        }
+        false
+    }
+
+    // Like Nagle's algorithm: too little data to fill a packet waits while earlier data is in
+    // flight, since its acknowledgement will come along to release it
+    fn holds_back(&self) -> bool {
+        // Nothing more is coming once the stream is finished
+        if self.send != SendState::Ready {
+            return false;
+        }
+        let threshold = match self.coalesce {
+            Some(threshold) => threshold,
+            None => return false,
+        };
+        let pending = self.queued.iter().map(|f| f.data.len()).sum::<usize>();
+        pending < threshold && self.offset - pending as u64 > self.acked
+    }
+
+    fn check_writable(&self, id: StreamId) -> QuicResult<()> {
+        match self.send {
+            SendState::Ready => Ok(()),
+            SendState::Stopped(code) => Err(QuicError::StreamReset(id, code)),
+            _ => Err(QuicError::Transport(
+                TransportError::StreamStateError,
+                format!("write to closed stream {}", id),
+            )),
+        }
+    }
+
+    fn check_all_read(&mut self) {
+        if self.recv == RecvState::SizeKnown && self.final_offset == Some(self.received.offset()) {
+            self.recv = RecvState::DataRead;
+        }
+    }
+
+    fn notify_reader(&mut self) {
+        if let Some(task) = self.read_task.take() {
+            task.notify();
+        }
+    }
+
+    fn notify_writer(&mut self) {
+        if let Some(task) = self.write_task.take() {
+            task.notify();
+        }
+    }
+}
+
+// The sending half of a stream, following the transport draft's state machine
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum SendState {
+    Ready,
+    // Finished, waiting for everything to be acknowledged
+    DataSent,
+    DataRecvd,
+    ResetSent(u16),
+    // Reset at the peer's request, with the code it asked for
+    Stopped(u16),
+}
+
+impl SendState {
+    fn is_closed(self) -> bool {
+        match self {
+            SendState::Ready | SendState::DataSent => false,
+            _ => true,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum RecvState {
+    Recv,
+    // The final offset is known, but not everything has been read yet
+    SizeKnown,
+    DataRead,
+    ResetRecvd(u16),
+    // We asked the peer to stop sending, so anything else that arrives is discarded
+    Stopped,
+}
+
+impl RecvState {
+    fn is_closed(self) -> bool {
+        match self {
+            RecvState::Recv | RecvState::SizeKnown => false,
+            _ => true,
+        }
+    }
+}
+
+// Tasks waiting on an event, each registered once and woken together
+#[derive(Default)]
+struct Wakers(Vec<task::Task>);
+
+impl Wakers {
+    fn register(&mut self, task: task::Task) {
+        if !self.0.iter().any(|t| t.will_notify_current()) {
+            self.0.push(task);
+        }
+    }
+
+    fn wake(&mut self) {
+        for task in self.0.drain(..) {
+            task.notify();
+        }
+    }
+}
+
+pub struct SendStream {
+    stream: StreamRef,
+}
+
+impl SendStream {
+    pub fn id(&self) -> StreamId {
+        self.stream.id
+    }
+
+    pub fn finish(mut self) -> Finish {
+        self.stream.finish();
+        Finish {
+            stream: self.stream.clone(),
+        }
+    }
+
+    pub fn reset(&mut self, error_code: u16) {
+        self.stream.reset(error_code);
+    }
+
+    pub fn set_priority(&mut self, priority: u8) {
+        self.stream.set_priority(priority);
+    }
+
+    pub fn flush(&mut self) {
+        self.stream.flush();
+    }
+
+    pub fn poll_write_bytes(&mut self, data: &Bytes) -> Poll<usize, QuicError> {
+        self.stream.poll_write_bytes(data)
+    }
+
+    pub fn poll_write_chunks(&mut self, chunks: &[Bytes]) -> Poll<usize, QuicError> {
+        self.stream.poll_write_chunks(chunks)
+    }
+
+    // Resolves to the stream once all of the data is queued; dropping it early resets the stream
+    pub fn write_all(self, data: Bytes) -> WriteAll {
+        WriteAll {
+            stream: Some(self),
+            data,
+        }
+    }
+}
+
+impl Drop for SendStream {
+    fn drop(&mut self) {
+        let error_code = {
+            let me = self.stream.inner.lock().unwrap();
+            let send = self.stream.stream.lock().unwrap().send;
+            if send != SendState::Ready || !me.can_send(self.stream.id) {
+                return;
+            }
+            me.reset_code
+        };
+        self.stream.reset(error_code);
+    }
+}
+
+pub struct Finish {
+    stream: StreamRef,
+}
+
+impl Future for Finish {
+    type Item = ();
+    type Error = QuicError;
+
+    fn poll(&mut self) -> Poll<(), QuicError> {
+        self.stream.poll_finish()
+    }
+}
+
+pub struct WriteAll {
+    stream: Option<SendStream>,
+    data: Bytes,
+}
+
+impl WriteAll {
+    pub fn timeout(self, after: Duration) -> Timeout<Self> {
+        let inner = match self.stream {
+            Some(ref stream) => stream.stream.inner.clone(),
+            None => panic!("WriteAll already completed"),
+        };
+        let me = inner.lock().unwrap();
+        me.timeout(self, after)
+    }
+}
+
+impl Future for WriteAll {
+    type Item = SendStream;
+    type Error = QuicError;
+
+    fn poll(&mut self) -> Poll<SendStream, QuicError> {
+        {
+            let stream = self.stream.as_mut().expect("polled WriteAll after completion");
+            while !self.data.is_empty() {
+                let len = try_ready!(stream.poll_write_bytes(&self.data));
+                self.data.split_to(len);
+            }
+        }
+        Ok(Async::Ready(self.stream.take().unwrap()))
+    }
+}
+
+impl Write for SendStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self.stream.poll_write(buf)? {
+            Async::Ready(len) => Ok(len),
+            Async::NotReady => Err(io::ErrorKind::WouldBlock.into()),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.stream.flush();
+        Ok(())
+    }
+}
+
+impl AsyncWrite for SendStream {
+    fn shutdown(&mut self) -> Poll<(), io::Error> {
+        self.stream.finish();
+        Ok(self.stream.poll_finish()?)
+    }
+}
+
+pub struct RecvStream {
+    stream: StreamRef,
+    // Left over from a partial io::Read, starting at offset
+    buf: Bytes,
+    offset: u64,
+}
+
+impl RecvStream {
+    pub fn id(&self) -> StreamId {
+        self.stream.id
+    }
+
+    pub fn poll_read(&mut self) -> Poll<Option<Bytes>, QuicError> {
+        let chunk = try_ready!(self.poll_chunk(true));
+        Ok(Async::Ready(chunk.map(|(_, data)| data)))
+    }
+
+    pub fn poll_read_unordered(&mut self) -> Poll<Option<(u64, Bytes)>, QuicError> {
+        self.poll_chunk(false)
+    }
+
+    // Collects the rest of the stream; a peer that sends more than limit bytes, or whose data is
+    // no longer wanted because the future was dropped, is told to stop sending
+    pub fn read_to_end(self, limit: usize) -> ReadToEnd {
+        ReadToEnd {
+            stream: Some(self),
+            buf: Vec::new(),
+            limit,
+        }
+    }
+
+    fn poll_chunk(&mut self, ordered: bool) -> Poll<Option<(u64, Bytes)>, QuicError> {
+        if !self.buf.is_empty() {
+            let data = mem::replace(&mut self.buf, Bytes::new());
+            return Ok(Async::Ready(Some((self.offset, data))));
+        }
+        self.stream.poll_chunk(ordered)
+    }
+}
+
+impl Read for RecvStream {
+    fn read(&mut self, out: &mut [u8]) -> io::Result<usize> {
+        if self.buf.is_empty() {
+            match self.stream.poll_chunk(true)? {
+                Async::Ready(Some((offset, data))) => {
+                    self.offset = offset;
+                    self.buf = data;
+                }
+                Async::Ready(None) => return Ok(0),
+                Async::NotReady => return Err(io::ErrorKind::WouldBlock.into()),
+            }
+        }
+        let len = cmp::min(out.len(), self.buf.len());
+        out[..len].copy_from_slice(&self.buf.split_to(len));
+        self.offset += len as u64;
+        Ok(len)
+    }
+}
+
+impl AsyncRead for RecvStream {}
+
+pub struct ReadToEnd {
+    stream: Option<RecvStream>,
+    buf: Vec<u8>,
+    limit: usize,
+}
+
+impl ReadToEnd {
+    pub fn timeout(self, after: Duration) -> Timeout<Self> {
+        let inner = match self.stream {
+            Some(ref stream) => stream.stream.inner.clone(),
+            None => panic!("ReadToEnd already completed"),
+        };
+        let me = inner.lock().unwrap();
+        me.timeout(self, after)
+    }
+
+    fn stop(&mut self) {
+        if let Some(mut recv) = self.stream.take() {
+            let error_code = recv.stream.inner.lock().unwrap().reset_code;
+            recv.stream.stop_sending(error_code);
+        }
+    }
+}
+
+impl Future for ReadToEnd {
+    type Item = Vec<u8>;
+    type Error = QuicError;
+
+    fn poll(&mut self) -> Poll<Vec<u8>, QuicError> {
+        loop {
+            let read = self.stream
+                .as_mut()
+                .expect("polled ReadToEnd after completion")
+                .poll_read();
+            match read {
+                Ok(Async::Ready(Some(data))) => {
+                    if self.buf.len() + data.len() > self.limit {
+                        self.stop();
+                        return Err(QuicError::StreamTooLong(self.limit));
+                    }
+                    self.buf.extend_from_slice(&data);
+                }
+                Ok(Async::Ready(None)) => {
+                    self.stream = None;
+                    return Ok(Async::Ready(mem::replace(&mut self.buf, Vec::new())));
+                }
+                Ok(Async::NotReady) => return Ok(Async::NotReady),
+                Err(e) => {
+                    self.stream = None;
+                    return Err(e);
+                }
+            }
+        }
+    }
+}
+
+impl Drop for ReadToEnd {
+    fn drop(&mut self) {
+        self.stop();
+    }
+}
+
+pub enum NewStream {
+    Bidi(StreamRef),
+    Uni(RecvStream),
+}
+
+pub struct IncomingStreams {
+    inner: Arc<Mutex<Inner>>,
+}
+
+impl FuturesStream for IncomingStreams {
+    type Item = (StreamId, NewStream);
+    type Error = QuicError;
+
+    fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
+        let mut me = self.inner.lock().unwrap();
+        let (id, stream) = match me.incoming.pop_front() {
+            Some(incoming) => incoming,
+            None => {
+                me.wait_incoming();
+                return Ok(Async::NotReady);
+            }
+        };
+
+        let stream = StreamRef {
+            inner: self.inner.clone(),
+            stream,
+            id,
+        };
+        let new = if id.dir() == Dir::Bidi {
+            NewStream::Bidi(stream)
+        } else {
+            NewStream::Uni(stream.split().1)
+        };
+        Ok(Async::Ready(Some((id, new))))
+    }
+}
+
+pub struct AcceptUni {
+    inner: Arc<Mutex<Inner>>,
+}
+
+impl Future for AcceptUni {
+    type Item = RecvStream;
+    type Error = QuicError;
+
+    fn poll(&mut self) -> Poll<RecvStream, QuicError> {
+        let mut me = self.inner.lock().unwrap();
+        let pos = me.incoming.iter().position(|&(id, _)| id.dir() == Dir::Uni);
+        let (id, stream) = match pos {
+            Some(pos) => me.incoming.remove(pos).unwrap(),
+            None => {
+                me.wait_incoming();
+                return Ok(Async::NotReady);
+            }
+        };
+        let stream = StreamRef {
+            inner: self.inner.clone(),
+            stream,
+            id,
+        };
+        Ok(Async::Ready(stream.split().1))
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct OpenOptions {
+    priority: u8,
+    wait: bool,
+}
+
+impl Default for OpenOptions {
+    fn default() -> Self {
+        Self {
+            priority: 0,
+            wait: true,
+        }
+    }
+}
+
+impl OpenOptions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn priority(mut self, priority: u8) -> Self {
+        self.priority = priority;
+        self
+    }
+
+    // Fail with StreamLimitReached instead of waiting for the peer to allow more streams
+    pub fn fail_fast(mut self, fail: bool) -> Self {
+        self.wait = !fail;
+        self
+    }
+}
+
+pub struct OpenStream {
+    streams: Streams,
+    dir: Dir,
+    count: usize,
+    options: OpenOptions,
+    waiting: Option<oneshot::Receiver<StreamId>>,
+}
+
+impl OpenStream {
+    fn poll_batch(&mut self) -> Poll<Vec<StreamRef>, QuicError> {
+        // More streams than the peer allows open at once would never all be opened together
+        if self.streams.exceeds_send_window(self.dir, self.count) {
+            return Err(QuicError::StreamLimitReached);
+        }
+        loop {
+            if let Some(ref mut waiting) = self.waiting {
+                match waiting.poll() {
+                    Ok(Async::Ready(_)) => {}
+                    Ok(Async::NotReady) => return Ok(Async::NotReady),
+                    Err(_) => return Err(QuicError::General("stream limit update canceled".into())),
+                }
+            }
+            if let Some(mut streams) = self.streams.init_send_batch(self.dir, self.count) {
+                for stream in &mut streams {
+                    stream.set_priority(self.options.priority);
+                }
+                return Ok(Async::Ready(streams));
+            }
+            if !self.options.wait {
+                return Err(QuicError::StreamLimitReached);
+            }
+            self.waiting = Some(self.streams.wait_for_open(self.dir, self.count));
+        }
+    }
+}
+
+impl Future for OpenStream {
+    type Item = StreamRef;
+    type Error = QuicError;
+
+    fn poll(&mut self) -> Poll<StreamRef, QuicError> {
+        let mut streams = try_ready!(self.poll_batch());
+        Ok(Async::Ready(streams.pop().expect("opened a stream")))
+    }
+}
+
+pub struct OpenUni {
+    open: OpenStream,
+}
+
+impl OpenUni {
+    pub(crate) fn new(streams: &Streams, options: OpenOptions) -> Self {
+        Self {
+            open: streams.open_with(Dir::Uni, options),
+        }
+    }
+}
+
+impl Future for OpenUni {
+    type Item = SendStream;
+    type Error = QuicError;
+
+    fn poll(&mut self) -> Poll<SendStream, QuicError> {
+        let stream = try_ready!(self.open.poll());
+        Ok(Async::Ready(stream.split().0))
+    }
+}
+
+pub struct OpenBi {
+    open: OpenStream,
+}
+
+impl OpenBi {
+    pub(crate) fn new(streams: &Streams, options: OpenOptions) -> Self {
+        Self {
+            open: streams.open_with(Dir::Bidi, options),
+        }
+    }
+}
+
+impl Future for OpenBi {
+    type Item = (SendStream, RecvStream);
+    type Error = QuicError;
+
+    fn poll(&mut self) -> Poll<(SendStream, RecvStream), QuicError> {
+        let stream = try_ready!(self.open.poll());
+        Ok(Async::Ready(stream.split()))
+    }
+}
+
+pub struct OpenBiBatch {
+    open: OpenStream,
+}
+
+impl OpenBiBatch {
+    pub(crate) fn new(streams: &Streams, count: usize) -> Self {
+        Self {
+            open: streams.open_batch(Dir::Bidi, count, OpenOptions::default()),
+        }
+    }
+}
+
+impl Future for OpenBiBatch {
+    type Item = Vec<(SendStream, RecvStream)>;
+    type Error = QuicError;
+
+    fn poll(&mut self) -> Poll<Vec<(SendStream, RecvStream)>, QuicError> {
+        let streams = try_ready!(self.open.poll_batch());
+        Ok(Async::Ready(streams.into_iter().map(StreamRef::split).collect()))
+    }
+}
+
+struct OpenStreams {
+    next: Option<StreamId>,
+    max: StreamId,
+    remote: u64,
+    updates: Vec<(StreamId, oneshot::Sender<StreamId>)>,
+    // How many streams the limit allows open at once, and for peer streams, how many times in
+    // a row the peer has been refused more
+    window: u64,
+    blocked: usize,
+}
+
+impl OpenStreams {
+    fn new() -> Self {
+        Self {
+            next: None,
+            max: StreamId(0),
+            remote: 0,
+            updates: Vec::new(),
+            window: 0,
+            blocked: 0,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct StreamLimits {
+    pub max_bidi: StreamId,
+    pub max_uni: StreamId,
+    pub peer_max_bidi: StreamId,
+    pub peer_max_uni: StreamId,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Dir {
+    Bidi,
+    Uni,
+}
+
+impl Dir {
+    pub(crate) fn to_bit(&self) -> u64 {
+        match self {
+            Dir::Bidi => 0,
+            Dir::Uni => 2,
+        }
+    }
+}
+
+fn stype(initiator: Side, dir: Dir) -> usize {
+    (initiator.to_bit() + dir.to_bit()) as usize
+}
+
+pub const DEFAULT_RECEIVE_BUFFER: usize = 1 << 20;
+pub const DEFAULT_SEND_BUFFER: usize = 1 << 20;
+pub const DEFAULT_CONNECTION_BUFFER: usize = 4 << 20;
+const SEND_QUANTUM: usize = 1200;
+// Consecutive refusals after which a blocked peer is reported
+const PERSISTENTLY_BLOCKED: usize = 3;
+
+#[cfg(test)]
+mod tests {
+    use super::{Dir, NewStream, OpenBi, OpenBiBatch, OpenOptions, RecvStream, SendStream, StreamRef,
+                Streams};
+    use bytes::Bytes;
+    use events::{Event, Events};
+    use frame::{BlockedFrame, Frame, MaxDataFrame, MaxStreamIdFrame, StopSendingFrame,
+                StreamBlockedFrame, StreamFrame, StreamIdBlockedFrame};
+    use futures::executor::{self, Notify, NotifyHandle};
+    use futures::{future, Async, Future, Stream};
+    use parameters::TransportConfig;
+    use std::io::{self, Read, Write};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+    use std::thread;
+    use types::{Side, StreamId};
+    use {QuicError, TransportError};
+
+    fn frame(id: u64, offset: u64, data: &[u8], fin: bool) -> StreamFrame {
+        StreamFrame {
+            id: StreamId(id),
+            fin,
+            offset,
+            len: Some(data.len() as u64),
+            data: Bytes::from(data),
+        }
+    }
+
+    #[test]
+    fn test_recv_stream_eof() {
+        let mut streams = Streams::new(Side::Server);
+        streams.update_max_id(StreamId(4));
+        streams.set_receive_windows(1024, 1024);
+        streams.received_frame(&frame(4, 5, b" world", true)).unwrap();
+
+        let (_, mut recv) = streams.received(StreamId(4)).unwrap().split();
+        future::lazy(move || {
+            let mut buf = [0; 16];
+            assert!(recv.read(&mut buf).is_err());
+            streams.received_frame(&frame(4, 0, b"hello", false)).unwrap();
+
+            let mut out = Vec::new();
+            recv.read_to_end(&mut out).unwrap();
+            assert_eq!(&out[..], b"hello world");
+            Ok::<_, ()>(())
+        }).wait()
+            .unwrap();
+    }
+
+    #[test]
+    fn test_read_to_end() {
+        let mut streams = Streams::new(Side::Server);
+        streams.update_max_id(StreamId(8));
+        streams.set_receive_windows(1024, 1024);
+        streams.received_frame(&frame(4, 0, b"hello", false)).unwrap();
+        streams.received_frame(&frame(8, 0, b"hello", false)).unwrap();
+
+        let (_, recv) = streams.received(StreamId(4)).unwrap().split();
+        let mut whole = recv.read_to_end(16);
+        let (_, recv) = streams.received(StreamId(8)).unwrap().split();
+        let mut limited = recv.read_to_end(8);
+        future::lazy(move || {
+            assert_eq!(whole.poll().unwrap(), Async::NotReady);
+            assert_eq!(limited.poll().unwrap(), Async::NotReady);
+            streams.received_frame(&frame(4, 5, b" world", true)).unwrap();
+            streams.received_frame(&frame(8, 5, b" world", true)).unwrap();
+
+            assert_eq!(whole.poll().unwrap(), Async::Ready(b"hello world".to_vec()));
+            match limited.poll() {
+                Err(QuicError::StreamTooLong(8)) => {}
+                res => panic!("unexpected result {:?}", res),
+            }
+            let stopped = streams.queued().unwrap();
+            assert_eq!(
+                stopped,
+                Frame::StopSending(StopSendingFrame {
+                    id: StreamId(8),
+                    error_code: 0,
+                })
+            );
+            Ok::<_, ()>(())
+        }).wait()
+            .unwrap();
+    }
+
+    #[test]
+    fn test_write_all() {
+        let mut streams = Streams::new(Side::Client);
+        streams.update_max_id(StreamId(0));
+        streams.set_send_limits(1024, 100);
+        let (send, _) = streams.init_send(Dir::Bidi).unwrap().split();
+
+        let mut write = send.write_all(Bytes::from(vec![7; 300]));
+        future::lazy(move || {
+            assert!(write.poll().unwrap().is_not_ready());
+            streams.update_max_stream_data(StreamId(0), 300).unwrap();
+            assert!(write.poll().unwrap().is_ready());
+            Ok::<_, ()>(())
+        }).wait()
+            .unwrap();
+    }
+
+    #[test]
+    fn test_unordered_reads() {
+        let mut streams = Streams::new(Side::Server);
+        streams.update_max_id(StreamId(4));
+        streams.set_receive_windows(1024, 1024);
+        streams.received_frame(&frame(4, 5, b" world", false)).unwrap();
+
+        let mut stream = streams.received(StreamId(4)).unwrap();
+        assert_eq!(stream.read().unwrap(), None);
+        let chunk = stream.read_unordered().unwrap();
+        assert_eq!(chunk, Some((5, Bytes::from_static(b" world"))));
+
+        // Only the missing part of a retransmission comes through
+        streams.received_frame(&frame(4, 0, b"hello world", true)).unwrap();
+        assert_eq!(stream.read().unwrap(), Some(Bytes::from_static(b"hello")));
+        assert_eq!(stream.read_unordered().unwrap(), None);
+        assert_eq!(stream.read().unwrap(), None);
+    }
+
+    #[test]
+    fn test_incoming_streams() {
+        let mut streams = Streams::new(Side::Server);
+        streams.update_max_id(StreamId(8));
+        streams.update_max_id(StreamId(2));
+        streams.set_receive_windows(1024, 1024);
+        let mut incoming = streams.incoming();
+
+        future::lazy(move || {
+            assert!(incoming.poll().unwrap().is_not_ready());
+            streams.received_frame(&frame(8, 0, b"a", false)).unwrap();
+            streams.received_frame(&frame(2, 0, b"b", false)).unwrap();
+
+            let mut ids = Vec::new();
+            while let Async::Ready(Some((id, stream))) = incoming.poll().unwrap() {
+                match stream {
+                    NewStream::Bidi(_) => assert_eq!(id.dir(), Dir::Bidi),
+                    NewStream::Uni(_) => assert_eq!(id, StreamId(2)),
+                }
+                ids.push(id);
+            }
+            assert_eq!(ids, vec![StreamId(4), StreamId(8), StreamId(2)]);
+            Ok::<_, ()>(())
+        }).wait()
+            .unwrap();
+    }
+
+    #[test]
+    fn test_finish_and_drop() {
+        let mut streams = Streams::new(Side::Client);
+        streams.update_max_id(StreamId(12));
+        streams.set_send_limits(1024, 1024);
+        streams.init_send(Dir::Bidi).unwrap();
+
+        let (mut send, _) = streams.init_send(Dir::Bidi).unwrap().split();
+        let (dropped, _) = streams.init_send(Dir::Bidi).unwrap().split();
+        future::lazy(move || {
+            assert_eq!(send.write(b"abc").unwrap(), 3);
+            let mut finish = send.finish();
+            let sent = match streams.queued() {
+                Some(Frame::Stream(f)) => f,
+                _ => panic!("expected a stream frame"),
+            };
+            assert!(sent.fin);
+            assert!(finish.poll().unwrap().is_not_ready());
+            streams.on_stream_acked(&sent);
+            assert!(finish.poll().unwrap().is_ready());
+
+            drop(dropped);
+            match streams.queued() {
+                Some(Frame::RstStream(f)) => assert_eq!(f.id, StreamId(8)),
+                _ => panic!("expected a reset"),
+            }
+            Ok::<_, ()>(())
+        }).wait()
+            .unwrap();
+    }
+
+    #[test]
+    fn test_closed_streams_raise_limit() {
+        let mut streams = Streams::new(Side::Server);
+        streams.update_max_id(StreamId(2));
+        streams.set_receive_windows(1024, 1024);
+        assert_eq!(streams.limits().peer_max_uni, StreamId(2));
+
+        streams.received_frame(&frame(2, 0, b"hi", true)).unwrap();
+        let mut stream = streams.received(StreamId(2)).unwrap();
+        assert_eq!(stream.read().unwrap(), Some(Bytes::from_static(b"hi")));
+        assert_eq!(streams.limits().peer_max_uni, StreamId(6));
+
+        let mut raised = Vec::new();
+        while let Some(frame) = streams.queued() {
+            if let Frame::MaxStreamId(MaxStreamIdFrame(id)) = frame {
+                raised.push(id);
+            }
+        }
+        assert_eq!(raised, vec![StreamId(6)]);
+        assert_eq!(stream.read().unwrap(), None);
+        assert_eq!(streams.queued(), None);
+    }
+
+    #[test]
+    fn test_max_id_resumes_allocation() {
+        let mut streams = Streams::new(Side::Client);
+        streams.update_max_id(StreamId(4));
+        assert_eq!(streams.init_send(Dir::Bidi).map(|s| s.id()), Some(StreamId(0)));
+        assert_eq!(streams.init_send(Dir::Bidi).map(|s| s.id()), Some(StreamId(4)));
+        assert_eq!(streams.init_send(Dir::Bidi).map(|s| s.id()), None);
+
+        // A stale limit must not move the allocator back
+        streams.update_max_id(StreamId(0));
+        assert_eq!(streams.init_send(Dir::Bidi).map(|s| s.id()), None);
+        streams.update_max_id(StreamId(12));
+        assert_eq!(streams.init_send(Dir::Bidi).map(|s| s.id()), Some(StreamId(8)));
+        assert_eq!(streams.init_send(Dir::Bidi).map(|s| s.id()), Some(StreamId(12)));
+        assert_eq!(streams.init_send(Dir::Bidi).map(|s| s.id()), None);
+    }
+
+    #[test]
+    fn test_uni_streams() {
+        let mut client = Streams::new(Side::Client);
+        client.update_max_id(StreamId(2));
+        let mut server = Streams::new(Side::Server);
+        server.update_max_id(StreamId(2));
+        server.set_receive_windows(1024, 1024);
+
+        future::lazy(move || {
+            let mut first = client.open(Dir::Uni);
+            assert_eq!(first.poll().unwrap().map(|s| s.id()), Async::Ready(StreamId(2)));
+            let mut second = client.open(Dir::Uni);
+            assert!(second.poll().unwrap().is_not_ready());
+            match client.queued() {
+                Some(Frame::StreamIdBlocked(StreamIdBlockedFrame(id))) => {
+                    assert_eq!(id, StreamId(6))
+                }
+                _ => panic!("expected a stream ID blocked frame"),
+            }
+            client.update_max_id(StreamId(6));
+            assert_eq!(second.poll().unwrap().map(|s| s.id()), Async::Ready(StreamId(6)));
+            assert!(client.received_frame(&frame(2, 0, b"x", false)).is_err());
+
+            let mut accept = server.accept_uni();
+            assert!(accept.poll().unwrap().is_not_ready());
+            server.received_frame(&frame(2, 0, b"x", true)).unwrap();
+            match accept.poll().unwrap() {
+                Async::Ready(recv) => assert_eq!(recv.id(), StreamId(2)),
+                Async::NotReady => panic!("expected an incoming stream"),
+            }
+            // Dropping the send half of a receive-only stream must not reset it
+            assert_eq!(server.queued(), None);
+            Ok::<_, ()>(())
+        }).wait()
+            .unwrap();
+    }
+
+    #[test]
+    fn test_open_options() {
+        let mut client = Streams::new(Side::Client);
+        client.update_max_id(StreamId(0));
+
+        future::lazy(move || {
+            let options = OpenOptions::new().priority(3).fail_fast(true);
+            let (send, recv) = match OpenBi::new(&client, options).poll().unwrap() {
+                Async::Ready(halves) => halves,
+                Async::NotReady => panic!("expected a stream"),
+            };
+            assert_eq!((send.id(), recv.id()), (StreamId(0), StreamId(0)));
+            assert_eq!(send.stream.stream.lock().unwrap().priority, 3);
+
+            match client.open_with(Dir::Bidi, options).poll() {
+                Err(QuicError::StreamLimitReached) => {}
+                res => panic!("unexpected result {:?}", res.map(|s| s.map(|s| s.id()))),
+            }
+            assert_eq!(client.queued(), None);
+            assert!(OpenBi::new(&client, OpenOptions::new()).poll().unwrap().is_not_ready());
+            Ok::<_, ()>(())
+        }).wait()
+            .unwrap();
+    }
+
+    #[test]
+    fn test_open_batch() {
+        let mut client = Streams::new(Side::Client);
+        client.update_max_id(StreamId(8));
+
+        future::lazy(move || {
+            let ids = |streams: Vec<(SendStream, RecvStream)>| {
+                streams.iter().map(|&(ref send, _)| send.id()).collect::<Vec<_>>()
+            };
+            match OpenBiBatch::new(&client, 2).poll().unwrap() {
+                Async::Ready(streams) => assert_eq!(ids(streams), vec![StreamId(0), StreamId(4)]),
+                Async::NotReady => panic!("expected streams"),
+            }
+
+            // Only one more fits, so none are opened until the peer allows both
+            let mut batch = OpenBiBatch::new(&client, 2);
+            assert!(batch.poll().unwrap().is_not_ready());
+            match client.queued() {
+                Some(Frame::StreamIdBlocked(StreamIdBlockedFrame(id))) => {
+                    assert_eq!(id, StreamId(12))
+                }
+                _ => panic!("expected a stream ID blocked frame"),
+            }
+            client.update_max_id(StreamId(12));
+            match batch.poll().unwrap() {
+                Async::Ready(streams) => assert_eq!(ids(streams), vec![StreamId(8), StreamId(12)]),
+                Async::NotReady => panic!("expected streams"),
+            }
+            Ok::<_, ()>(())
+        }).wait()
+            .unwrap();
+    }
+
+    #[test]
+    fn test_open_batch_over_window() {
+        let mut client = Streams::new(Side::Client);
+        client.update_max_id(StreamId(8));
+        client.set_send_window(Dir::Bidi, 3);
+
+        future::lazy(move || {
+            match OpenBiBatch::new(&client, 4).poll() {
+                Err(QuicError::StreamLimitReached) => {}
+                res => panic!("unexpected result {:?}", res.map(|_| ())),
+            }
+            assert_eq!(client.queued(), None);
+            assert!(OpenBiBatch::new(&client, 3).poll().unwrap().is_ready());
+            Ok::<_, ()>(())
+        }).wait()
+            .unwrap();
+    }
+
+    #[test]
+    fn test_weighted_scheduling() {
+        let mut streams = Streams::new(Side::Client);
+        streams.update_max_id(StreamId(8));
+        streams.set_send_limits(1 << 20, 1 << 20);
+        streams.init_send(Dir::Bidi).unwrap();
+        let mut low = streams.init_send(Dir::Bidi).unwrap();
+        let mut high = streams.init_send(Dir::Bidi).unwrap();
+        high.set_priority(1);
+        assert_eq!(low.write(&[1; 6000]).unwrap(), 6000);
+        assert_eq!(high.write(&[2; 6000]).unwrap(), 6000);
+
+        let mut sent = Vec::new();
+        while let Some(frame) = streams.queued() {
+            match frame {
+                Frame::Stream(f) => sent.push((f.id.0, f.data.len())),
+                _ => panic!("expected a stream frame"),
+            }
+        }
+        assert_eq!(
+            sent,
+            vec![
+                (4, 1200),
+                (8, 2400),
+                (4, 1200),
+                (8, 2400),
+                (4, 1200),
+                (8, 1200),
+                (4, 1200),
+                (4, 1200),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_write_bytes_without_copy() {
+        let mut streams = Streams::new(Side::Client);
+        streams.update_max_id(StreamId(0));
+        streams.set_send_limits(1024, 1024);
+        let mut stream = streams.init_send(Dir::Bidi).unwrap();
+
+        let data = Bytes::from(vec![7; 300]);
+        assert_eq!(stream.write_bytes(&data).unwrap(), 300);
+        match streams.queued() {
+            Some(Frame::Stream(f)) => {
+                assert_eq!(f.data.len(), 300);
+                assert_eq!(f.data.as_ptr(), data.as_ptr());
+            }
+            _ => panic!("expected a stream frame"),
+        }
+
+        // Back to back writes aren't merged, since that would copy them
+        let more = Bytes::from(vec![8; 100]);
+        assert_eq!(stream.write_bytes(&data).unwrap(), 300);
+        assert_eq!(stream.write_bytes(&more).unwrap(), 100);
+        let mut sent = Vec::new();
+        while let Some(frame) = streams.queued() {
+            if let Frame::Stream(f) = frame {
+                sent.push((f.offset, f.data.len(), f.data.as_ptr()));
+            }
+        }
+        assert_eq!(
+            sent,
+            vec![(300, 300, data.as_ptr()), (600, 100, more.as_ptr())]
+        );
+    }
+
+    #[test]
+    fn test_write_chunks() {
+        let mut streams = Streams::new(Side::Client);
+        streams.update_max_id(StreamId(0));
+        streams.set_send_limits(1024, 400);
+        let mut stream = streams.init_send(Dir::Bidi).unwrap();
+
+        let chunks = [
+            Bytes::from(vec![1; 100]),
+            Bytes::new(),
+            Bytes::from(vec![2; 500]),
+        ];
+        assert_eq!(stream.write_chunks(&chunks).unwrap(), 400);
+        // Stream credit ran out partway through the last chunk
+        let mut frames = Vec::new();
+        while let Some(frame) = streams.queued() {
+            if let Frame::Stream(f) = frame {
+                frames.push(f);
+            }
+        }
+        let sent = frames
+            .iter()
+            .map(|f| (f.offset, f.data.len(), f.data.as_ptr()))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            sent,
+            vec![(0, 100, chunks[0].as_ptr()), (100, 300, chunks[2].as_ptr())]
+        );
+    }
+
+    #[test]
+    fn test_write_coalescing() {
+        let mut streams = Streams::new(Side::Client);
+        streams.update_max_id(StreamId(4));
+        streams.set_send_limits(4096, 4096);
+        let mut stream = streams.init_send(Dir::Bidi).unwrap();
+        let frames = |streams: &mut Streams| {
+            let mut sent = Vec::new();
+            while let Some(frame) = streams.queued() {
+                if let Frame::Stream(f) = frame {
+                    sent.push(f);
+                }
+            }
+            sent
+        };
+
+        // Writes that haven't gone out yet share a frame
+        for _ in 0..3 {
+            stream.write(b"abc").unwrap();
+        }
+        let sent = frames(&mut streams);
+        assert_eq!(sent.len(), 1);
+        assert_eq!(&sent[0].data[..], b"abcabcabc");
+
+        // Small writes wait for the data ahead of them, or for a flush
+        streams.set_write_coalescing(Some(1000));
+        stream.write(&[1; 100]).unwrap();
+        stream.write(&[2; 100]).unwrap();
+        assert!(frames(&mut streams).is_empty());
+        stream.flush();
+        let flushed = frames(&mut streams);
+        assert_eq!(flushed.len(), 1);
+        assert_eq!((flushed[0].offset, flushed[0].data.len()), (9, 200));
+
+        stream.write(&[3; 100]).unwrap();
+        assert!(frames(&mut streams).is_empty());
+        streams.on_stream_acked(&sent[0]);
+        assert!(frames(&mut streams).is_empty());
+        streams.on_stream_acked(&flushed[0]);
+        let released = frames(&mut streams);
+        assert_eq!((released[0].offset, released[0].data.len()), (209, 100));
+
+        // Low latency sends every write by itself
+        streams.set_write_coalescing(None);
+        stream.write(b"abc").unwrap();
+        stream.write(b"def").unwrap();
+        assert_eq!(frames(&mut streams).len(), 2);
+
+        // Finishing releases a held back write, carrying the FIN
+        streams.set_write_coalescing(Some(1000));
+        stream.write(&[4; 100]).unwrap();
+        assert!(frames(&mut streams).is_empty());
+        stream.finish();
+        let finished = frames(&mut streams);
+        assert_eq!(finished.len(), 1);
+        assert_eq!((finished[0].offset, finished[0].data.len()), (315, 100));
+        assert!(finished[0].fin);
+    }
+
+    #[test]
+    fn test_send_buffer_backpressure() {
+        let mut streams = Streams::new(Side::Client);
+        streams.update_max_id(StreamId(0));
+        streams.set_send_limits(4096, 4096);
+        streams.set_send_buffer(500);
+        let mut stream = streams.init_send(Dir::Bidi).unwrap();
+
+        assert_eq!(stream.write(&[0; 1000]).unwrap(), 500);
+        match stream.write(&[0; 100]) {
+            Err(QuicError::Io(ref e)) if e.kind() == io::ErrorKind::WouldBlock => {}
+            res => panic!("expected the write to block, got {:?}", res),
+        }
+
+        // Sending alone doesn't make room; the peer has to acknowledge the data
+        let sent = match streams.queued() {
+            Some(Frame::Stream(f)) => f,
+            _ => panic!("expected a stream frame"),
+        };
+        assert!(stream.write(&[0; 100]).is_err());
+        streams.on_stream_acked(&sent);
+        assert_eq!(stream.write(&[0; 1000]).unwrap(), sent.data.len());
+    }
+
+    struct Wakeups(AtomicUsize);
+
+    impl Notify for Wakeups {
+        fn notify(&self, _: usize) {
+            self.0.fetch_add(1, Ordering::SeqCst);
+        }
+    }
+
+    // Polls a write that can't proceed, returning a count of the wakeups it gets
+    fn block_writer(mut stream: StreamRef) -> Arc<Wakeups> {
+        let wakeups = Arc::new(Wakeups(AtomicUsize::new(0)));
+        let mut writer = executor::spawn(future::poll_fn(move || stream.poll_write(&[0; 100])));
+        let handle = NotifyHandle::from(wakeups.clone());
+        assert_eq!(writer.poll_future_notify(&handle, 0).unwrap(), Async::NotReady);
+        wakeups
+    }
+
+    #[test]
+    fn test_max_data_wakes_only_blocked_writers() {
+        let mut streams = Streams::new(Side::Client);
+        streams.update_max_id(StreamId(4));
+        streams.set_send_limits(1000, 600);
+        let mut first = streams.init_send(Dir::Bidi).unwrap();
+        let mut second = streams.init_send(Dir::Bidi).unwrap();
+
+        // The first stream runs out of its own credit, the second of the connection's
+        assert_eq!(first.write(&[0; 600]).unwrap(), 600);
+        let first = block_writer(first);
+        assert_eq!(second.write(&[0; 600]).unwrap(), 400);
+        let second = block_writer(second);
+
+        streams.update_max_data(2000);
+        assert_eq!(first.0.load(Ordering::SeqCst), 0);
+        assert_eq!(second.0.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn test_blocked_frames() {
+        let mut streams = Streams::new(Side::Client);
+        streams.update_max_id(StreamId(4));
+        streams.set_send_limits(1000, 600);
+        let mut first = streams.init_send(Dir::Bidi).unwrap();
+        let mut second = streams.init_send(Dir::Bidi).unwrap();
+
+        // The first stream runs out of its own credit, the second of the connection's
+        assert_eq!(first.write(&[0; 700]).unwrap(), 600);
+        assert_eq!(second.write(&[0; 700]).unwrap(), 400);
+        // Each limit is only reported once
+        assert!(first.write(&[0; 100]).is_err());
+        assert!(second.write(&[0; 100]).is_err());
+
+        let mut blocked = Vec::new();
+        while let Some(frame) = streams.queued() {
+            match frame {
+                Frame::Stream(_) => {}
+                frame => blocked.push(frame),
+            }
+        }
+        assert_eq!(
+            blocked,
+            vec![
+                Frame::StreamBlocked(StreamBlockedFrame {
+                    id: StreamId(0),
+                    offset: 600,
+                }),
+                Frame::Blocked(BlockedFrame(1000)),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_concurrent_writers() {
+        const TOTAL: usize = 65536;
+        let mut streams = Streams::new(Side::Client);
+        streams.update_max_id(StreamId(12));
+        streams.set_send_limits(1 << 20, 1 << 20);
+        streams.set_send_buffer(4096);
+        let writers = (0..4)
+            .map(|_| {
+                let mut stream = streams.init_send(Dir::Bidi).unwrap();
+                thread::spawn(move || {
+                    let data = vec![stream.id().index() as u8; TOTAL];
+                    let mut written = 0;
+                    while written < TOTAL {
+                        match stream.write(&data[written..]) {
+                            Ok(len) => written += len,
+                            Err(_) => thread::yield_now(),
+                        }
+                    }
+                })
+            })
+            .collect::<Vec<_>>();
+
+        // Acknowledge data as soon as it's sent, making room for the writers
+        let mut received = [0; 4];
+        while received.iter().sum::<usize>() < 4 * TOTAL {
+            match streams.queued() {
+                Some(Frame::Stream(f)) => {
+                    assert!(f.data.iter().all(|&b| u64::from(b) == f.id.index()));
+                    received[f.id.index() as usize] += f.data.len();
+                    streams.on_stream_acked(&f);
+                }
+                Some(_) => {}
+                None => thread::yield_now(),
+            }
+        }
+        for writer in writers {
+            writer.join().unwrap();
+        }
+        assert_eq!(received, [TOTAL; 4]);
+    }
+
+    #[test]
+    fn test_regenerate_lost_frames() {
+        let mut streams = Streams::new(Side::Client);
+        streams.update_max_id(StreamId(4));
+        streams.set_send_limits(1024, 1024);
+        streams.set_receive_windows(1024, 1024);
+        streams.init_send(Dir::Bidi).unwrap();
+        let mut stream = streams.init_send(Dir::Bidi).unwrap();
+
+        let lost = || Frame::Stream(frame(4, 0, b"abc", false));
+        assert_eq!(streams.regenerate(lost()), Some(lost()));
+        stream.reset(1);
+        assert_eq!(streams.regenerate(lost()), None);
+
+        let stale = Frame::MaxData(MaxDataFrame(1));
+        assert_eq!(streams.regenerate(stale), Some(Frame::MaxData(MaxDataFrame(1024))));
+        assert_eq!(streams.regenerate(Frame::Ping), Some(Frame::Ping));
+    }
+
+    #[test]
+    fn test_stream_id_blocked() {
+        let config = TransportConfig::default().max_concurrent_bidi_streams(1);
+        let mut streams = Streams::with_config(Side::Server, &config);
+        let events = Events::new();
+        streams.set_events(events.clone());
+
+        // The peer missed our last update
+        streams.stream_id_blocked_received(StreamId(4)).unwrap();
+        let raised = Frame::MaxStreamId(MaxStreamIdFrame(StreamId(4)));
+        assert_eq!(streams.queued(), Some(raised));
+
+        streams.received_frame(&frame(4, 0, b"hi", false)).unwrap();
+        let mut listener = events.listen().unwrap();
+        for _ in 0..3 {
+            streams.stream_id_blocked_received(StreamId(8)).unwrap();
+        }
+        assert_eq!(streams.queued(), None);
+        assert_eq!(streams.peer_blocked(), 3);
+        match listener.poll().unwrap() {
+            Async::Ready(Some(Event::PeerStreamsBlocked(id))) => assert_eq!(id, StreamId(8)),
+            event => panic!("unexpected event {:?}", event),
+        }
+
+        assert!(streams.stream_id_blocked_received(StreamId(1)).is_err());
+    }
+
+    #[test]
+    fn test_reject_unopened_local_streams() {
+        let mut streams = Streams::new(Side::Server);
+        streams.update_max_id(StreamId(5));
+        streams.set_receive_windows(1024, 1024);
+        match streams.received_frame(&frame(1, 0, b"x", false)) {
+            Err(QuicError::Transport(TransportError::StreamStateError, _)) => {}
+            _ => panic!("expected a stream state error"),
+        }
+        assert!(streams.received(StreamId(1)).is_none());
+
+        streams.init_send(Dir::Bidi).unwrap();
+        streams.received_frame(&frame(1, 0, b"x", false)).unwrap();
+        match streams.received_frame(&frame(8, 0, b"x", false)) {
+            Err(QuicError::Transport(TransportError::StreamIdError, _)) => {}
+            _ => panic!("expected a stream ID error"),
+        }
+    }
+
+    #[test]
+    fn test_reset_before_data() {
+        let mut streams = Streams::new(Side::Server);
+        streams.update_max_id(StreamId(4));
+        streams.set_receive_windows(1024, 1024);
+        streams.reset(StreamId(4), 7, 5).unwrap();
+        match streams.received(StreamId(4)).unwrap().read() {
+            Err(QuicError::StreamReset(_, 7)) => {}
+            res => panic!("unexpected result {:?}", res),
+        }
+        match streams.reset(StreamId(1), 7, 0) {
+            Err(QuicError::Transport(TransportError::StreamStateError, _)) => {}
+            _ => panic!("expected a stream state error"),
+        }
+    }
+
+    #[test]
+    fn test_stop_sending() {
+        let mut streams = Streams::new(Side::Client);
+        streams.update_max_id(StreamId(0));
+        streams.set_send_limits(1024, 1024);
+        let mut stream = streams.init_send(Dir::Bidi).unwrap();
+        assert_eq!(stream.write(b"hello").unwrap(), 5);
+
+        streams.stop_sending_received(StreamId(0), 7).unwrap();
+        match stream.write(b"world") {
+            Err(QuicError::StreamReset(id, 7)) => assert_eq!(id, StreamId(0)),
+            res => panic!("unexpected result {:?}", res),
+        }
+        // Data that hasn't gone out yet is dropped in favour of the reset
+        match streams.queued() {
+            Some(Frame::RstStream(f)) => {
+                assert_eq!((f.id, f.error_code, f.final_offset), (StreamId(0), 7, 5))
+            }
+            frame => panic!("expected a reset, got {:?}", frame),
+        }
+        assert_eq!(streams.queued(), None);
+    }
+
+    #[test]
+    fn test_closed_streams_are_forgotten() {
+        let mut streams = Streams::new(Side::Server);
+        streams.update_max_id(StreamId(3));
+        streams.update_max_id(StreamId(2));
+        streams.set_send_limits(1024, 1024);
+        streams.set_receive_windows(1024, 1024);
+
+        let mut send = streams.init_send(Dir::Uni).unwrap();
+        assert_eq!(send.write(b"hi").unwrap(), 2);
+        send.finish();
+        let sent = match streams.queued() {
+            Some(Frame::Stream(f)) => f,
+            _ => panic!("expected a stream frame"),
+        };
+        match send.write(b"more") {
+            Err(QuicError::Transport(TransportError::StreamStateError, _)) => {}
+            _ => panic!("expected a stream state error"),
+        }
+        streams.on_stream_acked(&sent);
+        assert!(send.poll_finish().unwrap().is_ready());
+        assert!(streams.inner.lock().unwrap().streams.is_empty());
+
+        streams.received_frame(&frame(2, 0, b"ok", true)).unwrap();
+        let mut stream = streams.received(StreamId(2)).unwrap();
+        assert_eq!(stream.read().unwrap(), Some(Bytes::from_static(b"ok")));
+        assert!(streams.inner.lock().unwrap().streams.is_empty());
+
+        // A retransmission for a closed stream doesn't bring it back
+        streams.received_frame(&frame(2, 0, b"ok", true)).unwrap();
+        assert!(streams.received(StreamId(2)).is_none());
+        assert_eq!(streams.inner.lock().unwrap().incoming.len(), 1);
+    }
+
+    #[test]
+    fn test_connection_buffer_holds_credit() {
+        let mut streams = Streams::new(Side::Server);
+        streams.update_max_id(StreamId(4));
+        streams.set_receive_windows(100, 100);
+        streams.set_connection_buffer(30);
+
+        streams.received_frame(&frame(0, 0, &[0; 60], false)).unwrap();
+        streams.received_frame(&frame(4, 0, &[0; 30], false)).unwrap();
+        let mut first = streams.received(StreamId(0)).unwrap();
+        assert_eq!(first.read().unwrap().map(|b| b.len()), Some(60));
+        // Still at the limit with the other stream's data unread
+        assert_eq!(streams.inner.lock().unwrap().buffered, 30);
+        assert_eq!(streams.buffered(), 30);
+        while let Some(frame) = streams.queued() {
+            if let Frame::MaxData(_) = frame {
+                panic!("credit issued while the buffer was full");
+            }
+        }
+
+        let mut second = streams.received(StreamId(4)).unwrap();
+        assert_eq!(second.read().unwrap().map(|b| b.len()), Some(30));
+        let mut credit = None;
+        while let Some(frame) = streams.queued() {
+            if let Frame::MaxData(MaxDataFrame(max)) = frame {
+                credit = Some(max);
+            }
         }
+        assert_eq!(credit, Some(160));
     }
 }
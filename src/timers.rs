@@ -0,0 +1,344 @@
+use futures::{task, Async, Future, Poll};
+
+use std::cmp;
+use std::collections::HashMap;
+use std::mem;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use super::QuicError;
+use clock::Clock;
+
+// A hierarchical timer wheel shared by all connections on an endpoint, so that the endpoint
+// driver keeps a single timer armed however many connections there are. Each connection
+// registers one deadline, the earliest of its own timers, and gets woken once it has passed.
+// Level 0 has a slot per millisecond; every level above it covers a full turn of the one below.
+#[derive(Clone)]
+pub(crate) struct Timers {
+    inner: Arc<Mutex<Inner>>,
+}
+
+impl Timers {
+    pub fn new(start: Instant) -> Self {
+        Self {
+            inner: Arc::new(Mutex::new(Inner {
+                start,
+                elapsed: 0,
+                levels: vec![vec![Vec::new(); SLOTS]; LEVELS],
+                entries: HashMap::new(),
+                next_key: 0,
+                driver: None,
+                armed: None,
+            })),
+        }
+    }
+
+    pub fn handle(&self) -> TimerHandle {
+        let mut me = self.inner.lock().unwrap();
+        let key = me.next_key;
+        me.next_key += 1;
+        TimerHandle {
+            inner: self.inner.clone(),
+            key,
+        }
+    }
+
+    // Wakes every connection whose deadline has passed, and returns when the driver should
+    // next call in
+    pub fn poll(&self, now: Instant) -> Option<Instant> {
+        let mut me = self.inner.lock().unwrap();
+        me.advance(now);
+        me.driver = Some(task::current());
+        me.armed = me.next_tick();
+        let start = me.start;
+        me.armed.map(|tick| start + Duration::from_millis(tick))
+    }
+}
+
+pub(crate) struct TimerHandle {
+    inner: Arc<Mutex<Inner>>,
+    key: u64,
+}
+
+impl TimerHandle {
+    // Replaces any earlier deadline; the current task is woken once it has passed
+    pub fn set(&self, deadline: Option<Instant>) {
+        let mut me = self.inner.lock().unwrap();
+        let tick = deadline.map(|deadline| me.tick_after(deadline));
+        let current = me.entries.get(&self.key).and_then(|entry| entry.tick);
+        if let Some(tick) = tick {
+            if current != Some(tick) {
+                me.insert(self.key, tick);
+            }
+        }
+        me.entries.insert(
+            self.key,
+            Entry {
+                tick,
+                task: Some(task::current()),
+            },
+        );
+
+        let earlier = match (tick, me.armed) {
+            (Some(tick), Some(armed)) => tick < armed,
+            (Some(_), None) => true,
+            (None, _) => false,
+        };
+        if earlier {
+            if let Some(driver) = me.driver.take() {
+                driver.notify();
+            }
+        }
+    }
+}
+
+impl Drop for TimerHandle {
+    fn drop(&mut self) {
+        let mut me = self.inner.lock().unwrap();
+        me.entries.remove(&self.key);
+    }
+}
+
+// Fails with QuicError::TimedOut unless the future completes by the deadline. Without timers
+// there's nothing to wake it, so the deadline is only checked whenever it gets polled.
+#[must_use = "futures do nothing unless polled"]
+pub struct Timeout<F> {
+    future: F,
+    deadline: Instant,
+    clock: Arc<Clock>,
+    timer: Option<TimerHandle>,
+}
+
+impl<F> Timeout<F> {
+    pub(crate) fn new(
+        future: F,
+        deadline: Instant,
+        clock: Arc<Clock>,
+        timers: Option<&Timers>,
+    ) -> Self {
+        Self {
+            future,
+            deadline,
+            clock,
+            timer: timers.map(|timers| timers.handle()),
+        }
+    }
+
+    pub fn deadline(&self) -> Instant {
+        self.deadline
+    }
+}
+
+impl<F> Future for Timeout<F>
+where
+    F: Future<Error = QuicError>,
+{
+    type Item = F::Item;
+    type Error = QuicError;
+
+    fn poll(&mut self) -> Poll<F::Item, QuicError> {
+        if let Async::Ready(item) = self.future.poll()? {
+            return Ok(Async::Ready(item));
+        }
+        if self.clock.now() >= self.deadline {
+            return Err(QuicError::TimedOut);
+        }
+        if let Some(ref timer) = self.timer {
+            timer.set(Some(self.deadline));
+        }
+        Ok(Async::NotReady)
+    }
+}
+
+struct Inner {
+    start: Instant,
+    // Ticks that have been processed
+    elapsed: u64,
+    // Slots hold (key, tick) pairs; entries that have since been rescheduled are skipped
+    levels: Vec<Vec<Vec<(u64, u64)>>>,
+    entries: HashMap<u64, Entry>,
+    next_key: u64,
+    driver: Option<task::Task>,
+    armed: Option<u64>,
+}
+
+impl Inner {
+    fn tick_after(&self, deadline: Instant) -> u64 {
+        let ticks = if deadline > self.start {
+            let since = deadline - self.start;
+            since.as_secs() * 1000 + u64::from((since.subsec_nanos() + 999_999) / 1_000_000)
+        } else {
+            0
+        };
+        // Anything that is already due goes off on the next tick
+        cmp::max(ticks, self.elapsed + 1)
+    }
+
+    fn tick_before(&self, now: Instant) -> u64 {
+        if now > self.start {
+            let since = now - self.start;
+            since.as_secs() * 1000 + u64::from(since.subsec_millis())
+        } else {
+            0
+        }
+    }
+
+    // The highest bit in which the tick differs from the current time picks the level
+    fn insert(&mut self, key: u64, tick: u64) {
+        let differing = 64 - (tick ^ self.elapsed).leading_zeros() as usize;
+        let level = cmp::min(differing.saturating_sub(1) / BITS, LEVELS - 1);
+        let slot = (tick >> (level * BITS)) as usize & (SLOTS - 1);
+        self.levels[level][slot].push((key, tick));
+    }
+
+    fn advance(&mut self, now: Instant) {
+        let target = self.tick_before(now);
+        while self.elapsed < target {
+            // Nothing happens in between, so there's no need to step through every tick
+            match self.next_tick() {
+                Some(next) if next <= target => self.elapsed = next - 1,
+                _ => {
+                    self.elapsed = target;
+                    break;
+                }
+            }
+            self.elapsed += 1;
+            let elapsed = self.elapsed;
+            // Coarser slots are due when the time crosses into them, and move their entries down
+            for level in (0..LEVELS).rev() {
+                let shift = level * BITS;
+                if elapsed & ((1 << shift) - 1) != 0 {
+                    continue;
+                }
+                let slot = (elapsed >> shift) as usize & (SLOTS - 1);
+                let due = mem::replace(&mut self.levels[level][slot], Vec::new());
+                for (key, tick) in due {
+                    self.expire(key, tick);
+                }
+            }
+        }
+    }
+
+    fn expire(&mut self, key: u64, tick: u64) {
+        if tick > self.elapsed {
+            if self.entries.get(&key).map_or(false, |entry| entry.tick == Some(tick)) {
+                self.insert(key, tick);
+            }
+            return;
+        }
+        if let Some(entry) = self.entries.get_mut(&key) {
+            if entry.tick == Some(tick) {
+                entry.tick = None;
+                if let Some(task) = entry.task.take() {
+                    task.notify();
+                }
+            }
+        }
+    }
+
+    // When the next non-empty slot comes up; entries only ever sit in slots ahead of the
+    // current time at their level, except on the top level, which wraps around
+    fn next_tick(&mut self) -> Option<u64> {
+        let entries = &self.entries;
+        for level in 0..LEVELS {
+            let shift = level * BITS;
+            let position = self.elapsed >> shift;
+            let turn = (position >> BITS) << BITS;
+            let current = position as usize & (SLOTS - 1);
+            let ahead = if level == LEVELS - 1 { SLOTS } else { SLOTS - 1 - current };
+            for offset in 1..=ahead {
+                let slot = (current + offset) & (SLOTS - 1);
+                // Rescheduled entries would only cause spurious wakeups
+                self.levels[level][slot].retain(|&(key, tick)| {
+                    entries
+                        .get(&key)
+                        .map_or(false, |entry| entry.tick == Some(tick))
+                });
+                if self.levels[level][slot].is_empty() {
+                    continue;
+                }
+                let wrapped = if current + offset >= SLOTS { 1 << BITS } else { 0 };
+                return Some((turn + wrapped + slot as u64) << shift);
+            }
+        }
+        None
+    }
+}
+
+struct Entry {
+    tick: Option<u64>,
+    task: Option<task::Task>,
+}
+
+const BITS: usize = 6;
+const SLOTS: usize = 1 << BITS;
+const LEVELS: usize = 4;
+
+#[cfg(test)]
+mod tests {
+    use super::{Timeout, Timers};
+    use clock::{Clock, MockClock};
+    use futures::{future, Async, Future};
+    use std::sync::Arc;
+    use std::time::{Duration, Instant};
+    use QuicError;
+
+    #[test]
+    fn test_timer_wheel() {
+        let start = Instant::now();
+        let timers = Timers::new(start);
+        let (near, far, cancelled) = (timers.handle(), timers.handle(), timers.handle());
+        future::lazy(|| {
+            near.set(Some(start + Duration::from_millis(5)));
+            far.set(Some(start + Duration::from_secs(90)));
+            cancelled.set(Some(start + Duration::from_millis(3)));
+            cancelled.set(None);
+
+            assert_eq!(timers.poll(start), Some(start + Duration::from_millis(5)));
+            let next = timers.poll(start + Duration::from_millis(5)).unwrap();
+            assert!(next <= start + Duration::from_secs(90));
+            {
+                let me = timers.inner.lock().unwrap();
+                let scheduled = me.entries.values().filter(|entry| entry.tick.is_some());
+                assert_eq!(scheduled.count(), 1);
+            }
+
+            // The far deadline cascades down through the levels without going off early
+            let mut now = next;
+            while let Some(next) = timers.poll(now) {
+                assert!(next > now);
+                now = next;
+            }
+            assert!(now >= start + Duration::from_secs(90));
+            assert!(now < start + Duration::from_secs(90) + Duration::from_millis(1));
+            Ok::<_, ()>(())
+        }).wait()
+            .unwrap();
+    }
+
+    #[test]
+    fn test_timeout() {
+        let clock = MockClock::new();
+        let start = clock.now();
+        let timers = Timers::new(start);
+        let deadline = start + Duration::from_millis(10);
+        let source = Arc::new(clock.clone());
+        let (done, stuck) = (future::ok::<_, QuicError>(1), future::empty::<(), QuicError>());
+        let mut done = Timeout::new(done, deadline, source.clone(), Some(&timers));
+        let mut stuck = Timeout::new(stuck, deadline, source, Some(&timers));
+        future::lazy(|| {
+            assert_eq!(done.poll().unwrap(), Async::Ready(1));
+            assert_eq!(stuck.poll().unwrap(), Async::NotReady);
+            assert_eq!(timers.poll(start), Some(deadline));
+
+            clock.advance(Duration::from_millis(10));
+            assert_eq!(timers.poll(clock.now()), None);
+            match stuck.poll() {
+                Err(QuicError::TimedOut) => {}
+                res => panic!("unexpected result {:?}", res),
+            }
+            Ok::<_, ()>(())
+        }).wait()
+            .unwrap();
+    }
+}
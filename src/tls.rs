@@ -1,19 +1,23 @@
 use rustls::quic::{ClientQuicExt, ServerQuicExt};
-use rustls::{ClientConfig, KeyLogFile, NoClientAuth, ProtocolVersion, TLSError};
+use rustls::sign::{CertifiedKey, RSASigningKey};
+use rustls::{KeyLogFile, NoClientAuth, ProtocolVersion, ResolvesServerCertUsingSNI, RootCertStore,
+             SupportedCipherSuite, TLSError};
 
 use std::io::Cursor;
 use std::sync::Arc;
 
 use super::{QuicError, QuicResult};
 use codec::Codec;
-use crypto::Secret;
+use crypto::{AeadAlgorithm, HashAlgorithm, Secret};
 use parameters::{ClientTransportParameters, ServerTransportParameters};
+use session::{LruSessionCache, SessionCache, SessionStore, DEFAULT_SESSION_CACHE_SIZE};
 use types::Side;
 
 use webpki::{DNSNameRef, TLSServerTrustAnchors};
 use webpki_roots;
 
-pub use rustls::{Certificate, ClientSession, PrivateKey, ServerConfig, ServerSession, Session};
+pub use rustls::{Certificate, ClientConfig, ClientSession, PrivateKey, ResolvesServerCert,
+                 ServerConfig, ServerSession, Session};
 
 pub fn client_session(
     config: Option<ClientConfig>,
@@ -36,9 +40,52 @@ pub fn build_client_config(anchors: Option<&TLSServerTrustAnchors>) -> ClientCon
     config.versions = vec![ProtocolVersion::TLSv1_3];
     config.alpn_protocols = vec![ALPN_PROTOCOL.into()];
     config.key_log = Arc::new(KeyLogFile::new());
+    config.enable_early_data = true;
+    config.set_persistence(Arc::new(SessionStore(Arc::new(LruSessionCache::new(
+        DEFAULT_SESSION_CACHE_SIZE,
+    )))));
     config
 }
 
+pub struct ClientConfigBuilder {
+    config: ClientConfig,
+}
+
+impl ClientConfigBuilder {
+    pub fn new() -> Self {
+        Self {
+            config: build_client_config(None),
+        }
+    }
+
+    pub fn trust_anchors(mut self, anchors: &TLSServerTrustAnchors) -> Self {
+        self.config.root_store = RootCertStore::empty();
+        self.config.root_store.add_server_trust_anchors(anchors);
+        self
+    }
+
+    pub fn session_cache(mut self, cache: Arc<SessionCache>) -> Self {
+        self.config.set_persistence(Arc::new(SessionStore(cache)));
+        self
+    }
+
+    // In order of preference
+    pub fn protocols(mut self, protocols: &[&str]) -> Self {
+        self.config.alpn_protocols = protocols.iter().map(|&p| p.into()).collect();
+        self
+    }
+
+    pub fn build(self) -> ClientConfig {
+        self.config
+    }
+}
+
+impl Default for ClientConfigBuilder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 pub fn server_session(
     config: &Arc<ServerConfig>,
     params: &ServerTransportParameters,
@@ -47,11 +94,76 @@ pub fn server_session(
 }
 
 pub fn build_server_config(cert_chain: Vec<Certificate>, key: PrivateKey) -> ServerConfig {
-    let mut config = ServerConfig::new(NoClientAuth::new());
-    config.set_protocols(&[ALPN_PROTOCOL.into()]);
-    config.set_single_cert(cert_chain, key);
-    config.key_log = Arc::new(KeyLogFile::new());
-    config
+    ServerConfigBuilder::new().certificate(cert_chain, key).build()
+}
+
+pub struct ServerConfigBuilder {
+    config: ServerConfig,
+    sni: Option<ResolvesServerCertUsingSNI>,
+}
+
+impl ServerConfigBuilder {
+    pub fn new() -> Self {
+        let mut config = ServerConfig::new(NoClientAuth::new());
+        config.set_protocols(&[ALPN_PROTOCOL.into()]);
+        config.key_log = Arc::new(KeyLogFile::new());
+        Self { config, sni: None }
+    }
+
+    pub fn certificate(mut self, cert_chain: Vec<Certificate>, key: PrivateKey) -> Self {
+        self.config.set_single_cert(cert_chain, key);
+        self.sni = None;
+        self
+    }
+
+    // Clients are served the certificate registered for the name they send in the SNI
+    // extension; handshakes without a matching name fail. Only RSA keys can be registered:
+    // the rustls signing keys we can build don't cover ECDSA, so those are refused here rather
+    // than failing every handshake later.
+    pub fn sni_certificate(
+        mut self,
+        hostname: &str,
+        cert_chain: Vec<Certificate>,
+        key: PrivateKey,
+    ) -> QuicResult<Self> {
+        let key = RSASigningKey::new(&key).map_err(|_| {
+            QuicError::General(format!(
+                "unsupported private key for {}: only RSA keys can be used",
+                hostname
+            ))
+        })?;
+        let certified = CertifiedKey::new(cert_chain, Arc::new(Box::new(key)));
+        self.sni
+            .get_or_insert_with(ResolvesServerCertUsingSNI::new)
+            .add(hostname, certified)?;
+        Ok(self)
+    }
+
+    pub fn cert_resolver(mut self, resolver: Arc<ResolvesServerCert>) -> Self {
+        self.config.cert_resolver = resolver;
+        self.sni = None;
+        self
+    }
+
+    // The first protocol offered by the client that also appears here is selected
+    pub fn protocols(mut self, protocols: &[&str]) -> Self {
+        let protocols = protocols.iter().map(|&p| p.into()).collect::<Vec<String>>();
+        self.config.set_protocols(&protocols);
+        self
+    }
+
+    pub fn build(mut self) -> ServerConfig {
+        if let Some(sni) = self.sni {
+            self.config.cert_resolver = Arc::new(sni);
+        }
+        self.config
+    }
+}
+
+impl Default for ServerConfigBuilder {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 pub fn process_handshake_messages<T>(session: &mut T, msgs: Option<&[u8]>) -> QuicResult<TlsResult>
@@ -82,7 +194,7 @@ where
         let mut server_secret = vec![0u8; suite.enc_key_len];
         session.export_keying_material(&mut server_secret, b"EXPORTER-QUIC server 1rtt", None)?;
 
-        let (aead_alg, hash_alg) = (suite.get_aead_alg(), suite.get_hash());
+        let (aead_alg, hash_alg) = suite_algorithms(suite)?;
         Some(Secret::For1Rtt(
             aead_alg,
             hash_alg,
@@ -96,20 +208,126 @@ where
     Ok((messages, secret))
 }
 
+pub fn early_secret<T>(session: &T) -> Option<Secret>
+where
+    T: Session,
+{
+    // Before the ServerHello the client can't know the suite that will be
+    // negotiated, so fall back to the mandatory one
+    let (aead_alg, hash_alg, len) = match session.get_negotiated_ciphersuite() {
+        Some(suite) => {
+            let (aead_alg, hash_alg) = suite_algorithms(suite).ok()?;
+            (aead_alg, hash_alg, suite.enc_key_len)
+        }
+        None => (
+            AeadAlgorithm::Aes128Gcm,
+            HashAlgorithm::Sha256,
+            AeadAlgorithm::Aes128Gcm.key_len(),
+        ),
+    };
+    let mut secret = vec![0u8; len];
+    session
+        .export_keying_material(&mut secret, b"EXPORTER-QUIC 0rtt", None)
+        .ok()?;
+    Some(Secret::For1Rtt(aead_alg, hash_alg, secret.clone(), secret))
+}
+
+fn suite_algorithms(suite: &SupportedCipherSuite) -> QuicResult<(AeadAlgorithm, HashAlgorithm)> {
+    match (
+        AeadAlgorithm::from_ring(suite.get_aead_alg()),
+        HashAlgorithm::from_ring(suite.get_hash()),
+    ) {
+        (Some(aead_alg), Some(hash_alg)) => Ok((aead_alg, hash_alg)),
+        _ => Err(QuicError::General(format!(
+            "unsupported cipher suite {:?}",
+            suite.suite
+        ))),
+    }
+}
+
 pub trait QuicSide {
     fn side(&self) -> Side;
+    fn early_data_accepted(&self) -> bool;
+    fn server_name(&self) -> Option<String>;
 }
 
 impl QuicSide for ClientSession {
     fn side(&self) -> Side {
         Side::Client
     }
+
+    fn early_data_accepted(&self) -> bool {
+        self.is_early_data_accepted()
+    }
+
+    // Clients know the name from when they started connecting
+    fn server_name(&self) -> Option<String> {
+        None
+    }
 }
 
 impl QuicSide for ServerSession {
     fn side(&self) -> Side {
         Side::Server
     }
+
+    fn early_data_accepted(&self) -> bool {
+        false
+    }
+
+    fn server_name(&self) -> Option<String> {
+        self.get_sni_hostname().map(String::from)
+    }
+}
+
+// Finds the server name in a client's first flight without handing it to TLS, so a server can
+// turn connections away cheaply
+pub fn client_hello_sni(mut data: &[u8]) -> Option<String> {
+    if data.first() == Some(&HANDSHAKE_RECORD) {
+        take(&mut data, 5)?;
+    }
+    if take(&mut data, 1)?[0] != CLIENT_HELLO {
+        return None;
+    }
+    let mut hello = take_vec(&mut data, 3)?;
+    take(&mut hello, 2 + 32)?; // version and random
+    take_vec(&mut hello, 1)?; // session ID
+    take_vec(&mut hello, 2)?; // cipher suites
+    take_vec(&mut hello, 1)?; // compression methods
+    let mut extensions = take_vec(&mut hello, 2)?;
+    while !extensions.is_empty() {
+        let kind = take(&mut extensions, 2)?;
+        let mut extension = take_vec(&mut extensions, 2)?;
+        if kind != [0, SERVER_NAME_EXTENSION] {
+            continue;
+        }
+        let mut names = take_vec(&mut extension, 2)?;
+        while !names.is_empty() {
+            let name_type = take(&mut names, 1)?[0];
+            let name = take_vec(&mut names, 2)?;
+            if name_type == HOST_NAME {
+                return String::from_utf8(name.to_vec()).ok();
+            }
+        }
+    }
+    None
+}
+
+fn take<'a>(data: &mut &'a [u8], len: usize) -> Option<&'a [u8]> {
+    if data.len() < len {
+        return None;
+    }
+    let (head, rest) = data.split_at(len);
+    *data = rest;
+    Some(head)
+}
+
+// Reads a vector prefixed with its length in `len_bytes` bytes
+fn take_vec<'a>(data: &mut &'a [u8], len_bytes: usize) -> Option<&'a [u8]> {
+    let len = take(data, len_bytes)?
+        .iter()
+        .fold(0, |len, &b| len << 8 | usize::from(b));
+    take(data, len)
 }
 
 type TlsResult = (Vec<u8>, Option<Secret>);
@@ -122,13 +340,33 @@ fn to_vec<T: Codec>(val: &T) -> Vec<u8> {
 
 const ALPN_PROTOCOL: &str = "hq-11";
 
+const HANDSHAKE_RECORD: u8 = 22;
+const CLIENT_HELLO: u8 = 1;
+const SERVER_NAME_EXTENSION: u8 = 0;
+const HOST_NAME: u8 = 0;
+
+pub const HANDSHAKE_FAILURE: u8 = 40;
+pub const BAD_CERTIFICATE: u8 = 42;
+pub const NO_APPLICATION_PROTOCOL: u8 = 120;
+
+// The alert to close the connection with when TLS gives up
+pub fn alert_for(error: &TLSError) -> u8 {
+    match *error {
+        TLSError::AlertReceived(ref alert) => alert.get_u8(),
+        TLSError::WebPKIError(_) => BAD_CERTIFICATE,
+        _ => HANDSHAKE_FAILURE,
+    }
+}
+
 #[cfg(test)]
 pub(crate) mod tests {
     extern crate untrusted;
     use self::untrusted::Input;
+    use parameters::ClientTransportParameters;
     use rustls::internal::pemfile;
     use std::{fs::File, io::{BufReader, Read}};
     use webpki;
+    use QuicError;
 
     pub fn client_config() -> super::ClientConfig {
         let mut f = File::open("certs/ca.der").expect("cannot open 'certs/ca.der'");
@@ -142,6 +380,11 @@ pub(crate) mod tests {
     }
 
     pub fn server_config() -> super::ServerConfig {
+        let (certs, key) = server_certs();
+        super::build_server_config(certs, key)
+    }
+
+    pub fn server_certs() -> (Vec<super::Certificate>, super::PrivateKey) {
         let certs = {
             let f = File::open("certs/server.chain").expect("cannot open 'certs/server.chain'");
             let mut reader = BufReader::new(f);
@@ -154,6 +397,35 @@ pub(crate) mod tests {
             pemfile::rsa_private_keys(&mut reader).expect("cannot read private keys")
         };
 
-        super::build_server_config(certs, keys[0].clone())
+        (certs, keys[0].clone())
+    }
+
+    #[test]
+    fn test_client_hello_sni() {
+        let params = ClientTransportParameters::default();
+        let mut session =
+            super::client_session(Some(client_config()), "example.com", &params).unwrap();
+        let (hello, _) = super::process_handshake_messages(&mut session, None).unwrap();
+        assert_eq!(super::client_hello_sni(&hello), Some("example.com".into()));
+        assert_eq!(super::client_hello_sni(&hello[..20]), None);
+        assert_eq!(super::client_hello_sni(&[2, 0, 0, 0]), None);
+    }
+
+    #[test]
+    fn test_sni_certificate_key_types() {
+        let (certs, key) = server_certs();
+        let builder = super::ServerConfigBuilder::new()
+            .sni_certificate("localhost", certs.clone(), key)
+            .unwrap();
+
+        let mut ecdsa = Vec::new();
+        File::open("certs/ecdsa.der")
+            .expect("cannot open 'certs/ecdsa.der'")
+            .read_to_end(&mut ecdsa)
+            .expect("error while reading");
+        match builder.sni_certificate("example.com", certs, super::PrivateKey(ecdsa)) {
+            Err(QuicError::General(ref reason)) => assert!(reason.contains("only RSA")),
+            res => panic!("unexpected result {:?}", res.map(|_| ())),
+        }
     }
 }
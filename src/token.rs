@@ -0,0 +1,218 @@
+use bytes::{Buf, BufMut};
+use rand::{thread_rng, Rng};
+use ring::{digest, hmac};
+
+use std::io::Cursor;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+// Mints the tokens handed out in Retry and NEW_TOKEN frames and checks the ones clients bring
+// back. Servers behind the same address need to share a store, or at least its keys, for tokens
+// minted by one to be accepted by the others.
+pub trait TokenStore: Send + Sync {
+    fn mint(&self, addr: &SocketAddr) -> Vec<u8>;
+    fn validate(&self, token: &[u8], addr: &SocketAddr) -> bool;
+}
+
+// Tokens are signed with the newest key; keys rotated out keep validating until every token
+// they signed has expired. A fleet rolls out a new key in two steps: every server stages it, so
+// tokens signed with it are accepted everywhere, and only then do they rotate to it.
+pub struct HmacTokenStore {
+    lifetime: Duration,
+    keys: Mutex<Vec<TokenKey>>,
+}
+
+impl HmacTokenStore {
+    pub fn new(secret: &[u8], lifetime: Duration) -> Self {
+        Self {
+            lifetime,
+            keys: Mutex::new(vec![TokenKey::new(secret, lifetime)]),
+        }
+    }
+
+    pub fn random() -> Self {
+        let mut secret = [0; 32];
+        thread_rng().fill_bytes(&mut secret);
+        Self::new(&secret, Duration::from_secs(DEFAULT_LIFETIME))
+    }
+
+    // Accepts tokens signed with the key without signing any with it yet
+    pub fn stage(&self, secret: &[u8]) {
+        let mut key = TokenKey::new(secret, self.lifetime);
+        key.staged = true;
+        self.keys.lock().unwrap().push(key);
+    }
+
+    // Starts signing with the key, promoting it if it was staged
+    pub fn rotate(&self, secret: &[u8]) {
+        let now = now();
+        let mut keys = self.keys.lock().unwrap();
+        keys.retain(|key| key.retired.map_or(true, |retired| now <= retired));
+        let fingerprint = fingerprint(secret);
+        let staged = keys
+            .iter()
+            .position(|key| key.staged && key.fingerprint == fingerprint);
+        let mut next = match staged {
+            Some(index) => keys.remove(index),
+            None => TokenKey::new(secret, self.lifetime),
+        };
+        let retired = now.saturating_add(self.lifetime.as_secs());
+        for key in keys
+            .iter_mut()
+            .filter(|key| !key.staged && key.retired.is_none())
+        {
+            key.retired = Some(retired);
+        }
+        next.staged = false;
+        keys.insert(0, next);
+    }
+}
+
+impl TokenStore for HmacTokenStore {
+    // Staged keys always come after the one rotated in last
+    fn mint(&self, addr: &SocketAddr) -> Vec<u8> {
+        self.keys.lock().unwrap()[0].mint(addr)
+    }
+
+    fn validate(&self, token: &[u8], addr: &SocketAddr) -> bool {
+        let now = now();
+        let keys = self.keys.lock().unwrap();
+        keys.iter()
+            .filter(|key| key.retired.map_or(true, |retired| now <= retired))
+            .any(|key| key.validate(token, addr))
+    }
+}
+
+struct TokenKey {
+    key: hmac::SigningKey,
+    // Tells a staged key apart when it is rotated in, without holding on to the secret
+    fingerprint: Vec<u8>,
+    lifetime: Duration,
+    staged: bool,
+    // When the last token signed with it expires, once it's been rotated out
+    retired: Option<u64>,
+}
+
+impl TokenKey {
+    fn new(secret: &[u8], lifetime: Duration) -> Self {
+        Self {
+            key: hmac::SigningKey::new(&digest::SHA256, secret),
+            fingerprint: fingerprint(secret),
+            lifetime,
+            staged: false,
+            retired: None,
+        }
+    }
+
+    fn mint(&self, addr: &SocketAddr) -> Vec<u8> {
+        let issued = now();
+        let tag = hmac::sign(&self.key, &signed_data(addr, issued));
+        let mut token = Vec::with_capacity(8 + tag.as_ref().len());
+        token.put_u64_be(issued);
+        token.extend_from_slice(tag.as_ref());
+        token
+    }
+
+    fn validate(&self, token: &[u8], addr: &SocketAddr) -> bool {
+        if token.len() < 8 {
+            return false;
+        }
+        let issued = Cursor::new(&token[..8]).get_u64_be();
+        if now() > issued.saturating_add(self.lifetime.as_secs()) {
+            return false;
+        }
+        hmac::verify_with_own_key(&self.key, &signed_data(addr, issued), &token[8..]).is_ok()
+    }
+}
+
+fn signed_data(addr: &SocketAddr, issued: u64) -> Vec<u8> {
+    // Only the IP address is covered so tokens survive the client picking a new source port
+    let mut data = Vec::with_capacity(16 + 8);
+    match addr.ip() {
+        IpAddr::V4(ip) => data.extend_from_slice(&ip.octets()),
+        IpAddr::V6(ip) => data.extend_from_slice(&ip.octets()),
+    }
+    data.put_u64_be(issued);
+    data
+}
+
+fn fingerprint(secret: &[u8]) -> Vec<u8> {
+    digest::digest(&digest::SHA256, secret).as_ref().to_vec()
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+const DEFAULT_LIFETIME: u64 = 24 * 60 * 60;
+
+#[cfg(test)]
+mod tests {
+    use super::{HmacTokenStore, TokenKey, TokenStore};
+    use std::net::SocketAddr;
+    use std::time::Duration;
+
+    #[test]
+    fn test_token_validation() {
+        let key = TokenKey::new(b"some secret key", Duration::from_secs(60));
+        let addr = "192.0.2.1:4433".parse::<SocketAddr>().unwrap();
+        let token = key.mint(&addr);
+        assert!(key.validate(&token, &addr));
+        assert!(key.validate(&token, &"192.0.2.1:5544".parse().unwrap()));
+        assert!(!key.validate(&token, &"192.0.2.2:4433".parse().unwrap()));
+        assert!(!key.validate(&token[..8], &addr));
+
+        let other = TokenKey::new(b"another secret key", Duration::from_secs(60));
+        assert!(!other.validate(&token, &addr));
+    }
+
+    #[test]
+    fn test_key_rotation() {
+        let lifetime = Duration::from_secs(60);
+        let addr = "192.0.2.1:4433".parse::<SocketAddr>().unwrap();
+        let first = HmacTokenStore::new(b"first secret", lifetime);
+        let second = HmacTokenStore::new(b"first secret", lifetime);
+        let old = first.mint(&addr);
+        assert!(second.validate(&old, &addr));
+
+        // Tokens from before a rotation stay good, and the rest of the fleet can follow later
+        first.rotate(b"second secret");
+        let new = first.mint(&addr);
+        assert!(first.validate(&old, &addr));
+        assert!(first.validate(&new, &addr));
+        assert!(!second.validate(&new, &addr));
+        second.rotate(b"second secret");
+        assert!(second.validate(&new, &addr));
+        assert!(second.validate(&old, &addr));
+
+        let other = HmacTokenStore::new(b"second secret", lifetime);
+        assert!(!other.validate(&old, &addr));
+    }
+
+    #[test]
+    fn test_staged_rotation() {
+        let lifetime = Duration::from_secs(60);
+        let addr = "192.0.2.1:4433".parse::<SocketAddr>().unwrap();
+        let first = HmacTokenStore::new(b"first secret", lifetime);
+        let second = HmacTokenStore::new(b"first secret", lifetime);
+
+        // Staged keys are accepted, but tokens are still signed with the current one
+        first.stage(b"second secret");
+        second.stage(b"second secret");
+        let old = first.mint(&addr);
+        assert!(second.validate(&old, &addr));
+        assert!(!HmacTokenStore::new(b"second secret", lifetime).validate(&old, &addr));
+
+        // Servers that rotate first mint tokens the others already take
+        first.rotate(b"second secret");
+        let new = first.mint(&addr);
+        assert!(second.validate(&new, &addr));
+        assert!(first.validate(&old, &addr));
+        assert!(HmacTokenStore::new(b"second secret", lifetime).validate(&new, &addr));
+        assert_eq!(first.keys.lock().unwrap().len(), 2);
+    }
+}
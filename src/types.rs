@@ -1,4 +1,5 @@
 use rand::{self, Rng};
+use streams::Dir;
 
 use std::fmt;
 use std::ops::Deref;
@@ -22,6 +23,16 @@ impl ConnectionId {
         res
     }
 
+    pub fn random<R: Rng + ?Sized>(rng: &mut R, len: u8) -> Self {
+        debug_assert!(len == 0 || (len > 3 && len < 19));
+        let mut res = Self {
+            len,
+            bytes: [0; 18],
+        };
+        rng.fill_bytes(&mut res.bytes[..len as usize]);
+        res
+    }
+
     pub fn cil(&self) -> u8 {
         if self.len > 0 {
             self.len - 3
@@ -50,12 +61,7 @@ impl Deref for ConnectionId {
 
 impl rand::distributions::Distribution<ConnectionId> for rand::distributions::Standard {
     fn sample<R: Rng + ?Sized>(&self, rng: &mut R) -> ConnectionId {
-        let mut res = ConnectionId {
-            len: GENERATED_CID_LENGTH,
-            bytes: [0; 18],
-        };
-        rng.fill_bytes(&mut res.bytes[..res.len as usize]);
-        res
+        ConnectionId::random(rng, GENERATED_CID_LENGTH)
     }
 }
 
@@ -83,4 +89,63 @@ impl Side {
 
 impl Copy for Side {}
 
+// The two low bits of a stream ID say who opened it and whether it's unidirectional
+#[derive(Clone, Copy, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
+pub struct StreamId(pub u64);
+
+impl StreamId {
+    pub fn new(initiator: Side, dir: Dir, index: u64) -> Self {
+        StreamId(index << 2 | dir.to_bit() | initiator.to_bit())
+    }
+
+    pub fn initiator(self) -> Side {
+        if self.0 & 1 == 0 {
+            Side::Client
+        } else {
+            Side::Server
+        }
+    }
+
+    pub fn dir(self) -> Dir {
+        if self.0 & 2 == 0 {
+            Dir::Bidi
+        } else {
+            Dir::Uni
+        }
+    }
+
+    pub fn index(self) -> u64 {
+        self.0 >> 2
+    }
+
+    pub fn next(self) -> Self {
+        StreamId(self.0 + 4)
+    }
+}
+
+impl fmt::Display for StreamId {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
 pub const GENERATED_CID_LENGTH: u8 = 8;
+
+#[cfg(test)]
+mod tests {
+    use super::{Side, StreamId};
+    use streams::Dir;
+
+    #[test]
+    fn test_stream_id() {
+        let id = StreamId::new(Side::Server, Dir::Uni, 5);
+        assert_eq!(id, StreamId(23));
+        assert_eq!(id.initiator(), Side::Server);
+        assert_eq!(id.dir(), Dir::Uni);
+        assert_eq!(id.index(), 5);
+        assert_eq!(id.next(), StreamId::new(Side::Server, Dir::Uni, 6));
+
+        let id = StreamId(4);
+        assert_eq!((id.initiator(), id.dir(), id.index()), (Side::Client, Dir::Bidi, 1));
+    }
+}